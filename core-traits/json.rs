@@ -0,0 +1,82 @@
+// Copyright 2018 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! The JSON mapping of query results, described in `mentat::json`.
+//!
+//! `TypedValue`'s own `Serialize` is the format transactions are synced in, and can't change.
+//! Values are written in the JSON mapping when they're inside a `Binding`, or wrapped in
+//! `JsonValue`.
+
+use chrono::SecondsFormat;
+
+use serde::ser::{Serialize, SerializeMap, Serializer};
+
+use crate::{Binding, StructuredMap, TypedValue};
+
+fn tagged<S, V>(serializer: S, tag: &str, value: &V) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+    V: Serialize + ?Sized,
+{
+    let mut map = serializer.serialize_map(Some(1))?;
+    map.serialize_entry(tag, value)?;
+    map.end()
+}
+
+/// A value written in the JSON mapping: as plain JSON where JSON can represent it, and as an
+/// object with a single tag otherwise.
+pub struct JsonValue<'a>(pub &'a TypedValue);
+
+impl<'a> Serialize for JsonValue<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match *self.0 {
+            TypedValue::Ref(e) => tagged(serializer, "ref", &e),
+            TypedValue::Boolean(b) => serializer.serialize_bool(b),
+            TypedValue::Long(l) => serializer.serialize_i64(l),
+            TypedValue::Double(d) => {
+                let d = d.into_inner();
+                if d.is_nan() {
+                    tagged(serializer, "double", "NaN")
+                } else if d.is_infinite() && d > 0.0 {
+                    tagged(serializer, "double", "Infinity")
+                } else if d.is_infinite() {
+                    tagged(serializer, "double", "-Infinity")
+                } else {
+                    serializer.serialize_f64(d)
+                }
+            }
+            TypedValue::Instant(ref t) => tagged(
+                serializer,
+                "instant",
+                &t.to_rfc3339_opts(SecondsFormat::Micros, true),
+            ),
+            TypedValue::String(ref s) => serializer.serialize_str(s),
+            TypedValue::Keyword(ref k) => tagged(serializer, "keyword", &k.to_string()),
+            TypedValue::Uuid(ref u) => tagged(serializer, "uuid", &u.to_hyphenated().to_string()),
+        }
+    }
+}
+
+impl Serialize for Binding {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match *self {
+            Binding::Scalar(ref v) => JsonValue(v).serialize(serializer),
+            Binding::Vec(ref vs) => serializer.collect_seq(vs.iter()),
+            Binding::Map(ref m) => m.serialize(serializer),
+        }
+    }
+}
+
+/// Pulled entities are objects keyed by keyword strings.
+impl Serialize for StructuredMap {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(self.0.iter().map(|(k, v)| (k.to_string(), v)))
+    }
+}
@@ -12,6 +12,8 @@ extern crate chrono;
 extern crate enum_set;
 extern crate indexmap;
 extern crate ordered_float;
+#[cfg(feature = "json")]
+extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 extern crate edn;
@@ -49,6 +51,8 @@ use edn::entities::{
     AttributePlace, EntidOrIdent, EntityPlace, TransactableValueMarker, ValuePlace,
 };
 
+#[cfg(feature = "json")]
+pub mod json;
 mod value_type_set;
 pub mod values;
 
@@ -20,6 +20,14 @@ pub trait CachedAttributes {
     fn is_attribute_cached_forward(&self, entid: Entid) -> bool;
     fn has_cached_attributes(&self) -> bool;
 
+    /// Whether the forward cache of `attribute` knows `entid`'s values, even if there are none.
+    /// A cache kept within a memory budget might only hold some entities; every other answer from
+    /// it must come from the store instead.
+    fn is_entity_cached_forward(&self, attribute: Entid, entid: Entid) -> bool {
+        let _ = entid;
+        self.is_attribute_cached_forward(attribute)
+    }
+
     fn get_values_for_entid(
         &self,
         schema: &Schema,
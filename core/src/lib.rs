@@ -13,6 +13,8 @@ extern crate enum_set;
 extern crate failure;
 extern crate indexmap;
 extern crate ordered_float;
+#[cfg(feature = "json")]
+extern crate serde;
 extern crate uuid;
 
 extern crate core_traits;
@@ -41,6 +43,7 @@ mod sql_types;
 mod tx_report;
 /// Core types defining a Mentat knowledge base.
 mod types;
+mod views;
 
 pub use crate::tx_report::TxReport;
 
@@ -48,6 +51,8 @@ pub use crate::types::ValueTypeTag;
 
 pub use crate::sql_types::{SQLTypeAffinity, SQLValueType, SQLValueTypeSet};
 
+pub use crate::views::{DatomsView, DatomsViewTable};
+
 /// Map `Keyword` idents (`:db/ident`) to positive integer entids (`1`).
 pub type IdentMap = BTreeMap<Keyword, Entid>;
 
@@ -12,8 +12,14 @@
 
 use std::collections::BTreeMap;
 
+#[cfg(feature = "json")]
+use serde::ser::{Serialize, SerializeMap, Serializer};
+
 use core_traits::Entid;
 
+#[cfg(feature = "json")]
+use core_traits::{json::JsonValue, TypedValue};
+
 use crate::{DateTime, Utc};
 
 /// A transaction report summarizes an applied transaction.
@@ -32,3 +38,18 @@ pub struct TxReport {
     /// literal tempids to all unify to a single freshly allocated entid.)
     pub tempids: BTreeMap<String, Entid>,
 }
+
+/// `{"tx_id": 268435457, "tx_instant": {"instant": …}, "tempids": {"a": 65536}}`.
+#[cfg(feature = "json")]
+impl Serialize for TxReport {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(3))?;
+        map.serialize_entry("tx_id", &self.tx_id)?;
+        map.serialize_entry(
+            "tx_instant",
+            &JsonValue(&TypedValue::Instant(self.tx_instant)),
+        )?;
+        map.serialize_entry("tempids", &self.tempids)?;
+        map.end()
+    }
+}
@@ -0,0 +1,125 @@
+// Copyright 2018 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+use core_traits::Entid;
+
+/// A point-in-time or incremental view of the datoms in a store.
+///
+/// `Current` is the store as it is now. `AsOf(tx)` is the store as it was immediately after
+/// transaction `tx`, reconstructed from the `transactions` log. `Since(tx)` is the current store
+/// restricted to datoms asserted after transaction `tx`. `History` is every assertion and
+/// retraction in the log, each with its `added` flag.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialOrd, PartialEq)]
+pub enum DatomsView {
+    Current,
+    AsOf(Entid),
+    Since(Entid),
+    History,
+}
+
+impl Default for DatomsView {
+    fn default() -> DatomsView {
+        DatomsView::Current
+    }
+}
+
+/// The datom-shaped tables that can be viewed through a `DatomsView`.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialOrd, PartialEq)]
+pub enum DatomsViewTable {
+    Datoms,
+    FulltextDatoms,
+    AllDatoms,
+}
+
+impl DatomsViewTable {
+    pub fn name(self) -> &'static str {
+        match self {
+            DatomsViewTable::Datoms => "datoms",
+            DatomsViewTable::FulltextDatoms => "fulltext_datoms",
+            DatomsViewTable::AllDatoms => "all_datoms",
+        }
+    }
+}
+
+impl DatomsView {
+    pub fn is_current(&self) -> bool {
+        *self == DatomsView::Current
+    }
+
+    /// Whether this view's rows have an `added` column. Only the history view does: it includes
+    /// retractions as well as assertions.
+    pub fn has_added(&self) -> bool {
+        *self == DatomsView::History
+    }
+
+    /// Return SQL that can be used in a `FROM` clause in place of `table`.  For the current view
+    /// this is just the table name; otherwise it's a parenthesized subquery producing the columns
+    /// `e`, `a`, `v`, `tx`, and `value_type_tag`, and for the history view `added`.
+    pub fn sql(&self, table: DatomsViewTable) -> String {
+        match *self {
+            DatomsView::Current => table.name().to_string(),
+            DatomsView::Since(tx) => format!(
+                "(SELECT e, a, v, tx, value_type_tag FROM {} WHERE tx > {})",
+                table.name(),
+                tx
+            ),
+            DatomsView::AsOf(tx) => format!("({})", from_log(table, &as_of_datoms(tx), &[])),
+            DatomsView::History => format!(
+                "({})",
+                from_log(
+                    table,
+                    "SELECT e, a, v, tx, value_type_tag, added FROM transactions",
+                    &["added"]
+                )
+            ),
+        }
+    }
+}
+
+// Fulltext values are stored in `transactions` as integer rowids into `fulltext_values`, tagged
+// as strings; every other string is stored as text.  That's how we tell them apart without the
+// `index_fulltext` flag that `datoms` carries.
+const FULLTEXT_CONDITION: &str = "value_type_tag = 10 AND typeof(v) = 'integer'";
+
+fn as_of_datoms(tx: Entid) -> String {
+    // The most recent assertion or retraction of each datom at or before `tx` tells us whether
+    // that datom was present.  SQLite takes the bare `added` column from the row with `MAX(tx)`.
+    format!(
+        "SELECT e, a, v, tx, value_type_tag FROM \
+         (SELECT e, a, v, value_type_tag, MAX(tx) AS tx, added FROM transactions \
+         WHERE tx <= {} GROUP BY e, a, v, value_type_tag) WHERE added = 1",
+        tx
+    )
+}
+
+/// Shape `datoms`, a query over the transaction log producing `e`, `a`, `v`, `tx`,
+/// `value_type_tag`, and then `extra` columns, like `table`: with fulltext rowids left alone,
+/// replaced by their text, or both.
+fn from_log(table: DatomsViewTable, datoms: &str, extra: &[&str]) -> String {
+    let extra_h: String = extra
+        .iter()
+        .map(|c| format!(", h.{} AS {}", c, c))
+        .collect();
+    let extra: String = extra.iter().map(|c| format!(", {}", c)).collect();
+    let fulltext = format!(
+        "SELECT h.e AS e, h.a AS a, fulltext_values.text AS v, h.tx AS tx, h.value_type_tag AS value_type_tag{} \
+         FROM ({}) AS h, fulltext_values \
+         WHERE h.value_type_tag = 10 AND typeof(h.v) = 'integer' AND h.v = fulltext_values.rowid",
+        extra_h, datoms
+    );
+    match table {
+        DatomsViewTable::Datoms => datoms.to_string(),
+        DatomsViewTable::FulltextDatoms => fulltext,
+        DatomsViewTable::AllDatoms => format!(
+            "SELECT e, a, v, tx, value_type_tag{} FROM ({}) WHERE NOT ({}) UNION ALL {}",
+            extra, datoms, FULLTEXT_CONDITION, fulltext
+        ),
+    }
+}
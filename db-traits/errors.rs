@@ -293,6 +293,33 @@ pub enum DbErrorKind {
     #[fail(display = "Supplied an invalid transaction range")]
     TimelinesInvalidRange,
 
+    #[fail(display = "Replayed transaction {} as transaction {}", _0, _1)]
+    TimelinesReplayMismatch(Entid, Entid),
+
+    #[fail(display = "Can't undo transaction {}: it has been synced", _0)]
+    TimelinesUndoSynced(Entid),
+
+    #[fail(display = "no branch named {}", _0)]
+    UnknownBranch(String),
+
+    #[fail(display = "a branch named {} already exists", _0)]
+    BranchExists(String),
+
+    #[fail(display = "branch {} is checked out", _0)]
+    BranchCheckedOut(String),
+
+    #[fail(
+        display = "can't merge branch {}: it and main both changed (entity, attribute) pairs {:?}",
+        _0, _1
+    )]
+    BranchMergeConflict(String, Vec<(Entid, Entid)>),
+
+    #[fail(
+        display = "transaction {} is before the history horizon {}, and its history has been pruned",
+        _0, _1
+    )]
+    HistoryPruned(Entid, Entid),
+
     // It would be better to capture the underlying `rusqlite::Error`, but that type doesn't
     // implement many useful traits, including `Clone`, `Eq`, and `PartialEq`.
     #[fail(display = "SQL error: {}", _0)]
@@ -0,0 +1,723 @@
+// Copyright 2018 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Named branches of the store.
+//!
+//! A branch is forked from the main branch at a transaction, its base, and is otherwise a timeline
+//! of its own. Whichever branch is checked out lives on the main timeline, so that transacting,
+//! querying and caching all work against it unchanged. Switching to a branch rewinds the main
+//! branch's transactions after the branch's base onto a parking timeline, and replays the branch's
+//! transactions with their original IDs; switching back does the reverse. Transactions on a
+//! branch therefore never touch the main branch's log, and deleting a branch leaves no trace.
+//!
+//! Merging a branch replays its transactions onto the main branch as new transactions, giving the
+//! entities it created new entids. It fails if the branch and the main branch both changed an
+//! attribute of an entity that existed at the base.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use rusqlite;
+
+use db_traits::errors::{DbErrorKind, Result};
+
+use core_traits::{Entid, KnownEntid, TypedValue};
+
+use mentat_core::util::Either;
+use mentat_core::{Schema, TxReport};
+
+use edn::entities::TempId;
+use edn::InternSet;
+
+use crate::db;
+use crate::entids;
+use crate::history::ensure_history_from;
+use crate::internal_types::{Term, TermWithTempIds};
+use crate::timelines::{move_transactions_to, replay, rewind, terms_for};
+use crate::tx::transact_terms;
+use crate::types::PartitionMap;
+use crate::watcher::TransactWatcher;
+
+/// The branch that the store starts out on. It can't be forked, merged or deleted.
+pub const MAIN_BRANCH: &str = "main";
+
+/// A branch of the store.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Branch {
+    pub name: String,
+
+    /// The transaction on the main branch that the branch was forked at.
+    pub base: Entid,
+
+    pub checked_out: bool,
+}
+
+struct BranchRow {
+    timeline: Entid,
+    undo_timeline: Entid,
+    base: Entid,
+}
+
+/// List the store's branches, other than the main branch.
+pub fn branches(conn: &rusqlite::Connection) -> Result<Vec<Branch>> {
+    let mut stmt = conn.prepare("SELECT name, base, checked_out FROM branches ORDER BY name")?;
+    let branches = stmt
+        .query_and_then(rusqlite::params![], |row| -> Result<Branch> {
+            Ok(Branch {
+                name: row.get(0)?,
+                base: row.get(1)?,
+                checked_out: row.get(2)?,
+            })
+        })?
+        .collect();
+    branches
+}
+
+/// The name of the checked out branch.
+pub fn current_branch(conn: &rusqlite::Connection) -> Result<String> {
+    Ok(branches(conn)?
+        .into_iter()
+        .find(|branch| branch.checked_out)
+        .map_or_else(|| MAIN_BRANCH.to_string(), |branch| branch.name))
+}
+
+fn branch(conn: &rusqlite::Connection, name: &str) -> Result<BranchRow> {
+    let mut stmt =
+        conn.prepare("SELECT timeline, undo_timeline, base FROM branches WHERE name = ?")?;
+    let mut rows = stmt.query_and_then(&[&name], |row| -> Result<BranchRow> {
+        Ok(BranchRow {
+            timeline: row.get(0)?,
+            undo_timeline: row.get(1)?,
+            base: row.get(2)?,
+        })
+    })?;
+    match rows.next() {
+        Some(row) => row,
+        None => bail!(DbErrorKind::UnknownBranch(name.to_string())),
+    }
+}
+
+/// Fail unless the main branch is checked out. Syncing, exporting and pruning history only make
+/// sense for the main branch's log.
+pub fn ensure_main_checked_out(conn: &rusqlite::Connection) -> Result<()> {
+    let current = current_branch(conn)?;
+    if current != MAIN_BRANCH {
+        bail!(DbErrorKind::BranchCheckedOut(current));
+    }
+    Ok(())
+}
+
+/// Fork a new branch called `name` from the main branch at transaction `base`. The main branch
+/// must be checked out.
+pub fn fork_branch(conn: &rusqlite::Connection, name: &str, base: Entid) -> Result<()> {
+    if name == MAIN_BRANCH || branch(conn, name).is_ok() {
+        bail!(DbErrorKind::BranchExists(name.to_string()));
+    }
+    ensure_main_checked_out(conn)?;
+
+    let on_main: bool = conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM timelined_transactions WHERE timeline = ? AND tx = ?)",
+        &[&crate::TIMELINE_MAIN, &base],
+        |row| row.get(0),
+    )?;
+    if !on_main {
+        bail!(DbErrorKind::TimelinesInvalidRange);
+    }
+
+    // Branch timelines count down from below the reserved ones, each followed by the timeline its
+    // undone transactions wait on while it isn't checked out; synced timelines count up.
+    let timeline: Entid = conn.query_row(
+        "SELECT IFNULL(MIN(undo_timeline), ?) - 1 FROM branches",
+        &[&crate::TIMELINE_PARKED_UNDO],
+        |row| row.get(0),
+    )?;
+    conn.execute(
+        "INSERT INTO branches (name, timeline, undo_timeline, base) VALUES (?, ?, ?, ?)",
+        rusqlite::params![name, timeline, timeline - 1, base],
+    )?;
+    Ok(())
+}
+
+/// Delete the branch called `name`, and its transactions. It must not be checked out.
+pub fn delete_branch(conn: &rusqlite::Connection, name: &str) -> Result<()> {
+    if name == MAIN_BRANCH || current_branch(conn)? == name {
+        bail!(DbErrorKind::BranchCheckedOut(name.to_string()));
+    }
+    let row = branch(conn, name)?;
+    conn.execute(
+        "DELETE FROM timelined_transactions WHERE timeline IN (?, ?)",
+        &[&row.timeline, &row.undo_timeline],
+    )?;
+    conn.execute("DELETE FROM branches WHERE name = ?", &[&name])?;
+    Ok(())
+}
+
+/// The distinct transactions on `timeline` after `after`, in ascending order.
+fn txs_after(conn: &rusqlite::Connection, timeline: Entid, after: Entid) -> Result<Vec<Entid>> {
+    let mut stmt = conn.prepare(
+        "SELECT DISTINCT tx FROM timelined_transactions WHERE timeline = ? AND tx > ? ORDER BY tx ASC",
+    )?;
+    let txs = stmt
+        .query_and_then(&[&timeline, &after], |row| -> Result<Entid> {
+            Ok(row.get(0)?)
+        })?
+        .collect();
+    txs
+}
+
+/// Rewind the main timeline's transactions after `base` onto `timeline`.
+fn rewind_onto<W>(
+    conn: &rusqlite::Connection,
+    schema: &Schema,
+    partition_map: &PartitionMap,
+    base: Entid,
+    timeline: Entid,
+    watcher: W,
+) -> Result<(Option<Schema>, W)>
+where
+    W: TransactWatcher,
+{
+    let mut txs = txs_after(conn, crate::TIMELINE_MAIN, base)?;
+    txs.reverse();
+    if let Some(&first) = txs.last() {
+        ensure_history_from(conn, first)?;
+    }
+    let (schema, watcher) = rewind(conn, schema, partition_map, &txs, watcher)?;
+    move_transactions_to(conn, &txs, crate::TIMELINE_MAIN, timeline)?;
+    Ok((schema, watcher))
+}
+
+/// Transactions can't be undone past the checked out branch's base, nor, on the main branch, past
+/// the base of any branch: the branch would lose its footing. Returns the last transaction that
+/// can't be undone.
+pub(crate) fn undo_floor(conn: &rusqlite::Connection) -> Result<Entid> {
+    let current = current_branch(conn)?;
+    if current != MAIN_BRANCH {
+        return Ok(branch(conn, &current)?.base);
+    }
+    let floor = conn.query_row(
+        "SELECT MAX(IFNULL(MAX(base), ?1), ?1) FROM branches",
+        &[&crate::TX0],
+        |row| row.get(0),
+    )?;
+    Ok(floor)
+}
+
+/// The timeline a branch's undone transactions wait on while it isn't checked out.
+fn undo_timeline_of(conn: &rusqlite::Connection, name: &str) -> Result<Entid> {
+    if name == MAIN_BRANCH {
+        Ok(crate::TIMELINE_PARKED_UNDO)
+    } else {
+        Ok(branch(conn, name)?.undo_timeline)
+    }
+}
+
+fn move_timeline(conn: &rusqlite::Connection, timeline: Entid, new_timeline: Entid) -> Result<()> {
+    conn.execute(
+        "UPDATE timelined_transactions SET timeline = ? WHERE timeline = ?",
+        &[&new_timeline, &timeline],
+    )?;
+    Ok(())
+}
+
+/// Check out the branch called `name`, rewinding the checked out branch's changes and replaying
+/// the named branch's. Each branch keeps its own undone transactions, to be redone once it's
+/// checked out again.
+pub fn switch_branch<W>(
+    conn: &rusqlite::Connection,
+    schema: &Schema,
+    partition_map: PartitionMap,
+    name: &str,
+    watcher: W,
+) -> Result<(Option<Schema>, PartitionMap, W)>
+where
+    W: TransactWatcher,
+{
+    let current = current_branch(conn)?;
+    if current == name {
+        return Ok((None, partition_map, watcher));
+    }
+    let target = if name == MAIN_BRANCH {
+        None
+    } else {
+        Some(branch(conn, name)?)
+    };
+
+    move_timeline(
+        conn,
+        crate::TIMELINE_UNDO,
+        undo_timeline_of(conn, &current)?,
+    )?;
+
+    let mut last_schema: Option<Schema> = None;
+    let mut partition_map = partition_map;
+    let mut watcher = watcher;
+
+    // Go back to the main branch.
+    if current != MAIN_BRANCH {
+        let row = branch(conn, &current)?;
+        let (new_schema, next_watcher) = rewind_onto(
+            conn,
+            schema,
+            &partition_map,
+            row.base,
+            row.timeline,
+            watcher,
+        )?;
+        last_schema = new_schema.or(last_schema);
+        partition_map = db::read_partition_map(conn)?;
+
+        let parked = txs_after(conn, crate::TIMELINE_PARKED, row.base)?;
+        let (new_schema, next_partition_map, next_watcher) = replay(
+            conn,
+            last_schema.as_ref().unwrap_or(schema),
+            partition_map,
+            crate::TIMELINE_PARKED,
+            &parked,
+            next_watcher,
+        )?;
+        last_schema = new_schema.or(last_schema);
+        partition_map = next_partition_map;
+        watcher = next_watcher;
+    }
+
+    // And on to the named branch.
+    if let Some(row) = target {
+        let (new_schema, next_watcher) = rewind_onto(
+            conn,
+            last_schema.as_ref().unwrap_or(schema),
+            &partition_map,
+            row.base,
+            crate::TIMELINE_PARKED,
+            watcher,
+        )?;
+        last_schema = new_schema.or(last_schema);
+        partition_map = db::read_partition_map(conn)?;
+
+        let txs = txs_after(conn, row.timeline, row.base)?;
+        let (new_schema, next_partition_map, next_watcher) = replay(
+            conn,
+            last_schema.as_ref().unwrap_or(schema),
+            partition_map,
+            row.timeline,
+            &txs,
+            next_watcher,
+        )?;
+        last_schema = new_schema.or(last_schema);
+        partition_map = next_partition_map;
+        watcher = next_watcher;
+    }
+
+    move_timeline(conn, undo_timeline_of(conn, name)?, crate::TIMELINE_UNDO)?;
+    conn.execute("UPDATE branches SET checked_out = (name = ?)", &[&name])?;
+    Ok((last_schema, partition_map, watcher))
+}
+
+/// The first entid in each partition that hadn't been allocated on the main branch as of `base`.
+fn unallocated_at(conn: &rusqlite::Connection, base: Entid) -> Result<Vec<(Entid, Entid)>> {
+    let mut stmt = conn.prepare(
+        "SELECT
+             (SELECT IFNULL(MAX(e) + 1, known_parts.start) FROM timelined_transactions
+              WHERE timeline = ? AND tx <= ? AND e >= known_parts.start AND e <= known_parts.end),
+             known_parts.end
+         FROM known_parts",
+    )?;
+    let parts = stmt
+        .query_and_then(
+            &[&crate::TIMELINE_MAIN, &base],
+            |row| -> Result<(Entid, Entid)> { Ok((row.get(0)?, row.get(1)?)) },
+        )?
+        .collect();
+    parts
+}
+
+/// Merge the branch called `name` into the main branch, which must be checked out, and delete
+/// it. Each of the branch's transactions is transacted anew on the main branch; their reports are
+/// returned in order.
+pub fn merge_branch<W>(
+    conn: &rusqlite::Connection,
+    schema: &Schema,
+    partition_map: PartitionMap,
+    name: &str,
+    watcher: W,
+) -> Result<(Vec<TxReport>, Option<Schema>, PartitionMap, W)>
+where
+    W: TransactWatcher,
+{
+    ensure_main_checked_out(conn)?;
+    let row = branch(conn, name)?;
+
+    // Entities created on the branch might share entids with those created on the main branch
+    // since the base, so they're transacted as tempids.
+    let unallocated = unallocated_at(conn, row.base)?;
+    let created_on_branch = |e: Entid| unallocated.iter().any(|&(next, end)| e >= next && e <= end);
+
+    let conflicts: Vec<(Entid, Entid)> = {
+        let mut stmt = conn.prepare(
+            "SELECT DISTINCT b.e, b.a FROM timelined_transactions AS b, timelined_transactions AS m
+             WHERE b.timeline = ? AND m.timeline = ? AND m.tx > ? AND b.e = m.e AND b.a = m.a
+             ORDER BY b.e, b.a",
+        )?;
+        let pairs: Vec<(Entid, Entid)> = stmt
+            .query_and_then(
+                &[&row.timeline, &crate::TIMELINE_MAIN, &row.base],
+                |row| -> Result<(Entid, Entid)> { Ok((row.get(0)?, row.get(1)?)) },
+            )?
+            .collect::<Result<_>>()?;
+        pairs
+            .into_iter()
+            .filter(|&(e, _)| !created_on_branch(e))
+            .collect()
+    };
+    if !conflicts.is_empty() {
+        bail!(DbErrorKind::BranchMergeConflict(
+            name.to_string(),
+            conflicts
+        ));
+    }
+
+    let mut reports = vec![];
+    let mut last_schema: Option<Schema> = None;
+    let mut partition_map = partition_map;
+    let mut watcher = watcher;
+    // Branch entids to the entids they were given on the main branch.
+    let mut merged: BTreeMap<Entid, Entid> = BTreeMap::new();
+
+    for tx in txs_after(conn, row.timeline, row.base)? {
+        let schema = last_schema.as_ref().unwrap_or(schema);
+        let merged_tx = partition_map[":db.part/tx"].next_entid();
+        merged.insert(tx, merged_tx);
+
+        let mut tempids: InternSet<TempId> = InternSet::new();
+        let mut created: BTreeSet<Entid> = BTreeSet::new();
+        let mut place = |e: Entid| match merged.get(&e) {
+            Some(&e) => Either::Left(KnownEntid(e)),
+            None if created_on_branch(e) => {
+                created.insert(e);
+                Either::Right(tempids.intern(TempId::External(e.to_string())))
+            }
+            None => Either::Left(KnownEntid(e)),
+        };
+
+        let mut terms: Vec<TermWithTempIds> = vec![];
+        for Term::AddOrRetract(op, KnownEntid(e), a, v) in
+            terms_for(conn, schema, tx, row.timeline, false)?
+        {
+            // The merged transaction gets an instant of its own.
+            if e == tx && a == entids::DB_TX_INSTANT {
+                continue;
+            }
+            let a = merged.get(&a).cloned().unwrap_or(a);
+            let e = place(e);
+            let v = match v {
+                TypedValue::Ref(v) => match place(v) {
+                    Either::Left(v) => Either::Left(TypedValue::Ref(v.0)),
+                    Either::Right(tempid) => Either::Right(tempid),
+                },
+                v => Either::Left(v),
+            };
+            terms.push(Term::AddOrRetract(op, e, a, v));
+        }
+
+        let (report, next_partition_map, new_schema, next_watcher) =
+            transact_terms(conn, partition_map, schema, schema, watcher, terms, tempids)?;
+        watcher = next_watcher;
+        partition_map = next_partition_map;
+        if new_schema.is_some() {
+            last_schema = new_schema;
+        }
+        for e in created {
+            if let Some(&merged_e) = report.tempids.get(&e.to_string()) {
+                merged.insert(e, merged_e);
+            }
+        }
+        reports.push(report);
+    }
+
+    delete_branch(conn, name)?;
+    Ok((reports, last_schema, partition_map, watcher))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::borrow::Borrow;
+
+    use edn::Keyword;
+    use mentat_core::HasSchema;
+
+    use crate::debug::TestConn;
+    use crate::watcher::NullWatcher;
+
+    fn switch(conn: &mut TestConn, name: &str) {
+        let (schema, partition_map, _) = switch_branch(
+            &conn.sqlite,
+            &conn.schema,
+            conn.partition_map.clone(),
+            name,
+            NullWatcher(),
+        )
+        .expect("switched");
+        if let Some(schema) = schema {
+            conn.schema = schema;
+        }
+        conn.partition_map = partition_map;
+    }
+
+    fn merge(conn: &mut TestConn, name: &str) -> Result<Vec<TxReport>> {
+        let (reports, schema, partition_map, _) = merge_branch(
+            &conn.sqlite,
+            &conn.schema,
+            conn.partition_map.clone(),
+            name,
+            NullWatcher(),
+        )?;
+        if let Some(schema) = schema {
+            conn.schema = schema;
+        }
+        conn.partition_map = partition_map;
+        Ok(reports)
+    }
+
+    #[test]
+    fn test_fork_and_switch() {
+        let mut conn = TestConn::default();
+        conn.sanitized_partition_map();
+
+        assert_transact!(
+            conn,
+            r#"[{:db/ident :test/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one}]"#
+        );
+        let base = assert_transact!(conn, r#"[{:db/ident :test/a :test/name "Alice"}]"#).tx_id;
+        fork_branch(&conn.sqlite, "draft", base).expect("forked");
+        assert_eq!(
+            fork_branch(&conn.sqlite, "draft", base)
+                .map_err(|e| e.kind())
+                .unwrap_err(),
+            DbErrorKind::BranchExists("draft".to_string())
+        );
+
+        assert_transact!(conn, r#"[[:db/add :test/a :test/name "Alicia"]]"#);
+        let main_datoms = conn.datoms().to_edn();
+        let main_transactions = conn.transactions().to_edn();
+        let main_partition_map = conn.partition_map.clone();
+
+        // The branch starts from the base, and its transactions don't touch the main branch.
+        switch(&mut conn, "draft");
+        assert_eq!(current_branch(&conn.sqlite).expect("current"), "draft");
+        assert_matches!(
+            conn.datoms(),
+            r#"[[?e :db/ident :test/name]
+                [?e :db/valueType :db.type/string]
+                [?e :db/cardinality :db.cardinality/one]
+                [?a :db/ident :test/a]
+                [?a :test/name "Alice"]]"#
+        );
+        assert_transact!(conn, r#"[[:db/add :test/a :test/name "Ally"]]"#);
+        let branch_datoms = conn.datoms().to_edn();
+
+        switch(&mut conn, MAIN_BRANCH);
+        assert_eq!(current_branch(&conn.sqlite).expect("current"), MAIN_BRANCH);
+        assert_eq!(conn.datoms().to_edn(), main_datoms);
+        assert_eq!(conn.transactions().to_edn(), main_transactions);
+        assert_eq!(conn.partition_map, main_partition_map);
+
+        switch(&mut conn, "draft");
+        assert_eq!(conn.datoms().to_edn(), branch_datoms);
+
+        // A checked out branch can't be deleted; the main branch has to be checked out to merge.
+        assert!(delete_branch(&conn.sqlite, "draft").is_err());
+        assert!(merge(&mut conn, "draft").is_err());
+
+        switch(&mut conn, MAIN_BRANCH);
+        delete_branch(&conn.sqlite, "draft").expect("deleted");
+        assert_eq!(branches(&conn.sqlite).expect("branches"), vec![]);
+        assert_eq!(conn.transactions().to_edn(), main_transactions);
+        let leftovers: i64 = conn
+            .sqlite
+            .query_row(
+                "SELECT COUNT(*) FROM timelined_transactions WHERE timeline != 0",
+                rusqlite::params![],
+                |row| row.get(0),
+            )
+            .expect("counted");
+        assert_eq!(leftovers, 0);
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut conn = TestConn::default();
+        conn.sanitized_partition_map();
+
+        assert_transact!(
+            conn,
+            r#"[{:db/ident :test/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+                {:db/ident :test/friend :db/valueType :db.type/ref :db/cardinality :db.cardinality/many}]"#
+        );
+        let base = assert_transact!(conn, r#"[{:db/ident :test/a :test/name "Alice"}]"#).tx_id;
+        fork_branch(&conn.sqlite, "conflicting", base).expect("forked");
+        fork_branch(&conn.sqlite, "draft", base).expect("forked");
+        assert_eq!(
+            branches(&conn.sqlite).expect("branches"),
+            vec![
+                Branch {
+                    name: "conflicting".to_string(),
+                    base,
+                    checked_out: false,
+                },
+                Branch {
+                    name: "draft".to_string(),
+                    base,
+                    checked_out: false,
+                },
+            ]
+        );
+
+        // Both main and the branch create an entity, which get the same entid.
+        let created_on_main =
+            assert_transact!(conn, r#"[{:db/id "c" :test/name "Carol"}]"#).tempids["c"];
+
+        switch(&mut conn, "draft");
+        let report = assert_transact!(
+            conn,
+            r#"[{:db/id "b" :test/name "Bob"}
+                [:db/add :test/a :test/friend "b"]]"#
+        );
+        assert_eq!(report.tempids["b"], created_on_main);
+
+        switch(&mut conn, "conflicting");
+        assert_transact!(conn, r#"[[:db/add :test/a :test/name "Ally"]]"#);
+        switch(&mut conn, MAIN_BRANCH);
+        assert_transact!(conn, r#"[[:db/add :test/a :test/name "Alicia"]]"#);
+
+        let a = conn
+            .schema
+            .get_entid(&Keyword::namespaced("test", "a"))
+            .expect(":test/a")
+            .0;
+        let name = conn
+            .schema
+            .get_entid(&Keyword::namespaced("test", "name"))
+            .expect(":test/name")
+            .0;
+        assert_eq!(
+            merge(&mut conn, "conflicting")
+                .map_err(|e| e.kind())
+                .unwrap_err(),
+            DbErrorKind::BranchMergeConflict("conflicting".to_string(), vec![(a, name)])
+        );
+
+        // Bob gets an entid of his own on the main branch.
+        let reports = merge(&mut conn, "draft").expect("merged");
+        assert_eq!(reports.len(), 1);
+        assert_matches!(
+            conn.datoms(),
+            r#"[[?name :db/ident :test/name]
+                [?name :db/valueType :db.type/string]
+                [?name :db/cardinality :db.cardinality/one]
+                [?friend :db/ident :test/friend]
+                [?friend :db/valueType :db.type/ref]
+                [?friend :db/cardinality :db.cardinality/many]
+                [?a :db/ident :test/a]
+                [?a :test/name "Alicia"]
+                [?a :test/friend ?b]
+                [?c :test/name "Carol"]
+                [?b :test/name "Bob"]]"#
+        );
+        assert_ne!(
+            reports[0].tempids[&created_on_main.to_string()],
+            created_on_main
+        );
+        assert_eq!(
+            branches(&conn.sqlite)
+                .expect("branches")
+                .into_iter()
+                .map(|branch| branch.name)
+                .collect::<Vec<_>>(),
+            vec!["conflicting".to_string()]
+        );
+    }
+
+    fn undo(conn: &mut TestConn, n: usize) -> Vec<Entid> {
+        let moved = crate::timelines::undo(
+            &conn.sqlite,
+            &conn.schema,
+            conn.partition_map.clone(),
+            n,
+            NullWatcher(),
+        )
+        .expect("undone");
+        if let Some(schema) = moved.schema {
+            conn.schema = schema;
+        }
+        conn.partition_map = moved.partition_map;
+        moved.txs
+    }
+
+    fn redo(conn: &mut TestConn, n: usize) -> Vec<Entid> {
+        let moved = crate::timelines::redo(
+            &conn.sqlite,
+            &conn.schema,
+            conn.partition_map.clone(),
+            n,
+            NullWatcher(),
+        )
+        .expect("redone");
+        if let Some(schema) = moved.schema {
+            conn.schema = schema;
+        }
+        conn.partition_map = moved.partition_map;
+        moved.txs
+    }
+
+    #[test]
+    fn test_undo_with_branches() {
+        let mut conn = TestConn::default();
+        conn.sanitized_partition_map();
+
+        let base = assert_transact!(conn, r#"[{:db/id :db/doc :db/doc "base"}]"#).tx_id;
+        fork_branch(&conn.sqlite, "draft", base).expect("forked");
+        let on_main = assert_transact!(conn, r#"[{:db/id :db/doc :db/doc "main"}]"#).tx_id;
+
+        // The base of a branch can't be undone from the main branch.
+        assert_eq!(undo(&mut conn, 2), vec![on_main]);
+        assert_matches!(conn.datoms(), r#"[[?e :db/doc "base"]]"#);
+
+        // Nor from the branch, and the main branch's undone transaction waits for it.
+        switch(&mut conn, "draft");
+        let on_draft = assert_transact!(conn, r#"[{:db/id :db/doc :db/doc "draft"}]"#).tx_id;
+        assert_eq!(undo(&mut conn, 2), vec![on_draft]);
+        assert_matches!(conn.datoms(), r#"[[?e :db/doc "base"]]"#);
+
+        switch(&mut conn, MAIN_BRANCH);
+        assert_eq!(redo(&mut conn, 1), vec![on_main]);
+        assert_matches!(conn.datoms(), r#"[[?e :db/doc "main"]]"#);
+
+        switch(&mut conn, "draft");
+        assert_eq!(redo(&mut conn, 1), vec![on_draft]);
+        assert_matches!(conn.datoms(), r#"[[?e :db/doc "draft"]]"#);
+    }
+
+    #[test]
+    fn test_prune_needs_main() {
+        let mut conn = TestConn::default();
+        conn.sanitized_partition_map();
+
+        let base = assert_transact!(conn, r#"[{:db/id :db/doc :db/doc "base"}]"#).tx_id;
+        fork_branch(&conn.sqlite, "draft", base).expect("forked");
+        switch(&mut conn, "draft");
+        assert_eq!(
+            crate::history::prune_history(&conn.sqlite, crate::history::HistoryCutoff::Tx(base))
+                .map_err(|e| e.kind())
+                .unwrap_err(),
+            DbErrorKind::BranchCheckedOut("draft".to_string())
+        );
+    }
+}
@@ -44,9 +44,14 @@
 ///! absorbe them back into the stable cache. This uses `Arc::make_mut`, so if nobody is looking at
 ///! the old cache, we modify it in place.
 ///!
+///! Caches can be held to a memory budget. When they grow past it, the least recently used
+///! entities are evicted from forward caches, which then answer only for the entities still
+///! resident; lookups of anything else miss, are answered from SQL, and load the entity back in.
+///! A partially cached attribute is only cached forward: its reverse cache, if any, is dropped.
+///!
 ///! Most of the tests for this module are actually in `conn.rs`, where we can set up transactions
 ///! and test the external API.
-use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 
 use std::collections::btree_map::Entry;
 
@@ -54,7 +59,10 @@ use std::collections::btree_map::Entry::{Occupied, Vacant};
 
 use std::iter::once;
 
-use std::sync::Arc;
+use std::mem::size_of;
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 
 use std::iter::Peekable;
 
@@ -64,7 +72,9 @@ use rusqlite;
 
 use core_traits::{Binding, Entid, TypedValue};
 
-use mentat_core::{CachedAttributes, HasSchema, Schema, UpdateableCache, ValueRc};
+use mentat_core::{
+    CachedAttributes, DatomsView, DatomsViewTable, HasSchema, Schema, UpdateableCache, ValueRc,
+};
 
 use mentat_core::util::Either;
 
@@ -647,6 +657,119 @@ impl AccumulationBehavior {
     }
 }
 
+/// The most entities whose lookups missed we remember, to be loaded at the next opportunity.
+const MAX_PENDING_MISSES: usize = 1024;
+
+/// A rough guess at what each entry costs a `BTreeMap` beyond the key and value themselves.
+const MAP_ENTRY_OVERHEAD: usize = 2 * size_of::<usize>();
+
+fn approximate_value_size(v: &TypedValue) -> usize {
+    size_of::<TypedValue>()
+        + match v {
+            TypedValue::String(s) => s.len(),
+            TypedValue::Keyword(k) => k.name().len() + k.namespace().map_or(0, |ns| ns.len()),
+            _ => 0,
+        }
+}
+
+/// Lookups of one cached attribute. Shared by every copy of the cache, so that lookups made
+/// through a snapshot still count.
+#[derive(Debug, Default)]
+struct CacheCounters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+/// Whether `e`'s values of `a` are held, given which entities each partial cache holds.
+fn is_resident_in(partial_forward: &BTreeMap<Entid, BTreeSet<Entid>>, a: Entid, e: Entid) -> bool {
+    match partial_forward.get(&a) {
+        Some(es) => es.contains(&e),
+        None => true,
+    }
+}
+
+/// What a memory budget evicts: one entity's forward values, or an attribute's reverse cache.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+enum Resident {
+    Forward(Entid, Entid),
+    Reverse(Entid),
+}
+
+/// When each resident entry was last used, and which entities were looked up but not resident.
+/// Only kept while there's a memory budget; shared by every copy of the cache.
+#[derive(Debug, Default)]
+struct CacheUsage {
+    clock: AtomicU64,
+    last_used: Mutex<HashMap<Resident, u64>>,
+    missed: Mutex<BTreeSet<(Entid, Entid)>>,
+}
+
+impl CacheUsage {
+    fn touch(&self, resident: Resident) {
+        let now = self.clock.fetch_add(1, Ordering::Relaxed) + 1;
+        self.last_used.lock().unwrap().insert(resident, now);
+    }
+
+    fn forget(&self, resident: &Resident) {
+        self.last_used.lock().unwrap().remove(resident);
+    }
+
+    fn forget_attribute(&self, a: Entid) {
+        self.last_used
+            .lock()
+            .unwrap()
+            .retain(|resident, _| match *resident {
+                Resident::Forward(attribute, _) | Resident::Reverse(attribute) => attribute != a,
+            });
+    }
+
+    fn miss(&self, a: Entid, e: Entid) {
+        let mut missed = self.missed.lock().unwrap();
+        if missed.len() < MAX_PENDING_MISSES {
+            missed.insert((a, e));
+        }
+    }
+
+    fn take_missed(&self) -> BTreeSet<(Entid, Entid)> {
+        ::std::mem::take(&mut *self.missed.lock().unwrap())
+    }
+}
+
+/// How much of the memory budget one attribute's cache uses, and how often it has been useful.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct AttributeCacheStats {
+    /// Entities cached forward, plus values cached in reverse.
+    pub entries: usize,
+
+    /// A rough estimate of the memory those entries occupy.
+    pub approximate_bytes: usize,
+
+    /// Forward lookups answered by the cache, and reverse lookups made against it.
+    pub hits: u64,
+
+    /// Forward lookups of entities that weren't resident, and were answered from SQL instead.
+    pub misses: u64,
+
+    /// True if the forward cache only holds some entities.
+    pub partial: bool,
+
+    /// True if the reverse cache was dropped to stay within the memory budget. It stays dropped
+    /// until the attribute is registered again.
+    pub reverse_suspended: bool,
+}
+
+impl AttributeCacheStats {
+    /// The fraction of lookups answered by the cache, if there have been any lookups.
+    pub fn hit_rate(&self) -> Option<f64> {
+        let lookups = self.hits + self.misses;
+        if lookups == 0 {
+            None
+        } else {
+            Some(self.hits as f64 / lookups as f64)
+        }
+    }
+}
+
 #[derive(Clone, Debug, Default)]
 pub struct AttributeCaches {
     reverse_cached_attributes: BTreeSet<Entid>,
@@ -656,6 +779,26 @@ pub struct AttributeCaches {
     multi_vals: BTreeMap<Entid, MultiValAttributeCache>,
     unique_reverse: BTreeMap<Entid, UniqueReverseAttributeCache>,
     non_unique_reverse: BTreeMap<Entid, NonUniqueReverseAttributeCache>,
+
+    /// For each attribute whose forward cache has had entities evicted, the entities it still
+    /// holds. Values in the maps above for any other entity are stale and ignored.
+    partial_forward: BTreeMap<Entid, BTreeSet<Entid>>,
+
+    /// Attributes whose reverse caches were dropped to stay within the memory budget.
+    suspended_reverse: BTreeSet<Entid>,
+
+    /// Attributes rebuilt from the store by an in-progress transaction. Their overlays are
+    /// complete, whatever the stable cache says.
+    rebuilt: BTreeSet<Entid>,
+
+    counters: BTreeMap<Entid, Arc<CacheCounters>>,
+    usage: Option<Arc<CacheUsage>>,
+    memory_budget: Option<usize>,
+
+    /// Roughly how much memory each attribute's caches use, for attributes that haven't changed
+    /// since they were last measured, and the sum of those sizes.
+    sizes: BTreeMap<Entid, usize>,
+    measured_size: usize,
 }
 
 // TODO: if an entity or attribute is ever re-numbered, the cache will need to be rebuilt.
@@ -823,6 +966,7 @@ impl AttributeCaches {
         I: Iterator<Item = Aev>,
     {
         if let Some(&(a, _, _)) = iter.peek() {
+            self.resized(a);
             if let Some(attribute) = schema.attribute_for_entid(a) {
                 let fallback_cached_forward =
                     fallback.map_or(false, |c| c.is_attribute_cached_forward(a));
@@ -980,11 +1124,20 @@ impl AttributeCaches {
         self.multi_vals.clear();
         self.unique_reverse.clear();
         self.non_unique_reverse.clear();
+        self.sizes.clear();
+        self.measured_size = 0;
     }
 
     fn unregister_all_attributes(&mut self) {
         self.reverse_cached_attributes.clear();
         self.forward_cached_attributes.clear();
+        self.partial_forward.clear();
+        self.suspended_reverse.clear();
+        self.rebuilt.clear();
+        self.counters.clear();
+        if let Some(ref usage) = self.usage {
+            usage.last_used.lock().unwrap().clear();
+        }
         self.clear_cache();
     }
 
@@ -999,6 +1152,14 @@ impl AttributeCaches {
         self.multi_vals.remove(&a);
         self.unique_reverse.remove(&a);
         self.non_unique_reverse.remove(&a);
+        self.partial_forward.remove(&a);
+        self.suspended_reverse.remove(&a);
+        self.rebuilt.remove(&a);
+        self.counters.remove(&a);
+        self.resized(a);
+        if let Some(ref usage) = self.usage {
+            usage.forget_attribute(a);
+        }
     }
 }
 
@@ -1043,6 +1204,17 @@ impl AttributeCaches {
         sqlite: &rusqlite::Connection,
         attribute: Entid,
     ) -> Result<()> {
+        // Whatever was evicted is about to be loaded again.
+        self.counters.entry(attribute).or_default();
+        self.resized(attribute);
+        if self.partial_forward.remove(&attribute).is_some() {
+            self.single_vals.remove(&attribute);
+            self.multi_vals.remove(&attribute);
+        }
+        if self.reverse_cached_attributes.contains(&attribute) {
+            self.suspended_reverse.remove(&attribute);
+        }
+
         let is_fulltext = schema
             .attribute_for_entid(attribute)
             .map_or(false, |s| s.fulltext);
@@ -1129,6 +1301,7 @@ impl AttributeCaches {
         sqlite: &'c rusqlite::Connection,
         attrs: AttributeSpec,
         entities: &[Entid],
+        view: DatomsView,
     ) -> Result<()> {
         // Mark the attributes as cached as we go. We do this because we're going in through the
         // back door here, and the usual caching API won't have taken care of this for us.
@@ -1136,7 +1309,8 @@ impl AttributeCaches {
         qb.push_sql("SELECT a, e, v, value_type_tag FROM ");
         match attrs {
             AttributeSpec::All => {
-                qb.push_sql("all_datoms WHERE e IN (");
+                qb.push_sql(&view.sql(DatomsViewTable::AllDatoms));
+                qb.push_sql(" WHERE e IN (");
                 interpose!(item, entities, { qb.push_sql(&item.to_string()) }, {
                     qb.push_sql(", ")
                 });
@@ -1155,7 +1329,8 @@ impl AttributeCaches {
                 }
 
                 if has_non_fts {
-                    qb.push_sql("datoms WHERE e IN (");
+                    qb.push_sql(&view.sql(DatomsViewTable::Datoms));
+                    qb.push_sql(" WHERE e IN (");
                     interpose!(item, entities, { qb.push_sql(&item.to_string()) }, {
                         qb.push_sql(", ")
                     });
@@ -1174,7 +1349,8 @@ impl AttributeCaches {
                 }
 
                 if has_fts {
-                    qb.push_sql("fulltext_datoms WHERE e IN (");
+                    qb.push_sql(&view.sql(DatomsViewTable::FulltextDatoms));
+                    qb.push_sql(" WHERE e IN (");
                     interpose!(item, entities, { qb.push_sql(&item.to_string()) }, {
                         qb.push_sql(", ")
                     });
@@ -1272,19 +1448,26 @@ impl AttributeCaches {
             }
         }
 
-        self.populate_cache_for_entities_and_attributes(schema, sqlite, attrs, entities)
+        self.populate_cache_for_entities_and_attributes(
+            schema,
+            sqlite,
+            attrs,
+            entities,
+            DatomsView::Current,
+        )
     }
 
-    /// Fetch the requested entities and attributes and put them in a new cache.
-    /// The caller is responsible for ensuring that `entities` is unique.
+    /// Fetch the requested entities and attributes, as seen through `view`, and put them in a new
+    /// cache. The caller is responsible for ensuring that `entities` is unique.
     pub fn make_cache_for_entities_and_attributes<'s, 'c>(
         schema: &'s Schema,
         sqlite: &'c rusqlite::Connection,
         attrs: AttributeSpec,
         entities: &[Entid],
+        view: DatomsView,
     ) -> Result<AttributeCaches> {
         let mut cache = AttributeCaches::default();
-        cache.populate_cache_for_entities_and_attributes(schema, sqlite, attrs, entities)?;
+        cache.populate_cache_for_entities_and_attributes(schema, sqlite, attrs, entities, view)?;
         Ok(cache)
     }
 }
@@ -1296,6 +1479,9 @@ impl CachedAttributes for AttributeCaches {
         attribute: Entid,
         entid: Entid,
     ) -> Option<&Vec<TypedValue>> {
+        if !self.is_resident(attribute, entid) {
+            return None;
+        }
         self.values_pairs(schema, attribute)
             .and_then(|c| c.get(&entid))
     }
@@ -1306,6 +1492,9 @@ impl CachedAttributes for AttributeCaches {
         attribute: Entid,
         entid: Entid,
     ) -> Option<&TypedValue> {
+        if !self.is_resident(attribute, entid) {
+            return None;
+        }
         if let Some(&Some(ref tv)) = self
             .value_pairs(schema, attribute)
             .and_then(|c| c.get(&entid))
@@ -1328,8 +1517,32 @@ impl CachedAttributes for AttributeCaches {
         self.forward_cached_attributes.contains(&attribute)
     }
 
+    fn is_entity_cached_forward(&self, attribute: Entid, entid: Entid) -> bool {
+        if !self.is_attribute_cached_forward(attribute) {
+            return false;
+        }
+        let resident = self.is_resident(attribute, entid);
+        if let Some(counters) = self.counters.get(&attribute) {
+            let counter = if resident {
+                &counters.hits
+            } else {
+                &counters.misses
+            };
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+        if let Some(ref usage) = self.usage {
+            if resident {
+                usage.touch(Resident::Forward(attribute, entid));
+            } else {
+                usage.miss(attribute, entid);
+            }
+        }
+        resident
+    }
+
     fn get_entid_for_value(&self, attribute: Entid, value: &TypedValue) -> Option<Entid> {
         if self.is_attribute_cached_reverse(attribute) {
+            self.used_reverse(attribute);
             self.unique_reverse
                 .get(&attribute)
                 .and_then(|c| c.get_e(value))
@@ -1344,6 +1557,7 @@ impl CachedAttributes for AttributeCaches {
         value: &TypedValue,
     ) -> Option<&BTreeSet<Entid>> {
         if self.is_attribute_cached_reverse(attribute) {
+            self.used_reverse(attribute);
             self.non_unique_reverse
                 .get(&attribute)
                 .and_then(|c| c.get_es(value))
@@ -1373,16 +1587,34 @@ impl AttributeCaches {
     where
         I: Iterator<Item = (Entid, Entid, TypedValue)>,
     {
-        let r_aevs = retractions.peekable();
-        self.accumulate_into_cache(fallback, schema, r_aevs, AccumulationBehavior::Remove)?;
+        // Changes to entities that a partial cache doesn't hold would only make stale entries.
+        let mut own_partial_forward = BTreeMap::new();
+        if fallback.is_none() {
+            ::std::mem::swap(&mut own_partial_forward, &mut self.partial_forward);
+        }
+        let partial_forward = fallback.map_or(&own_partial_forward, |c| &c.partial_forward);
+        let rebuilt = self.rebuilt.clone();
+        let resident =
+            |&(a, e, _): &Aev| rebuilt.contains(&a) || is_resident_in(partial_forward, a, e);
+
+        let r_aevs = retractions.filter(&resident).peekable();
+        let mut result =
+            self.accumulate_into_cache(fallback, schema, r_aevs, AccumulationBehavior::Remove);
+
+        if result.is_ok() {
+            let aevs = assertions.filter(&resident).peekable();
+            result = self.accumulate_into_cache(
+                fallback,
+                schema,
+                aevs,
+                AccumulationBehavior::Add { replacing: false },
+            );
+        }
 
-        let aevs = assertions.peekable();
-        self.accumulate_into_cache(
-            fallback,
-            schema,
-            aevs,
-            AccumulationBehavior::Add { replacing: false },
-        )
+        if fallback.is_none() {
+            self.partial_forward = own_partial_forward;
+        }
+        result
     }
 
     fn values_pairs<U>(
@@ -1426,6 +1658,19 @@ impl Absorb for AttributeCaches {
     // Replace or insert attribute-cache pairs from `other` into `self`.
     // Fold in any in-place deletions.
     fn absorb(&mut self, other: Self) {
+        let changed: BTreeSet<Entid> = other
+            .single_vals
+            .keys()
+            .chain(other.multi_vals.keys())
+            .chain(other.unique_reverse.keys())
+            .chain(other.non_unique_reverse.keys())
+            .chain(other.rebuilt.iter())
+            .cloned()
+            .collect();
+        for a in changed {
+            self.resized(a);
+        }
+
         self.forward_cached_attributes
             .extend(other.forward_cached_attributes);
         self.reverse_cached_attributes
@@ -1437,6 +1682,359 @@ impl Absorb for AttributeCaches {
             .extend_by_absorbing(other.unique_reverse);
         self.non_unique_reverse
             .extend_by_absorbing(other.non_unique_reverse);
+
+        for a in other.rebuilt {
+            self.partial_forward.remove(&a);
+            self.suspended_reverse.remove(&a);
+        }
+        for (a, counters) in other.counters {
+            self.counters.entry(a).or_insert(counters);
+        }
+    }
+}
+
+/// Memory budget.
+impl AttributeCaches {
+    fn is_resident(&self, a: Entid, e: Entid) -> bool {
+        is_resident_in(&self.partial_forward, a, e)
+    }
+
+    fn used_reverse(&self, a: Entid) {
+        if let Some(counters) = self.counters.get(&a) {
+            counters.hits.fetch_add(1, Ordering::Relaxed);
+        }
+        if let Some(ref usage) = self.usage {
+            usage.touch(Resident::Reverse(a));
+        }
+    }
+
+    fn forward_entry_size(&self, a: Entid, e: Entid) -> usize {
+        if let Some(v) = self.single_vals.get(&a).and_then(|c| c.e_v.get(&e)) {
+            return MAP_ENTRY_OVERHEAD
+                + size_of::<Entid>()
+                + size_of::<Option<TypedValue>>()
+                + v.as_ref()
+                    .map_or(0, |v| approximate_value_size(v) - size_of::<TypedValue>());
+        }
+        if let Some(vs) = self.multi_vals.get(&a).and_then(|c| c.e_vs.get(&e)) {
+            return MAP_ENTRY_OVERHEAD
+                + size_of::<Entid>()
+                + size_of::<Vec<TypedValue>>()
+                + vs.iter().map(approximate_value_size).sum::<usize>();
+        }
+        0
+    }
+
+    fn forward_size(&self, a: Entid) -> usize {
+        let single = self
+            .single_vals
+            .get(&a)
+            .into_iter()
+            .flat_map(|c| c.e_v.keys());
+        let multi = self
+            .multi_vals
+            .get(&a)
+            .into_iter()
+            .flat_map(|c| c.e_vs.keys());
+        single
+            .chain(multi)
+            .map(|&e| self.forward_entry_size(a, e))
+            .sum()
+    }
+
+    fn reverse_size(&self, a: Entid) -> usize {
+        let unique: usize = self.unique_reverse.get(&a).map_or(0, |c| {
+            c.v_e
+                .keys()
+                .map(|v| {
+                    MAP_ENTRY_OVERHEAD + approximate_value_size(v) + size_of::<Option<Entid>>()
+                })
+                .sum()
+        });
+        let non_unique: usize = self.non_unique_reverse.get(&a).map_or(0, |c| {
+            c.v_es
+                .iter()
+                .map(|(v, es)| {
+                    MAP_ENTRY_OVERHEAD
+                        + approximate_value_size(v)
+                        + size_of::<BTreeSet<Entid>>()
+                        + es.len() * (MAP_ENTRY_OVERHEAD + size_of::<Entid>())
+                })
+                .sum()
+        });
+        unique + non_unique
+    }
+
+    fn cached_attributes(&self) -> BTreeSet<Entid> {
+        self.forward_cached_attributes
+            .iter()
+            .chain(self.reverse_cached_attributes.iter())
+            .chain(self.suspended_reverse.iter())
+            .cloned()
+            .collect()
+    }
+
+    fn attribute_size(&self, a: Entid) -> usize {
+        match self.sizes.get(&a) {
+            Some(&size) => size,
+            None => self.forward_size(a) + self.reverse_size(a),
+        }
+    }
+
+    /// Forget the measured size of `a`, whose caches have changed.
+    fn resized(&mut self, a: Entid) {
+        if let Some(size) = self.sizes.remove(&a) {
+            self.measured_size -= size;
+        }
+    }
+
+    /// Account for `freed` bytes having been dropped from the caches of `a`.
+    fn shrunk(&mut self, a: Entid, freed: usize) {
+        if let Some(size) = self.sizes.get_mut(&a) {
+            let freed = ::std::cmp::min(freed, *size);
+            *size -= freed;
+            self.measured_size -= freed;
+        }
+    }
+
+    fn approximate_size(&self) -> usize {
+        let unmeasured: usize = self
+            .cached_attributes()
+            .into_iter()
+            .filter(|a| !self.sizes.contains_key(a))
+            .map(|a| self.forward_size(a) + self.reverse_size(a))
+            .sum();
+        self.measured_size + unmeasured
+    }
+
+    /// Measure the attributes that changed since they were last measured, returning the size of
+    /// all of the caches.
+    fn measure(&mut self) -> usize {
+        for a in self.cached_attributes() {
+            if !self.sizes.contains_key(&a) {
+                let size = self.forward_size(a) + self.reverse_size(a);
+                self.sizes.insert(a, size);
+                self.measured_size += size;
+            }
+        }
+        self.measured_size
+    }
+
+    /// Drop entries for entities that partial caches no longer hold.
+    fn prune_non_resident(&mut self) {
+        let mut pruned = vec![];
+        for (&a, resident) in self.partial_forward.iter() {
+            let stale: Vec<Entid> = self
+                .single_vals
+                .get(&a)
+                .into_iter()
+                .flat_map(|c| c.e_v.keys())
+                .chain(
+                    self.multi_vals
+                        .get(&a)
+                        .into_iter()
+                        .flat_map(|c| c.e_vs.keys()),
+                )
+                .filter(|e| !resident.contains(e))
+                .cloned()
+                .collect();
+            if stale.is_empty() {
+                continue;
+            }
+            if let Some(c) = self.single_vals.get_mut(&a) {
+                for e in stale.iter() {
+                    c.e_v.remove(e);
+                }
+            }
+            if let Some(c) = self.multi_vals.get_mut(&a) {
+                for e in stale.iter() {
+                    c.e_vs.remove(e);
+                }
+            }
+            pruned.push(a);
+        }
+        for a in pruned {
+            self.resized(a);
+        }
+    }
+
+    /// Drop the reverse cache of `a`, returning roughly how much memory that freed.
+    fn suspend_reverse(&mut self, a: Entid) -> usize {
+        if !self.reverse_cached_attributes.remove(&a) {
+            return 0;
+        }
+        let freed = self.reverse_size(a);
+        self.unique_reverse.remove(&a);
+        self.non_unique_reverse.remove(&a);
+        self.suspended_reverse.insert(a);
+        self.shrunk(a, freed);
+        if let Some(ref usage) = self.usage {
+            usage.forget(&Resident::Reverse(a));
+        }
+        freed
+    }
+
+    /// Evict `resident`, returning roughly how much memory that freed.
+    fn evict(&mut self, resident: Resident) -> usize {
+        let (a, e) = match resident {
+            Resident::Reverse(a) => return self.suspend_reverse(a),
+            Resident::Forward(a, e) => (a, e),
+        };
+
+        let mut freed = 0;
+        if !self.partial_forward.contains_key(&a) {
+            // Everything cached so far is resident. A reverse cache can't be kept in step with a
+            // partial forward cache, so it goes.
+            let resident: BTreeSet<Entid> = self
+                .single_vals
+                .get(&a)
+                .into_iter()
+                .flat_map(|c| c.e_v.keys())
+                .chain(
+                    self.multi_vals
+                        .get(&a)
+                        .into_iter()
+                        .flat_map(|c| c.e_vs.keys()),
+                )
+                .cloned()
+                .collect();
+            self.partial_forward.insert(a, resident);
+            freed += self.suspend_reverse(a);
+        }
+
+        let entry_size = self.forward_entry_size(a, e);
+        self.shrunk(a, entry_size);
+        freed += entry_size;
+        if let Some(es) = self.partial_forward.get_mut(&a) {
+            es.remove(&e);
+        }
+        if let Some(c) = self.single_vals.get_mut(&a) {
+            c.e_v.remove(&e);
+        }
+        if let Some(c) = self.multi_vals.get_mut(&a) {
+            c.e_vs.remove(&e);
+        }
+        if let Some(ref usage) = self.usage {
+            usage.forget(&resident);
+        }
+        freed
+    }
+
+    /// Evict the least recently used entries until the caches fit within the memory budget.
+    fn enforce_memory_budget(&mut self) {
+        let budget = match self.memory_budget {
+            Some(budget) => budget,
+            None => return,
+        };
+
+        self.prune_non_resident();
+        let mut size = self.measure();
+        if size <= budget {
+            return;
+        }
+
+        let mut candidates: Vec<(u64, Resident)> =
+            {
+                let last_used = self
+                    .usage
+                    .as_ref()
+                    .map(|usage| usage.last_used.lock().unwrap());
+                let stamp = |resident: Resident| {
+                    let stamp = last_used
+                        .as_ref()
+                        .and_then(|last_used| last_used.get(&resident).cloned());
+                    (stamp.unwrap_or(0), resident)
+                };
+                let forward =
+                    self.single_vals
+                        .iter()
+                        .flat_map(|(&a, c)| c.e_v.keys().map(move |&e| Resident::Forward(a, e)))
+                        .chain(self.multi_vals.iter().flat_map(|(&a, c)| {
+                            c.e_vs.keys().map(move |&e| Resident::Forward(a, e))
+                        }))
+                        .filter(|resident| match *resident {
+                            Resident::Forward(a, _) => self.forward_cached_attributes.contains(&a),
+                            Resident::Reverse(_) => false,
+                        });
+                let reverse = self
+                    .reverse_cached_attributes
+                    .iter()
+                    .map(|&a| Resident::Reverse(a));
+                forward.chain(reverse).map(stamp).collect()
+            };
+        candidates.sort();
+
+        for (_, resident) in candidates {
+            if size <= budget {
+                break;
+            }
+            size = size.saturating_sub(self.evict(resident));
+        }
+    }
+
+    /// Load `entities`' values of the partially cached attribute `a` from the store.
+    fn fault_in(
+        &mut self,
+        schema: &Schema,
+        sqlite: &rusqlite::Connection,
+        a: Entid,
+        entities: &[Entid],
+    ) -> Result<()> {
+        self.resized(a);
+        for e in entities {
+            if let Some(c) = self.single_vals.get_mut(&a) {
+                c.e_v.remove(e);
+            }
+            if let Some(c) = self.multi_vals.get_mut(&a) {
+                c.e_vs.remove(e);
+            }
+        }
+
+        let attrs = AttributeSpec::specified(&once(a).collect(), schema);
+        self.populate_cache_for_entities_and_attributes(
+            schema,
+            sqlite,
+            attrs,
+            entities,
+            DatomsView::Current,
+        )?;
+
+        if let Some(resident) = self.partial_forward.get_mut(&a) {
+            resident.extend(entities.iter().cloned());
+        }
+        if let Some(ref usage) = self.usage {
+            for &e in entities {
+                usage.touch(Resident::Forward(a, e));
+            }
+        }
+        Ok(())
+    }
+
+    fn stats_for_attribute(&self, a: Entid) -> AttributeCacheStats {
+        let forward_entries = self.single_vals.get(&a).map_or(0, |c| {
+            c.e_v
+                .iter()
+                .filter(|&(e, v)| v.is_some() && self.is_resident(a, *e))
+                .count()
+        }) + self.multi_vals.get(&a).map_or(0, |c| {
+            c.e_vs.keys().filter(|&e| self.is_resident(a, *e)).count()
+        });
+        let reverse_entries = self.unique_reverse.get(&a).map_or(0, |c| c.v_e.len())
+            + self.non_unique_reverse.get(&a).map_or(0, |c| c.v_es.len());
+        let (hits, misses) = self.counters.get(&a).map_or((0, 0), |c| {
+            (
+                c.hits.load(Ordering::Relaxed),
+                c.misses.load(Ordering::Relaxed),
+            )
+        });
+        AttributeCacheStats {
+            entries: forward_entries + reverse_entries,
+            approximate_bytes: self.attribute_size(a),
+            hits,
+            misses,
+            partial: self.partial_forward.contains_key(&a),
+            reverse_suspended: self.suspended_reverse.contains(&a),
+        }
     }
 }
 
@@ -1474,7 +2072,9 @@ impl SQLiteAttributeCache {
             .ok_or_else(|| DbErrorKind::UnknownAttribute(a))?;
         let caches = self.make_mut();
         caches.forward_cached_attributes.insert(a);
-        caches.repopulate(schema, sqlite, a)
+        caches.repopulate(schema, sqlite, a)?;
+        caches.enforce_memory_budget();
+        Ok(())
     }
 
     pub fn register_reverse<U>(
@@ -1495,7 +2095,9 @@ impl SQLiteAttributeCache {
 
         let caches = self.make_mut();
         caches.reverse_cached_attributes.insert(a);
-        caches.repopulate(schema, sqlite, a)
+        caches.repopulate(schema, sqlite, a)?;
+        caches.enforce_memory_budget();
+        Ok(())
     }
 
     pub fn register<U>(
@@ -1514,7 +2116,9 @@ impl SQLiteAttributeCache {
         let caches = self.make_mut();
         caches.forward_cached_attributes.insert(a);
         caches.reverse_cached_attributes.insert(a);
-        caches.repopulate(schema, sqlite, a)
+        caches.repopulate(schema, sqlite, a)?;
+        caches.enforce_memory_budget();
+        Ok(())
     }
 
     pub fn unregister<U>(&mut self, attribute: U)
@@ -1527,6 +2131,86 @@ impl SQLiteAttributeCache {
     pub fn unregister_all(&mut self) {
         self.make_mut().unregister_all_attributes();
     }
+
+    /// Take over every attribute cached by `other`, replacing our caches of those attributes.
+    pub fn absorb_from(&mut self, other: SQLiteAttributeCache) {
+        let other = Arc::try_unwrap(other.inner).unwrap_or_else(|inner| (*inner).clone());
+        let caches = self.make_mut();
+        caches.absorb(other);
+        caches.enforce_memory_budget();
+    }
+
+    pub fn memory_budget(&self) -> Option<usize> {
+        self.inner.memory_budget
+    }
+
+    /// Hold the caches to roughly `budget` bytes, evicting the least recently used entries
+    /// whenever they grow past it, or lift the limit with `None`. Attributes that are already
+    /// partially cached stay that way; they reload entities as they're looked up.
+    pub fn set_memory_budget(&mut self, budget: Option<usize>) {
+        let caches = self.make_mut();
+        caches.memory_budget = budget;
+        if budget.is_none() {
+            caches.usage = None;
+        } else if caches.usage.is_none() {
+            caches.usage = Some(Default::default());
+        }
+        caches.enforce_memory_budget();
+    }
+
+    /// Evict entries until the caches fit within their memory budget, if they have one.
+    pub fn enforce_memory_budget(&mut self) {
+        if self.inner.memory_budget.is_some() {
+            self.make_mut().enforce_memory_budget();
+        }
+    }
+
+    /// Load the entities whose forward lookups missed since the last call, so that the next
+    /// lookups of them hit, evicting other entries to make room.
+    pub fn fault_in_missed(
+        &mut self,
+        schema: &Schema,
+        sqlite: &rusqlite::Connection,
+    ) -> Result<()> {
+        let missed = match self.inner.usage {
+            Some(ref usage) => usage.take_missed(),
+            None => return Ok(()),
+        };
+
+        let mut by_attribute: BTreeMap<Entid, Vec<Entid>> = BTreeMap::new();
+        for (a, e) in missed {
+            if self.inner.partial_forward.contains_key(&a)
+                && self.inner.is_attribute_cached_forward(a)
+                && !self.inner.is_resident(a, e)
+            {
+                by_attribute.entry(a).or_default().push(e);
+            }
+        }
+        if by_attribute.is_empty() {
+            return Ok(());
+        }
+
+        let caches = self.make_mut();
+        for (a, entities) in by_attribute {
+            caches.fault_in(schema, sqlite, a, &entities)?;
+        }
+        caches.enforce_memory_budget();
+        Ok(())
+    }
+
+    /// Return the size and usefulness of the cache of each cached attribute.
+    pub fn stats(&self) -> BTreeMap<Entid, AttributeCacheStats> {
+        self.inner
+            .cached_attributes()
+            .into_iter()
+            .map(|a| (a, self.inner.stats_for_attribute(a)))
+            .collect()
+    }
+
+    /// A rough estimate of the memory used by every cached attribute.
+    pub fn approximate_size(&self) -> usize {
+        self.inner.approximate_size()
+    }
 }
 
 impl UpdateableCache<DbError> for SQLiteAttributeCache {
@@ -1565,6 +2249,10 @@ impl CachedAttributes for SQLiteAttributeCache {
         self.inner.is_attribute_cached_forward(attribute)
     }
 
+    fn is_entity_cached_forward(&self, attribute: Entid, entid: Entid) -> bool {
+        self.inner.is_entity_cached_forward(attribute, entid)
+    }
+
     fn has_cached_attributes(&self) -> bool {
         !self.inner.forward_cached_attributes.is_empty()
             || !self.inner.reverse_cached_attributes.is_empty()
@@ -1646,12 +2334,13 @@ impl InProgressSQLiteAttributeCache {
             .attribute_for_entid(a)
             .ok_or_else(|| DbErrorKind::UnknownAttribute(a))?;
 
-        if self.is_attribute_cached_forward(a) {
+        if self.is_attribute_wholly_cached_forward(a) {
             return Ok(());
         }
 
         self.unregistered_forward.remove(&a);
         self.overlay.forward_cached_attributes.insert(a);
+        self.overlay.rebuilt.insert(a);
         self.overlay.repopulate(schema, sqlite, a)
     }
 
@@ -1677,6 +2366,7 @@ impl InProgressSQLiteAttributeCache {
 
         self.unregistered_reverse.remove(&a);
         self.overlay.reverse_cached_attributes.insert(a);
+        self.overlay.rebuilt.insert(a);
         self.overlay.repopulate(schema, sqlite, a)
     }
 
@@ -1698,7 +2388,7 @@ impl InProgressSQLiteAttributeCache {
 
         // TODO: reverse-index unique by default?
         let reverse_done = self.is_attribute_cached_reverse(a);
-        let forward_done = self.is_attribute_cached_forward(a);
+        let forward_done = self.is_attribute_wholly_cached_forward(a);
 
         if forward_done && reverse_done {
             return Ok(());
@@ -1713,9 +2403,16 @@ impl InProgressSQLiteAttributeCache {
             self.overlay.forward_cached_attributes.insert(a);
         }
 
+        self.overlay.rebuilt.insert(a);
         self.overlay.repopulate(schema, sqlite, a)
     }
 
+    /// Registering an attribute that the memory budget left partially cached loads it again.
+    fn is_attribute_wholly_cached_forward(&self, a: Entid) -> bool {
+        self.is_attribute_cached_forward(a)
+            && (self.overlay.rebuilt.contains(&a) || !self.inner.partial_forward.contains_key(&a))
+    }
+
     pub fn unregister<U>(&mut self, attribute: U)
     where
         U: Into<Entid>,
@@ -1798,6 +2495,18 @@ impl CachedAttributes for InProgressSQLiteAttributeCache {
                 || self.overlay.forward_cached_attributes.contains(&attribute))
     }
 
+    fn is_entity_cached_forward(&self, attribute: Entid, entid: Entid) -> bool {
+        if !self.is_attribute_cached_forward(attribute) {
+            return false;
+        }
+        if self.overlay.rebuilt.contains(&attribute)
+            || !self.inner.forward_cached_attributes.contains(&attribute)
+        {
+            return self.overlay.is_entity_cached_forward(attribute, entid);
+        }
+        self.inner.is_entity_cached_forward(attribute, entid)
+    }
+
     fn has_cached_attributes(&self) -> bool {
         // If we've added any, we're definitely not empty.
         if self.overlay.has_cached_attributes() {
@@ -1889,9 +2598,13 @@ impl InProgressSQLiteAttributeCache {
     }
 
     pub fn commit_to(self, destination: &mut SQLiteAttributeCache) {
-        // If the destination is empty, great: just take `overlay`.
+        // If the destination is empty, great: just take `overlay`, keeping the memory budget.
         if !destination.has_cached_attributes() {
-            destination.inner = Arc::new(self.overlay);
+            let mut overlay = self.overlay;
+            overlay.rebuilt.clear();
+            overlay.memory_budget = destination.inner.memory_budget;
+            overlay.usage = destination.inner.usage.clone();
+            destination.inner = Arc::new(overlay);
             return;
         }
 
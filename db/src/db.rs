@@ -34,7 +34,7 @@ use crate::entids;
 
 use core_traits::{attribute, Attribute, AttributeBitFlags, Entid, TypedValue, ValueType};
 
-use mentat_core::{AttributeMap, FromMicros, IdentMap, Schema, ToMicros, ValueRc};
+use mentat_core::{AttributeMap, FromMicros, HasSchema, IdentMap, Schema, ToMicros, ValueRc};
 
 use db_traits::errors::{DbErrorKind, Result};
 
@@ -142,7 +142,10 @@ where
 /// Version history:
 ///
 /// 1: initial Rust Mentat schema.
-pub const CURRENT_VERSION: i32 = 1;
+/// 2: `cached_attributes`, recording attribute cache registrations.
+/// 3: `history_horizon`, recording how far history has been pruned.
+/// 4: `branches`, recording named branches and which is checked out.
+pub const CURRENT_VERSION: i32 = 4;
 
 /// MIN_SQLITE_VERSION should be changed when there's a new minimum version of sqlite required
 /// for the project to work.
@@ -249,6 +252,36 @@ lazy_static! {
         r#"CREATE TABLE known_parts (part TEXT NOT NULL PRIMARY KEY, start INTEGER NOT NULL, end INTEGER NOT NULL, allow_excision SMALLINT NOT NULL)"#,
         ]
     };
+
+    /// SQL statements to be executed, in order, to move the Mentat SQL schema from each version to
+    /// the next, starting from version 1: the first entry moves it to version 2, and so on up to
+    /// `CURRENT_VERSION`.
+    #[cfg_attr(rustfmt, rustfmt_skip)]
+    static ref UPGRADE_STATEMENTS: Vec<Vec<&'static str>> = { vec![
+        // Version 2: attribute cache registrations to restore when the store is reopened.
+        vec![
+            r#"CREATE TABLE cached_attributes (a INTEGER NOT NULL PRIMARY KEY, forward TINYINT NOT NULL, reverse TINYINT NOT NULL)"#,
+        ],
+        // Version 3: the history horizon, before which transactions may have been pruned.
+        vec![
+            r#"CREATE TABLE history_horizon (horizon INTEGER NOT NULL)"#,
+        ],
+        // Version 4: named branches, each with its own timeline and one for its undone transactions.
+        vec![
+            r#"CREATE TABLE branches (name TEXT NOT NULL PRIMARY KEY, timeline INTEGER NOT NULL UNIQUE, undo_timeline INTEGER NOT NULL UNIQUE, base INTEGER NOT NULL, checked_out TINYINT NOT NULL DEFAULT 0)"#,
+        ],
+        ]
+    };
+}
+
+/// Move the Mentat SQL schema from version `from` to `CURRENT_VERSION`, within `tx`.
+fn upgrade_from_version(tx: &rusqlite::Transaction, from: i32) -> Result<()> {
+    for statements in UPGRADE_STATEMENTS.iter().skip(from as usize - 1) {
+        for statement in statements {
+            tx.execute(statement, rusqlite::params![])?;
+        }
+    }
+    set_user_version(tx, CURRENT_VERSION)
 }
 
 /// Set the SQLite user version.
@@ -268,7 +301,7 @@ fn set_user_version(conn: &rusqlite::Connection, version: i32) -> Result<()> {
 ///
 /// Mentat manages its own SQL schema version using the user version.  See the [SQLite
 /// documentation](https://www.sqlite.org/pragma.html#pragma_user_version).
-fn get_user_version(conn: &rusqlite::Connection) -> Result<i32> {
+pub fn get_user_version(conn: &rusqlite::Connection) -> Result<i32> {
     let v = conn
         .query_row("PRAGMA user_version", rusqlite::params![], |row| row.get(0))
         .context(DbErrorKind::CouldNotGetVersionPragma)?;
@@ -285,7 +318,7 @@ pub fn create_empty_current_version(
         tx.execute(statement, rusqlite::params![])?;
     }
 
-    set_user_version(&tx, CURRENT_VERSION)?;
+    upgrade_from_version(&tx, 1)?;
 
     let bootstrap_schema = bootstrap::bootstrap_schema();
     let bootstrap_partition_map = bootstrap::bootstrap_partition_map();
@@ -382,8 +415,13 @@ pub fn ensure_current_version(conn: &mut rusqlite::Connection) -> Result<DB> {
     match user_version {
         0 => create_current_version(conn),
         CURRENT_VERSION => read_db(conn),
+        v if v > 0 && v < CURRENT_VERSION => {
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Exclusive)?;
+            upgrade_from_version(&tx, v)?;
+            tx.commit()?;
+            read_db(conn)
+        }
 
-        // TODO: support updating an existing store.
         v => bail!(DbErrorKind::NotYetImplemented(format!(
             "Opening databases with Mentat version: {}",
             v
@@ -487,6 +525,27 @@ impl TypedSQLValue for TypedValue {
     }
 }
 
+/// Read the value of a datom of attribute `a` from the `v` and `value_type_tag` columns of a
+/// datom-shaped table such as `transactions`. Fulltext values are stored there as rowids into
+/// `fulltext_values`, so their text is looked up.
+pub fn datom_value(
+    conn: &rusqlite::Connection,
+    schema: &Schema,
+    a: Entid,
+    value: rusqlite::types::Value,
+    value_type_tag: i32,
+) -> Result<TypedValue> {
+    let fulltext = schema
+        .attribute_for_entid(a)
+        .map_or(false, |attribute| attribute.fulltext);
+    if !fulltext {
+        return TypedValue::from_sql_value_pair(value, value_type_tag);
+    }
+    let mut stmt = conn.prepare_cached("SELECT text FROM fulltext_values WHERE rowid = ?")?;
+    let text: String = stmt.query_row(&[&value], |row| row.get(0))?;
+    Ok(TypedValue::typed_string(text))
+}
+
 /// Read an arbitrary [e a v value_type_tag] materialized view from the given table in the SQL
 /// store.
 pub(crate) fn read_materialized_view(
@@ -571,7 +630,7 @@ pub(crate) fn read_attribute_map(conn: &rusqlite::Connection) -> Result<Attribut
 
 /// Read the materialized views from the given SQL store and return a Mentat `DB` for querying and
 /// applying transactions.
-pub(crate) fn read_db(conn: &rusqlite::Connection) -> Result<DB> {
+pub fn read_db(conn: &rusqlite::Connection) -> Result<DB> {
     let partition_map = read_partition_map(conn)?;
     let ident_map = read_ident_map(conn)?;
     let attribute_map = read_attribute_map(conn)?;
@@ -1873,6 +1932,27 @@ mod tests {
         assert_eq!(222, conn.limit(Limit::SQLITE_LIMIT_VARIABLE_NUMBER));
     }
 
+    #[test]
+    fn test_upgrade_from_version_1() {
+        let mut conn = new_connection("").expect("Couldn't open in-memory db");
+        ensure_current_version(&mut conn).expect("created");
+        conn.execute_batch(
+            "DROP TABLE cached_attributes; DROP TABLE history_horizon; DROP TABLE branches; PRAGMA user_version = 1;",
+        )
+        .expect("downgraded");
+
+        ensure_current_version(&mut conn).expect("upgraded");
+        assert_eq!(get_user_version(&conn).expect("version"), CURRENT_VERSION);
+        let tables: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name IN ('cached_attributes', 'history_horizon', 'branches')",
+                rusqlite::params![],
+                |row| row.get(0),
+            )
+            .expect("counted");
+        assert_eq!(tables, 3);
+    }
+
     #[test]
     fn test_db_install() {
         let mut conn = TestConn::default();
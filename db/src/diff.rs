@@ -0,0 +1,313 @@
+// Copyright 2018 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Diffs between two points on the main timeline.
+//!
+//! A diff is the net change in the store's datoms from one transaction to another: the datoms
+//! present after the later transaction but not the earlier, and vice versa. Anything asserted and
+//! then retracted in between, or retracted and then asserted again, cancels out. Each
+//! transaction's own metadata, such as its `:db/txInstant`, isn't part of a diff.
+//!
+//! Like reversing transactions when moving them off the main timeline, a diff can be turned into
+//! terms that undo it. Unlike that, transacting them makes a new transaction, and leaves the
+//! timelines alone.
+
+use rusqlite;
+
+use db_traits::errors::Result;
+
+use core_traits::{Entid, KnownEntid, TypedValue};
+
+use mentat_core::{HasSchema, Schema};
+
+use edn;
+
+use edn::entities::OpType;
+
+use crate::db::{datom_value, TypedSQLValue};
+
+use crate::internal_types::{Term, TermWithTempIds};
+
+/// A datom that differs between two points in time.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialOrd, PartialEq)]
+pub struct DiffDatom {
+    pub e: Entid,
+    pub a: Entid,
+    pub v: TypedValue,
+}
+
+/// The net change in the store's datoms between transactions `from` and `to`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Diff {
+    pub from: Entid,
+    pub to: Entid,
+
+    /// Datoms present after `to` but not after `from`.
+    pub added: Vec<DiffDatom>,
+
+    /// Datoms present after `from` but not after `to`.
+    pub retracted: Vec<DiffDatom>,
+}
+
+impl Diff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.retracted.is_empty()
+    }
+
+    /// Terms that take the datoms in this diff from how they are after `to` back to how they were
+    /// after `from`.
+    pub fn reverting_terms(&self) -> Vec<TermWithTempIds> {
+        let retract = self.added.iter().map(|d| (OpType::Retract, d));
+        let add = self.retracted.iter().map(|d| (OpType::Add, d));
+        retract
+            .chain(add)
+            .map(|(op, d)| Term::AddOrRetract(op, KnownEntid(d.e), d.a, d.v.clone()).rewrap())
+            .collect()
+    }
+
+    /// The terms of `reverting_terms` as an EDN transaction, naming things as `to_edn` does, to
+    /// be inspected or transacted later:
+    ///
+    /// ```edn
+    /// [[:db/retract 65536 :foo/bar 2] [:db/add 65536 :foo/bar 1]]
+    /// ```
+    pub fn reverting_edn(&self, schema: &Schema) -> edn::Value {
+        let retract = self.added.iter().map(|d| ("retract", d));
+        let add = self.retracted.iter().map(|d| ("add", d));
+        edn::Value::Vector(
+            retract
+                .chain(add)
+                .map(|(op, d)| {
+                    let mut term = vec![edn::Value::Keyword(edn::Keyword::namespaced("db", op))];
+                    if let edn::Value::Vector(datom) = datom_to_edn(schema, d) {
+                        term.extend(datom);
+                    }
+                    edn::Value::Vector(term)
+                })
+                .collect(),
+        )
+    }
+
+    /// Render this diff as EDN, naming entities, attributes, and referenced entities by their
+    /// idents where they have them:
+    ///
+    /// ```edn
+    /// {:from 268435457 :to 268435459 :added [[65536 :foo/bar 2]] :retracted [[65536 :foo/bar 1]]}
+    /// ```
+    pub fn to_edn(&self, schema: &Schema) -> edn::Value {
+        let datoms = |datoms: &[DiffDatom]| -> edn::Value {
+            edn::Value::Vector(datoms.iter().map(|d| datom_to_edn(schema, d)).collect())
+        };
+        edn::Value::Map(
+            vec![
+                (
+                    edn::Value::Keyword(edn::Keyword::plain("from")),
+                    edn::Value::Integer(self.from),
+                ),
+                (
+                    edn::Value::Keyword(edn::Keyword::plain("to")),
+                    edn::Value::Integer(self.to),
+                ),
+                (
+                    edn::Value::Keyword(edn::Keyword::plain("added")),
+                    datoms(&self.added),
+                ),
+                (
+                    edn::Value::Keyword(edn::Keyword::plain("retracted")),
+                    datoms(&self.retracted),
+                ),
+            ]
+            .into_iter()
+            .collect(),
+        )
+    }
+}
+
+fn entid_to_edn(schema: &Schema, entid: Entid) -> edn::Value {
+    schema
+        .get_ident(entid)
+        .map_or(edn::Value::Integer(entid), |ident| {
+            edn::Value::Keyword(ident.clone())
+        })
+}
+
+fn datom_to_edn(schema: &Schema, datom: &DiffDatom) -> edn::Value {
+    let v = match datom.v {
+        TypedValue::Ref(e) => entid_to_edn(schema, e),
+        ref v => v.to_edn_value_pair().0,
+    };
+    edn::Value::Vector(vec![
+        entid_to_edn(schema, datom.e),
+        entid_to_edn(schema, datom.a),
+        v,
+    ])
+}
+
+/// Compute the net change in the store's datoms from transaction `from` to transaction `to` on
+/// the main timeline. If `to` is before `from`, the diff runs backwards in time.
+pub fn diff(conn: &rusqlite::Connection, schema: &Schema, from: Entid, to: Entid) -> Result<Diff> {
+    // The state after the earlier transaction is reconstructed from the log.
+    crate::history::ensure_history_from(conn, from.min(to))?;
+
+    let (earlier, later) = (from.min(to), from.max(to));
+
+    // For each datom touched in between, compare its last assertion or retraction with whether it
+    // was present after the earlier transaction.  SQLite takes the bare `added` column from the
+    // row with `MAX(tx)`.
+    let mut stmt = conn.prepare(
+        "SELECT l.e, l.a, l.v, l.value_type_tag, l.added FROM
+             (SELECT e, a, v, value_type_tag, MAX(tx) AS tx, added FROM transactions
+              WHERE tx > ?1 AND tx <= ?2 AND e != tx GROUP BY e, a, v, value_type_tag) AS l
+         LEFT JOIN
+             (SELECT e, a, v, value_type_tag, MAX(tx) AS tx, added FROM transactions
+              WHERE tx <= ?1 GROUP BY e, a, v, value_type_tag) AS r
+         ON l.e = r.e AND l.a = r.a AND l.v = r.v AND l.value_type_tag = r.value_type_tag
+         WHERE l.added != COALESCE(r.added, 0)
+         ORDER BY l.e, l.a, l.value_type_tag, l.v",
+    )?;
+    let rows = stmt.query_and_then(&[&earlier, &later], |row| -> Result<(DiffDatom, bool)> {
+        let a: Entid = row.get(1)?;
+        let v = datom_value(conn, schema, a, row.get(2)?, row.get(3)?)?;
+        Ok((
+            DiffDatom {
+                e: row.get(0)?,
+                a,
+                v,
+            },
+            row.get(4)?,
+        ))
+    })?;
+
+    let mut added = vec![];
+    let mut retracted = vec![];
+    for row in rows {
+        let (datom, asserted) = row?;
+        // Running backwards in time, what was asserted in between is what the diff retracts.
+        if asserted == (from <= to) {
+            added.push(datom);
+        } else {
+            retracted.push(datom);
+        }
+    }
+
+    Ok(Diff {
+        from,
+        to,
+        added,
+        retracted,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::borrow::Borrow;
+
+    use crate::debug::TestConn;
+
+    fn assert_diff(conn: &TestConn, from: Entid, to: Entid, expected: &str) {
+        let expected = edn::parse::value(expected).expect("parsed").without_spans();
+        let diff = diff(&conn.sqlite, &conn.schema, from, to).expect("diffed");
+        assert_eq!(diff.to_edn(&conn.schema), expected);
+    }
+
+    #[test]
+    fn test_diff() {
+        let mut conn = TestConn::default();
+        conn.sanitized_partition_map();
+
+        assert_transact!(
+            conn,
+            r#"[{:db/ident :test/one :db/valueType :db.type/long :db/cardinality :db.cardinality/one}
+                {:db/ident :test/many :db/valueType :db.type/ref :db/cardinality :db.cardinality/many}
+                {:db/ident :test/text :db/valueType :db.type/string :db/cardinality :db.cardinality/one :db/fulltext true :db/index true}]"#
+        );
+        let base = conn.last_tx_id();
+        assert_transact!(
+            conn,
+            r#"[{:db/ident :test/e :test/one 1 :test/many :test/one :test/text "first"}]"#
+        );
+        let first = conn.last_tx_id();
+        assert_transact!(
+            conn,
+            r#"[[:db/add :test/e :test/one 2] [:db/add :test/e :test/many :test/text] [:db/add :test/e :test/text "second"]]"#
+        );
+        assert_transact!(
+            conn,
+            r#"[[:db/add :test/e :test/one 3] [:db/retract :test/e :test/many :test/text] [:db/retract :test/e :test/many :test/one] [:db/add :test/e :test/many :test/many]]"#
+        );
+        let last = conn.last_tx_id();
+
+        // Intermediate values, and references added and then retracted, cancel out.
+        assert_diff(
+            &conn,
+            first,
+            last,
+            &format!(
+                r#"{{:from {} :to {}
+                    :added [[:test/e :test/one 3] [:test/e :test/many :test/many] [:test/e :test/text "second"]]
+                    :retracted [[:test/e :test/one 1] [:test/e :test/many :test/one] [:test/e :test/text "first"]]}}"#,
+                first, last
+            ),
+        );
+        assert_diff(
+            &conn,
+            last,
+            first,
+            &format!(
+                r#"{{:from {} :to {}
+                    :added [[:test/e :test/one 1] [:test/e :test/many :test/one] [:test/e :test/text "first"]]
+                    :retracted [[:test/e :test/one 3] [:test/e :test/many :test/many] [:test/e :test/text "second"]]}}"#,
+                last, first
+            ),
+        );
+        assert!(diff(&conn.sqlite, &conn.schema, last, last)
+            .expect("diffed")
+            .is_empty());
+
+        // The reverting transaction can be written out as EDN and transacted from that.
+        let reverting = diff(&conn.sqlite, &conn.schema, first, last)
+            .expect("diffed")
+            .reverting_edn(&conn.schema);
+        assert_eq!(
+            reverting,
+            edn::parse::value(
+                r#"[[:db/retract :test/e :test/one 3] [:db/retract :test/e :test/many :test/many] [:db/retract :test/e :test/text "second"]
+                    [:db/add :test/e :test/one 1] [:db/add :test/e :test/many :test/one] [:db/add :test/e :test/text "first"]]"#
+            )
+            .expect("parsed")
+            .without_spans()
+        );
+        let report = assert_transact!(conn, reverting.to_string().as_str());
+        assert!(diff(&conn.sqlite, &conn.schema, first, report.tx_id)
+            .expect("diffed")
+            .is_empty());
+
+        // Reverting a diff brings the datoms back to how they were.
+        let reverting = diff(&conn.sqlite, &conn.schema, base, conn.last_tx_id())
+            .expect("diffed")
+            .reverting_terms();
+        let sqlite = &conn.sqlite;
+        let (report, _, _, _) = crate::tx::transact_terms(
+            sqlite,
+            conn.partition_map.clone(),
+            conn.schema.borrow(),
+            conn.schema.borrow(),
+            crate::watcher::NullWatcher(),
+            reverting,
+            edn::InternSet::new(),
+        )
+        .expect("reverted");
+        assert!(diff(&conn.sqlite, &conn.schema, base, report.tx_id)
+            .expect("diffed")
+            .is_empty());
+    }
+}
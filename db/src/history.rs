@@ -0,0 +1,244 @@
+// Copyright 2018 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Pruning the transaction log.
+//!
+//! The log records every assertion and retraction ever made, so it grows without bound even when
+//! the current state doesn't. Pruning history before a cutoff removes the retractions made before
+//! it, along with the assertions they retracted. What remains before the cutoff still replays to
+//! the current state, and every transaction keeps its `:db/txInstant`, so `tx-ids` is unaffected.
+//! But the individual transactions before the cutoff are no longer complete, so the cutoff is
+//! recorded as the store's history horizon: anything that needs whole transactions from before
+//! it -- `tx-data`, moving transactions off the main timeline, syncing -- fails instead.
+
+use rusqlite;
+
+use db_traits::errors::{DbErrorKind, Result};
+
+use core_traits::{Entid, TypedValue};
+
+use mentat_core::{DateTime, Utc};
+
+use crate::db::TypedSQLValue;
+
+use crate::entids;
+
+/// Where to prune history up to.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum HistoryCutoff {
+    /// Prune transactions before this one.
+    Tx(Entid),
+
+    /// Prune transactions made before this instant.
+    Instant(DateTime<Utc>),
+}
+
+impl From<Entid> for HistoryCutoff {
+    fn from(tx: Entid) -> HistoryCutoff {
+        HistoryCutoff::Tx(tx)
+    }
+}
+
+impl From<DateTime<Utc>> for HistoryCutoff {
+    fn from(instant: DateTime<Utc>) -> HistoryCutoff {
+        HistoryCutoff::Instant(instant)
+    }
+}
+
+/// What `prune_history` did.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PruneReport {
+    /// The store's history horizon: the first transaction whose history is complete.
+    pub horizon: Entid,
+
+    /// The number of rows removed from the transaction log.
+    pub removed: usize,
+}
+
+/// Return the store's history horizon, if its history has ever been pruned. Transactions before
+/// the horizon might be incomplete.
+pub fn history_horizon(conn: &rusqlite::Connection) -> Result<Option<Entid>> {
+    Ok(conn.query_row(
+        "SELECT MAX(horizon) FROM history_horizon",
+        rusqlite::params![],
+        |row| row.get(0),
+    )?)
+}
+
+/// Fail unless the history of every transaction from `tx` onwards is complete.
+pub fn ensure_history_from(conn: &rusqlite::Connection, tx: Entid) -> Result<()> {
+    match history_horizon(conn)? {
+        Some(horizon) if tx < horizon => bail!(DbErrorKind::HistoryPruned(tx, horizon)),
+        _ => Ok(()),
+    }
+}
+
+/// The transaction after the last one on the main timeline.
+fn next_tx(conn: &rusqlite::Connection) -> Result<Entid> {
+    let last: Option<Entid> = conn.query_row(
+        "SELECT MAX(tx) FROM timelined_transactions WHERE timeline = ?",
+        &[&crate::TIMELINE_MAIN],
+        |row| row.get(0),
+    )?;
+    Ok(last.map_or(0, |tx| tx + 1))
+}
+
+/// The first transaction on the main timeline made at or after `instant`.
+fn first_tx_at(conn: &rusqlite::Connection, instant: DateTime<Utc>) -> Result<Option<Entid>> {
+    let instant = TypedValue::Instant(instant);
+    let (value, tag) = instant.to_sql_value_pair();
+    Ok(conn.query_row(
+        "SELECT MIN(e) FROM datoms WHERE a = ? AND value_type_tag = ? AND v >= ?",
+        rusqlite::params![&entids::DB_TX_INSTANT, &tag, &value],
+        |row| row.get(0),
+    )?)
+}
+
+/// Remove history from the main timeline before `cutoff`, and record the new history horizon.
+/// The current state of the store -- the `datoms` table -- is untouched. The horizon never moves
+/// backwards, and never past the next transaction. The main branch must be checked out.
+pub fn prune_history(conn: &rusqlite::Connection, cutoff: HistoryCutoff) -> Result<PruneReport> {
+    crate::branches::ensure_main_checked_out(conn)?;
+    let next = next_tx(conn)?;
+    let requested = match cutoff {
+        HistoryCutoff::Tx(tx) => tx,
+        HistoryCutoff::Instant(instant) => first_tx_at(conn, instant)?.unwrap_or(next),
+    };
+    let previous = history_horizon(conn)?;
+    let horizon = previous
+        .map_or(requested, |previous| previous.max(requested))
+        .min(next);
+    if let Some(previous) = previous {
+        if previous >= horizon {
+            return Ok(PruneReport {
+                horizon: previous,
+                removed: 0,
+            });
+        }
+    }
+
+    // Retractions, and assertions retracted before the horizon, are superseded. The assertions
+    // that remain are those still in `datoms`, and those retracted after the horizon.
+    let removed = conn.execute(
+        "DELETE FROM timelined_transactions
+         WHERE timeline = ?1 AND tx < ?2 AND (added = 0 OR EXISTS (
+             SELECT 1 FROM timelined_transactions AS r
+             WHERE r.timeline = ?1 AND r.added = 0 AND r.tx < ?2
+               AND r.tx > timelined_transactions.tx
+               AND r.e = timelined_transactions.e
+               AND r.a = timelined_transactions.a
+               AND r.value_type_tag = timelined_transactions.value_type_tag
+               AND r.v = timelined_transactions.v))",
+        rusqlite::params![&crate::TIMELINE_MAIN, &horizon],
+    )?;
+
+    conn.execute("DELETE FROM history_horizon", rusqlite::params![])?;
+    conn.execute(
+        "INSERT INTO history_horizon (horizon) VALUES (?)",
+        &[&horizon],
+    )?;
+
+    Ok(PruneReport { horizon, removed })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::borrow::Borrow;
+
+    use crate::debug::TestConn;
+
+    use crate::timelines::move_from_main_timeline;
+
+    #[test]
+    fn test_prune_history() {
+        let mut conn = TestConn::default();
+
+        assert_transact!(
+            conn,
+            r#"[{:db/ident :test/one :db/valueType :db.type/long :db/cardinality :db.cardinality/one}
+                {:db/ident :test/many :db/valueType :db.type/long :db/cardinality :db.cardinality/many}]"#
+        );
+        assert_transact!(
+            conn,
+            r#"[[:db/add 100 :test/one 1] [:db/add 100 :test/many 1]]"#
+        );
+        assert_transact!(
+            conn,
+            r#"[[:db/add 100 :test/one 2] [:db/retract 100 :test/many 1]]"#
+        );
+
+        // Retracted after the horizon, so kept.
+        assert_transact!(conn, r#"[[:db/add 100 :test/one 3]]"#);
+        let horizon = conn.last_tx_id();
+        let later = horizon;
+        let datoms = conn.datoms().to_edn();
+
+        let report = prune_history(&conn.sqlite, HistoryCutoff::Tx(horizon)).expect("pruned");
+        assert_eq!(report.horizon, horizon);
+        // The retractions of `1` and their assertions.
+        assert_eq!(report.removed, 4);
+        assert_eq!(
+            history_horizon(&conn.sqlite).expect("horizon"),
+            Some(horizon)
+        );
+
+        // Transactions before the horizon keep their instants and what's still current.
+        assert_matches!(
+            conn.transactions(),
+            r#"[[[?one :db/ident :test/one ?tx1 true]
+                 [?one :db/valueType :db.type/long ?tx1 true]
+                 [?one :db/cardinality :db.cardinality/one ?tx1 true]
+                 [?many :db/ident :test/many ?tx1 true]
+                 [?many :db/valueType :db.type/long ?tx1 true]
+                 [?many :db/cardinality :db.cardinality/many ?tx1 true]
+                 [?tx1 :db/txInstant ?ms1 ?tx1 true]]
+                [[?tx2 :db/txInstant ?ms2 ?tx2 true]]
+                [[100 :test/one 2 ?tx3 true]
+                 [?tx3 :db/txInstant ?ms3 ?tx3 true]]
+                [[100 :test/one 2 ?tx4 false]
+                 [100 :test/one 3 ?tx4 true]
+                 [?tx4 :db/txInstant ?ms4 ?tx4 true]]]"#
+        );
+        assert_eq!(conn.datoms().to_edn(), datoms);
+
+        // The horizon doesn't move backwards, or past the next transaction.
+        let report = prune_history(&conn.sqlite, HistoryCutoff::Tx(horizon - 1)).expect("pruned");
+        assert_eq!(
+            report,
+            PruneReport {
+                horizon,
+                removed: 0
+            }
+        );
+        let report = prune_history(&conn.sqlite, HistoryCutoff::Tx(later + 100)).expect("pruned");
+        assert_eq!(report.horizon, later + 1);
+
+        ensure_history_from(&conn.sqlite, later + 1).expect("complete");
+        match ensure_history_from(&conn.sqlite, later)
+            .expect_err("pruned")
+            .kind()
+        {
+            DbErrorKind::HistoryPruned(tx, horizon) => {
+                assert_eq!((tx, horizon), (later, later + 1));
+            }
+            e => panic!("expected pruned history, got {:?}", e),
+        }
+        move_from_main_timeline(
+            &conn.sqlite,
+            &conn.schema,
+            conn.partition_map.clone(),
+            later..,
+            1,
+        )
+        .expect_err("history pruned");
+    }
+}
@@ -45,9 +45,12 @@ pub mod debug;
 
 mod add_retract_alter_set;
 mod bootstrap;
+pub mod branches;
 pub mod cache;
 pub mod db;
+pub mod diff;
 pub mod entids;
+pub mod history;
 pub mod internal_types; // pub because we need them for building entities programmatically.
 mod metadata;
 mod schema;
@@ -57,6 +60,7 @@ mod tx_checking;
 pub mod tx_observer;
 pub mod types;
 mod upsert_resolution;
+pub mod verify;
 mod watcher;
 
 // Export these for reference from sync code and tests.
@@ -64,6 +68,15 @@ pub use crate::bootstrap::{TX0, USER0, V1_PARTS};
 
 pub static TIMELINE_MAIN: i64 = 0;
 
+/// Undone transactions wait here to be redone.  Synced timelines are named by positive entids.
+pub static TIMELINE_UNDO: i64 = -1;
+
+/// The main branch's transactions wait here while another branch is checked out.
+pub static TIMELINE_PARKED: i64 = -2;
+
+/// The main branch's undone transactions wait here while another branch is checked out.
+pub static TIMELINE_PARKED_UNDO: i64 = -3;
+
 pub use crate::schema::{AttributeBuilder, AttributeValidation};
 
 pub use crate::bootstrap::CORE_SCHEMA_VERSION;
@@ -85,6 +98,8 @@ pub use crate::tx_observer::{InProgressObserverTransactWatcher, TxObservationSer
 
 pub use crate::types::{AttributeSet, Partition, PartitionMap, TransactableValue, DB};
 
+pub use crate::verify::{verify, Problem, VerifyReport};
+
 pub fn to_namespaced_keyword(s: &str) -> Result<symbols::Keyword> {
     let splits = [':', '/'];
     let mut i = s.split(&splits[..]);
@@ -14,7 +14,7 @@ use rusqlite;
 
 use db_traits::errors::{DbErrorKind, Result};
 
-use core_traits::{Entid, KnownEntid, TypedValue};
+use core_traits::{Entid, KnownEntid};
 
 use mentat_core::Schema;
 
@@ -23,7 +23,6 @@ use edn::InternSet;
 use edn::entities::OpType;
 
 use crate::db;
-use crate::db::TypedSQLValue;
 
 use crate::tx::{transact_terms_with_action, TransactorAction};
 
@@ -31,7 +30,7 @@ use crate::types::PartitionMap;
 
 use crate::internal_types::{Term, TermWithoutTempIds};
 
-use crate::watcher::NullWatcher;
+use crate::watcher::{NullWatcher, TransactWatcher};
 
 /// Collects a supplied tx range into an DESC ordered Vec of valid txs,
 /// ensuring they all belong to the same timeline.
@@ -69,16 +68,22 @@ fn collect_ordered_txs_to_move(
     Ok(txs)
 }
 
-fn move_transactions_to(
+pub(crate) fn move_transactions_to(
     conn: &rusqlite::Connection,
     tx_ids: &[Entid],
+    timeline: Entid,
     new_timeline: Entid,
 ) -> Result<()> {
-    // Move specified transactions over to a specified timeline.
+    if tx_ids.is_empty() {
+        return Ok(());
+    }
+    // Move specified transactions over to a specified timeline. Transactions on different
+    // timelines can share IDs, so only move those on the given one.
     conn.execute(
         &format!(
-            "UPDATE timelined_transactions SET timeline = {} WHERE tx IN {}",
+            "UPDATE timelined_transactions SET timeline = {} WHERE timeline = {} AND tx IN {}",
             new_timeline,
+            timeline,
             crate::repeat_values(tx_ids.len(), 1)
         ),
         &(tx_ids
@@ -102,28 +107,26 @@ fn is_timeline_empty(conn: &rusqlite::Connection, timeline: Entid) -> Result<boo
     Ok(rows.count() == 0)
 }
 
-/// Get terms for tx_id, reversing them in meaning (swap add & retract).
-fn reversed_terms_for(
+/// Get terms for tx_id on the given timeline, reversing them in meaning (swap add & retract) if
+/// `reverse` is set.
+pub(crate) fn terms_for(
     conn: &rusqlite::Connection,
+    schema: &Schema,
     tx_id: Entid,
+    timeline: Entid,
+    reverse: bool,
 ) -> Result<Vec<TermWithoutTempIds>> {
     let mut stmt = conn.prepare("SELECT e, a, v, value_type_tag, tx, added FROM timelined_transactions WHERE tx = ? AND timeline = ? ORDER BY tx DESC")?;
-    let rows = stmt.query_and_then(
-        &[&tx_id, &crate::TIMELINE_MAIN],
-        |row| -> Result<TermWithoutTempIds> {
-            let op = if row.get(5)? {
-                OpType::Retract
-            } else {
-                OpType::Add
-            };
-            Ok(Term::AddOrRetract(
-                op,
-                KnownEntid(row.get(0)?),
-                row.get(1)?,
-                TypedValue::from_sql_value_pair(row.get(2)?, row.get(3)?)?,
-            ))
-        },
-    )?;
+    let rows = stmt.query_and_then(&[&tx_id, &timeline], |row| -> Result<TermWithoutTempIds> {
+        let op = if row.get::<_, bool>(5)? != reverse {
+            OpType::Add
+        } else {
+            OpType::Retract
+        };
+        let a: Entid = row.get(1)?;
+        let v = db::datom_value(conn, schema, a, row.get(2)?, row.get(3)?)?;
+        Ok(Term::AddOrRetract(op, KnownEntid(row.get(0)?), a, v))
+    })?;
 
     let mut terms = vec![];
 
@@ -133,6 +136,52 @@ fn reversed_terms_for(
     Ok(terms)
 }
 
+/// Rewind schema and datoms through the given main timeline transactions, in the order given,
+/// telling `watcher` about the datoms that change.
+pub(crate) fn rewind<W>(
+    conn: &rusqlite::Connection,
+    schema: &Schema,
+    partition_map: &PartitionMap,
+    txs_to_move: &[Entid],
+    mut watcher: W,
+) -> Result<(Option<Schema>, W)>
+where
+    W: TransactWatcher,
+{
+    let mut last_schema: Option<Schema> = None;
+    for tx_id in txs_to_move {
+        let schema = last_schema.as_ref().unwrap_or(schema);
+        let reversed_terms = terms_for(conn, schema, *tx_id, crate::TIMELINE_MAIN, true)?;
+
+        let (report, _, new_schema, next_watcher) = transact_terms_with_action(
+            conn,
+            partition_map.clone(),
+            schema,
+            schema,
+            watcher,
+            reversed_terms.into_iter().map(|t| t.rewrap()),
+            InternSet::new(),
+            TransactorAction::Materialize,
+        )?;
+        watcher = next_watcher;
+
+        // Rewind operation generated a 'tx' and a 'txInstant' assertion, which got
+        // inserted into the 'datoms' table (due to TransactorAction::Materialize).
+        // This is problematic. If we transact a few more times, the transactor will
+        // generate the same 'tx', but with a different 'txInstant'.
+        // The end result will be a transaction which has a phantom
+        // retraction of a txInstant, since transactor operates against the state of
+        // 'datoms', and not against the 'transactions' table.
+        // A quick workaround is to just remove the bad txInstant datom.
+        // See test_clashing_tx_instants test case.
+        remove_tx_from_datoms(conn, report.tx_id)?;
+        if new_schema.is_some() {
+            last_schema = new_schema;
+        }
+    }
+    Ok((last_schema, watcher))
+}
+
 /// Move specified transaction RangeFrom off of main timeline.
 pub fn move_from_main_timeline(
     conn: &rusqlite::Connection,
@@ -154,41 +203,209 @@ pub fn move_from_main_timeline(
         bail!(DbErrorKind::TimelinesMoveToNonEmpty);
     }
 
+    // Moving transactions means reversing them, which needs all of their history.
+    crate::history::ensure_history_from(conn, txs_from.start)?;
+
     let txs_to_move = collect_ordered_txs_to_move(conn, txs_from, crate::TIMELINE_MAIN)?;
 
-    let mut last_schema = None;
-    for tx_id in &txs_to_move {
-        let reversed_terms = reversed_terms_for(conn, *tx_id)?;
+    let (last_schema, _) = rewind(conn, schema, &partition_map, &txs_to_move, NullWatcher())?;
 
-        // Rewind schema and datoms.
-        let (report, _, new_schema, _) = transact_terms_with_action(
+    // Move transactions over to the target timeline.
+    move_transactions_to(conn, &txs_to_move, crate::TIMELINE_MAIN, new_timeline)?;
+
+    Ok((last_schema, db::read_partition_map(conn)?))
+}
+
+/// What `undo` or `redo` did.
+pub struct TimelineMove<W> {
+    /// The transactions undone or redone, in the order they were.
+    pub txs: Vec<Entid>,
+
+    /// The schema afterwards, if it changed.
+    pub schema: Option<Schema>,
+
+    /// The partition map afterwards.
+    pub partition_map: PartitionMap,
+
+    pub watcher: W,
+}
+
+/// Undone transactions wait on the undo timeline to be redone, but only until the main timeline
+/// moves on: new transactions reuse the entids of undone ones, so redoing them would clash.
+fn discard_stale_redos(conn: &rusqlite::Connection) -> Result<()> {
+    conn.execute(
+        "DELETE FROM timelined_transactions WHERE timeline = ?1 AND
+             (SELECT MIN(tx) FROM timelined_transactions WHERE timeline = ?1) <=
+             (SELECT MAX(tx) FROM timelined_transactions WHERE timeline = ?2)",
+        &[&crate::TIMELINE_UNDO, &crate::TIMELINE_MAIN],
+    )?;
+    Ok(())
+}
+
+/// Synced transactions can't be undone: the remote would still have them, and undoing rewinds the
+/// transaction partition, so new transactions would reuse their IDs.
+fn ensure_not_synced(conn: &rusqlite::Connection, txs: &[Entid]) -> Result<()> {
+    // Sync keeps its mapping from local transactions to remote ones in `tolstoy_tu`, which only
+    // exists once a store has been synced.
+    let syncing: bool = conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'tolstoy_tu')",
+        rusqlite::params![],
+        |row| row.get(0),
+    )?;
+    if !syncing {
+        return Ok(());
+    }
+
+    let mut stmt = conn.prepare_cached("SELECT EXISTS (SELECT 1 FROM tolstoy_tu WHERE tx = ?)")?;
+    for &tx in txs {
+        let synced: bool = stmt.query_row(&[&tx], |row| row.get(0))?;
+        if synced {
+            bail!(DbErrorKind::TimelinesUndoSynced(tx));
+        }
+    }
+    Ok(())
+}
+
+/// Undo the last `n` transactions on the main timeline, most recent first, by rewinding them and
+/// moving them onto the undo timeline. The bootstrap transaction can't be undone, so fewer than
+/// `n` transactions might be, and neither can the base of a branch or anything before it; see
+/// `branches::undo_floor`. Transactions that have been synced can't be undone.
+pub fn undo<W>(
+    conn: &rusqlite::Connection,
+    schema: &Schema,
+    partition_map: PartitionMap,
+    n: usize,
+    watcher: W,
+) -> Result<TimelineMove<W>>
+where
+    W: TransactWatcher,
+{
+    discard_stale_redos(conn)?;
+
+    let mut stmt = conn.prepare(
+        "SELECT DISTINCT tx FROM timelined_transactions WHERE timeline = ? AND tx > ?
+         ORDER BY tx DESC LIMIT ?",
+    )?;
+    let txs: Vec<Entid> = stmt
+        .query_and_then(
+            &[
+                &crate::TIMELINE_MAIN,
+                &crate::branches::undo_floor(conn)?,
+                &(n as i64),
+            ],
+            |row| -> Result<Entid> { Ok(row.get(0)?) },
+        )?
+        .collect::<Result<_>>()?;
+    ensure_not_synced(conn, &txs)?;
+    if let Some(&first) = txs.last() {
+        // Undoing a transaction means reversing it, which needs all of its history.
+        crate::history::ensure_history_from(conn, first)?;
+    }
+
+    let (schema, watcher) = rewind(conn, schema, &partition_map, &txs, watcher)?;
+    move_transactions_to(conn, &txs, crate::TIMELINE_MAIN, crate::TIMELINE_UNDO)?;
+
+    Ok(TimelineMove {
+        txs,
+        schema,
+        partition_map: db::read_partition_map(conn)?,
+        watcher,
+    })
+}
+
+/// Redo the last `n` undone transactions, in the order they were originally made, by replaying
+/// them with their original transaction IDs and moving them back onto the main timeline. Undone
+/// transactions are forgotten once the main timeline moves on, so fewer than `n` might be redone.
+pub fn redo<W>(
+    conn: &rusqlite::Connection,
+    schema: &Schema,
+    partition_map: PartitionMap,
+    n: usize,
+    watcher: W,
+) -> Result<TimelineMove<W>>
+where
+    W: TransactWatcher,
+{
+    discard_stale_redos(conn)?;
+
+    let mut stmt = conn.prepare(
+        "SELECT DISTINCT tx FROM timelined_transactions WHERE timeline = ?
+         ORDER BY tx ASC LIMIT ?",
+    )?;
+    let txs: Vec<Entid> = stmt
+        .query_and_then(
+            &[&crate::TIMELINE_UNDO, &(n as i64)],
+            |row| -> Result<Entid> { Ok(row.get(0)?) },
+        )?
+        .collect::<Result<_>>()?;
+
+    let (schema, partition_map, watcher) = replay(
+        conn,
+        schema,
+        partition_map,
+        crate::TIMELINE_UNDO,
+        &txs,
+        watcher,
+    )?;
+
+    Ok(TimelineMove {
+        txs,
+        schema,
+        partition_map,
+        watcher,
+    })
+}
+
+/// Replay the given transactions from `timeline`, in the order given, with their original
+/// transaction IDs, and move them onto the main timeline. Each must follow on from the main
+/// timeline as it is when it is replayed.
+pub(crate) fn replay<W>(
+    conn: &rusqlite::Connection,
+    schema: &Schema,
+    partition_map: PartitionMap,
+    timeline: Entid,
+    txs: &[Entid],
+    mut watcher: W,
+) -> Result<(Option<Schema>, PartitionMap, W)>
+where
+    W: TransactWatcher,
+{
+    let mut partition_map = partition_map;
+    let mut last_schema: Option<Schema> = None;
+    for &tx_id in txs {
+        let schema = last_schema.as_ref().unwrap_or(schema);
+        let terms = terms_for(conn, schema, tx_id, timeline, false)?;
+
+        // Replay with the original transaction ID, so the replayed datoms match the log.  The
+        // terms include the original :db/txInstant, which the transactor then keeps.
+        let mut replay_partition_map = partition_map.clone();
+        replay_partition_map
+            .get_mut(":db.part/tx")
+            .ok_or(DbErrorKind::FailedToUpdatePartitionMap)?
+            .set_next_entid(tx_id);
+        let (report, _, new_schema, next_watcher) = transact_terms_with_action(
             conn,
-            partition_map.clone(),
+            replay_partition_map,
             schema,
             schema,
-            NullWatcher(),
-            reversed_terms.into_iter().map(|t| t.rewrap()),
+            watcher,
+            terms.into_iter().map(|t| t.rewrap()),
             InternSet::new(),
             TransactorAction::Materialize,
         )?;
+        watcher = next_watcher;
+        if report.tx_id != tx_id {
+            bail!(DbErrorKind::TimelinesReplayMismatch(tx_id, report.tx_id));
+        }
+        if new_schema.is_some() {
+            last_schema = new_schema;
+        }
 
-        // Rewind operation generated a 'tx' and a 'txInstant' assertion, which got
-        // inserted into the 'datoms' table (due to TransactorAction::Materialize).
-        // This is problematic. If we transact a few more times, the transactor will
-        // generate the same 'tx', but with a different 'txInstant'.
-        // The end result will be a transaction which has a phantom
-        // retraction of a txInstant, since transactor operates against the state of
-        // 'datoms', and not against the 'transactions' table.
-        // A quick workaround is to just remove the bad txInstant datom.
-        // See test_clashing_tx_instants test case.
-        remove_tx_from_datoms(conn, report.tx_id)?;
-        last_schema = new_schema;
+        move_transactions_to(conn, &[tx_id], timeline, crate::TIMELINE_MAIN)?;
+        partition_map = db::read_partition_map(conn)?;
     }
 
-    // Move transactions over to the target timeline.
-    move_transactions_to(conn, &txs_to_move, new_timeline)?;
-
-    Ok((last_schema, db::read_partition_map(conn)?))
+    Ok((last_schema, partition_map, watcher))
 }
 
 #[cfg(test)]
@@ -862,4 +1079,155 @@ mod tests {
         assert_matches!(conn.datoms(), "[]");
         assert_matches!(conn.transactions(), "[]");
     }
+
+    #[test]
+    fn test_undo_redo() {
+        let mut conn = TestConn::default();
+        conn.sanitized_partition_map();
+
+        let partition_map0 = conn.partition_map.clone();
+        let schema0 = conn.schema.clone();
+
+        assert_transact!(
+            conn,
+            r#"[
+            {:db/ident :test/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one :db/index true :db/fulltext true}
+            {:db/ident :test/many :db/valueType :db.type/long :db/cardinality :db.cardinality/many}
+        ]"#
+        );
+        assert_transact!(
+            conn,
+            r#"[
+            {:db/id "a" :test/name "Alice" :test/many [1 2]}
+        ]"#
+        );
+        let datoms = conn.datoms().to_edn();
+        let transactions = conn.transactions().to_edn();
+        let fulltext_values = conn.fulltext_values().to_edn();
+        let partition_map = conn.partition_map.clone();
+        let schema = conn.schema.clone();
+
+        // Undo both transactions; there's nothing before them to undo.
+        let moved = undo(
+            &conn.sqlite,
+            &conn.schema,
+            conn.partition_map.clone(),
+            3,
+            NullWatcher(),
+        )
+        .expect("undone");
+        assert_eq!(moved.txs, vec![bootstrap::TX0 + 2, bootstrap::TX0 + 1]);
+        update_conn(&mut conn, &moved.schema, &moved.partition_map);
+
+        assert_matches!(conn.datoms(), "[]");
+        assert_matches!(conn.transactions(), "[]");
+        assert_eq!(conn.partition_map, partition_map0);
+        assert_eq!(conn.schema, schema0);
+
+        // Redo them both, in the order they were made.
+        let moved = redo(
+            &conn.sqlite,
+            &conn.schema,
+            conn.partition_map.clone(),
+            3,
+            NullWatcher(),
+        )
+        .expect("redone");
+        assert_eq!(moved.txs, vec![bootstrap::TX0 + 1, bootstrap::TX0 + 2]);
+        update_conn(&mut conn, &moved.schema, &moved.partition_map);
+
+        assert_eq!(conn.datoms().to_edn(), datoms);
+        assert_eq!(conn.transactions().to_edn(), transactions);
+        assert_eq!(conn.fulltext_values().to_edn(), fulltext_values);
+        assert_eq!(conn.partition_map, partition_map);
+        assert_eq!(conn.schema, schema);
+
+        // Nothing left to redo.
+        let moved = redo(
+            &conn.sqlite,
+            &conn.schema,
+            conn.partition_map.clone(),
+            1,
+            NullWatcher(),
+        )
+        .expect("redone");
+        assert!(moved.txs.is_empty());
+    }
+
+    #[test]
+    fn test_redo_forgotten_after_transact() {
+        let mut conn = TestConn::default();
+        conn.sanitized_partition_map();
+
+        assert_transact!(conn, "[{:db/id :db/doc :db/doc \"first\"}]");
+        let report = assert_transact!(conn, "[{:db/id :db/doc :db/doc \"second\"}]");
+
+        let moved = undo(
+            &conn.sqlite,
+            &conn.schema,
+            conn.partition_map.clone(),
+            1,
+            NullWatcher(),
+        )
+        .expect("undone");
+        assert_eq!(moved.txs, vec![report.tx_id]);
+        update_conn(&mut conn, &moved.schema, &moved.partition_map);
+        assert_matches!(conn.datoms(), "[[?e :db/doc \"first\"]]");
+
+        // The new transaction reuses the undone transaction's ID, so that can't be redone.
+        let report = assert_transact!(conn, "[{:db/id :db/doc :db/doc \"third\"}]");
+        assert_eq!(moved.txs, vec![report.tx_id]);
+
+        let moved = redo(
+            &conn.sqlite,
+            &conn.schema,
+            conn.partition_map.clone(),
+            1,
+            NullWatcher(),
+        )
+        .expect("redone");
+        assert!(moved.txs.is_empty());
+        assert_matches!(conn.datoms(), "[[?e :db/doc \"third\"]]");
+    }
+
+    #[test]
+    fn test_undo_refuses_synced() {
+        let mut conn = TestConn::default();
+        conn.sanitized_partition_map();
+
+        let synced = assert_transact!(conn, "[{:db/id :db/doc :db/doc \"synced\"}]");
+        let local = assert_transact!(conn, "[{:db/id :db/doc :db/doc \"local\"}]");
+
+        // As sync leaves things once it has uploaded the first transaction.
+        conn.sqlite
+            .execute_batch(&format!(
+                "CREATE TABLE tolstoy_tu (tx INTEGER PRIMARY KEY, uuid BLOB NOT NULL UNIQUE) WITHOUT ROWID;
+                 INSERT INTO tolstoy_tu (tx, uuid) VALUES ({}, x'00');",
+                synced.tx_id
+            ))
+            .expect("synced");
+
+        let moved = undo(
+            &conn.sqlite,
+            &conn.schema,
+            conn.partition_map.clone(),
+            1,
+            NullWatcher(),
+        )
+        .expect("undone");
+        assert_eq!(moved.txs, vec![local.tx_id]);
+        update_conn(&mut conn, &moved.schema, &moved.partition_map);
+
+        match undo(
+            &conn.sqlite,
+            &conn.schema,
+            conn.partition_map.clone(),
+            1,
+            NullWatcher(),
+        ) {
+            Err(e) => assert_eq!(e.kind(), DbErrorKind::TimelinesUndoSynced(synced.tx_id)),
+            Ok(_) => panic!("undid a synced transaction"),
+        }
+        assert_matches!(conn.datoms(), "[[?e :db/doc \"synced\"]]");
+    }
 }
@@ -149,6 +149,22 @@ impl TxObservationService {
         let cmd = Box::new(TxCommand::new(&self.observers, txes));
         executor.send(cmd).unwrap();
     }
+
+    /// The store was replaced wholesale, so anything any observer watches may have changed: tell
+    /// each of them so, as of the replacement's latest transaction `tx`.
+    pub fn store_replaced(&mut self, tx: Entid) {
+        let everything: AttributeSet = self
+            .observers
+            .values()
+            .flat_map(|observer| observer.attributes.iter().cloned())
+            .collect();
+        if everything.is_empty() {
+            return;
+        }
+        let mut txes = IndexMap::new();
+        txes.insert(tx, everything);
+        self.in_progress_did_commit(txes);
+    }
 }
 
 impl Drop for TxObservationService {
@@ -178,8 +194,12 @@ impl TransactWatcher for InProgressObserverTransactWatcher {
     }
 
     fn done(&mut self, t: &Entid, _schema: &Schema) -> Result<()> {
+        // Rewinding several transactions in a row (see `timelines::undo`) reuses a transaction ID.
         let collected_attributes = ::std::mem::take(&mut self.collected_attributes);
-        self.txes.insert(*t, collected_attributes);
+        self.txes
+            .entry(*t)
+            .or_default()
+            .extend(collected_attributes);
         Ok(())
     }
 }
@@ -0,0 +1,679 @@
+// Copyright 2018 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Checking the integrity of a store.
+//!
+//! The transactor maintains a number of invariants that nothing in SQLite enforces: that values
+//! have the type their attribute says they have, that the per-datom index flags agree with the
+//! schema, that the materialized `idents` and `schema` tables agree with `datoms`, and that
+//! `datoms` is exactly what the transaction log says it should be. A store that has been written
+//! to by something other than Mentat, or restored from a damaged file, might break any of them.
+//! `verify` checks them all and reports what it finds, without changing anything.
+
+use std::fmt;
+
+use rusqlite;
+
+use db_traits::errors::Result;
+
+use core_traits::{Attribute, AttributeBitFlags, Entid, TypedValue, ValueType};
+
+use mentat_core::{HasSchema, SQLTypeAffinity, SQLValueType, Schema, ValueTypeTag};
+
+use crate::db::{read_db, TypedSQLValue};
+
+use crate::entids;
+
+/// One of the per-datom flags that `datoms` derives from an attribute's schema.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialOrd, PartialEq)]
+pub enum IndexFlag {
+    AVET,
+    VAET,
+    Fulltext,
+    UniqueValue,
+}
+
+impl IndexFlag {
+    fn column(self) -> &'static str {
+        match self {
+            IndexFlag::AVET => "index_avet",
+            IndexFlag::VAET => "index_vaet",
+            IndexFlag::Fulltext => "index_fulltext",
+            IndexFlag::UniqueValue => "unique_value",
+        }
+    }
+}
+
+/// Something wrong with a store.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Problem {
+    /// `datoms` has rows for an attribute that isn't in the schema.
+    UnknownAttribute { a: Entid, datoms: usize },
+
+    /// `datoms` has rows for attribute `a` that aren't of its value type.
+    ValueTypeMismatch {
+        a: Entid,
+        expected: ValueType,
+        value_type_tag: ValueTypeTag,
+        sql_type: String,
+        datoms: usize,
+    },
+
+    /// More than one entity has the same value for the unique attribute `a`.
+    UniqueConflict { a: Entid, entities: Vec<Entid> },
+
+    /// Entity `e` has more than one value for the cardinality-one attribute `a`.
+    CardinalityConflict { e: Entid, a: Entid, datoms: usize },
+
+    /// `datoms` has rows for attribute `a` whose `flag` isn't what the schema says it should be.
+    IndexFlagMismatch {
+        a: Entid,
+        flag: IndexFlag,
+        expected: bool,
+        datoms: usize,
+    },
+
+    /// A fulltext datom refers to a row of `fulltext_values` that doesn't exist.
+    DanglingFulltextValue { e: Entid, a: Entid, rowid: i64 },
+
+    /// `[e a v]` is in `datoms`, but not in the `view` materialized table.
+    MissingFromView {
+        view: &'static str,
+        e: Entid,
+        a: Entid,
+        v: TypedValue,
+    },
+
+    /// `[e a v]` is in the `view` materialized table, but not in `datoms`.
+    ExtraInView {
+        view: &'static str,
+        e: Entid,
+        a: Entid,
+        v: TypedValue,
+    },
+
+    /// The entid `entid` is in use, but `part` would allocate it again.
+    PartitionIndexTooLow {
+        part: String,
+        index: Entid,
+        entid: Entid,
+    },
+
+    /// The transaction log leaves a datom asserted by `tx` that isn't in `datoms`.
+    MissingFromDatoms { e: Entid, a: Entid, tx: Entid },
+
+    /// `datoms` has a datom asserted by `tx` that the transaction log doesn't leave asserted.
+    MissingFromLog { e: Entid, a: Entid, tx: Entid },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Problem::UnknownAttribute { a, datoms } => {
+                write!(f, "{} datoms have unknown attribute {}", datoms, a)
+            }
+            Problem::ValueTypeMismatch {
+                a,
+                expected,
+                value_type_tag,
+                sql_type,
+                datoms,
+            } => write!(
+                f,
+                "{} datoms for attribute {} have tag {} and SQL type {}, not {}",
+                datoms, a, value_type_tag, sql_type, expected
+            ),
+            Problem::UniqueConflict { a, entities } => write!(
+                f,
+                "entities {:?} share a value for unique attribute {}",
+                entities, a
+            ),
+            Problem::CardinalityConflict { e, a, datoms } => write!(
+                f,
+                "entity {} has {} values for cardinality-one attribute {}",
+                e, datoms, a
+            ),
+            Problem::IndexFlagMismatch {
+                a,
+                flag,
+                expected,
+                datoms,
+            } => write!(
+                f,
+                "{} datoms for attribute {} have {} {}, not {}",
+                datoms,
+                a,
+                flag.column(),
+                !expected,
+                expected
+            ),
+            Problem::DanglingFulltextValue { e, a, rowid } => write!(
+                f,
+                "[{} {}] refers to missing fulltext value {}",
+                e, a, rowid
+            ),
+            Problem::MissingFromView { view, e, a, v } => {
+                write!(f, "[{} {} {:?}] is missing from {}", e, a, v, view)
+            }
+            Problem::ExtraInView { view, e, a, v } => {
+                write!(f, "[{} {} {:?}] is in {} but not datoms", e, a, v, view)
+            }
+            Problem::PartitionIndexTooLow { part, index, entid } => write!(
+                f,
+                "partition {} would allocate {} next, but {} is in use",
+                part, index, entid
+            ),
+            Problem::MissingFromDatoms { e, a, tx } => write!(
+                f,
+                "[{} {}] asserted in {} is in the transaction log but not datoms",
+                e, a, tx
+            ),
+            Problem::MissingFromLog { e, a, tx } => write!(
+                f,
+                "[{} {}] asserted in {} is in datoms but not the transaction log",
+                e, a, tx
+            ),
+        }
+    }
+}
+
+/// What `verify` found.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct VerifyReport {
+    /// The number of datoms checked.
+    pub datoms: usize,
+
+    pub problems: Vec<Problem>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+impl fmt::Display for VerifyReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} datoms checked, {} problems found",
+            self.datoms,
+            self.problems.len()
+        )?;
+        for problem in &self.problems {
+            write!(f, "\n{}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+fn sql_type_name(affinity: SQLTypeAffinity) -> &'static str {
+    match affinity {
+        SQLTypeAffinity::Null => "null",
+        SQLTypeAffinity::Integer => "integer",
+        SQLTypeAffinity::Real => "real",
+        SQLTypeAffinity::Text => "text",
+        SQLTypeAffinity::Blob => "blob",
+    }
+}
+
+fn attributes_where<F>(schema: &Schema, f: F) -> String
+where
+    F: Fn(&Attribute) -> bool,
+{
+    let entids: Vec<String> = schema
+        .attribute_map
+        .iter()
+        .filter(|(_, attribute)| f(attribute))
+        .map(|(entid, _)| entid.to_string())
+        .collect();
+    format!("({})", entids.join(", "))
+}
+
+/// Check every datom's value type and index flags against its attribute.
+fn verify_attributes(
+    conn: &rusqlite::Connection,
+    schema: &Schema,
+    problems: &mut Vec<Problem>,
+) -> Result<()> {
+    let mut stmt = conn.prepare(
+        "SELECT a, value_type_tag, typeof(v), index_avet, index_vaet, index_fulltext, unique_value, COUNT(*)
+         FROM datoms
+         GROUP BY a, value_type_tag, typeof(v), index_avet, index_vaet, index_fulltext, unique_value
+         ORDER BY a",
+    )?;
+    let mut rows = stmt.query(rusqlite::params![])?;
+    while let Some(row) = rows.next()? {
+        let a: Entid = row.get(0)?;
+        let value_type_tag: ValueTypeTag = row.get(1)?;
+        let sql_type: String = row.get(2)?;
+        let datoms = row.get::<_, i64>(7)? as usize;
+
+        let attribute = match schema.attribute_for_entid(a) {
+            Some(attribute) => attribute,
+            None => {
+                problems.push(Problem::UnknownAttribute { a, datoms });
+                continue;
+            }
+        };
+
+        // Fulltext values are stored as rowids into `fulltext_values`, so their SQL type is
+        // never the type's own.
+        let (expected_tag, affinity) = attribute.value_type.sql_representation();
+        let affinity_matches = attribute.fulltext
+            || affinity.map_or(true, |affinity| sql_type == sql_type_name(affinity));
+        if value_type_tag != expected_tag || !affinity_matches {
+            problems.push(Problem::ValueTypeMismatch {
+                a,
+                expected: attribute.value_type,
+                value_type_tag,
+                sql_type,
+                datoms,
+            });
+        }
+
+        let expected = attribute.flags();
+        let flags = [
+            (IndexFlag::AVET, 3, AttributeBitFlags::IndexAVET as u8),
+            (IndexFlag::VAET, 4, AttributeBitFlags::IndexVAET as u8),
+            (
+                IndexFlag::Fulltext,
+                5,
+                AttributeBitFlags::IndexFulltext as u8,
+            ),
+            (
+                IndexFlag::UniqueValue,
+                6,
+                AttributeBitFlags::UniqueValue as u8,
+            ),
+        ];
+        for &(flag, column, bit) in flags.iter() {
+            let expected = expected & bit != 0;
+            let actual = row.get::<_, bool>(column)?;
+            if actual != expected {
+                problems.push(Problem::IndexFlagMismatch {
+                    a,
+                    flag,
+                    expected,
+                    datoms,
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Check that unique attributes have unique values, and cardinality-one attributes one value.
+fn verify_constraints(
+    conn: &rusqlite::Connection,
+    schema: &Schema,
+    problems: &mut Vec<Problem>,
+) -> Result<()> {
+    let unique = attributes_where(schema, |attribute| attribute.unique.is_some());
+    let mut stmt = conn.prepare(&format!(
+        "SELECT a, group_concat(e) FROM datoms WHERE a IN {}
+         GROUP BY a, value_type_tag, v HAVING COUNT(DISTINCT e) > 1 ORDER BY a",
+        unique
+    ))?;
+    let mut rows = stmt.query(rusqlite::params![])?;
+    while let Some(row) = rows.next()? {
+        let entities: String = row.get(1)?;
+        let mut entities: Vec<Entid> = entities.split(',').filter_map(|e| e.parse().ok()).collect();
+        entities.sort_unstable();
+        entities.dedup();
+        problems.push(Problem::UniqueConflict {
+            a: row.get(0)?,
+            entities,
+        });
+    }
+
+    let one = attributes_where(schema, |attribute| !attribute.multival);
+    let mut stmt = conn.prepare(&format!(
+        "SELECT e, a, COUNT(*) FROM datoms WHERE a IN {}
+         GROUP BY e, a HAVING COUNT(*) > 1 ORDER BY e, a",
+        one
+    ))?;
+    let mut rows = stmt.query(rusqlite::params![])?;
+    while let Some(row) = rows.next()? {
+        problems.push(Problem::CardinalityConflict {
+            e: row.get(0)?,
+            a: row.get(1)?,
+            datoms: row.get::<_, i64>(2)? as usize,
+        });
+    }
+    Ok(())
+}
+
+fn verify_fulltext(conn: &rusqlite::Connection, problems: &mut Vec<Problem>) -> Result<()> {
+    let mut stmt = conn.prepare(
+        "SELECT e, a, v FROM datoms
+         WHERE index_fulltext IS NOT 0 AND v NOT IN (SELECT rowid FROM fulltext_values)
+         ORDER BY e, a",
+    )?;
+    let mut rows = stmt.query(rusqlite::params![])?;
+    while let Some(row) = rows.next()? {
+        problems.push(Problem::DanglingFulltextValue {
+            e: row.get(0)?,
+            a: row.get(1)?,
+            rowid: row.get(2)?,
+        });
+    }
+    Ok(())
+}
+
+/// Compare a materialized table with the slice of `datoms` it is materialized from. This
+/// mirrors the queries in `update_metadata`.
+fn verify_view(
+    conn: &rusqlite::Connection,
+    view: &'static str,
+    source: &str,
+    problems: &mut Vec<Problem>,
+) -> Result<()> {
+    let materialized = format!("SELECT e, a, v, value_type_tag FROM {}", view);
+    for &(ref query, in_view) in [
+        (format!("{} EXCEPT {}", source, materialized), false),
+        (format!("{} EXCEPT {}", materialized, source), true),
+    ]
+    .iter()
+    {
+        let mut stmt = conn.prepare(query)?;
+        let mut rows = stmt.query(rusqlite::params![])?;
+        while let Some(row) = rows.next()? {
+            let e = row.get(0)?;
+            let a = row.get(1)?;
+            let v = TypedValue::from_sql_value_pair(row.get(2)?, row.get(3)?)?;
+            problems.push(if in_view {
+                Problem::ExtraInView { view, e, a, v }
+            } else {
+                Problem::MissingFromView { view, e, a, v }
+            });
+        }
+    }
+    Ok(())
+}
+
+fn verify_views(conn: &rusqlite::Connection, problems: &mut Vec<Problem>) -> Result<()> {
+    let idents = format!(
+        "SELECT e, a, v, value_type_tag FROM datoms WHERE a IN {}",
+        entids::IDENTS_SQL_LIST.as_str()
+    );
+    verify_view(conn, "idents", &idents, problems)?;
+
+    let schema = format!(
+        "SELECT e, a, v, value_type_tag FROM datoms
+         WHERE e IN (SELECT e FROM datoms WHERE a = {}) AND a IN {}",
+        entids::DB_VALUE_TYPE,
+        entids::SCHEMA_SQL_LIST.as_str()
+    );
+    verify_view(conn, "schema", &schema, problems)
+}
+
+/// Check that no partition would allocate an entid that is already in use, as an entity, a
+/// transaction, or a ref value.
+fn verify_partitions(
+    conn: &rusqlite::Connection,
+    partition_map: &crate::PartitionMap,
+    problems: &mut Vec<Problem>,
+) -> Result<()> {
+    let mut stmt = conn.prepare(
+        "SELECT MAX(entid) FROM (
+             SELECT e AS entid FROM datoms
+             UNION ALL SELECT tx FROM datoms
+             UNION ALL SELECT v FROM datoms WHERE value_type_tag = 0)
+         WHERE entid >= ? AND entid <= ?",
+    )?;
+    for (part, partition) in partition_map.iter() {
+        let max: Option<Entid> =
+            stmt.query_row(&[&partition.start, &partition.end], |row| row.get(0))?;
+        if let Some(entid) = max.filter(|&entid| entid >= partition.next_entid()) {
+            problems.push(Problem::PartitionIndexTooLow {
+                part: part.clone(),
+                index: partition.next_entid(),
+                entid,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Check that `datoms` is exactly what folding the transaction log gives: the datoms whose last
+/// appearance in the log is an assertion, each with the transaction that asserted it.
+fn verify_log(conn: &rusqlite::Connection, problems: &mut Vec<Problem>) -> Result<()> {
+    // SQLite takes bare columns from the row that `MAX` picks, so `added` is from the last
+    // transaction to mention each datom.
+    let folded = "SELECT e, a, v, value_type_tag, tx FROM (
+                      SELECT e, a, v, value_type_tag, MAX(tx) AS tx, added FROM transactions
+                      GROUP BY e, a, value_type_tag, v)
+                  WHERE added = 1";
+    let current = "SELECT e, a, v, value_type_tag, tx FROM datoms";
+    for &(ref query, in_log) in [
+        (format!("{} EXCEPT {}", folded, current), true),
+        (format!("{} EXCEPT {}", current, folded), false),
+    ]
+    .iter()
+    {
+        let mut stmt = conn.prepare(&format!(
+            "SELECT e, a, tx FROM ({}) ORDER BY tx, e, a",
+            query
+        ))?;
+        let mut rows = stmt.query(rusqlite::params![])?;
+        while let Some(row) = rows.next()? {
+            let (e, a, tx) = (row.get(0)?, row.get(1)?, row.get(2)?);
+            problems.push(if in_log {
+                Problem::MissingFromDatoms { e, a, tx }
+            } else {
+                Problem::MissingFromLog { e, a, tx }
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Check the integrity of the store behind `conn`. Problems with the store's data are reported,
+/// not returned as errors; an error means the store couldn't be read at all. Run this inside a
+/// transaction to check a consistent snapshot.
+pub fn verify(conn: &rusqlite::Connection) -> Result<VerifyReport> {
+    let db = read_db(conn)?;
+    let datoms: i64 =
+        conn.query_row("SELECT COUNT(*) FROM datoms", rusqlite::params![], |row| {
+            row.get(0)
+        })?;
+
+    let mut problems = vec![];
+    verify_attributes(conn, &db.schema, &mut problems)?;
+    verify_constraints(conn, &db.schema, &mut problems)?;
+    verify_fulltext(conn, &mut problems)?;
+    verify_views(conn, &mut problems)?;
+    verify_partitions(conn, &db.partition_map, &mut problems)?;
+    verify_log(conn, &mut problems)?;
+
+    Ok(VerifyReport {
+        datoms: datoms as usize,
+        problems,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::debug::TestConn;
+
+    use crate::history::{prune_history, HistoryCutoff};
+
+    fn problems_after(conn: &mut TestConn, corruption: &str) -> Vec<Problem> {
+        let tx = conn.sqlite.transaction().expect("began");
+        tx.execute_batch(corruption).expect("corrupted");
+        let report = verify(&tx).expect("verified");
+        // Dropping the transaction rolls the corruption back.
+        report.problems
+    }
+
+    #[test]
+    fn test_verify() {
+        let mut conn = TestConn::default();
+
+        assert_transact!(
+            conn,
+            r#"[{:db/id 200 :db/ident :test/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one :db/unique :db.unique/identity :db/index true}
+                {:db/id 201 :db/ident :test/one :db/valueType :db.type/long :db/cardinality :db.cardinality/one}
+                {:db/id 202 :db/ident :test/text :db/valueType :db.type/string :db/cardinality :db.cardinality/one :db/fulltext true :db/index true}
+                {:db/id 203 :db/ident :test/ref :db/valueType :db.type/ref :db/cardinality :db.cardinality/many}]"#
+        );
+        assert_transact!(
+            conn,
+            r#"[[:db/add 100 :test/name "a"]
+                [:db/add 100 :test/one 1]
+                [:db/add 100 :test/text "hello"]
+                [:db/add 101 :test/ref 100]]"#
+        );
+        assert_transact!(conn, r#"[[:db/add 100 :test/one 2]]"#);
+        let tx = conn.last_tx_id();
+
+        let report = verify(&conn.sqlite).expect("verified");
+        assert!(report.is_ok(), "{}", report);
+        assert!(report.datoms > 0);
+
+        // Pruned history still folds to the current state.
+        prune_history(&conn.sqlite, HistoryCutoff::Tx(tx)).expect("pruned");
+        let report = verify(&conn.sqlite).expect("verified");
+        assert!(report.is_ok(), "{}", report);
+
+        assert_eq!(
+            problems_after(&mut conn, "UPDATE datoms SET index_avet = 1 WHERE a = 201"),
+            vec![Problem::IndexFlagMismatch {
+                a: 201,
+                flag: IndexFlag::AVET,
+                expected: false,
+                datoms: 1,
+            }]
+        );
+
+        assert_eq!(
+            problems_after(
+                &mut conn,
+                "UPDATE datoms SET v = 2.5 WHERE a = 201;
+                 UPDATE timelined_transactions SET v = 2.5 WHERE a = 201 AND v = 2"
+            ),
+            vec![Problem::ValueTypeMismatch {
+                a: 201,
+                expected: ValueType::Long,
+                value_type_tag: 5,
+                sql_type: "real".to_string(),
+                datoms: 1,
+            }]
+        );
+
+        assert_eq!(
+            problems_after(
+                &mut conn,
+                &format!(
+                    "INSERT INTO datoms (e, a, v, tx, value_type_tag, index_avet)
+                     VALUES (99, 200, 'a', {}, 10, 1)",
+                    tx
+                )
+            ),
+            vec![
+                Problem::IndexFlagMismatch {
+                    a: 200,
+                    flag: IndexFlag::UniqueValue,
+                    expected: true,
+                    datoms: 1,
+                },
+                Problem::UniqueConflict {
+                    a: 200,
+                    entities: vec![99, 100],
+                },
+                Problem::MissingFromLog { e: 99, a: 200, tx },
+            ]
+        );
+
+        assert_eq!(
+            problems_after(
+                &mut conn,
+                "INSERT INTO datoms (e, a, v, tx, value_type_tag)
+                 SELECT e, a, 3, tx, value_type_tag FROM datoms WHERE a = 201;
+                 INSERT INTO timelined_transactions (e, a, v, tx, value_type_tag)
+                 SELECT e, a, 3, tx, value_type_tag FROM datoms WHERE a = 201 AND v = 2"
+            ),
+            vec![Problem::CardinalityConflict {
+                e: 100,
+                a: 201,
+                datoms: 2,
+            }]
+        );
+
+        let rowid: i64 = conn
+            .sqlite
+            .query_row(
+                "SELECT v FROM datoms WHERE a = 202",
+                rusqlite::params![],
+                |row| row.get(0),
+            )
+            .expect("rowid");
+        assert_eq!(
+            problems_after(&mut conn, "DELETE FROM fulltext_values"),
+            vec![Problem::DanglingFulltextValue {
+                e: 100,
+                a: 202,
+                rowid,
+            }]
+        );
+
+        assert_eq!(
+            problems_after(
+                &mut conn,
+                "INSERT INTO idents (e, a, v, value_type_tag) VALUES (99, 1, ':test/bogus', 13)"
+            ),
+            vec![Problem::ExtraInView {
+                view: "idents",
+                e: 99,
+                a: entids::DB_IDENT,
+                v: TypedValue::typed_ns_keyword("test", "bogus"),
+            }]
+        );
+        assert_eq!(
+            problems_after(
+                &mut conn,
+                &format!(
+                    "DELETE FROM schema WHERE e = 201 AND a = {}",
+                    entids::DB_CARDINALITY
+                )
+            ),
+            vec![Problem::MissingFromView {
+                view: "schema",
+                e: 201,
+                a: entids::DB_CARDINALITY,
+                v: TypedValue::Ref(entids::DB_CARDINALITY_ONE),
+            }]
+        );
+
+        assert_eq!(
+            problems_after(
+                &mut conn,
+                "UPDATE datoms SET v = 65600 WHERE a = 203;
+                 UPDATE timelined_transactions SET v = 65600 WHERE a = 203"
+            ),
+            vec![Problem::PartitionIndexTooLow {
+                part: ":db.part/user".to_string(),
+                index: crate::USER0,
+                entid: 65600,
+            }]
+        );
+
+        assert_eq!(
+            problems_after(&mut conn, "DELETE FROM datoms WHERE a = 201"),
+            vec![Problem::MissingFromDatoms { e: 100, a: 201, tx }]
+        );
+
+        let report = verify(&conn.sqlite).expect("verified");
+        assert!(report.is_ok(), "{}", report);
+    }
+}
@@ -113,7 +113,14 @@ peg::parser!(pub grammar parse() for str {
 
     // TODO: standalone characters: \<char>, \newline, \return, \space and \tab.
     // rule string_standalone_chars() ->
-    rule string_special_char() -> &'input str = "\\" c:$(['\\' | '"' | 'n' | 't' | 'r']) { c }
+    rule string_special_char() -> &'input str = "\\" c:$(['\\' | '"' | 'n' | 't' | 'r']) {
+        match c {
+            "n" => "\n",
+            "t" => "\t",
+            "r" => "\r",
+            c => c,
+        }
+    }
     rule string_normal_chars() -> &'input str = c:$((!['\"' | '\\'][_])+) { c }
 
     // This is what we need to do in order to unescape. We can't just match the entire string slice:
@@ -348,8 +355,8 @@ peg::parser!(pub grammar parse() for str {
     rule pull_attribute() -> query::PullAttributeSpec
         = __ "*" __ { query::PullAttributeSpec::Wildcard }
         / __ k:raw_forward_namespaced_keyword() __ alias:(":as" __ alias:raw_forward_keyword() __ { alias })? {
-            let attribute = query::PullConcreteAttribute::Ident(::std::rc::Rc::new(k));
-            let alias = alias.map(::std::rc::Rc::new);
+            let attribute = query::PullConcreteAttribute::Ident(ValueRc::new(k));
+            let alias = alias.map(ValueRc::new);
             query::PullAttributeSpec::Attribute(
                 query::NamedPullAttribute {
                     attribute,
@@ -386,10 +393,12 @@ peg::parser!(pub grammar parse() for str {
           a:pattern_non_value_place()
           v:pattern_value_place()?
           tx:pattern_non_value_place()?
+          added:pattern_value_place()?
         "]" __
         {?
             let v = v.unwrap_or(query::PatternValuePlace::Placeholder);
             let tx = tx.unwrap_or(query::PatternNonValuePlace::Placeholder);
+            let added = added.unwrap_or(query::PatternValuePlace::Placeholder);
 
             // Pattern::new takes care of reversal of reversed
             // attributes: [?x :foo/_bar ?y] turns into
@@ -410,7 +419,7 @@ peg::parser!(pub grammar parse() for str {
             //
             // is nonsense. That leaves us with a nested optional, which we unwrap here.
             query::Pattern::new(src, e, a, v, tx)
-                .map(query::WhereClause::Pattern)
+                .map(|p| query::WhereClause::Pattern(p.with_added(added)))
                 .ok_or("expected pattern")
         }
 
@@ -497,7 +506,7 @@ peg::parser!(pub grammar parse() for str {
 
     rule query_part() -> query::QueryPart
         = __ ":find" fs:find_spec() { query::QueryPart::FindSpec(fs) }
-        / __ ":in" in_vars:variable()+ { query::QueryPart::InVars(in_vars) }
+        / __ ":in" ins:in_binding()+ { query::QueryPart::In(ins) }
         / __ ":limit" l:limit() { query::QueryPart::Limit(l) }
         / __ ":order" os:order()+ { query::QueryPart::Order(os) }
         / __ ":where" ws:where_clause()+ { query::QueryPart::WhereClauses(ws) }
@@ -512,6 +521,10 @@ peg::parser!(pub grammar parse() for str {
     rule src_var() -> query::SrcVar
         = v:value() {? query::SrcVar::from_value(&v).ok_or("expected src_var") }
 
+    rule in_binding() -> query::InBinding
+        = s:src_var() { query::InBinding::Source(s) }
+        / v:variable() { query::InBinding::Variable(v) }
+
     rule variable_or_placeholder() -> query::VariableOrPlaceholder
         = v:variable() { query::VariableOrPlaceholder::Variable(v) }
         / __ "_" __ { query::VariableOrPlaceholder::Placeholder }
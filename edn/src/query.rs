@@ -44,7 +44,7 @@ pub use crate::{Keyword, PlainSymbol};
 pub type SrcVarName = String; // Do not include the required syntactic '$'.
 
 #[derive(Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
-pub struct Variable(pub Rc<PlainSymbol>);
+pub struct Variable(pub ValueRc<PlainSymbol>);
 
 impl Variable {
     pub fn as_str(&self) -> &str {
@@ -59,7 +59,7 @@ impl Variable {
     pub fn from_valid_name(name: &str) -> Variable {
         let s = PlainSymbol::plain(name);
         assert!(s.is_var_symbol());
-        Variable(Rc::new(s))
+        Variable(ValueRc::new(s))
     }
 }
 
@@ -83,7 +83,7 @@ impl FromValue<Variable> for Variable {
 impl Variable {
     pub fn from_rc(sym: Rc<PlainSymbol>) -> Option<Variable> {
         if sym.is_var_symbol() {
-            Some(Variable(sym))
+            Some(Variable(ValueRc::from_rc(sym)))
         } else {
             None
         }
@@ -92,7 +92,7 @@ impl Variable {
     /// TODO: intern strings. #398.
     pub fn from_symbol(sym: &PlainSymbol) -> Option<Variable> {
         if sym.is_var_symbol() {
-            Some(Variable(Rc::new(sym.clone())))
+            Some(Variable(ValueRc::new(sym.clone())))
         } else {
             None
         }
@@ -147,7 +147,7 @@ pub enum Direction {
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct Order(pub Direction, pub Variable); // Future: Element instead of Variable?
 
-#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
 pub enum SrcVar {
     DefaultSrc,
     NamedSrc(SrcVarName),
@@ -175,6 +175,13 @@ impl SrcVar {
             None
         }
     }
+
+    pub fn name(&self) -> PlainSymbol {
+        match *self {
+            SrcVar::DefaultSrc => PlainSymbol::plain("$"),
+            SrcVar::NamedSrc(ref name) => PlainSymbol::plain(format!("${}", name)),
+        }
+    }
 }
 
 /// These are the scalar values representable in EDN.
@@ -461,14 +468,14 @@ impl PatternValuePlace {
 
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum PullConcreteAttribute {
-    Ident(Rc<Keyword>),
+    Ident(ValueRc<Keyword>),
     Entid(i64),
 }
 
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct NamedPullAttribute {
     pub attribute: PullConcreteAttribute,
-    pub alias: Option<Rc<Keyword>>,
+    pub alias: Option<ValueRc<Keyword>>,
 }
 
 impl From<PullConcreteAttribute> for NamedPullAttribute {
@@ -790,6 +797,8 @@ pub struct Pattern {
     pub attribute: PatternNonValuePlace,
     pub value: PatternValuePlace,
     pub tx: PatternNonValuePlace,
+    /// Whether the datom was asserted or retracted. Only meaningful against a history source.
+    pub added: PatternValuePlace,
 }
 
 impl Pattern {
@@ -822,6 +831,7 @@ impl Pattern {
                         attribute: k.to_reversed().into(),
                         value: e_v,
                         tx,
+                        added: PatternValuePlace::Placeholder,
                     });
                 } else {
                     return None;
@@ -834,8 +844,14 @@ impl Pattern {
             attribute: a,
             value: v,
             tx,
+            added: PatternValuePlace::Placeholder,
         })
     }
+
+    /// Set the fifth place of this pattern, which matches the `added` flag of a history source.
+    pub fn with_added(self, added: PatternValuePlace) -> Pattern {
+        Pattern { added, ..self }
+    }
 }
 
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -957,10 +973,16 @@ pub struct ParsedQuery {
     pub order: Option<Vec<Order>>,
 }
 
+/// A single entry in a query's `:in` list: either a source or a variable.
+pub(crate) enum InBinding {
+    Source(SrcVar),
+    Variable(Variable),
+}
+
 pub(crate) enum QueryPart {
     FindSpec(FindSpec),
     WithVars(Vec<Variable>),
-    InVars(Vec<Variable>),
+    In(Vec<InBinding>),
     Limit(Limit),
     WhereClauses(Vec<WhereClause>),
     Order(Vec<Order>),
@@ -979,6 +1001,7 @@ impl ParsedQuery {
         let mut find_spec: Option<FindSpec> = None;
         let mut with: Option<Vec<Variable>> = None;
         let mut in_vars: Option<Vec<Variable>> = None;
+        let mut in_sources: BTreeSet<SrcVar> = BTreeSet::default();
         let mut limit: Option<Limit> = None;
         let mut where_clauses: Option<Vec<WhereClause>> = None;
         let mut order: Option<Vec<Order>> = None;
@@ -997,11 +1020,22 @@ impl ParsedQuery {
                     }
                     with = Some(x)
                 }
-                QueryPart::InVars(x) => {
+                QueryPart::In(x) => {
                     if in_vars.is_some() {
                         return Err("find query has repeated :in");
                     }
-                    in_vars = Some(x)
+                    let mut vars = Vec::with_capacity(x.len());
+                    for binding in x.into_iter() {
+                        match binding {
+                            InBinding::Source(s) => {
+                                if !in_sources.insert(s) {
+                                    return Err("find query has repeated source in :in");
+                                }
+                            }
+                            InBinding::Variable(v) => vars.push(v),
+                        }
+                    }
+                    in_vars = Some(vars)
                 }
                 QueryPart::Limit(x) => {
                     if limit.is_some() {
@@ -1029,7 +1063,7 @@ impl ParsedQuery {
             default_source: SrcVar::DefaultSrc,
             with: with.unwrap_or_else(Vec::new), //
             in_vars: in_vars.unwrap_or_else(Vec::new),
-            in_sources: BTreeSet::default(),
+            in_sources,
             limit: limit.unwrap_or(Limit::None),
             where_clauses: where_clauses.ok_or("expected :where")?,
             order,
@@ -1207,5 +1241,8 @@ impl ContainsVariables for Pattern {
         if let PatternNonValuePlace::Variable(ref v) = self.tx {
             acc_ref(acc, v)
         }
+        if let PatternValuePlace::Variable(ref v) = self.added {
+            acc_ref(acc, v)
+        }
     }
 }
@@ -544,7 +544,6 @@ macro_rules! def_common_value_display {
                 v.to_rfc3339_opts(SecondsFormat::AutoSi, true)
             ),
             $t::BigInteger(ref v) => write!($f, "{}N", v),
-            // TODO: make sure float syntax is correct.
             $t::Float(ref v) => {
                 if *v == OrderedFloat(f64::INFINITY) {
                     write!($f, "#f +Infinity")
@@ -553,11 +552,25 @@ macro_rules! def_common_value_display {
                 } else if *v == OrderedFloat(f64::NAN) {
                     write!($f, "#f NaN")
                 } else {
-                    write!($f, "{}", v)
+                    // Debug formatting keeps a decimal point or exponent, so this reads back as a
+                    // float rather than an integer.
+                    write!($f, "{:?}", v.0)
                 }
             }
-            // TODO: EDN escaping.
-            $t::Text(ref v) => write!($f, "\"{}\"", v),
+            $t::Text(ref v) => {
+                write!($f, "\"")?;
+                for c in v.chars() {
+                    match c {
+                        '"' => write!($f, "\\\"")?,
+                        '\\' => write!($f, "\\\\")?,
+                        '\n' => write!($f, "\\n")?,
+                        '\t' => write!($f, "\\t")?,
+                        '\r' => write!($f, "\\r")?,
+                        c => write!($f, "{}", c)?,
+                    }
+                }
+                write!($f, "\"")
+            }
             $t::Uuid(ref u) => write!($f, "#uuid \"{}\"", u.to_hyphenated().to_string()),
             $t::PlainSymbol(ref v) => v.fmt($f),
             $t::NamespacedSymbol(ref v) => v.fmt($f),
@@ -14,7 +14,8 @@ use edn::{Keyword, PlainSymbol};
 
 use edn::query::{
     Direction, Element, FindSpec, FnArg, Limit, NonIntegerConstant, OrJoin, OrWhereClause, Order,
-    Pattern, PatternNonValuePlace, PatternValuePlace, Predicate, UnifyVars, Variable, WhereClause,
+    Pattern, PatternNonValuePlace, PatternValuePlace, Predicate, SrcVar, UnifyVars, Variable,
+    WhereClause,
 };
 
 use edn::parse::parse_query;
@@ -42,6 +43,7 @@ fn can_parse_predicates() {
                 attribute: PatternNonValuePlace::Placeholder,
                 value: PatternValuePlace::Variable(Variable::from_valid_name("?y")),
                 tx: PatternNonValuePlace::Placeholder,
+                added: PatternValuePlace::Placeholder,
             }),
             WhereClause::Pred(Predicate {
                 operator: PlainSymbol::plain("<"),
@@ -74,6 +76,7 @@ fn can_parse_simple_or() {
                     attribute: PatternNonValuePlace::Placeholder,
                     value: PatternValuePlace::EntidOrInteger(10),
                     tx: PatternNonValuePlace::Placeholder,
+                    added: PatternValuePlace::Placeholder,
                 })),
                 OrWhereClause::Clause(WhereClause::Pattern(Pattern {
                     source: None,
@@ -81,6 +84,7 @@ fn can_parse_simple_or() {
                     attribute: PatternNonValuePlace::Placeholder,
                     value: PatternValuePlace::EntidOrInteger(15),
                     tx: PatternNonValuePlace::Placeholder,
+                    added: PatternValuePlace::Placeholder,
                 })),
             ],
         )),]
@@ -106,6 +110,7 @@ fn can_parse_unit_or_join() {
                 attribute: PatternNonValuePlace::Placeholder,
                 value: PatternValuePlace::EntidOrInteger(15),
                 tx: PatternNonValuePlace::Placeholder,
+                added: PatternValuePlace::Placeholder,
             })),],
         )),]
     );
@@ -131,6 +136,7 @@ fn can_parse_simple_or_join() {
                     attribute: PatternNonValuePlace::Placeholder,
                     value: PatternValuePlace::EntidOrInteger(10),
                     tx: PatternNonValuePlace::Placeholder,
+                    added: PatternValuePlace::Placeholder,
                 })),
                 OrWhereClause::Clause(WhereClause::Pattern(Pattern {
                     source: None,
@@ -138,6 +144,7 @@ fn can_parse_simple_or_join() {
                     attribute: PatternNonValuePlace::Placeholder,
                     value: PatternValuePlace::EntidOrInteger(-15),
                     tx: PatternNonValuePlace::Placeholder,
+                    added: PatternValuePlace::Placeholder,
                 })),
             ],
         )),]
@@ -169,6 +176,7 @@ fn can_parse_simple_or_and_join() {
                     attribute: PatternNonValuePlace::Placeholder,
                     value: PatternValuePlace::EntidOrInteger(10),
                     tx: PatternNonValuePlace::Placeholder,
+                    added: PatternValuePlace::Placeholder,
                 })),
                 OrWhereClause::And(vec![
                     WhereClause::OrJoin(OrJoin::new(
@@ -182,6 +190,7 @@ fn can_parse_simple_or_and_join() {
                                 attribute: ident("foo", "bar"),
                                 value: PatternValuePlace::Variable(Variable::from_valid_name("?y")),
                                 tx: PatternNonValuePlace::Placeholder,
+                                added: PatternValuePlace::Placeholder,
                             })),
                             OrWhereClause::Clause(WhereClause::Pattern(Pattern {
                                 source: None,
@@ -191,6 +200,7 @@ fn can_parse_simple_or_and_join() {
                                 attribute: ident("foo", "baz"),
                                 value: PatternValuePlace::Variable(Variable::from_valid_name("?y")),
                                 tx: PatternNonValuePlace::Placeholder,
+                                added: PatternValuePlace::Placeholder,
                             })),
                         ],
                     )),
@@ -280,6 +290,54 @@ fn can_parse_limit() {
     );
 }
 
+#[test]
+fn can_parse_in_sources() {
+    let s = "[:find ?x :in $ $before ?y :where [$before ?x :foo/baz ?y]]";
+    let p = parse_query(s).unwrap();
+    assert_eq!(p.in_vars, vec![Variable::from_valid_name("?y")]);
+    assert_eq!(
+        p.in_sources,
+        vec![SrcVar::DefaultSrc, SrcVar::NamedSrc("before".to_string())]
+            .into_iter()
+            .collect()
+    );
+
+    let repeated = "[:find ?x :in $a $a :where [$a ?x :foo/baz ?y]]";
+    assert!(parse_query(repeated).is_err());
+}
+
+#[test]
+fn can_parse_history_pattern() {
+    let s = "[:find ?v ?tx ?added :in $h :where [$h ?e :foo/bar ?v ?tx ?added]]";
+    let p = parse_query(s).unwrap();
+    assert_eq!(
+        p.where_clauses,
+        vec![WhereClause::Pattern(
+            Pattern::new(
+                Some(SrcVar::NamedSrc("h".to_string())),
+                PatternNonValuePlace::Variable(Variable::from_valid_name("?e")),
+                Keyword::namespaced("foo", "bar").into(),
+                PatternValuePlace::Variable(Variable::from_valid_name("?v")),
+                PatternNonValuePlace::Variable(Variable::from_valid_name("?tx"))
+            )
+            .expect("valid pattern")
+            .with_added(PatternValuePlace::Variable(Variable::from_valid_name(
+                "?added"
+            )))
+        )]
+    );
+
+    let s = "[:find ?v :in $h :where [$h ?e :foo/bar ?v _ false]]";
+    let p = parse_query(s).unwrap();
+    match p.where_clauses.first() {
+        Some(WhereClause::Pattern(ref p)) => assert_eq!(
+            p.added,
+            PatternValuePlace::Constant(NonIntegerConstant::Boolean(false))
+        ),
+        x => panic!("expected a pattern, got {:?}", x),
+    }
+}
+
 #[test]
 fn can_parse_uuid() {
     let expected =
@@ -412,6 +412,29 @@ fn test_text() {
     );
 }
 
+#[test]
+fn test_display_round_trip() {
+    use self::Value::*;
+
+    assert_eq!(
+        text(r#""tab\there\nnewline\r""#).unwrap(),
+        Text("tab\there\nnewline\r".to_string())
+    );
+
+    let values = vec![
+        Text("quote \" backslash \\ newline \n tab \t return \r".to_string()),
+        Float(OrderedFloat(1.0)),
+        Float(OrderedFloat(-0.5)),
+        Float(OrderedFloat(1e300)),
+        Float(OrderedFloat(f64::INFINITY)),
+    ];
+    for value in values {
+        let printed = value.to_string();
+        assert!(!printed.contains('\n'), "{} should be on one line", printed);
+        assert_eq!(parse::value(&printed).unwrap().without_spans(), value);
+    }
+}
+
 #[test]
 fn test_span_text() {
     assert_eq!(
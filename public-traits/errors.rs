@@ -20,7 +20,7 @@ use uuid;
 
 use edn;
 
-use core_traits::{Attribute, ValueType};
+use core_traits::{Attribute, Entid, ValueType};
 
 use db_traits::errors::DbError;
 use query_algebrizer_traits::errors::AlgebrizerError;
@@ -34,11 +34,30 @@ use tolstoy_traits::errors::TolstoyError;
 #[cfg(feature = "syncable")]
 use hyper;
 
-#[cfg(feature = "syncable")]
+#[cfg(any(feature = "syncable", feature = "json"))]
 use serde_json;
 
 pub type Result<T> = std::result::Result<T, MentatError>;
 
+/// Why SQLite was told to abandon an operation part-way through.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum InterruptReason {
+    /// The operation's cancellation token was cancelled.
+    Cancelled,
+
+    /// The operation ran past its deadline.
+    DeadlineExceeded,
+}
+
+impl ::std::fmt::Display for InterruptReason {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        match *self {
+            InterruptReason::Cancelled => write!(f, "cancelled"),
+            InterruptReason::DeadlineExceeded => write!(f, "deadline exceeded"),
+        }
+    }
+}
+
 #[derive(Debug, Fail)]
 pub enum MentatError {
     #[fail(display = "bad uuid {}", _0)]
@@ -74,12 +93,42 @@ pub enum MentatError {
     #[fail(display = "core schema: wanted version {}, got version {:?}", _0, _1)]
     UnexpectedCoreSchema(u32, Option<u32>),
 
+    #[fail(display = "store version: wanted version {}, got version {}", _0, _1)]
+    UnexpectedStoreVersion(i32, i32),
+
+    #[fail(display = "bad export: {}", _0)]
+    BadExport(String),
+
+    #[fail(
+        display = "can only import into an empty store, but found transaction {}",
+        _0
+    )]
+    ImportIntoNonEmptyStore(Entid),
+
+    #[fail(display = "import doesn't match its export: {}", _0)]
+    ImportMismatch(String),
+
+    #[fail(display = "bad JSON: {}", _0)]
+    BadJson(String),
+
+    #[fail(display = "backup couldn't get the locks it needed for {:?}", _0)]
+    BackupBusy(::std::time::Duration),
+
+    #[fail(display = "can't pool connections to an in-memory store")]
+    InMemoryStorePool,
+
+    #[fail(display = "transaction report queues need a non-zero capacity")]
+    ZeroQueueCapacity,
+
     #[fail(display = "Lost the transact() race!")]
     UnexpectedLostTransactRace,
 
     #[fail(display = "missing core attribute {}", _0)]
     MissingCoreVocabulary(edn::query::Keyword),
 
+    #[fail(display = "interrupted: {}", _0)]
+    Interrupted(InterruptReason),
+
     #[fail(display = "schema changed since query was prepared")]
     PreparedQuerySchemaMismatch,
 
@@ -135,7 +184,7 @@ pub enum MentatError {
     #[fail(display = "{}", _0)]
     UriError(#[cause] http::uri::InvalidUri),
 
-    #[cfg(feature = "syncable")]
+    #[cfg(any(feature = "syncable", feature = "json"))]
     #[fail(display = "{}", _0)]
     SerializationError(#[cause] serde_json::Error),
 }
@@ -205,7 +254,7 @@ impl From<TolstoyError> for MentatError {
     }
 }
 
-#[cfg(feature = "syncable")]
+#[cfg(any(feature = "syncable", feature = "json"))]
 impl From<serde_json::Error> for MentatError {
     fn from(error: serde_json::Error) -> Self {
         MentatError::SerializationError(error)
@@ -29,7 +29,7 @@ extern crate tolstoy_traits;
 #[cfg(feature = "syncable")]
 extern crate hyper;
 
-#[cfg(feature = "syncable")]
+#[cfg(any(feature = "syncable", feature = "json"))]
 extern crate serde_json;
 
 pub mod errors;
@@ -10,7 +10,7 @@
 
 use std; // To refer to std::result::Result.
 
-use core_traits::{ValueType, ValueTypeSet};
+use core_traits::{Entid, ValueType, ValueTypeSet};
 
 use edn::{query::PlainSymbol, ParseError};
 
@@ -96,6 +96,18 @@ pub enum AlgebrizerError {
     #[fail(display = "unbound variable {} in order clause or function call", _0)]
     UnboundVariable(PlainSymbol),
 
+    #[fail(display = "source {} not present in :in", _0)]
+    UnknownSource(PlainSymbol),
+
+    #[fail(display = "source {} in :in has no view bound", _0)]
+    UnboundSource(PlainSymbol),
+
+    #[fail(
+        display = "source {} is not a history source, so it has no added place",
+        _0
+    )]
+    NonHistorySource(PlainSymbol),
+
     // TODO: flesh out.
     #[fail(display = "non-matching variables in 'or' clause")]
     NonMatchingVariablesInOrClause,
@@ -106,6 +118,12 @@ pub enum AlgebrizerError {
     #[fail(display = "binding error in {}: {:?}", _0, _1)]
     InvalidBinding(PlainSymbol, BindingError),
 
+    #[fail(
+        display = "{} can't see transaction {}: history before {} has been pruned",
+        _0, _1, _2
+    )]
+    HistoryPruned(PlainSymbol, Entid, Entid),
+
     #[fail(display = "{}", _0)]
     EdnParseError(#[cause] ParseError),
 }
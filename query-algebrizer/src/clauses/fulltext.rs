@@ -14,7 +14,7 @@ use mentat_core::HasSchema;
 
 use mentat_core::util::Either;
 
-use edn::query::{Binding, FnArg, NonIntegerConstant, SrcVar, VariableOrPlaceholder, WhereFn};
+use edn::query::{Binding, FnArg, NonIntegerConstant, VariableOrPlaceholder, WhereFn};
 
 use crate::clauses::ConjoiningClauses;
 
@@ -90,15 +90,14 @@ impl ConjoiningClauses {
 
         let mut args = where_fn.args.into_iter();
 
-        // TODO(gburd): process source variables.
-        match args.next().unwrap() {
-            FnArg::SrcVar(SrcVar::DefaultSrc) => {}
+        let view = match args.next().unwrap() {
+            FnArg::SrcVar(source) => self.view_for_source(&source),
             _ => bail!(AlgebrizerError::InvalidArgument(
                 where_fn.operator.clone(),
                 "source variable",
                 0
             )),
-        }
+        };
 
         let schema = known.schema;
 
@@ -146,7 +145,8 @@ impl ConjoiningClauses {
         }
 
         let fulltext_values_alias = self.next_alias_for_table(DatomsTable::FulltextValues);
-        let datoms_table_alias = self.next_alias_for_table(DatomsTable::Datoms);
+        let datoms_table = DatomsTable::Datoms.in_view(view);
+        let datoms_table_alias = self.next_alias_for_table(datoms_table);
 
         // We do a fulltext lookup by joining the fulltext values table against datoms -- just
         // like applying a pattern, but two tables contribute instead of one.
@@ -155,10 +155,11 @@ impl ConjoiningClauses {
             fulltext_values_alias.clone(),
         ));
         self.from
-            .push(SourceAlias(DatomsTable::Datoms, datoms_table_alias.clone()));
+            .push(SourceAlias(datoms_table, datoms_table_alias.clone()));
 
         // TODO: constrain the type in the more general cases (e.g., `a` is a var).
         self.constrain_attribute(datoms_table_alias.clone(), a);
+        self.depend_on_attribute(a);
 
         // Join the datoms table to the fulltext values table.
         self.wheres.add_intersection(ColumnConstraint::Equals(
@@ -309,7 +310,7 @@ mod testing {
 
     use mentat_core::Schema;
 
-    use edn::query::{Binding, FnArg, Keyword, PlainSymbol, Variable};
+    use edn::query::{Binding, FnArg, Keyword, PlainSymbol, SrcVar, Variable};
 
     use crate::clauses::{add_attribute, associate_ident};
 
@@ -29,9 +29,9 @@ impl ConjoiningClauses {
     /// the provided types.
     /// Construct a computed table to yield this relation.
     /// This function will panic if some invariants are not met.
-    fn collect_named_bindings<'s>(
+    pub(crate) fn collect_named_bindings(
         &mut self,
-        schema: &'s Schema,
+        schema: &Schema,
         names: Vec<Variable>,
         types: Vec<ValueType>,
         values: Vec<TypedValue>,
@@ -12,7 +12,9 @@ use std::collections::BTreeMap;
 
 use core_traits::{TypedValue, ValueType};
 
-use edn::query::Variable;
+use mentat_core::DatomsView;
+
+use edn::query::{SrcVar, Variable};
 
 use query_algebrizer_traits::errors::{AlgebrizerError, Result};
 
@@ -22,9 +24,14 @@ use query_algebrizer_traits::errors::{AlgebrizerError, Result};
 /// the bindings that will be used at execution time.
 /// When built correctly, `types` is guaranteed to contain the types of `values` -- use
 /// `QueryInputs::new` or `QueryInputs::with_values` to construct an instance.
+///
+/// Sources are bound separately: each source named in `:in` must be bound to a `DatomsView`.
+/// The default source `$` is the current store unless it, too, is bound.
+#[derive(Clone)]
 pub struct QueryInputs {
     pub(crate) types: BTreeMap<Variable, ValueType>,
     pub(crate) values: BTreeMap<Variable, TypedValue>,
+    pub(crate) sources: BTreeMap<SrcVar, DatomsView>,
 }
 
 impl Default for QueryInputs {
@@ -32,6 +39,7 @@ impl Default for QueryInputs {
         QueryInputs {
             types: BTreeMap::default(),
             values: BTreeMap::default(),
+            sources: BTreeMap::default(),
         }
     }
 }
@@ -46,6 +54,7 @@ impl QueryInputs {
         QueryInputs {
             types: types.into_iter().collect(),
             values: BTreeMap::default(),
+            sources: BTreeMap::default(),
         }
     }
 
@@ -56,9 +65,48 @@ impl QueryInputs {
                 .map(|(var, val)| (var.clone(), val.value_type()))
                 .collect(),
             values,
+            sources: BTreeMap::default(),
         }
     }
 
+    /// Bind `source` to `view` for the query these inputs accompany.
+    pub fn with_source(mut self, source: SrcVar, view: DatomsView) -> QueryInputs {
+        self.sources.insert(source, view);
+        self
+    }
+
+    /// The types of every input variable, including those with known values.
+    pub fn types(&self) -> &BTreeMap<Variable, ValueType> {
+        &self.types
+    }
+
+    /// The values of those input variables that are known now.
+    pub fn values(&self) -> &BTreeMap<Variable, TypedValue> {
+        &self.values
+    }
+
+    /// The views bound to sources.
+    pub fn sources(&self) -> &BTreeMap<SrcVar, DatomsView> {
+        &self.sources
+    }
+
+    /// These inputs without their values: only the types of the variables, and the sources.
+    pub fn without_values(&self) -> QueryInputs {
+        QueryInputs {
+            types: self.types.clone(),
+            values: BTreeMap::default(),
+            sources: self.sources.clone(),
+        }
+    }
+
+    /// The view bound to the default source `$`.
+    pub fn default_view(&self) -> DatomsView {
+        self.sources
+            .get(&SrcVar::DefaultSrc)
+            .cloned()
+            .unwrap_or_default()
+    }
+
     pub fn new(
         mut types: BTreeMap<Variable, ValueType>,
         values: BTreeMap<Variable, TypedValue>,
@@ -73,6 +121,10 @@ impl QueryInputs {
                 }
             }
         }
-        Ok(QueryInputs { types, values })
+        Ok(QueryInputs {
+            types,
+            values,
+            sources: BTreeMap::default(),
+        })
     }
 }
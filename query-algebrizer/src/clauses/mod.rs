@@ -8,6 +8,8 @@
 // CONDITIONS OF ANY KIND, either express or implied. See the License for the
 // specific language governing permissions and limitations under the License.
 
+use std::cell::RefCell;
+
 use std::cmp;
 
 use std::collections::{BTreeMap, BTreeSet, VecDeque};
@@ -16,20 +18,26 @@ use std::collections::btree_map::Entry;
 
 use std::fmt::{Debug, Formatter};
 
+use std::rc::Rc;
+
 use core_traits::{Attribute, Entid, KnownEntid, TypedValue, ValueType, ValueTypeSet};
 
-use mentat_core::{Cloned, HasSchema, Schema};
+use mentat_core::{Cloned, DatomsView, HasSchema, Schema};
 
 use mentat_core::counter::RcCounter;
 
-use edn::query::{Element, FindSpec, Keyword, PatternNonValuePlace, Pull, Variable, WhereClause};
+use edn::query::{
+    Element, FindSpec, Keyword, NamedPullAttribute, PatternNonValuePlace, Pull, PullAttributeSpec,
+    PullConcreteAttribute, SrcVar, Variable, WhereClause,
+};
 
 use query_algebrizer_traits::errors::{AlgebrizerError, Result};
 
 use crate::types::{
-    Column, ColumnConstraint, ColumnIntersection, ComputedTable, DatomsColumn, DatomsTable,
-    EmptyBecause, EvolvedNonValuePlace, EvolvedPattern, EvolvedValuePlace, FulltextColumn,
-    PlaceOrEmpty, QualifiedAlias, QueryValue, SourceAlias, TableAlias,
+    AttributeDependencies, Column, ColumnConstraint, ColumnIntersection, ComputedTable,
+    DatomsColumn, DatomsTable, EmptyBecause, EvolvedNonValuePlace, EvolvedPattern,
+    EvolvedValuePlace, FulltextColumn, PlaceOrEmpty, QualifiedAlias, QueryValue, SourceAlias,
+    TableAlias,
 };
 
 mod convert; // Converting args to values.
@@ -134,6 +142,10 @@ pub struct ConjoiningClauses {
     /// A data source used to generate an alias for a table -- e.g., from "datoms" to "datoms123".
     alias_counter: RcCounter,
 
+    /// The attributes read by these clauses. Like `alias_counter`, this is shared with nested CCs,
+    /// so that `or` and `not` arms contribute to the dependencies of the whole query.
+    attribute_dependencies: Rc<RefCell<AttributeDependencies>>,
+
     /// A vector of source/alias pairs used to construct a SQL `FROM` list.
     pub from: Vec<SourceAlias>,
 
@@ -176,6 +188,10 @@ pub struct ConjoiningClauses {
 
     /// Map of variables to the set of type requirements we have for them.
     required_types: BTreeMap<Variable, ValueTypeSet>,
+
+    /// The view through which each bound source sees the store. Patterns against a source that
+    /// isn't present here -- usually the default source -- see the current store.
+    views: BTreeMap<SrcVar, DatomsView>,
 }
 
 impl PartialEq for ConjoiningClauses {
@@ -190,6 +206,7 @@ impl PartialEq for ConjoiningClauses {
             && self.known_types.eq(&other.known_types)
             && self.extracted_types.eq(&other.extracted_types)
             && self.required_types.eq(&other.required_types)
+            && self.views.eq(&other.views)
     }
 }
 
@@ -208,6 +225,7 @@ impl Debug for ConjoiningClauses {
             .field("known_types", &self.known_types)
             .field("extracted_types", &self.extracted_types)
             .field("required_types", &self.required_types)
+            .field("views", &self.views)
             .finish()
     }
 }
@@ -218,6 +236,7 @@ impl Default for ConjoiningClauses {
         ConjoiningClauses {
             empty_because: None,
             alias_counter: RcCounter::new(),
+            attribute_dependencies: Default::default(),
             from: vec![],
             computed_tables: vec![],
             wheres: ColumnIntersection::default(),
@@ -227,6 +246,7 @@ impl Default for ConjoiningClauses {
             value_bindings: BTreeMap::new(),
             known_types: BTreeMap::new(),
             extracted_types: BTreeMap::new(),
+            views: BTreeMap::new(),
         }
     }
 }
@@ -273,6 +293,7 @@ impl ConjoiningClauses {
             Some(QueryInputs {
                 mut types,
                 mut values,
+                sources,
             }) => {
                 // Discard any bindings not mentioned in our :in clause.
                 types.keep_intersected_keys(&in_variables);
@@ -282,6 +303,7 @@ impl ConjoiningClauses {
                     alias_counter,
                     input_variables: in_variables,
                     value_bindings: values,
+                    views: sources,
                     ..Default::default()
                 };
 
@@ -306,6 +328,32 @@ impl ConjoiningClauses {
             }
         }
     }
+
+    /// Pulled attributes are read after the query runs, but they're still dependencies.
+    pub(crate) fn derive_dependencies_from_find_spec(&self, schema: &Schema, find_spec: &FindSpec) {
+        for spec in find_spec.columns() {
+            if let Element::Pull(Pull { ref patterns, .. }) = spec {
+                for pattern in patterns {
+                    match pattern {
+                        PullAttributeSpec::Wildcard => self.depend_on_all_attributes(),
+                        PullAttributeSpec::Attribute(NamedPullAttribute {
+                            attribute: PullConcreteAttribute::Entid(e),
+                            ..
+                        }) => self.depend_on_attribute(*e),
+                        PullAttributeSpec::Attribute(NamedPullAttribute {
+                            attribute: PullConcreteAttribute::Ident(ref i),
+                            ..
+                        }) => {
+                            // `:db/id` isn't an attribute; unknown idents fail during projection.
+                            if let Some(e) = schema.get_entid(i) {
+                                self.depend_on_attribute(e.into());
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
 }
 
 /// Cloning.
@@ -313,12 +361,14 @@ impl ConjoiningClauses {
     fn make_receptacle(&self) -> ConjoiningClauses {
         ConjoiningClauses {
             alias_counter: self.alias_counter.clone(),
+            attribute_dependencies: self.attribute_dependencies.clone(),
             empty_because: self.empty_because.clone(),
             input_variables: self.input_variables.clone(),
             value_bindings: self.value_bindings.clone(),
             known_types: self.known_types.clone(),
             extracted_types: self.extracted_types.clone(),
             required_types: self.required_types.clone(),
+            views: self.views.clone(),
             ..Default::default()
         }
     }
@@ -328,17 +378,35 @@ impl ConjoiningClauses {
     fn use_as_template(&self, vars: &BTreeSet<Variable>) -> ConjoiningClauses {
         ConjoiningClauses {
             alias_counter: self.alias_counter.clone(),
+            attribute_dependencies: self.attribute_dependencies.clone(),
             empty_because: self.empty_because.clone(),
             input_variables: self.input_variables.intersection(vars).cloned().collect(),
             value_bindings: self.value_bindings.with_intersected_keys(&vars),
             known_types: self.known_types.with_intersected_keys(&vars),
             extracted_types: self.extracted_types.with_intersected_keys(&vars),
             required_types: self.required_types.with_intersected_keys(&vars),
+            views: self.views.clone(),
             ..Default::default()
         }
     }
 }
 
+/// Dependencies.
+impl ConjoiningClauses {
+    /// The attributes read by these clauses, including any nested within them.
+    pub fn attribute_dependencies(&self) -> AttributeDependencies {
+        self.attribute_dependencies.borrow().clone()
+    }
+
+    pub(crate) fn depend_on_attribute(&self, attribute: Entid) {
+        self.attribute_dependencies.borrow_mut().add(attribute);
+    }
+
+    pub(crate) fn depend_on_all_attributes(&self) {
+        self.attribute_dependencies.borrow_mut().add_all();
+    }
+}
+
 impl ConjoiningClauses {
     /// Be careful with this. It'll overwrite existing bindings.
     pub fn bind_value(&mut self, var: &Variable, value: TypedValue) {
@@ -901,14 +969,23 @@ impl ConjoiningClauses {
         schema: &'s Schema,
         pattern: &'a EvolvedPattern,
     ) -> Option<SourceAlias> {
+        let view = self.view_for_source(&pattern.source);
         self.table_for_places(schema, &pattern.attribute, &pattern.value)
             .map_err(|reason| {
                 self.mark_known_empty(reason);
             })
-            .map(|table: DatomsTable| SourceAlias(table, self.next_alias_for_table(table)))
+            .map(|table: DatomsTable| {
+                let table = table.in_view(view);
+                SourceAlias(table, self.next_alias_for_table(table))
+            })
             .ok()
     }
 
+    /// The view through which patterns against `source` see the store.
+    pub fn view_for_source(&self, source: &SrcVar) -> DatomsView {
+        self.views.get(source).cloned().unwrap_or_default()
+    }
+
     fn get_attribute_for_value<'s>(
         &self,
         schema: &'s Schema,
@@ -990,6 +1067,50 @@ impl ConjoiningClauses {
         }
     }
 
+    /// The column to which the unbound input variable `var` can be constrained, so that its value
+    /// is supplied as a bind parameter when the query is run: the first column `var` is bound to,
+    /// if that's a column of datoms or transactions. Value columns also need `var` to have a
+    /// single known type, so that their type tag can be constrained too.
+    pub(crate) fn parameter_column(&self, var: &Variable) -> Option<&QualifiedAlias> {
+        if !self.input_variables.contains(var) || self.is_value_bound(var) {
+            return None;
+        }
+        self.column_bindings
+            .get(var)
+            .and_then(|columns| columns.first())
+            .filter(|qa| match qa.1 {
+                Column::Fixed(_) | Column::Transactions(_) => {
+                    qa.for_associated_type_tag().is_none() || self.known_type(var).is_some()
+                }
+                Column::Fulltext(_) | Column::Variable(_) => false,
+            })
+    }
+
+    /// Constrain every unbound input variable that has a `parameter_column` to its bind parameter.
+    pub(crate) fn constrain_inputs_to_parameters(&mut self) {
+        let parameters: Vec<(Variable, QualifiedAlias)> = self
+            .input_variables
+            .iter()
+            .filter_map(|var| {
+                self.parameter_column(var)
+                    .map(|qa| (var.clone(), qa.clone()))
+            })
+            .collect();
+        for (var, qa) in parameters {
+            if qa.for_associated_type_tag().is_some() {
+                if let Some(value_type) = self.known_type(&var) {
+                    self.wheres
+                        .add_intersection(ColumnConstraint::has_unit_type(
+                            qa.0.clone(),
+                            value_type,
+                        ));
+                }
+            }
+            self.wheres
+                .add_intersection(ColumnConstraint::Equals(qa, QueryValue::Parameter(var)));
+        }
+    }
+
     /// Eliminate any type extractions for variables whose types are definitely known.
     pub(crate) fn prune_extracted_types(&mut self) {
         if self.extracted_types.is_empty() || self.known_types.is_empty() {
@@ -1110,8 +1231,45 @@ impl ConjoiningClauses {
         known: Known,
         mut patterns: VecDeque<EvolvedPattern>,
     ) -> Result<()> {
+        // Values bound from the cache can let the cache answer other patterns, wherever they are
+        // in the query, so we keep going over the patterns until the cache can't answer any more.
+        // Whatever's left goes to the store. Store patterns only bind columns, never values, so
+        // there's no point in asking the cache about them again.
+        let use_cache = match known.cache {
+            Some(cache) => cache.has_cached_attributes(),
+            None => false,
+        };
+        if use_cache {
+            let mut answered = true;
+            while answered && !self.is_known_empty() {
+                answered = false;
+                let mut remaining = VecDeque::with_capacity(patterns.len());
+                while let Some(pattern) = patterns.pop_front() {
+                    match self.evolve_pattern(known, pattern) {
+                        PlaceOrEmpty::Place(re_evolved) => {
+                            if !self.is_known_empty()
+                                && self.attempt_cache_lookup(known, &re_evolved)
+                            {
+                                answered = true;
+                            } else {
+                                remaining.push_back(re_evolved);
+                            }
+                        }
+                        PlaceOrEmpty::Empty(because) => {
+                            self.mark_known_empty(because);
+                            return Ok(());
+                        }
+                    }
+                }
+                patterns = remaining;
+            }
+        }
+
         while let Some(pattern) = patterns.pop_front() {
             match self.evolve_pattern(known, pattern) {
+                PlaceOrEmpty::Place(re_evolved) if use_cache => {
+                    self.apply_pattern_to_store(known, re_evolved)
+                }
                 PlaceOrEmpty::Place(re_evolved) => self.apply_pattern(known, re_evolved),
                 PlaceOrEmpty::Empty(because) => {
                     self.mark_known_empty(because);
@@ -219,7 +219,8 @@ impl ConjoiningClauses {
                                 _simply_matches_place(&template.entity, &p.entity) &&
                                 _simply_matches_place(&template.attribute, &p.attribute) &&
                                 _simply_matches_value_place(&template.value, &p.value) &&
-                                _simply_matches_place(&template.tx, &p.tx)
+                                _simply_matches_place(&template.tx, &p.tx) &&
+                                _simply_matches_value_place(&template.added, &p.added)
                         } else {
                             // No previous pattern.
                             true
@@ -10,7 +10,7 @@
 
 #![allow(clippy::single_match)]
 
-use core_traits::{Entid, TypedValue, ValueType, ValueTypeSet};
+use core_traits::{Attribute, Entid, TypedValue, ValueType, ValueTypeSet};
 
 use mentat_core::{Cloned, HasSchema};
 
@@ -22,7 +22,7 @@ use crate::clauses::ConjoiningClauses;
 
 use crate::types::{
     ColumnConstraint, DatomsColumn, EmptyBecause, EvolvedNonValuePlace, EvolvedPattern,
-    EvolvedValuePlace, PlaceOrEmpty, SourceAlias,
+    EvolvedValuePlace, PlaceOrEmpty, SourceAlias, TransactionsColumn,
 };
 
 use crate::Known;
@@ -271,160 +271,255 @@ impl ConjoiningClauses {
                 self.constrain_column_to_entity(col.clone(), DatomsColumn::Tx, entid);
             }
         }
+
+        // Only history sources have an `added` column; `validate_sources` makes sure of that.
+        match pattern.added {
+            EvolvedValuePlace::Placeholder => (),
+            EvolvedValuePlace::Variable(ref v) => {
+                self.constrain_var_to_type(v.clone(), ValueType::Boolean);
+                if self.is_known_empty() {
+                    return;
+                }
+                self.bind_column_to_var(schema, col.clone(), TransactionsColumn::Added, v.clone());
+            }
+            EvolvedValuePlace::Value(ref added) if added.value_type() == ValueType::Boolean => {
+                self.constrain_column_to_constant(
+                    col.clone(),
+                    TransactionsColumn::Added,
+                    added.clone(),
+                );
+            }
+            // Anything else -- a string constant, or a variable bound to an entity -- can't match.
+            EvolvedValuePlace::Value(ref added) => {
+                self.mark_known_empty(EmptyBecause::ValueTypeMismatch(
+                    ValueType::Boolean,
+                    added.clone(),
+                ));
+            }
+            EvolvedValuePlace::Entid(e) => {
+                self.mark_known_empty(EmptyBecause::ValueTypeMismatch(
+                    ValueType::Boolean,
+                    TypedValue::Ref(e),
+                ));
+            }
+            EvolvedValuePlace::EntidOrInteger(i) => {
+                self.mark_known_empty(EmptyBecause::ValueTypeMismatch(
+                    ValueType::Boolean,
+                    TypedValue::Long(i),
+                ));
+            }
+            EvolvedValuePlace::IdentOrKeyword(ref kw) => {
+                self.mark_known_empty(EmptyBecause::ValueTypeMismatch(
+                    ValueType::Boolean,
+                    TypedValue::Keyword(kw.clone()),
+                ));
+            }
+        }
     }
 
-    fn reverse_lookup(
+    /// Bind `var` to `values`, which must not be empty: directly if there's only one of them,
+    /// and otherwise through a computed table.
+    fn bind_cached_values(
         &mut self,
         known: Known,
         var: &Variable,
+        value_type: ValueType,
+        mut values: Vec<TypedValue>,
+    ) {
+        if values.len() == 1 {
+            self.bind_value(var, values.pop().unwrap());
+        } else {
+            self.collect_named_bindings(known.schema, vec![var.clone()], vec![value_type], values);
+        }
+    }
+
+    /// The value in `place` as the cache would store it for `attribute`, if there's a constant
+    /// there that can be compared against the cache.
+    fn cacheable_value(
+        known: Known,
+        attribute: &Attribute,
+        place: &EvolvedValuePlace,
+    ) -> Option<TypedValue> {
+        // Fulltext values are matched by their rowid, not their text.
+        if attribute.fulltext {
+            return None;
+        }
+        let value = match place {
+            EvolvedValuePlace::Value(ref value) => value.clone(),
+            EvolvedValuePlace::Entid(entid) => TypedValue::Ref(*entid),
+            EvolvedValuePlace::IdentOrKeyword(ref kw) => match attribute.value_type {
+                ValueType::Ref => TypedValue::Ref(known.schema.get_entid(kw)?.0),
+                _ => TypedValue::Keyword(kw.clone()),
+            },
+            EvolvedValuePlace::Placeholder
+            | EvolvedValuePlace::Variable(_)
+            | EvolvedValuePlace::EntidOrInteger(_) => return None,
+        };
+        // Mismatched types are reported by the regular path.
+        if value.value_type() == attribute.value_type {
+            Some(value)
+        } else {
+            None
+        }
+    }
+
+    /// Every cached value of `attr` for `entity`, which must be cached in the forward direction.
+    fn cached_values(
+        known: Known,
+        attribute: &Attribute,
+        attr: Entid,
+        entity: Entid,
+    ) -> Vec<TypedValue> {
+        if attribute.multival {
+            known
+                .get_values_for_entid(known.schema, attr, entity)
+                .cloned()
+                .unwrap_or_default()
+        } else {
+            known
+                .get_value_for_entid(known.schema, attr, entity)
+                .cloned()
+                .into_iter()
+                .collect()
+        }
+    }
+
+    /// Every entity with `value` for `attr`, which must be cached in the reverse direction.
+    fn cached_entities(
+        known: Known,
+        attribute: &Attribute,
+        attr: Entid,
+        value: &TypedValue,
+    ) -> Vec<Entid> {
+        if attribute.unique.is_some() {
+            known.get_entid_for_value(attr, value).into_iter().collect()
+        } else {
+            known
+                .get_entids_for_value(attr, value)
+                .map(|entities| entities.iter().cloned().collect())
+                .unwrap_or_default()
+        }
+    }
+
+    /// Answer a pattern with a known entity from the forward cache.
+    fn forward_lookup(
+        &mut self,
+        known: Known,
+        attribute: &Attribute,
         attr: Entid,
-        val: &TypedValue,
+        entity: Entid,
+        pattern: &EvolvedPattern,
     ) -> bool {
-        if let Some(attribute) = known.schema.attribute_for_entid(attr) {
-            let unique = attribute.unique.is_some();
-            if unique {
-                match known.get_entid_for_value(attr, val) {
-                    None => {
-                        self.mark_known_empty(EmptyBecause::CachedAttributeHasNoEntity {
-                            value: val.clone(),
-                            attr,
-                        });
-                        true
-                    }
-                    Some(item) => {
-                        self.bind_value(var, TypedValue::Ref(item));
-                        true
-                    }
-                }
-            } else {
-                match known.get_entids_for_value(attr, val) {
-                    None => {
-                        self.mark_known_empty(EmptyBecause::CachedAttributeHasNoEntity {
-                            value: val.clone(),
-                            attr,
-                        });
-                        true
-                    }
-                    Some(items) => {
-                        if items.len() == 1 {
-                            let item = items.iter().next().cloned().unwrap();
-                            self.bind_value(var, TypedValue::Ref(item));
-                            true
-                        } else {
-                            // Oh well.
-                            // TODO: handle multiple values.
-                            false
-                        }
-                    }
+        let value = match pattern.value {
+            EvolvedValuePlace::Variable(_) | EvolvedValuePlace::Placeholder => None,
+            ref place => match ConjoiningClauses::cacheable_value(known, attribute, place) {
+                Some(value) => Some(value),
+                None => return false,
+            },
+        };
+
+        let values = ConjoiningClauses::cached_values(known, attribute, attr, entity);
+        match (value, &pattern.value) {
+            (Some(value), _) => {
+                if !values.contains(&value) {
+                    self.mark_known_empty(EmptyBecause::CachedDatomNotPresent {
+                        entity,
+                        attr,
+                        value,
+                    });
                 }
             }
-        } else {
-            self.mark_known_empty(EmptyBecause::InvalidAttributeEntid(attr));
-            true
+            (None, _) if values.is_empty() => {
+                self.mark_known_empty(EmptyBecause::CachedAttributeHasNoValues { entity, attr });
+            }
+            (None, EvolvedValuePlace::Variable(ref var)) => {
+                self.bind_cached_values(known, var, attribute.value_type, values);
+            }
+            (None, _) => {}
         }
+        true
     }
 
-    // TODO: generalize.
-    // TODO: use constant values -- extract transformation code from apply_pattern_clause_for_alias.
-    // TODO: loop over all patterns until no more cache values apply?
-    fn attempt_cache_lookup(&mut self, known: Known, pattern: &EvolvedPattern) -> bool {
-        // Precondition: default source. If it's not default, don't call this.
-        assert!(pattern.source == SrcVar::DefaultSrc);
+    /// Answer a pattern with a known value from the reverse cache.
+    fn reverse_lookup(
+        &mut self,
+        known: Known,
+        attribute: &Attribute,
+        attr: Entid,
+        value: TypedValue,
+        entity: &EvolvedNonValuePlace,
+    ) -> bool {
+        let entities = ConjoiningClauses::cached_entities(known, attribute, attr, &value);
+        match entity {
+            EvolvedNonValuePlace::Entid(entity) => {
+                if !entities.contains(entity) {
+                    self.mark_known_empty(EmptyBecause::CachedDatomNotPresent {
+                        entity: *entity,
+                        attr,
+                        value,
+                    });
+                }
+            }
+            _ if entities.is_empty() => {
+                self.mark_known_empty(EmptyBecause::CachedAttributeHasNoEntity { value, attr });
+            }
+            EvolvedNonValuePlace::Variable(ref var) => {
+                let entities = entities.into_iter().map(TypedValue::Ref).collect();
+                self.bind_cached_values(known, var, ValueType::Ref, entities);
+            }
+            EvolvedNonValuePlace::Placeholder => {}
+        }
+        true
+    }
 
-        let schema = known.schema;
+    /// Answer `pattern` entirely from the attribute cache, if we can. Returns true if the
+    /// pattern has been applied -- by binding variables, by being found to hold, or by
+    /// proving the query empty -- and false if it should be applied to the store instead.
+    pub(crate) fn attempt_cache_lookup(&mut self, known: Known, pattern: &EvolvedPattern) -> bool {
+        // The cache only knows about the current store.
+        if !self.view_for_source(&pattern.source).is_current() {
+            return false;
+        }
 
         if pattern.tx != EvolvedNonValuePlace::Placeholder {
             return false;
         }
 
-        // See if we can use the cache.
-        match pattern.attribute {
-            EvolvedNonValuePlace::Entid(attr) => {
-                if !schema.is_attribute(attr) {
-                    // Furthermore, that entid must resolve to an attribute. If it doesn't, this
-                    // query is meaningless.
-                    self.mark_known_empty(EmptyBecause::InvalidAttributeEntid(attr));
-                    return true;
-                }
+        let attr = match pattern.attribute {
+            EvolvedNonValuePlace::Entid(attr) => attr,
+            _ => return false,
+        };
 
-                let cached_forward = known.is_attribute_cached_forward(attr);
-                let cached_reverse = known.is_attribute_cached_reverse(attr);
-
-                if (cached_forward || cached_reverse)
-                    && pattern.tx == EvolvedNonValuePlace::Placeholder
-                {
-                    let attribute = schema.attribute_for_entid(attr).unwrap();
-
-                    // There are two patterns we can handle:
-                    //     [?e :some/unique 123 _ _]     -- reverse lookup
-                    //     [123 :some/attr ?v _ _]       -- forward lookup
-                    match pattern.entity {
-                        // Reverse lookup.
-                        EvolvedNonValuePlace::Variable(ref var) => {
-                            match pattern.value {
-                                // TODO: EntidOrInteger etc.
-                                EvolvedValuePlace::IdentOrKeyword(ref kw) => {
-                                    match attribute.value_type {
-                                        ValueType::Ref => {
-                                            // It's an ident.
-                                            // TODO
-                                            return false;
-                                        }
-                                        ValueType::Keyword => {
-                                            let tv: TypedValue = TypedValue::Keyword(kw.clone());
-                                            return self.reverse_lookup(known, var, attr, &tv);
-                                        }
-                                        t => {
-                                            let tv: TypedValue = TypedValue::Keyword(kw.clone());
-                                            // Anything else can't match an IdentOrKeyword.
-                                            self.mark_known_empty(EmptyBecause::ValueTypeMismatch(
-                                                t, tv,
-                                            ));
-                                            return true;
-                                        }
-                                    }
-                                }
-                                EvolvedValuePlace::Value(ref val) => {
-                                    if cached_reverse {
-                                        return self.reverse_lookup(known, var, attr, val);
-                                    }
-                                }
-                                _ => {} // TODO: check constant values against cache.
-                            }
-                        }
+        let attribute = match known.schema.attribute_for_entid(attr) {
+            Some(attribute) => attribute,
+            None => {
+                // Furthermore, that entid must resolve to an attribute. If it doesn't, this
+                // query is meaningless.
+                self.mark_known_empty(EmptyBecause::InvalidAttributeEntid(attr));
+                return true;
+            }
+        };
 
-                        // Forward lookup.
-                        EvolvedNonValuePlace::Entid(entity) => {
-                            match pattern.value {
-                                EvolvedValuePlace::Variable(ref var) => {
-                                    if cached_forward {
-                                        match known.get_value_for_entid(known.schema, attr, entity)
-                                        {
-                                            None => {
-                                                self.mark_known_empty(
-                                                    EmptyBecause::CachedAttributeHasNoValues {
-                                                        entity,
-                                                        attr,
-                                                    },
-                                                );
-                                                return true;
-                                            }
-                                            Some(item) => {
-                                                self.bind_value(var, item.clone());
-                                                return true;
-                                            }
-                                        }
-                                    }
-                                }
-                                _ => {} // TODO: check constant values against the cache.
-                            }
-                        }
-                        _ => {}
-                    }
-                }
+        // We can handle these patterns:
+        //     [123 :some/attr ?v _ _]       -- forward lookup, binding ?v
+        //     [123 :some/attr 456 _ _]      -- forward or reverse lookup, checking the datom
+        //     [123 :some/attr _ _ _]        -- forward lookup, checking for any value
+        //     [?e :some/attr 456 _ _]       -- reverse lookup, binding ?e
+        //     [_ :some/attr 456 _ _]        -- reverse lookup, checking for any entity
+        if let EvolvedNonValuePlace::Entid(entity) = pattern.entity {
+            if known.is_entity_cached_forward(attr, entity) {
+                return self.forward_lookup(known, attribute, attr, entity, pattern);
+            }
+        }
+
+        if known.is_attribute_cached_reverse(attr) {
+            if let Some(value) =
+                ConjoiningClauses::cacheable_value(known, attribute, &pattern.value)
+            {
+                return self.reverse_lookup(known, attribute, attr, value, &pattern.entity);
             }
-            _ => {}
         }
+
         false
     }
 
@@ -484,6 +579,15 @@ impl ConjoiningClauses {
         self.make_evolved_non_value(known, DatomsColumn::Tx, tx)
     }
 
+    /// The `added` place of a history pattern can only ever match a boolean.
+    fn make_evolved_added(
+        &self,
+        known: &Known,
+        added: PatternValuePlace,
+    ) -> PlaceOrEmpty<EvolvedValuePlace> {
+        self.make_evolved_value(known, Some(ValueType::Boolean), added)
+    }
+
     pub(crate) fn make_evolved_attribute(
         &self,
         known: &Known,
@@ -495,11 +599,14 @@ impl ConjoiningClauses {
                 // Make sure that, if it's an entid, it names an attribute.
                 if let EvolvedNonValuePlace::Entid(e) = a {
                     if let Some(attr) = known.schema.attribute_for_entid(e) {
+                        self.depend_on_attribute(e);
                         Place((a, Some(attr.value_type)))
                     } else {
                         Empty(EmptyBecause::InvalidAttributeEntid(e))
                     }
                 } else {
+                    // Any attribute could match.
+                    self.depend_on_all_attributes();
                     Place((a, None))
                 }
             })
@@ -570,11 +677,12 @@ impl ConjoiningClauses {
         known: Known,
         pattern: Pattern,
     ) -> PlaceOrEmpty<EvolvedPattern> {
-        let (e, a, v, tx, source) = (
+        let (e, a, v, tx, added, source) = (
             pattern.entity,
             pattern.attribute,
             pattern.value,
             pattern.tx,
+            pattern.added,
             pattern.source,
         );
         use self::PlaceOrEmpty::*;
@@ -586,13 +694,17 @@ impl ConjoiningClauses {
                     Empty(because) => Empty(because),
                     Place(v) => match self.make_evolved_tx(&known, tx) {
                         Empty(because) => Empty(because),
-                        Place(tx) => PlaceOrEmpty::Place(EvolvedPattern {
-                            source: source.unwrap_or(SrcVar::DefaultSrc),
-                            entity: e,
-                            attribute: a,
-                            value: v,
-                            tx,
-                        }),
+                        Place(tx) => match self.make_evolved_added(&known, added) {
+                            Empty(because) => Empty(because),
+                            Place(added) => PlaceOrEmpty::Place(EvolvedPattern {
+                                source: source.unwrap_or(SrcVar::DefaultSrc),
+                                entity: e,
+                                attribute: a,
+                                value: v,
+                                tx,
+                                added,
+                            }),
+                        },
                     },
                 },
             },
@@ -662,15 +774,15 @@ impl ConjoiningClauses {
     }
 
     pub(crate) fn apply_pattern(&mut self, known: Known, pattern: EvolvedPattern) {
-        // For now we only support the default source.
-        if pattern.source != SrcVar::DefaultSrc {
-            unimplemented!();
-        }
-
         if self.attempt_cache_lookup(known, &pattern) {
             return;
         }
 
+        self.apply_pattern_to_store(known, pattern);
+    }
+
+    /// Apply `pattern` by joining against the datoms tables, without consulting the cache.
+    pub(crate) fn apply_pattern_to_store(&mut self, known: Known, pattern: EvolvedPattern) {
         if let Some(alias) = self.alias_table(known.schema, &pattern) {
             self.apply_pattern_clause_for_alias(known, &pattern, &alias);
             self.from.push(alias);
@@ -724,6 +836,7 @@ mod testing {
                 attribute: ident("foo", "bar"),
                 value: PatternValuePlace::Constant(NonIntegerConstant::Boolean(true)),
                 tx: PatternNonValuePlace::Placeholder,
+                added: PatternValuePlace::Placeholder,
             },
         );
 
@@ -746,6 +859,7 @@ mod testing {
                 attribute: ident("foo", "bar"),
                 value: PatternValuePlace::Constant(NonIntegerConstant::Boolean(true)),
                 tx: PatternNonValuePlace::Placeholder,
+                added: PatternValuePlace::Placeholder,
             },
         );
 
@@ -777,6 +891,7 @@ mod testing {
                 attribute: ident("foo", "bar"),
                 value: PatternValuePlace::Constant(NonIntegerConstant::Boolean(true)),
                 tx: PatternNonValuePlace::Placeholder,
+                added: PatternValuePlace::Placeholder,
             },
         );
 
@@ -828,6 +943,7 @@ mod testing {
                 attribute: PatternNonValuePlace::Placeholder,
                 value: PatternValuePlace::Constant(NonIntegerConstant::Boolean(true)),
                 tx: PatternNonValuePlace::Placeholder,
+                added: PatternValuePlace::Placeholder,
             },
         );
 
@@ -893,6 +1009,7 @@ mod testing {
                 attribute: PatternNonValuePlace::Variable(a),
                 value: PatternValuePlace::Variable(v.clone()),
                 tx: PatternNonValuePlace::Placeholder,
+                added: PatternValuePlace::Placeholder,
             },
         );
 
@@ -944,6 +1061,7 @@ mod testing {
                 attribute: PatternNonValuePlace::Variable(a),
                 value: PatternValuePlace::Variable(v),
                 tx: PatternNonValuePlace::Placeholder,
+                added: PatternValuePlace::Placeholder,
             },
         );
 
@@ -972,6 +1090,7 @@ mod testing {
                 attribute: PatternNonValuePlace::Variable(a),
                 value: PatternValuePlace::Variable(v),
                 tx: PatternNonValuePlace::Placeholder,
+                added: PatternValuePlace::Placeholder,
             },
         );
 
@@ -1012,6 +1131,7 @@ mod testing {
                 attribute: PatternNonValuePlace::Placeholder,
                 value: PatternValuePlace::Constant("hello".into()),
                 tx: PatternNonValuePlace::Placeholder,
+                added: PatternValuePlace::Placeholder,
             },
         );
 
@@ -1087,6 +1207,7 @@ mod testing {
                 attribute: ident("foo", "roz"),
                 value: PatternValuePlace::Constant("idgoeshere".into()),
                 tx: PatternNonValuePlace::Placeholder,
+                added: PatternValuePlace::Placeholder,
             },
         );
         cc.apply_parsed_pattern(
@@ -1097,6 +1218,7 @@ mod testing {
                 attribute: ident("foo", "bar"),
                 value: PatternValuePlace::Variable(y),
                 tx: PatternNonValuePlace::Placeholder,
+                added: PatternValuePlace::Placeholder,
             },
         );
 
@@ -1183,6 +1305,7 @@ mod testing {
                 attribute: ident("foo", "bar"),
                 value: PatternValuePlace::Variable(y.clone()),
                 tx: PatternNonValuePlace::Placeholder,
+                added: PatternValuePlace::Placeholder,
             },
         );
 
@@ -1243,6 +1366,7 @@ mod testing {
                 attribute: ident("foo", "bar"),
                 value: PatternValuePlace::Variable(y),
                 tx: PatternNonValuePlace::Placeholder,
+                added: PatternValuePlace::Placeholder,
             },
         );
 
@@ -1288,6 +1412,7 @@ mod testing {
                 attribute: ident("foo", "bar"),
                 value: PatternValuePlace::Variable(y),
                 tx: PatternNonValuePlace::Placeholder,
+                added: PatternValuePlace::Placeholder,
             },
         );
 
@@ -1334,6 +1459,7 @@ mod testing {
                 attribute: ident("foo", "roz"),
                 value: PatternValuePlace::Variable(y.clone()),
                 tx: PatternNonValuePlace::Placeholder,
+                added: PatternValuePlace::Placeholder,
             },
         );
         cc.apply_parsed_pattern(
@@ -1344,6 +1470,7 @@ mod testing {
                 attribute: ident("foo", "bar"),
                 value: PatternValuePlace::Variable(y.clone()),
                 tx: PatternNonValuePlace::Placeholder,
+                added: PatternValuePlace::Placeholder,
             },
         );
 
@@ -1385,6 +1512,7 @@ mod testing {
                 attribute: PatternNonValuePlace::Variable(y.clone()),
                 value: PatternValuePlace::Constant(NonIntegerConstant::Boolean(true)),
                 tx: PatternNonValuePlace::Placeholder,
+                added: PatternValuePlace::Placeholder,
             },
         );
         cc.apply_parsed_pattern(
@@ -1395,6 +1523,7 @@ mod testing {
                 attribute: PatternNonValuePlace::Variable(y),
                 value: PatternValuePlace::Variable(x.clone()),
                 tx: PatternNonValuePlace::Placeholder,
+                added: PatternValuePlace::Placeholder,
             },
         );
 
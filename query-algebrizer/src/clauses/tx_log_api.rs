@@ -8,7 +8,7 @@
 // CONDITIONS OF ANY KIND, either express or implied. See the License for the
 // specific language governing permissions and limitations under the License.
 
-use core_traits::ValueType;
+use core_traits::{TypedValue, ValueType};
 
 use edn::query::{Binding, FnArg, SrcVar, VariableOrPlaceholder, WhereFn};
 
@@ -107,6 +107,8 @@ impl ConjoiningClauses {
         let tx2 =
             self.resolve_tx_argument(&known.schema, &where_fn.operator, 2, args.next().unwrap())?;
 
+        // Every transaction adds to the log.
+        self.depend_on_all_attributes();
         let transactions = self.next_alias_for_table(DatomsTable::Transactions);
 
         self.from
@@ -227,6 +229,25 @@ impl ConjoiningClauses {
         let tx =
             self.resolve_tx_argument(&known.schema, &where_fn.operator, 1, args.next().unwrap())?;
 
+        // Transactions before the history horizon are incomplete. We refuse to look at one we
+        // know about now, and leave out any that turn up while the query runs.
+        let horizon = match (known.history_horizon, &tx) {
+            (Some(horizon), &QueryValue::Entid(tx))
+            | (Some(horizon), &QueryValue::TypedValue(TypedValue::Ref(tx))) => {
+                if tx < horizon {
+                    bail!(AlgebrizerError::HistoryPruned(
+                        where_fn.operator.clone(),
+                        tx,
+                        horizon
+                    ));
+                }
+                None
+            }
+            (horizon, _) => horizon,
+        };
+
+        // Every transaction adds to the log.
+        self.depend_on_all_attributes();
         let transactions = self.next_alias_for_table(DatomsTable::Transactions);
 
         self.from
@@ -241,6 +262,18 @@ impl ConjoiningClauses {
         );
         self.wheres.add_intersection(tx_constraint);
 
+        if let Some(horizon) = horizon {
+            let horizon_constraint = ColumnConstraint::Inequality {
+                operator: Inequality::GreaterThanOrEquals,
+                left: QueryValue::Column(QualifiedAlias(
+                    transactions.clone(),
+                    Column::Transactions(TransactionsColumn::Tx),
+                )),
+                right: QueryValue::Entid(horizon),
+            };
+            self.wheres.add_intersection(horizon_constraint);
+        }
+
         if let VariableOrPlaceholder::Variable(ref var) = b_e {
             // It must be a ref.
             self.constrain_var_to_type(var.clone(), ValueType::Ref);
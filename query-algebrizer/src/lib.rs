@@ -18,7 +18,7 @@ extern crate query_algebrizer_traits;
 
 use std::collections::BTreeSet;
 use std::ops::Sub;
-use std::rc::Rc;
+use std::sync::Arc;
 
 mod clauses;
 mod types;
@@ -34,6 +34,8 @@ use edn::query::{Element, FindSpec, Limit, Order, ParsedQuery, SrcVar, Variable,
 
 use query_algebrizer_traits::errors::{AlgebrizerError, Result};
 
+use crate::validate::validate_sources;
+
 pub use crate::clauses::{QueryInputs, VariableBindings};
 
 pub use crate::types::{EmptyBecause, FindQuery};
@@ -46,6 +48,9 @@ pub use crate::types::{EmptyBecause, FindQuery};
 pub struct Known<'s, 'c> {
     pub schema: &'s Schema,
     pub cache: Option<&'c dyn CachedAttributes>,
+
+    /// Transactions before this one have had their history pruned.
+    pub history_horizon: Option<Entid>,
 }
 
 impl<'s, 'c> Known<'s, 'c> {
@@ -53,6 +58,7 @@ impl<'s, 'c> Known<'s, 'c> {
         Known {
             schema: s,
             cache: None,
+            history_horizon: None,
         }
     }
 
@@ -60,6 +66,7 @@ impl<'s, 'c> Known<'s, 'c> {
         Known {
             schema: s,
             cache: c,
+            history_horizon: None,
         }
     }
 }
@@ -85,6 +92,16 @@ impl<'s, 'c> Known<'s, 'c> {
             .unwrap_or(false)
     }
 
+    pub fn is_entity_cached_forward<U, V>(&self, attribute: U, entid: V) -> bool
+    where
+        U: Into<Entid>,
+        V: Into<Entid>,
+    {
+        self.cache
+            .map(|cache| cache.is_entity_cached_forward(attribute.into(), entid.into()))
+            .unwrap_or(false)
+    }
+
     pub fn get_values_for_entid<U, V>(
         &self,
         schema: &Schema,
@@ -137,7 +154,7 @@ impl<'s, 'c> Known<'s, 'c> {
 #[derive(Debug)]
 pub struct AlgebraicQuery {
     default_source: SrcVar,
-    pub find_spec: Rc<FindSpec>,
+    pub find_spec: Arc<FindSpec>,
     has_aggregates: bool,
 
     /// The set of variables that the caller wishes to be used for grouping when aggregating.
@@ -193,6 +210,16 @@ impl AlgebraicQuery {
             .input_variables
             .sub(&self.cc.value_bound_variable_set())
     }
+
+    /// Return those unbound input variables whose values can be supplied as bind parameters when
+    /// the query is run, rather than substituted when it's algebrized. The SQL for the query names
+    /// each of them `$` followed by `mentat_query_sql::parameter_name`.
+    pub fn parameters(&self) -> BTreeSet<Variable> {
+        self.unbound_variables()
+            .into_iter()
+            .filter(|var| self.cc.parameter_column(var).is_some())
+            .collect()
+    }
 }
 
 pub fn algebrize_with_counter(
@@ -304,12 +331,20 @@ pub fn algebrize_with_inputs(
     counter: usize,
     inputs: QueryInputs,
 ) -> Result<AlgebraicQuery> {
+    validate_sources(
+        &parsed.in_sources,
+        inputs.sources(),
+        &parsed.where_clauses,
+        known.history_horizon,
+    )?;
+
     let alias_counter = RcCounter::with_initial(counter);
     let mut cc =
         ConjoiningClauses::with_inputs_and_alias_counter(parsed.in_vars, inputs, alias_counter);
 
     // This is so the rest of the query knows that `?x` is a ref if `(pull ?x …)` appears in `:find`.
     cc.derive_types_from_find_spec(&parsed.find_spec);
+    cc.derive_dependencies_from_find_spec(known.schema, &parsed.find_spec);
 
     // Do we have a variable limit? If so, tell the CC that the var must be numeric.
     if let Limit::Variable(ref var) = parsed.limit {
@@ -321,6 +356,7 @@ pub fn algebrize_with_inputs(
     cc.apply_clauses(known, parsed.where_clauses)?;
 
     cc.expand_column_bindings();
+    cc.constrain_inputs_to_parameters();
     cc.prune_extracted_types();
     cc.process_required_types()?;
 
@@ -334,7 +370,7 @@ pub fn algebrize_with_inputs(
     };
     let q = AlgebraicQuery {
         default_source: parsed.default_source,
-        find_spec: Rc::new(parsed.find_spec),
+        find_spec: Arc::new(parsed.find_spec),
         has_aggregates: false, // TODO: we don't parse them yet.
         with: parsed.with,
         named_projection: extra_vars,
@@ -350,9 +386,10 @@ pub fn algebrize_with_inputs(
 pub use crate::clauses::ConjoiningClauses;
 
 pub use crate::types::{
-    Column, ColumnAlternation, ColumnConstraint, ColumnConstraintOrAlternation, ColumnIntersection,
-    ColumnName, ComputedTable, DatomsColumn, DatomsTable, FulltextColumn, OrderBy, QualifiedAlias,
-    QueryValue, SourceAlias, TableAlias, VariableColumn,
+    AttributeDependencies, Column, ColumnAlternation, ColumnConstraint,
+    ColumnConstraintOrAlternation, ColumnIntersection, ColumnName, ComputedTable, DatomsColumn,
+    DatomsTable, FulltextColumn, OrderBy, QualifiedAlias, QueryValue, SourceAlias, TableAlias,
+    VariableColumn,
 };
 
 impl FindQuery {
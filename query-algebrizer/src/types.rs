@@ -13,20 +13,60 @@ use std::fmt::{Debug, Formatter};
 
 use core_traits::{Entid, TypedValue, ValueType, ValueTypeSet};
 
-use mentat_core::ValueRc;
+use mentat_core::{DatomsView, DatomsViewTable, ValueRc};
 
 use edn::query::{Direction, FindSpec, Keyword, Limit, Order, SrcVar, Variable, WhereClause};
 
+/// The attributes whose datoms a query reads, and so whose changes can alter its results.
+/// A pattern like `[?e _ ?v]`, or a call to the tx-log API, reads datoms of every attribute.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct AttributeDependencies {
+    attributes: BTreeSet<Entid>,
+    all: bool,
+}
+
+impl AttributeDependencies {
+    pub fn add(&mut self, attribute: Entid) {
+        if !self.all {
+            self.attributes.insert(attribute);
+        }
+    }
+
+    pub fn add_all(&mut self) {
+        self.all = true;
+        self.attributes.clear();
+    }
+
+    /// True if datoms of any attribute can alter the results.
+    pub fn is_all(&self) -> bool {
+        self.all
+    }
+
+    /// The attributes read, if `is_all` is false.
+    pub fn attributes(&self) -> &BTreeSet<Entid> {
+        &self.attributes
+    }
+
+    /// Return true if a change to a datom of any of `attributes` might alter the results.
+    pub fn intersects<'a, I>(&self, attributes: I) -> bool
+    where
+        I: IntoIterator<Item = &'a Entid>,
+    {
+        self.all || attributes.into_iter().any(|a| self.attributes.contains(a))
+    }
+}
+
 /// This enum models the fixed set of default tables we have -- two
 /// tables and two views -- and computed tables defined in the enclosing CC.
 #[derive(PartialEq, Eq, Clone, Copy, Debug)]
 pub enum DatomsTable {
-    Datoms,          // The non-fulltext datoms table.
-    FulltextValues,  // The virtual table mapping IDs to strings.
-    FulltextDatoms,  // The fulltext-datoms view.
-    AllDatoms,       // Fulltext and non-fulltext datoms.
-    Computed(usize), // A computed table, tracked elsewhere in the query.
-    Transactions,    // The transactions table, which makes the tx-data log API efficient.
+    Datoms,                              // The non-fulltext datoms table.
+    FulltextValues,                      // The virtual table mapping IDs to strings.
+    FulltextDatoms,                      // The fulltext-datoms view.
+    AllDatoms,                           // Fulltext and non-fulltext datoms.
+    Computed(usize),                     // A computed table, tracked elsewhere in the query.
+    Transactions, // The transactions table, which makes the tx-data log API efficient.
+    Viewed(DatomsViewTable, DatomsView), // A datoms table seen through an as-of or since view.
 }
 
 /// A source of rows that isn't a named table -- typically a subquery or union.
@@ -53,6 +93,22 @@ impl DatomsTable {
             DatomsTable::AllDatoms => "all_datoms",
             DatomsTable::Computed(_) => "c",
             DatomsTable::Transactions => "transactions",
+            DatomsTable::Viewed(table, _) => table.name(),
+        }
+    }
+
+    /// Wrap this table in `view`, if it's a datoms table and the view isn't the current store.
+    pub fn in_view(self, view: DatomsView) -> DatomsTable {
+        if view.is_current() {
+            return self;
+        }
+        match self {
+            DatomsTable::Datoms => DatomsTable::Viewed(DatomsViewTable::Datoms, view),
+            DatomsTable::FulltextDatoms => {
+                DatomsTable::Viewed(DatomsViewTable::FulltextDatoms, view)
+            }
+            DatomsTable::AllDatoms => DatomsTable::Viewed(DatomsViewTable::AllDatoms, view),
+            _ => self,
         }
     }
 }
@@ -292,6 +348,9 @@ pub enum QueryValue {
     // cannot be a boolean, so `datoms00.value_type_tag` must be in the set `#{0, 4, 5}`.
     // Note that `5 = 5.0` in SQLite, and we preserve that here.
     PrimitiveLong(i64),
+
+    // The value of an unbound input variable, supplied as a bind parameter when the query is run.
+    Parameter(Variable),
 }
 
 impl Debug for QueryValue {
@@ -302,6 +361,7 @@ impl Debug for QueryValue {
             Entid(ref entid) => write!(f, "entity({:?})", entid),
             TypedValue(ref typed_value) => write!(f, "value({:?})", typed_value),
             PrimitiveLong(value) => write!(f, "primitive({:?})", value),
+            Parameter(ref var) => write!(f, "parameter({:?})", var),
         }
     }
 }
@@ -588,6 +648,11 @@ pub enum EmptyBecause {
         value: TypedValue,
         attr: Entid,
     },
+    CachedDatomNotPresent {
+        entity: Entid,
+        attr: Entid,
+        value: TypedValue,
+    },
     ConflictingBindings {
         var: Variable,
         existing: TypedValue,
@@ -633,6 +698,15 @@ impl Debug for EmptyBecause {
                 ref entity,
                 ref attr,
             } => write!(f, "({}, {}, ?v, _) not present in store", entity, attr),
+            CachedDatomNotPresent {
+                ref entity,
+                ref attr,
+                ref value,
+            } => write!(
+                f,
+                "({}, {}, {:?}, _) not present in store",
+                entity, attr, value
+            ),
             ConflictingBindings {
                 ref var,
                 ref existing,
@@ -739,4 +813,5 @@ pub struct EvolvedPattern {
     pub attribute: EvolvedNonValuePlace,
     pub value: EvolvedValuePlace,
     pub tx: EvolvedNonValuePlace,
+    pub added: EvolvedValuePlace,
 }
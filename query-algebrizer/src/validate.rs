@@ -8,9 +8,16 @@
 // CONDITIONS OF ANY KIND, either express or implied. See the License for the
 // specific language governing permissions and limitations under the License.
 
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
 
-use edn::query::{ContainsVariables, NotJoin, OrJoin, UnifyVars, Variable};
+use core_traits::Entid;
+
+use mentat_core::DatomsView;
+
+use edn::query::{
+    ContainsVariables, FnArg, NotJoin, OrJoin, OrWhereClause, PatternValuePlace, PlainSymbol,
+    SrcVar, UnifyVars, Variable, WhereClause,
+};
 
 use query_algebrizer_traits::errors::{AlgebrizerError, Result};
 
@@ -81,6 +88,90 @@ pub(crate) fn validate_not_join(not_join: &NotJoin) -> Result<()> {
     }
 }
 
+/// Collect every source mentioned in `clauses` into `acc`, and the source of every pattern that
+/// uses its `added` place into `added`.
+fn collect_sources<'a, I>(clauses: I, acc: &mut BTreeSet<SrcVar>, added: &mut BTreeSet<SrcVar>)
+where
+    I: IntoIterator<Item = &'a WhereClause>,
+{
+    for clause in clauses {
+        match clause {
+            WhereClause::Pattern(ref p) => {
+                if let Some(ref source) = p.source {
+                    acc.insert(source.clone());
+                }
+                if p.added != PatternValuePlace::Placeholder {
+                    added.insert(p.source.clone().unwrap_or(SrcVar::DefaultSrc));
+                }
+            }
+            WhereClause::WhereFn(ref f) => {
+                for arg in f.args.iter() {
+                    if let FnArg::SrcVar(ref source) = arg {
+                        acc.insert(source.clone());
+                    }
+                }
+            }
+            WhereClause::OrJoin(ref o) => {
+                for arm in o.clauses.iter() {
+                    match arm {
+                        OrWhereClause::Clause(ref c) => collect_sources(Some(c), acc, added),
+                        OrWhereClause::And(ref cs) => collect_sources(cs, acc, added),
+                    }
+                }
+            }
+            WhereClause::NotJoin(ref n) => collect_sources(&n.clauses, acc, added),
+            _ => {}
+        }
+    }
+}
+
+/// Every source mentioned in the query's clauses must be the default source or be declared in
+/// `:in`, and every named source declared in `:in` must be bound to a view.
+/// Only a history source can bind a pattern's `added` place, and an as-of view can't look back
+/// past the history horizon.
+pub(crate) fn validate_sources(
+    in_sources: &BTreeSet<SrcVar>,
+    views: &BTreeMap<SrcVar, DatomsView>,
+    where_clauses: &[WhereClause],
+    history_horizon: Option<Entid>,
+) -> Result<()> {
+    let mut mentioned = BTreeSet::new();
+    let mut added = BTreeSet::new();
+    collect_sources(where_clauses, &mut mentioned, &mut added);
+    for source in mentioned.iter() {
+        if *source != SrcVar::DefaultSrc && !in_sources.contains(source) {
+            bail!(AlgebrizerError::UnknownSource(source.name()));
+        }
+    }
+
+    for source in in_sources.iter() {
+        if *source != SrcVar::DefaultSrc && !views.contains_key(source) {
+            bail!(AlgebrizerError::UnboundSource(source.name()));
+        }
+    }
+
+    for source in added.iter() {
+        if !views.get(source).map_or(false, DatomsView::has_added) {
+            bail!(AlgebrizerError::NonHistorySource(source.name()));
+        }
+    }
+
+    if let Some(horizon) = history_horizon {
+        for view in views.values() {
+            if let DatomsView::AsOf(tx) = *view {
+                if tx < horizon {
+                    bail!(AlgebrizerError::HistoryPruned(
+                        PlainSymbol::plain("as-of"),
+                        tx,
+                        horizon
+                    ));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     extern crate edn;
@@ -144,6 +235,7 @@ mod tests {
                         attribute: ident("artist", "type"),
                         value: value_ident("artist.type", "group"),
                         tx: PatternNonValuePlace::Placeholder,
+                        added: PatternValuePlace::Placeholder,
                     }))
                 );
                 assert_eq!(
@@ -157,6 +249,7 @@ mod tests {
                             attribute: ident("artist", "type"),
                             value: value_ident("artist.type", "person"),
                             tx: PatternNonValuePlace::Placeholder,
+                            added: PatternValuePlace::Placeholder,
                         }),
                         WhereClause::Pattern(Pattern {
                             source: None,
@@ -166,6 +259,7 @@ mod tests {
                             attribute: ident("artist", "gender"),
                             value: value_ident("artist.gender", "female"),
                             tx: PatternNonValuePlace::Placeholder,
+                            added: PatternValuePlace::Placeholder,
                         }),
                     ])
                 );
@@ -221,6 +315,7 @@ mod tests {
                         attribute: ident("artist", "type"),
                         value: value_ident("artist.type", "group"),
                         tx: PatternNonValuePlace::Placeholder,
+                        added: PatternValuePlace::Placeholder,
                     }))
                 );
                 assert_eq!(
@@ -234,6 +329,7 @@ mod tests {
                             attribute: ident("artist", "type"),
                             value: PatternValuePlace::Variable(Variable::from_valid_name("?type")),
                             tx: PatternNonValuePlace::Placeholder,
+                            added: PatternValuePlace::Placeholder,
                         }),
                         WhereClause::Pattern(Pattern {
                             source: None,
@@ -243,6 +339,7 @@ mod tests {
                             attribute: ident("artist", "role"),
                             value: value_ident("artist.role", "parody"),
                             tx: PatternNonValuePlace::Placeholder,
+                            added: PatternValuePlace::Placeholder,
                         }),
                     ])
                 );
@@ -298,6 +395,7 @@ mod tests {
                         attribute: artist_country.clone(),
                         value: value_ident("country", "CA"),
                         tx: PatternNonValuePlace::Placeholder,
+                        added: PatternValuePlace::Placeholder,
                     })
                 );
                 assert_eq!(
@@ -308,6 +406,7 @@ mod tests {
                         attribute: artist_country,
                         value: value_ident("country", "GB"),
                         tx: PatternNonValuePlace::Placeholder,
+                        added: PatternValuePlace::Placeholder,
                     })
                 );
             }
@@ -342,6 +441,7 @@ mod tests {
                         attribute: ident("release", "artists"),
                         value: artist,
                         tx: PatternNonValuePlace::Placeholder,
+                        added: PatternValuePlace::Placeholder,
                     })
                 );
                 assert_eq!(
@@ -352,6 +452,7 @@ mod tests {
                         attribute: ident("release", "year"),
                         value: PatternValuePlace::EntidOrInteger(1970),
                         tx: PatternNonValuePlace::Placeholder,
+                        added: PatternValuePlace::Placeholder,
                     })
                 );
             }
@@ -380,4 +481,44 @@ mod tests {
             _ => panic!(),
         }
     }
+
+    #[test]
+    fn test_validate_sources() {
+        let before = SrcVar::NamedSrc("before".to_string());
+        let mut views = BTreeMap::new();
+
+        // A named source must be declared in `:in`.
+        let parsed = parse_find_string(r#"[:find ?x :where [$before ?x :foo/bar 5]]"#)
+            .expect("expected successful parse");
+        match validate_sources(&parsed.in_sources, &views, &parsed.where_clauses, None) {
+            Err(AlgebrizerError::UnknownSource(s)) => assert_eq!(s, before.name()),
+            x => panic!("expected UnknownSource, got {:?}", x),
+        }
+
+        // … and bound to a view.
+        let parsed =
+            parse_find_string(r#"[:find ?x :in $before :where (not [$before ?x :foo/bar 5])]"#)
+                .expect("expected successful parse");
+        match validate_sources(&parsed.in_sources, &views, &parsed.where_clauses, None) {
+            Err(AlgebrizerError::UnboundSource(s)) => assert_eq!(s, before.name()),
+            x => panic!("expected UnboundSource, got {:?}", x),
+        }
+
+        views.insert(before, DatomsView::AsOf(1000));
+        validate_sources(&parsed.in_sources, &views, &parsed.where_clauses, None)
+            .expect("bound source");
+
+        // An as-of view can't see past the history horizon.
+        match validate_sources(
+            &parsed.in_sources,
+            &views,
+            &parsed.where_clauses,
+            Some(1001),
+        ) {
+            Err(AlgebrizerError::HistoryPruned(_, tx, horizon)) => {
+                assert_eq!((tx, horizon), (1000, 1001))
+            }
+            x => panic!("expected HistoryPruned, got {:?}", x),
+        }
+    }
 }
@@ -0,0 +1,119 @@
+// Copyright 2018 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+extern crate core_traits;
+extern crate edn;
+extern crate mentat_core;
+extern crate mentat_query_algebrizer;
+extern crate query_algebrizer_traits;
+
+mod utils;
+
+use std::collections::BTreeSet;
+
+use core_traits::{Entid, ValueType};
+
+use mentat_core::Schema;
+
+use mentat_query_algebrizer::Known;
+
+use crate::utils::{alg, SchemaBuilder};
+
+fn prepopulated_schema() -> Schema {
+    SchemaBuilder::new()
+        .define_simple_attr("foo", "name", ValueType::String, false)
+        .define_simple_attr("foo", "knows", ValueType::Ref, true)
+        .define_simple_attr("foo", "age", ValueType::Long, false)
+        .define_simple_attr("foo", "height", ValueType::Long, false)
+        .schema
+}
+
+fn attributes(entids: &[Entid]) -> BTreeSet<Entid> {
+    entids.iter().cloned().collect()
+}
+
+#[test]
+fn test_pattern_dependencies() {
+    let schema = prepopulated_schema();
+    let known = Known::for_schema(&schema);
+
+    let cc = alg(
+        known,
+        "[:find ?x ?n :where [?x :foo/knows ?y] [?y :foo/name ?n]]",
+    );
+    let deps = cc.attribute_dependencies();
+    assert!(!deps.is_all());
+    assert_eq!(deps.attributes(), &attributes(&[65, 66]));
+    assert!(deps.intersects(&[66]));
+    assert!(!deps.intersects(&[67, 68]));
+}
+
+#[test]
+fn test_nested_dependencies() {
+    let schema = prepopulated_schema();
+    let known = Known::for_schema(&schema);
+
+    // Both simple and complex `or`s, and `not`, contribute.
+    let cc = alg(
+        known,
+        r#"[:find ?x :where
+             [?x :foo/name _]
+             (or [?x :foo/age 5] [?x :foo/height 5])
+             (not [?x :foo/knows ?x])]"#,
+    );
+    assert_eq!(
+        cc.attribute_dependencies().attributes(),
+        &attributes(&[65, 66, 67, 68])
+    );
+
+    let cc = alg(
+        known,
+        r#"[:find ?x :where
+             (or-join [?x]
+               [?x :foo/age 5]
+               (and [?x :foo/knows ?y] [?y :foo/name "Bob"]))]"#,
+    );
+    assert_eq!(
+        cc.attribute_dependencies().attributes(),
+        &attributes(&[65, 66, 67])
+    );
+}
+
+#[test]
+fn test_pull_dependencies() {
+    let schema = prepopulated_schema();
+    let known = Known::for_schema(&schema);
+
+    let cc = alg(
+        known,
+        "[:find (pull ?x [:db/id :foo/height]) :where [?x :foo/age 5]]",
+    );
+    assert_eq!(
+        cc.attribute_dependencies().attributes(),
+        &attributes(&[67, 68])
+    );
+
+    let cc = alg(known, "[:find (pull ?x [*]) :where [?x :foo/age 5]]");
+    assert!(cc.attribute_dependencies().is_all());
+}
+
+#[test]
+fn test_unknown_attribute_dependencies() {
+    let schema = prepopulated_schema();
+    let known = Known::for_schema(&schema);
+
+    let cc = alg(known, "[:find ?x :where [?x :foo/age 5] [?x ?a 7]]");
+    let deps = cc.attribute_dependencies();
+    assert!(deps.is_all());
+    assert!(deps.intersects(&[1000]));
+
+    let cc = alg(known, "[:find ?tx :where [(tx-ids $ 1000 2000) [?tx ...]]]");
+    assert!(cc.attribute_dependencies().is_all());
+}
@@ -16,13 +16,15 @@ extern crate query_algebrizer_traits;
 
 mod utils;
 
-use crate::utils::{alg, bails, SchemaBuilder};
+use crate::utils::{alg, alg_with_inputs, bails, SchemaBuilder};
 
-use core_traits::ValueType;
+use core_traits::{TypedValue, ValueType};
 
-use mentat_core::Schema;
+use edn::query::{SrcVar, Variable};
 
-use mentat_query_algebrizer::Known;
+use mentat_core::{DatomsView, Schema};
+
+use mentat_query_algebrizer::{EmptyBecause, Known, QueryInputs};
 
 fn prepopulated_schema() -> Schema {
     SchemaBuilder::new()
@@ -77,3 +79,39 @@ fn test_unbound() {
     let known = Known::for_schema(&schema);
     bails(known, "[:find ?e :where [(type ?e :db.type/string)]]");
 }
+
+#[test]
+fn test_added_must_be_boolean() {
+    let schema = prepopulated_schema();
+    let known = Known::for_schema(&schema);
+    let history = || QueryInputs::default().with_source(SrcVar::DefaultSrc, DatomsView::History);
+
+    let cc = alg_with_inputs(
+        known,
+        r#"[:find ?e :where [?e :test/long ?v _ "yes"]]"#,
+        history(),
+    );
+    assert_eq!(
+        cc.empty_because,
+        Some(EmptyBecause::ValueTypeMismatch(
+            ValueType::Boolean,
+            TypedValue::typed_string("yes")
+        ))
+    );
+
+    let added = Variable::from_valid_name("?added");
+    let inputs = QueryInputs::with_value_sequence(vec![(added, TypedValue::Long(1))])
+        .with_source(SrcVar::DefaultSrc, DatomsView::History);
+    let cc = alg_with_inputs(
+        known,
+        "[:find ?e :in ?added :where [?e :test/long ?v _ ?added]]",
+        inputs,
+    );
+    assert_eq!(
+        cc.empty_because,
+        Some(EmptyBecause::ValueTypeMismatch(
+            ValueType::Boolean,
+            TypedValue::Long(1)
+        ))
+    );
+}
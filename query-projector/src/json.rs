@@ -0,0 +1,45 @@
+// Copyright 2018 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Query results in the JSON mapping described in `mentat::json`: a scalar is a value or `null`,
+//! a tuple is an array or `null`, a collection is an array, and a relation is an array of arrays.
+
+use serde::ser::{Serialize, SerializeMap, Serializer};
+
+use crate::{QueryOutput, QueryResults, RelResult};
+
+impl<T: Serialize> Serialize for RelResult<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.rows())
+    }
+}
+
+impl Serialize for QueryResults {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match *self {
+            QueryResults::Scalar(ref v) => v.serialize(serializer),
+            QueryResults::Tuple(ref vs) => vs.serialize(serializer),
+            QueryResults::Coll(ref vs) => vs.serialize(serializer),
+            QueryResults::Rel(ref rel) => rel.serialize(serializer),
+        }
+    }
+}
+
+/// `{"find": ["?x", "(count ?y)"], "results": …}`: the results, and the find elements that name
+/// their columns.
+impl Serialize for QueryOutput {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let find: Vec<String> = self.spec.columns().map(|e| e.to_string()).collect();
+        let mut map = serializer.serialize_map(Some(2))?;
+        map.serialize_entry("find", &find)?;
+        map.serialize_entry("results", &self.results)?;
+        map.end()
+    }
+}
@@ -12,6 +12,8 @@ extern crate failure;
 
 extern crate indexmap;
 extern crate rusqlite;
+#[cfg(feature = "json")]
+extern crate serde;
 
 extern crate db_traits;
 extern crate edn;
@@ -29,7 +31,7 @@ use std::collections::BTreeSet;
 
 use std::iter;
 
-use std::rc::Rc;
+use std::sync::Arc;
 
 use rusqlite::{Row, Rows};
 
@@ -50,6 +52,8 @@ use mentat_query_sql::{GroupBy, Projection};
 pub mod translate;
 
 mod binding_tuple;
+#[cfg(feature = "json")]
+mod json;
 pub use crate::binding_tuple::BindingTuple;
 mod project;
 mod projectors;
@@ -73,7 +77,7 @@ use query_projector_traits::errors::{ProjectorError, Result};
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct QueryOutput {
-    pub spec: Rc<FindSpec>,
+    pub spec: Arc<FindSpec>,
     pub results: QueryResults,
 }
 
@@ -92,7 +96,7 @@ impl From<QueryOutput> for QueryResults {
 }
 
 impl QueryOutput {
-    pub fn empty_factory(spec: &FindSpec) -> Box<dyn Fn() -> QueryResults> {
+    pub fn empty_factory(spec: &FindSpec) -> Box<dyn Fn() -> QueryResults + Send + Sync> {
         use self::FindSpec::*;
         match *spec {
             FindScalar(_) => Box::new(|| QueryResults::Scalar(None)),
@@ -113,7 +117,7 @@ impl QueryOutput {
         self.results.is_empty()
     }
 
-    pub fn empty(spec: &Rc<FindSpec>) -> QueryOutput {
+    pub fn empty(spec: &Arc<FindSpec>) -> QueryOutput {
         use self::FindSpec::*;
         let results = match **spec {
             FindScalar(_) => QueryResults::Scalar(None),
@@ -127,7 +131,7 @@ impl QueryOutput {
         }
     }
 
-    pub fn from_constants(spec: &Rc<FindSpec>, bindings: VariableBindings) -> QueryResults {
+    pub fn from_constants(spec: &Arc<FindSpec>, bindings: VariableBindings) -> QueryResults {
         use self::FindSpec::*;
         match **spec {
             FindScalar(Element::Variable(ref var))
@@ -272,6 +276,16 @@ impl QueryResults {
         }
     }
 
+    /// Turn these results into rows, each with one binding per element of the find spec.
+    pub fn into_rows(self) -> Vec<Vec<Binding>> {
+        match self {
+            QueryResults::Scalar(o) => o.into_iter().map(|b| vec![b]).collect(),
+            QueryResults::Tuple(t) => t.into_iter().collect(),
+            QueryResults::Coll(c) => c.into_iter().map(|b| vec![b]).collect(),
+            QueryResults::Rel(r) => r.into_iter().collect(),
+        }
+    }
+
     pub fn into_scalar(self) -> Result<Option<Binding>> {
         match self {
             QueryResults::Scalar(o) => Ok(o),
@@ -504,7 +518,7 @@ pub fn query_projection(
 #[test]
 fn test_into_tuple() {
     let query_output = QueryOutput {
-        spec: Rc::new(FindSpec::FindTuple(vec![
+        spec: Arc::new(FindSpec::FindTuple(vec![
             Element::Variable(Variable::from_valid_name("?x")),
             Element::Variable(Variable::from_valid_name("?y")),
         ])),
@@ -533,7 +547,7 @@ fn test_into_tuple() {
     }
 
     let query_output = QueryOutput {
-        spec: Rc::new(FindSpec::FindTuple(vec![
+        spec: Arc::new(FindSpec::FindTuple(vec![
             Element::Variable(Variable::from_valid_name("?x")),
             Element::Variable(Variable::from_valid_name("?y")),
         ])),
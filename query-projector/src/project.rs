@@ -18,7 +18,7 @@ use mentat_core::{SQLValueType, SQLValueTypeSet};
 
 use mentat_core::util::Either;
 
-use edn::query::{Element, Pull, Variable};
+use edn::query::{Element, Pull, SrcVar, Variable};
 
 use mentat_query_algebrizer::{
     AlgebraicQuery, ColumnName, ConjoiningClauses, QualifiedAlias, VariableColumn,
@@ -285,7 +285,10 @@ pub(crate) fn project_elements<'a, I: IntoIterator<Item = &'a Element>>(
                             sql_index: i,
                             output_index,
                         },
-                        op: PullOperation((*patterns).clone()),
+                        op: PullOperation(
+                            (*patterns).clone(),
+                            query.cc.view_for_source(&SrcVar::DefaultSrc),
+                        ),
                     });
                     i += 1; // We used one SQL column.
                 } else {
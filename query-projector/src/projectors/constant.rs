@@ -8,25 +8,27 @@
 // CONDITIONS OF ANY KIND, either express or implied. See the License for the
 // specific language governing permissions and limitations under the License.
 
-use std::rc::Rc;
+use std::sync::Arc;
+
+use core_traits::Binding;
 
 use crate::{rusqlite, Element, FindSpec, QueryOutput, QueryResults, Rows, Schema};
 
-use query_projector_traits::errors::Result;
+use query_projector_traits::errors::{ProjectorError, Result};
 
 use super::Projector;
 
 /// A projector that produces a `QueryResult` containing fixed data.
 /// Takes a boxed function that should return an empty result set of the desired type.
 pub struct ConstantProjector {
-    spec: Rc<FindSpec>,
-    results_factory: Box<dyn Fn() -> QueryResults>,
+    spec: Arc<FindSpec>,
+    results_factory: Box<dyn Fn() -> QueryResults + Send + Sync>,
 }
 
 impl ConstantProjector {
     pub fn new(
-        spec: Rc<FindSpec>,
-        results_factory: Box<dyn Fn() -> QueryResults>,
+        spec: Arc<FindSpec>,
+        results_factory: Box<dyn Fn() -> QueryResults + Send + Sync>,
     ) -> ConstantProjector {
         ConstantProjector {
             spec,
@@ -53,6 +55,19 @@ impl Projector for ConstantProjector {
         self.project_without_rows()
     }
 
+    fn project_page<'stmt, 's>(
+        &self,
+        _schema: &Schema,
+        _sqlite: &'s rusqlite::Connection,
+        _rows: &mut Rows<'stmt>,
+        _page_size: usize,
+    ) -> Result<Vec<Vec<Binding>>> {
+        // Constant results don't come from rows: use `project_without_rows` instead.
+        bail!(ProjectorError::InvalidProjection(
+            "constant projectors do not project rows".to_string()
+        ))
+    }
+
     fn columns<'s>(&'s self) -> Box<dyn Iterator<Item = &Element> + 's> {
         self.spec.columns()
     }
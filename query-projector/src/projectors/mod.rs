@@ -8,20 +8,56 @@
 // CONDITIONS OF ANY KIND, either express or implied. See the License for the
 // specific language governing permissions and limitations under the License.
 
-use super::{rusqlite, Element, QueryOutput, Rows, Schema};
+use core_traits::Binding;
+
+use super::{rusqlite, Element, QueryOutput, Row, Rows, Schema};
 
 use query_projector_traits::errors::Result;
 
-pub trait Projector {
+/// Projectors are `Send + Sync` so that translated queries can be cached and shared across
+/// threads.
+pub trait Projector: Send + Sync {
     fn project<'stmt, 's>(
         &self,
         schema: &Schema,
         sqlite: &'s rusqlite::Connection,
         rows: Rows<'stmt>,
     ) -> Result<QueryOutput>;
+
+    /// Project up to `page_size` of the remaining `rows`, producing one binding for each element
+    /// of the find spec per row. An empty page means that `rows` is exhausted. This allows
+    /// results to be streamed rather than collected up front; pull expressions are run once per
+    /// page, not once per row.
+    fn project_page<'stmt, 's>(
+        &self,
+        schema: &Schema,
+        sqlite: &'s rusqlite::Connection,
+        rows: &mut Rows<'stmt>,
+        page_size: usize,
+    ) -> Result<Vec<Vec<Binding>>>;
+
     fn columns<'s>(&'s self) -> Box<dyn Iterator<Item = &Element> + 's>;
 }
 
+/// Read up to `page_size` of the remaining `rows`, turning each into bindings with `f`.
+fn read_page<'stmt, F>(
+    rows: &mut Rows<'stmt>,
+    page_size: usize,
+    mut f: F,
+) -> Result<Vec<Vec<Binding>>>
+where
+    F: FnMut(&Row) -> Result<Vec<Binding>>,
+{
+    let mut page = Vec::with_capacity(page_size);
+    while page.len() < page_size {
+        match rows.next()? {
+            Some(row) => page.push(f(row)?),
+            None => break,
+        }
+    }
+    Ok(page)
+}
+
 mod constant;
 mod pull_two_stage;
 mod simple;
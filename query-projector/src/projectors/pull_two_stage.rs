@@ -8,13 +8,13 @@
 // CONDITIONS OF ANY KIND, either express or implied. See the License for the
 // specific language governing permissions and limitations under the License.
 
-use std::rc::Rc;
+use std::sync::Arc;
 
 use std::iter::once;
 
 use mentat_query_pull::Puller;
 
-use core_traits::Entid;
+use core_traits::{Entid, TypedValue};
 
 use crate::{
     rusqlite, Binding, CombinedProjection, Element, FindSpec, ProjectedElements, QueryOutput,
@@ -25,10 +25,48 @@ use crate::pull::{PullConsumer, PullOperation, PullTemplate};
 
 use query_projector_traits::errors::Result;
 
-use super::Projector;
+use super::{read_page, Projector};
+
+/// Read up to `page_size` of the remaining `rows` into bindings with `collect`, then run each of
+/// `pulls` once for the entities of the whole page, expanding the results into the page.
+fn project_page_with_pulls<'stmt, F>(
+    schema: &Schema,
+    sqlite: &rusqlite::Connection,
+    pulls: &[PullTemplate],
+    rows: &mut Rows<'stmt>,
+    page_size: usize,
+    collect: F,
+) -> Result<Vec<Vec<Binding>>>
+where
+    F: Fn(&Row) -> Result<Vec<Binding>>,
+{
+    let mut pull_consumers = pulls
+        .iter()
+        .map(|op| PullConsumer::for_template(schema, op))
+        .collect::<Result<Vec<PullConsumer>>>()?;
+
+    let mut page = read_page(rows, page_size, |row| {
+        for p in pull_consumers.iter_mut() {
+            p.collect_entity(row)?;
+        }
+        collect(row)
+    })?;
+
+    if !page.is_empty() {
+        for p in pull_consumers.iter_mut() {
+            p.pull(sqlite)?;
+        }
+        for bindings in page.iter_mut() {
+            for p in pull_consumers.iter() {
+                p.expand(bindings);
+            }
+        }
+    }
+    Ok(page)
+}
 
 pub(crate) struct ScalarTwoStagePullProjector {
-    spec: Rc<FindSpec>,
+    spec: Arc<FindSpec>,
     puller: Puller,
 }
 
@@ -38,18 +76,18 @@ pub(crate) struct ScalarTwoStagePullProjector {
 impl ScalarTwoStagePullProjector {
     fn with_template(
         schema: &Schema,
-        spec: Rc<FindSpec>,
+        spec: Arc<FindSpec>,
         pull: PullOperation,
     ) -> Result<ScalarTwoStagePullProjector> {
         Ok(ScalarTwoStagePullProjector {
             spec,
-            puller: Puller::prepare(schema, pull.0)?,
+            puller: Puller::prepare(schema, pull.0)?.in_view(pull.1),
         })
     }
 
     pub(crate) fn combine(
         schema: &Schema,
-        spec: Rc<FindSpec>,
+        spec: Arc<FindSpec>,
         mut elements: ProjectedElements,
     ) -> Result<CombinedProjection> {
         let pull = elements.pulls.pop().expect("Expected a single pull");
@@ -69,9 +107,9 @@ impl Projector for ScalarTwoStagePullProjector {
         mut rows: Rows<'stmt>,
     ) -> Result<QueryOutput> {
         // Scalar is pretty straightforward -- zero or one entity, do the pull directly.
-        let results = if let Some(r) = rows.next().unwrap() {
+        let results = if let Some(r) = rows.next()? {
             let row = r;
-            let entity: Entid = row.get(0).unwrap(); // This will always be 0 and a ref.
+            let entity: Entid = row.get(0)?; // This will always be 0 and a ref.
             let bindings = self.puller.pull(schema, sqlite, once(entity))?;
             let m = Binding::Map(
                 bindings
@@ -90,6 +128,33 @@ impl Projector for ScalarTwoStagePullProjector {
         })
     }
 
+    fn project_page<'stmt, 's>(
+        &self,
+        schema: &Schema,
+        sqlite: &'s rusqlite::Connection,
+        rows: &mut Rows<'stmt>,
+        page_size: usize,
+    ) -> Result<Vec<Vec<Binding>>> {
+        let mut entities: Vec<Entid> = Vec::with_capacity(page_size);
+        read_page(rows, page_size, |row| {
+            entities.push(row.get(0)?); // This will always be 0 and a ref.
+            Ok(vec![])
+        })?;
+        if entities.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let pulled = self.puller.pull(schema, sqlite, entities.iter().cloned())?;
+        Ok(entities
+            .iter()
+            .map(|entity| {
+                vec![Binding::Map(
+                    pulled.get(entity).cloned().unwrap_or_else(Default::default),
+                )]
+            })
+            .collect())
+    }
+
     fn columns<'s>(&'s self) -> Box<dyn Iterator<Item = &Element> + 's> {
         self.spec.columns()
     }
@@ -97,7 +162,7 @@ impl Projector for ScalarTwoStagePullProjector {
 
 /// A tuple projector produces a single vector. It's the single-result version of rel.
 pub(crate) struct TupleTwoStagePullProjector {
-    spec: Rc<FindSpec>,
+    spec: Arc<FindSpec>,
     len: usize,
     templates: Vec<TypedIndex>,
     pulls: Vec<PullTemplate>,
@@ -105,7 +170,7 @@ pub(crate) struct TupleTwoStagePullProjector {
 
 impl TupleTwoStagePullProjector {
     fn with_templates(
-        spec: Rc<FindSpec>,
+        spec: Arc<FindSpec>,
         len: usize,
         templates: Vec<TypedIndex>,
         pulls: Vec<PullTemplate>,
@@ -131,7 +196,7 @@ impl TupleTwoStagePullProjector {
     }
 
     pub(crate) fn combine(
-        spec: Rc<FindSpec>,
+        spec: Arc<FindSpec>,
         column_count: usize,
         mut elements: ProjectedElements,
     ) -> Result<CombinedProjection> {
@@ -153,7 +218,7 @@ impl Projector for TupleTwoStagePullProjector {
         sqlite: &'s rusqlite::Connection,
         mut rows: Rows<'stmt>,
     ) -> Result<QueryOutput> {
-        let results = if let Some(r) = rows.next().unwrap() {
+        let results = if let Some(r) = rows.next()? {
             let row = r;
 
             // Keeping the compiler happy.
@@ -166,7 +231,7 @@ impl Projector for TupleTwoStagePullProjector {
 
             // Collect the usual bindings and accumulate entity IDs for pull.
             for p in pull_consumers.iter_mut() {
-                p.collect_entity(&row);
+                p.collect_entity(row)?;
             }
 
             let mut bindings = self.collect_bindings(row)?;
@@ -191,6 +256,18 @@ impl Projector for TupleTwoStagePullProjector {
         })
     }
 
+    fn project_page<'stmt, 's>(
+        &self,
+        schema: &Schema,
+        sqlite: &'s rusqlite::Connection,
+        rows: &mut Rows<'stmt>,
+        page_size: usize,
+    ) -> Result<Vec<Vec<Binding>>> {
+        project_page_with_pulls(schema, sqlite, &self.pulls, rows, page_size, |row| {
+            self.collect_bindings(row)
+        })
+    }
+
     fn columns<'s>(&'s self) -> Box<dyn Iterator<Item = &Element> + 's> {
         self.spec.columns()
     }
@@ -201,7 +278,7 @@ impl Projector for TupleTwoStagePullProjector {
 /// Each column in each stride is the result of taking one or two columns from
 /// the `Row`: one for the value and optionally one for the type tag.
 pub(crate) struct RelTwoStagePullProjector {
-    spec: Rc<FindSpec>,
+    spec: Arc<FindSpec>,
     len: usize,
     templates: Vec<TypedIndex>,
     pulls: Vec<PullTemplate>,
@@ -209,7 +286,7 @@ pub(crate) struct RelTwoStagePullProjector {
 
 impl RelTwoStagePullProjector {
     fn with_templates(
-        spec: Rc<FindSpec>,
+        spec: Arc<FindSpec>,
         len: usize,
         templates: Vec<TypedIndex>,
         pulls: Vec<PullTemplate>,
@@ -237,7 +314,7 @@ impl RelTwoStagePullProjector {
     }
 
     pub(crate) fn combine(
-        spec: Rc<FindSpec>,
+        spec: Arc<FindSpec>,
         column_count: usize,
         mut elements: ProjectedElements,
     ) -> Result<CombinedProjection> {
@@ -280,10 +357,10 @@ impl Projector for RelTwoStagePullProjector {
         let mut pull_consumers = pull_consumers?;
 
         // Collect the usual bindings and accumulate entity IDs for pull.
-        while let Some(r) = rows.next().unwrap() {
+        while let Some(r) = rows.next()? {
             let row = r;
             for p in pull_consumers.iter_mut() {
-                p.collect_entity(&row);
+                p.collect_entity(row)?;
             }
             self.collect_bindings_into(row, &mut values)?;
         }
@@ -306,6 +383,20 @@ impl Projector for RelTwoStagePullProjector {
         })
     }
 
+    fn project_page<'stmt, 's>(
+        &self,
+        schema: &Schema,
+        sqlite: &'s rusqlite::Connection,
+        rows: &mut Rows<'stmt>,
+        page_size: usize,
+    ) -> Result<Vec<Vec<Binding>>> {
+        project_page_with_pulls(schema, sqlite, &self.pulls, rows, page_size, |row| {
+            let mut bindings = Vec::with_capacity(self.len);
+            self.collect_bindings_into(row, &mut bindings)?;
+            Ok(bindings)
+        })
+    }
+
     fn columns<'s>(&'s self) -> Box<dyn Iterator<Item = &Element> + 's> {
         self.spec.columns()
     }
@@ -314,17 +405,17 @@ impl Projector for RelTwoStagePullProjector {
 /// A coll projector produces a vector of values.
 /// Each value is sourced from the same column.
 pub(crate) struct CollTwoStagePullProjector {
-    spec: Rc<FindSpec>,
+    spec: Arc<FindSpec>,
     pull: PullOperation,
 }
 
 impl CollTwoStagePullProjector {
-    fn with_pull(spec: Rc<FindSpec>, pull: PullOperation) -> CollTwoStagePullProjector {
+    fn with_pull(spec: Arc<FindSpec>, pull: PullOperation) -> CollTwoStagePullProjector {
         CollTwoStagePullProjector { spec, pull }
     }
 
     pub(crate) fn combine(
-        spec: Rc<FindSpec>,
+        spec: Arc<FindSpec>,
         mut elements: ProjectedElements,
     ) -> Result<CombinedProjection> {
         let pull = elements.pulls.pop().expect("Expected a single pull");
@@ -347,9 +438,9 @@ impl Projector for CollTwoStagePullProjector {
     ) -> Result<QueryOutput> {
         let mut pull_consumer = PullConsumer::for_operation(schema, &self.pull)?;
 
-        while let Some(r) = rows.next().unwrap() {
+        while let Some(r) = rows.next()? {
             let row = r;
-            pull_consumer.collect_entity(&row);
+            pull_consumer.collect_entity(row)?;
         }
 
         // Run the pull expressions for the collected IDs.
@@ -364,6 +455,30 @@ impl Projector for CollTwoStagePullProjector {
         })
     }
 
+    fn project_page<'stmt, 's>(
+        &self,
+        schema: &Schema,
+        sqlite: &'s rusqlite::Connection,
+        rows: &mut Rows<'stmt>,
+        page_size: usize,
+    ) -> Result<Vec<Vec<Binding>>> {
+        let mut pull_consumer = PullConsumer::for_operation(schema, &self.pull)?;
+        let mut page = read_page(rows, page_size, |row| {
+            let entity = pull_consumer.collect_entity(row)?;
+            Ok(vec![Binding::Scalar(TypedValue::Ref(entity))])
+        })?;
+        if page.is_empty() {
+            return Ok(page);
+        }
+
+        // Unlike `project`, we always produce a row, even if nothing was pulled.
+        pull_consumer.pull(sqlite)?;
+        for bindings in page.iter_mut() {
+            pull_consumer.expand(bindings);
+        }
+        Ok(page)
+    }
+
     fn columns<'s>(&'s self) -> Box<dyn Iterator<Item = &Element> + 's> {
         self.spec.columns()
     }
@@ -8,7 +8,7 @@
 // CONDITIONS OF ANY KIND, either express or implied. See the License for the
 // specific language governing permissions and limitations under the License.
 
-use std::rc::Rc;
+use std::sync::Arc;
 
 use crate::{
     rusqlite, Binding, CombinedProjection, Element, FindSpec, ProjectedElements, QueryOutput,
@@ -17,20 +17,20 @@ use crate::{
 
 use query_projector_traits::errors::Result;
 
-use super::Projector;
+use super::{read_page, Projector};
 
 pub(crate) struct ScalarProjector {
-    spec: Rc<FindSpec>,
+    spec: Arc<FindSpec>,
     template: TypedIndex,
 }
 
 impl ScalarProjector {
-    fn with_template(spec: Rc<FindSpec>, template: TypedIndex) -> ScalarProjector {
+    fn with_template(spec: Arc<FindSpec>, template: TypedIndex) -> ScalarProjector {
         ScalarProjector { spec, template }
     }
 
     pub(crate) fn combine(
-        spec: Rc<FindSpec>,
+        spec: Arc<FindSpec>,
         mut elements: ProjectedElements,
     ) -> Result<CombinedProjection> {
         let template = elements
@@ -50,7 +50,7 @@ impl Projector for ScalarProjector {
         _sqlite: &'s rusqlite::Connection,
         mut rows: Rows<'stmt>,
     ) -> Result<QueryOutput> {
-        let results = if let Some(r) = rows.next().unwrap() {
+        let results = if let Some(r) = rows.next()? {
             let row = r;
             let binding = self.template.lookup(&row)?;
             QueryResults::Scalar(Some(binding))
@@ -63,6 +63,18 @@ impl Projector for ScalarProjector {
         })
     }
 
+    fn project_page<'stmt, 's>(
+        &self,
+        _schema: &Schema,
+        _sqlite: &'s rusqlite::Connection,
+        rows: &mut Rows<'stmt>,
+        page_size: usize,
+    ) -> Result<Vec<Vec<Binding>>> {
+        read_page(rows, page_size, |row| {
+            Ok(vec![self.template.lookup(row)?])
+        })
+    }
+
     fn columns<'s>(&'s self) -> Box<dyn Iterator<Item = &Element> + 's> {
         self.spec.columns()
     }
@@ -70,14 +82,14 @@ impl Projector for ScalarProjector {
 
 /// A tuple projector produces a single vector. It's the single-result version of rel.
 pub(crate) struct TupleProjector {
-    spec: Rc<FindSpec>,
+    spec: Arc<FindSpec>,
     len: usize,
     templates: Vec<TypedIndex>,
 }
 
 impl TupleProjector {
     fn with_templates(
-        spec: Rc<FindSpec>,
+        spec: Arc<FindSpec>,
         len: usize,
         templates: Vec<TypedIndex>,
     ) -> TupleProjector {
@@ -101,7 +113,7 @@ impl TupleProjector {
     }
 
     pub(crate) fn combine(
-        spec: Rc<FindSpec>,
+        spec: Arc<FindSpec>,
         column_count: usize,
         mut elements: ProjectedElements,
     ) -> Result<CombinedProjection> {
@@ -122,7 +134,7 @@ impl Projector for TupleProjector {
         _sqlite: &'s rusqlite::Connection,
         mut rows: Rows<'stmt>,
     ) -> Result<QueryOutput> {
-        let results = if let Some(r) = rows.next().unwrap() {
+        let results = if let Some(r) = rows.next()? {
             let row = r;
             let bindings = self.collect_bindings(row)?;
             QueryResults::Tuple(Some(bindings))
@@ -135,6 +147,16 @@ impl Projector for TupleProjector {
         })
     }
 
+    fn project_page<'stmt, 's>(
+        &self,
+        _schema: &Schema,
+        _sqlite: &'s rusqlite::Connection,
+        rows: &mut Rows<'stmt>,
+        page_size: usize,
+    ) -> Result<Vec<Vec<Binding>>> {
+        read_page(rows, page_size, |row| self.collect_bindings(row))
+    }
+
     fn columns<'s>(&'s self) -> Box<dyn Iterator<Item = &Element> + 's> {
         self.spec.columns()
     }
@@ -145,13 +167,13 @@ impl Projector for TupleProjector {
 /// Each column in each stride is the result of taking one or two columns from
 /// the `Row`: one for the value and optionally one for the type tag.
 pub(crate) struct RelProjector {
-    spec: Rc<FindSpec>,
+    spec: Arc<FindSpec>,
     len: usize,
     templates: Vec<TypedIndex>,
 }
 
 impl RelProjector {
-    fn with_templates(spec: Rc<FindSpec>, len: usize, templates: Vec<TypedIndex>) -> RelProjector {
+    fn with_templates(spec: Arc<FindSpec>, len: usize, templates: Vec<TypedIndex>) -> RelProjector {
         RelProjector {
             spec,
             len,
@@ -174,7 +196,7 @@ impl RelProjector {
     }
 
     pub(crate) fn combine(
-        spec: Rc<FindSpec>,
+        spec: Arc<FindSpec>,
         column_count: usize,
         mut elements: ProjectedElements,
     ) -> Result<CombinedProjection> {
@@ -207,7 +229,7 @@ impl Projector for RelProjector {
         let width = self.len;
         let mut values: Vec<_> = Vec::with_capacity(5 * width);
 
-        while let Some(r) = rows.next().unwrap() {
+        while let Some(r) = rows.next()? {
             let row = r;
             self.collect_bindings_into(row, &mut values)?;
         }
@@ -218,6 +240,20 @@ impl Projector for RelProjector {
         })
     }
 
+    fn project_page<'stmt, 's>(
+        &self,
+        _schema: &Schema,
+        _sqlite: &'s rusqlite::Connection,
+        rows: &mut Rows<'stmt>,
+        page_size: usize,
+    ) -> Result<Vec<Vec<Binding>>> {
+        read_page(rows, page_size, |row| {
+            let mut values = Vec::with_capacity(self.len);
+            self.collect_bindings_into(row, &mut values)?;
+            Ok(values)
+        })
+    }
+
     fn columns<'s>(&'s self) -> Box<dyn Iterator<Item = &Element> + 's> {
         self.spec.columns()
     }
@@ -226,17 +262,17 @@ impl Projector for RelProjector {
 /// A coll projector produces a vector of values.
 /// Each value is sourced from the same column.
 pub(crate) struct CollProjector {
-    spec: Rc<FindSpec>,
+    spec: Arc<FindSpec>,
     template: TypedIndex,
 }
 
 impl CollProjector {
-    fn with_template(spec: Rc<FindSpec>, template: TypedIndex) -> CollProjector {
+    fn with_template(spec: Arc<FindSpec>, template: TypedIndex) -> CollProjector {
         CollProjector { spec, template }
     }
 
     pub(crate) fn combine(
-        spec: Rc<FindSpec>,
+        spec: Arc<FindSpec>,
         mut elements: ProjectedElements,
     ) -> Result<CombinedProjection> {
         let template = elements
@@ -263,7 +299,7 @@ impl Projector for CollProjector {
         mut rows: Rows<'stmt>,
     ) -> Result<QueryOutput> {
         let mut out: Vec<_> = vec![];
-        while let Some(r) = rows.next().unwrap() {
+        while let Some(r) = rows.next()? {
             let row = r;
             let binding = self.template.lookup(&row)?;
             out.push(binding);
@@ -274,6 +310,18 @@ impl Projector for CollProjector {
         })
     }
 
+    fn project_page<'stmt, 's>(
+        &self,
+        _schema: &Schema,
+        _sqlite: &'s rusqlite::Connection,
+        rows: &mut Rows<'stmt>,
+        page_size: usize,
+    ) -> Result<Vec<Vec<Binding>>> {
+        read_page(rows, page_size, |row| {
+            Ok(vec![self.template.lookup(row)?])
+        })
+    }
+
     fn columns<'s>(&'s self) -> Box<dyn Iterator<Item = &Element> + 's> {
         self.spec.columns()
     }
@@ -12,7 +12,7 @@ use std::collections::{BTreeMap, BTreeSet};
 
 use core_traits::{Binding, Entid, StructuredMap, TypedValue};
 
-use mentat_core::{Schema, ValueRc};
+use mentat_core::{DatomsView, Schema, ValueRc};
 
 use edn::query::PullAttributeSpec;
 
@@ -23,7 +23,7 @@ use query_projector_traits::errors::Result;
 use super::{rusqlite, Index};
 
 #[derive(Clone, Debug)]
-pub(crate) struct PullOperation(pub(crate) Vec<PullAttributeSpec>);
+pub(crate) struct PullOperation(pub(crate) Vec<PullAttributeSpec>, pub(crate) DatomsView);
 
 #[derive(Clone, Copy, Debug)]
 pub(crate) struct PullIndices {
@@ -73,7 +73,7 @@ impl<'schema> PullConsumer<'schema> {
         schema: &'schema Schema,
         template: &PullTemplate,
     ) -> Result<PullConsumer<'schema>> {
-        let puller = Puller::prepare(schema, template.op.0.clone())?;
+        let puller = Puller::prepare(schema, template.op.0.clone())?.in_view(template.op.1);
         Ok(PullConsumer::for_puller(puller, schema, template.indices))
     }
 
@@ -81,7 +81,7 @@ impl<'schema> PullConsumer<'schema> {
         schema: &'schema Schema,
         operation: &PullOperation,
     ) -> Result<PullConsumer<'schema>> {
-        let puller = Puller::prepare(schema, operation.0.clone())?;
+        let puller = Puller::prepare(schema, operation.0.clone())?.in_view(operation.1);
         Ok(PullConsumer::for_puller(
             puller,
             schema,
@@ -89,10 +89,10 @@ impl<'schema> PullConsumer<'schema> {
         ))
     }
 
-    pub(crate) fn collect_entity<'a>(&mut self, row: &rusqlite::Row<'a>) -> Entid {
-        let entity = row.get(self.indices.sql_index).unwrap();
+    pub(crate) fn collect_entity<'a>(&mut self, row: &rusqlite::Row<'a>) -> Result<Entid> {
+        let entity = row.get(self.indices.sql_index)?;
         self.entities.insert(entity);
-        entity
+        Ok(entity)
     }
 
     pub(crate) fn pull(&mut self, sqlite: &rusqlite::Connection) -> Result<()> {
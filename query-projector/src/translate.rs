@@ -138,6 +138,10 @@ impl ToConstraint for ColumnConstraint {
                 Constraint::equal(left.to_column(), right.to_column())
             }
 
+            Equals(qa, QueryValue::Parameter(var)) => {
+                Constraint::equal(qa.to_column(), ColumnOrExpression::Parameter(var))
+            }
+
             Equals(qa, QueryValue::PrimitiveLong(value)) => {
                 let tag_column = qa
                     .for_associated_type_tag()
@@ -19,11 +19,11 @@ use std::collections::BTreeMap;
 
 use std::rc::Rc;
 
-use edn::query::{FindSpec, Keyword, Variable};
+use edn::query::{FindSpec, Keyword, SrcVar, Variable};
 
 use core_traits::{Attribute, Entid, TypedValue, ValueType};
 
-use mentat_core::Schema;
+use mentat_core::{DatomsView, Schema};
 
 use mentat_query_algebrizer::{
     algebrize, algebrize_with_inputs, parse_find_string, Known, QueryInputs,
@@ -270,10 +270,9 @@ fn test_bound_variable_limit_affects_types() {
     let select = query_to_select(&schema, algebrized).expect("query to translate");
     let SQLQuery { sql, args } = query_to_sql(select);
 
-    // TODO: this query isn't actually correct -- we don't yet algebrize for variables that are
-    // specified in `:in` but not provided at algebrizing time. But it shows what we care about
-    // at the moment: we don't project a type column, because we know it's a Long.
-    assert_eq!(sql, "SELECT DISTINCT `datoms00`.e AS `?x`, `datoms00`.v AS `?limit` FROM `datoms` AS `datoms00` LIMIT $ilimit");
+    // The unbound input is constrained by a bind parameter, and we don't project a type column,
+    // because we know it's a Long.
+    assert_eq!(sql, "SELECT DISTINCT `datoms00`.e AS `?x`, `datoms00`.v AS `?limit` FROM `datoms` AS `datoms00` WHERE (`datoms00`.value_type_tag = 5) AND `datoms00`.v = $ilimit LIMIT $ilimit");
     assert_eq!(args, vec![]);
 }
 
@@ -1186,7 +1185,7 @@ fn test_fulltext_inputs() {
     types.insert(Variable::from_valid_name("?entity"), ValueType::Ref);
     let inputs = QueryInputs::new(types, BTreeMap::default()).expect("valid inputs");
 
-    // Without binding the value. q_once will err if you try this!
+    // Without binding the value: the entity is left as a bind parameter.
     let SQLQuery { sql, args } = translate_with_inputs(&schema, query, inputs);
     assert_eq!(
         sql,
@@ -1196,7 +1195,8 @@ fn test_fulltext_inputs() {
          `datoms` AS `datoms01` \
          WHERE `datoms01`.a = 100 \
          AND `datoms01`.v = `fulltext_values00`.rowid \
-         AND `fulltext_values00`.text MATCH $v0"
+         AND `fulltext_values00`.text MATCH $v0 \
+         AND `datoms01`.e = $ientity"
     );
     assert_eq!(args, vec![make_arg("$v0", "hello"),]);
 
@@ -1581,3 +1581,72 @@ fn test_tx_data() {
     );
     assert_eq!(args, vec![]);
 }
+
+#[test]
+fn test_as_of_and_since_views() {
+    let schema = prepopulated_typed_schema(ValueType::Long);
+
+    let query = r#"[:find ?x :where [?x :foo/bar 5]]"#;
+    let inputs = QueryInputs::default().with_source(SrcVar::DefaultSrc, DatomsView::Since(1000));
+    let SQLQuery { sql, args } = translate_with_inputs(&schema, query, inputs);
+    assert_eq!(
+        sql,
+        "SELECT DISTINCT `datoms00`.e AS `?x` \
+         FROM (SELECT e, a, v, tx, value_type_tag FROM datoms WHERE tx > 1000) AS `datoms00` \
+         WHERE `datoms00`.a = 99 AND `datoms00`.v = 5"
+    );
+    assert_eq!(args, vec![]);
+
+    let inputs = QueryInputs::default().with_source(SrcVar::DefaultSrc, DatomsView::AsOf(1000));
+    let SQLQuery { sql, args } = translate_with_inputs(&schema, query, inputs);
+    assert_eq!(
+        sql,
+        "SELECT DISTINCT `datoms00`.e AS `?x` \
+         FROM (SELECT e, a, v, tx, value_type_tag FROM \
+         (SELECT e, a, v, value_type_tag, MAX(tx) AS tx, added FROM transactions \
+         WHERE tx <= 1000 GROUP BY e, a, v, value_type_tag) WHERE added = 1) AS `datoms00` \
+         WHERE `datoms00`.a = 99 AND `datoms00`.v = 5"
+    );
+    assert_eq!(args, vec![]);
+
+    // A named source sees its own view; the default source still sees the current store.
+    let query = r#"[:find ?x ?y :in $ $before :where [?x :foo/bar 5] [$before ?x :foo/fts ?y]]"#;
+    let inputs = QueryInputs::default().with_source(
+        SrcVar::NamedSrc("before".to_string()),
+        DatomsView::AsOf(1000),
+    );
+    let SQLQuery { sql, args } = translate_with_inputs(&schema, query, inputs);
+    assert!(sql.contains("FROM `datoms` AS `datoms00`, (SELECT h.e AS e"));
+    assert!(sql.contains("fulltext_values.text AS v"));
+    assert!(sql.contains("AS `fulltext_datoms01`"));
+    assert_eq!(args, vec![]);
+}
+
+#[test]
+fn test_history_view() {
+    let schema = prepopulated_typed_schema(ValueType::Long);
+
+    let query = r#"[:find ?x ?tx ?added :in $h :where [$h ?x :foo/bar 5 ?tx ?added]]"#;
+    let inputs =
+        QueryInputs::default().with_source(SrcVar::NamedSrc("h".to_string()), DatomsView::History);
+    let SQLQuery { sql, args } = translate_with_inputs(&schema, query, inputs);
+    assert_eq!(
+        sql,
+        "SELECT DISTINCT `datoms00`.e AS `?x`, `datoms00`.tx AS `?tx`, `datoms00`.added AS `?added` \
+         FROM (SELECT e, a, v, tx, value_type_tag, added FROM transactions) AS `datoms00` \
+         WHERE `datoms00`.a = 99 AND `datoms00`.v = 5"
+    );
+    assert_eq!(args, vec![]);
+
+    // Only retractions.
+    let query = r#"[:find ?x :where [?x :foo/bar 5 _ false]]"#;
+    let inputs = QueryInputs::default().with_source(SrcVar::DefaultSrc, DatomsView::History);
+    let SQLQuery { sql, args } = translate_with_inputs(&schema, query, inputs);
+    assert_eq!(
+        sql,
+        "SELECT DISTINCT `datoms00`.e AS `?x` \
+         FROM (SELECT e, a, v, tx, value_type_tag, added FROM transactions) AS `datoms00` \
+         WHERE `datoms00`.a = 99 AND `datoms00`.v = 5 AND `datoms00`.added = 0"
+    );
+    assert_eq!(args, vec![]);
+}
@@ -71,7 +71,7 @@ use std::iter::once;
 
 use core_traits::{Binding, Entid, StructuredMap, TypedValue};
 
-use mentat_core::{Cloned, HasSchema, Keyword, Schema, ValueRc};
+use mentat_core::{Cloned, DatomsView, HasSchema, Keyword, Schema, ValueRc};
 
 use mentat_db::cache;
 
@@ -90,11 +90,7 @@ pub fn pull_attributes_for_entity<A>(
 where
     A: IntoIterator<Item = Entid>,
 {
-    let attrs = attributes
-        .into_iter()
-        .map(|e| PullAttributeSpec::Attribute(PullConcreteAttribute::Entid(e).into()))
-        .collect();
-    Puller::prepare(schema, attrs)?
+    Puller::for_attributes(schema, attributes)?
         .pull(schema, db, once(entity))
         .map(|m| {
             m.into_iter()
@@ -117,11 +113,7 @@ where
     E: IntoIterator<Item = Entid>,
     A: IntoIterator<Item = Entid>,
 {
-    let attrs = attributes
-        .into_iter()
-        .map(|e| PullAttributeSpec::Attribute(PullConcreteAttribute::Entid(e).into()))
-        .collect();
-    Puller::prepare(schema, attrs)?.pull(schema, db, entities)
+    Puller::for_attributes(schema, attributes)?.pull(schema, db, entities)
 }
 
 /// A `Puller` constructs on demand a map from a provided set of entity IDs to a set of structured maps.
@@ -137,9 +129,24 @@ pub struct Puller {
     //  Mentat can use `TypedValue::Ref(1234)`, but it's sometimes convenient to fetch the entity ID
     // itself as part of a pull expression: `{:person 1234, :person/name "Peter"}`.
     db_id_alias: Option<ValueRc<Keyword>>,
+
+    // The view of the store to pull from. Usually the current store.
+    view: DatomsView,
 }
 
 impl Puller {
+    /// Prepare to pull exactly the given attributes.
+    pub fn for_attributes<A>(schema: &Schema, attributes: A) -> Result<Puller>
+    where
+        A: IntoIterator<Item = Entid>,
+    {
+        let attrs = attributes
+            .into_iter()
+            .map(|e| PullAttributeSpec::Attribute(PullConcreteAttribute::Entid(e).into()))
+            .collect();
+        Puller::prepare(schema, attrs)
+    }
+
     pub fn prepare(schema: &Schema, attributes: Vec<PullAttributeSpec>) -> Result<Puller> {
         // TODO: eventually this entry point will handle aliasing and that kind of
         // thing. For now it's just a convenience.
@@ -202,9 +209,22 @@ impl Puller {
             attributes: names,
             attribute_spec: cache::AttributeSpec::specified(&attrs, schema),
             db_id_alias,
+            view: DatomsView::Current,
         })
     }
 
+    /// Pull from the store as seen through `view` rather than from the current store.
+    /// The history view has no single value for an attribute, so pulls through it see the
+    /// current store.
+    pub fn in_view(mut self, view: DatomsView) -> Puller {
+        self.view = if view.has_added() {
+            DatomsView::Current
+        } else {
+            view
+        };
+        self
+    }
+
     pub fn pull<E>(
         &self,
         schema: &Schema,
@@ -230,6 +250,7 @@ impl Puller {
             db,
             self.attribute_spec.clone(),
             &entities,
+            self.view,
         )?;
 
         // Now construct the appropriate result format.
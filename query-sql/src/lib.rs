@@ -25,7 +25,8 @@ use mentat_core::SQLTypeAffinity;
 use edn::query::{Direction, Limit, Variable};
 
 use mentat_query_algebrizer::{
-    Column, OrderBy, QualifiedAlias, QueryValue, SourceAlias, TableAlias, VariableColumn,
+    Column, DatomsTable, OrderBy, QualifiedAlias, QueryValue, SourceAlias, TableAlias,
+    VariableColumn,
 };
 
 use sql_traits::errors::{BuildQueryResult, SQLError};
@@ -48,6 +49,7 @@ pub enum ColumnOrExpression {
     Integer(i32), // We use these for type codes etc.
     Long(i64),
     Value(TypedValue),
+    Parameter(Variable), // An input variable, bound when the query is run.
     // Some aggregates (`min`, `max`, `avg`) can be over 0 rows, and therefore can be `NULL`; that
     // needs special treatment.
     NullableAggregate(Box<Expression>, ValueType), // Track the return type.
@@ -69,6 +71,7 @@ impl From<QueryValue> for ColumnOrExpression {
             QueryValue::Entid(e) => ColumnOrExpression::Entid(e),
             QueryValue::PrimitiveLong(v) => ColumnOrExpression::Long(v),
             QueryValue::TypedValue(v) => ColumnOrExpression::Value(v),
+            QueryValue::Parameter(var) => ColumnOrExpression::Parameter(var),
         }
     }
 }
@@ -264,6 +267,7 @@ impl QueryFragment for ColumnOrExpression {
                 Ok(())
             }
             Value(ref v) => out.push_typed_value(v),
+            Parameter(ref var) => out.push_bind_param(parameter_name(var).as_str()),
             NullableAggregate(ref e, _) | &Expression(ref e, _) => e.push_sql(out),
         }
     }
@@ -422,7 +426,10 @@ fn qualified_alias_push_sql(out: &mut dyn QueryBuilder, qa: &QualifiedAlias) ->
 // We don't own SourceAlias or QueryFragment, so we can't implement the trait.
 fn source_alias_push_sql(out: &mut dyn QueryBuilder, sa: &SourceAlias) -> BuildQueryResult {
     let &SourceAlias(ref table, ref alias) = sa;
-    out.push_identifier(table.name())?;
+    match *table {
+        DatomsTable::Viewed(view_table, view) => out.push_sql(&view.sql(view_table)),
+        _ => out.push_identifier(table.name())?,
+    }
     out.push_sql(" AS ");
     out.push_identifier(alias.as_str())
 }
@@ -561,10 +568,15 @@ fn format_select_var(var: &str) -> String {
     once('i').chain(replaced_iter).collect()
 }
 
+/// The name, without its leading `$`, of the bind parameter that supplies the value of the input
+/// variable `var`.
+pub fn parameter_name(var: &Variable) -> String {
+    format_select_var(var.as_str())
+}
+
 impl SelectQuery {
     fn push_variable_param(&self, var: &Variable, out: &mut dyn QueryBuilder) -> BuildQueryResult {
-        let bind_param = format_select_var(var.as_str());
-        out.push_bind_param(bind_param.as_str())
+        out.push_bind_param(parameter_name(var).as_str())
     }
 }
 
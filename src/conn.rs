@@ -14,32 +14,84 @@ use std::borrow::Borrow;
 
 use std::collections::BTreeMap;
 
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 
 use rusqlite::TransactionBehavior;
 
-pub use core_traits::{Attribute, Entid, KnownEntid, StructuredMap, TypedValue, ValueType};
+pub use core_traits::{
+    Attribute, Binding, Entid, KnownEntid, StructuredMap, TypedValue, ValueType,
+};
 
 use mentat_core::{HasSchema, Keyword, Schema, TxReport, ValueRc};
 
-use mentat_db::cache::{InProgressSQLiteAttributeCache, SQLiteAttributeCache};
+use mentat_db::cache::{AttributeCacheStats, InProgressSQLiteAttributeCache, SQLiteAttributeCache};
 
 use mentat_db::db;
+use mentat_db::history;
 use mentat_db::{
-    InProgressObserverTransactWatcher, PartitionMap, TxObservationService, TxObserver,
+    AttributeSet, InProgressObserverTransactWatcher, PartitionMap, TxObservationService,
+    TxObserver, DB,
 };
 
 use mentat_query_pull::{pull_attributes_for_entities, pull_attributes_for_entity};
 
-use mentat_transaction::{CacheAction, CacheDirection, InProgress, InProgressRead, Metadata};
+use mentat_transaction::cache_registrations::{
+    cache_registrations, register_caches, CacheWarmer, CacheWarmerState,
+};
+
+use mentat_transaction::{
+    interruptible, CacheAction, CacheDirection, InProgress, InProgressRead, Interrupt,
+    LiveQueryDiff, LiveQueryService, Metadata, QueryPlanCache, QueryPlanCacheStats, Queryable,
+    TxReportQueues, TxReportReceiver,
+};
 
 use public_traits::errors::{MentatError, Result};
 
 use mentat_transaction::query::{
-    lookup_value_for_attribute, lookup_values_for_attribute, q_explain, q_once, q_prepare,
-    q_uncached, Known, PreparedResult, QueryExplanation, QueryInputs, QueryOutput,
+    lookup_value_for_attribute, lookup_values_for_attribute, q_dependencies, q_explain,
+    q_once_with_plan_cache, q_prepare, q_uncached, Known, PreparedResult, QueryExplanation,
+    QueryInputs, QueryOutput,
 };
 
+/// The next identity to give a SQLite connection we haven't seen before; see `DataVersion`.
+static NEXT_CONNECTION_ID: AtomicUsize = AtomicUsize::new(1);
+
+/// Where a SQLite connection's view of the database was when we last loaded metadata from it.
+/// SQLite's `PRAGMA data_version` changes whenever another connection commits, but not when this
+/// one does; versions from different connections aren't comparable, so we note which it was.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct DataVersion {
+    connection: i64,
+    version: i64,
+}
+
+impl DataVersion {
+    fn of(sqlite: &rusqlite::Connection) -> Result<DataVersion> {
+        Ok(DataVersion {
+            connection: DataVersion::connection_id(sqlite)?,
+            version: sqlite
+                .query_row("PRAGMA data_version", rusqlite::params![], |row| row.get(0))?,
+        })
+    }
+
+    /// Each connection has its own `temp` schema, so we stamp an id into its `user_version` the
+    /// first time we see the connection and read it back thereafter.
+    fn connection_id(sqlite: &rusqlite::Connection) -> Result<i64> {
+        let id: i64 = sqlite.query_row("PRAGMA temp.user_version", rusqlite::params![], |row| {
+            row.get(0)
+        })?;
+        if id != 0 {
+            return Ok(id);
+        }
+        // `user_version` is a 32-bit integer, so keep ids in range (and never zero).
+        let id =
+            (NEXT_CONNECTION_ID.fetch_add(1, Ordering::SeqCst) % (i32::MAX as usize)).max(1) as i64;
+        sqlite.execute_batch(&format!("PRAGMA temp.user_version = {}", id))?;
+        Ok(id)
+    }
+}
+
 /// A mutable, safe reference to the current Mentat store.
 pub struct Conn {
     /// `Mutex` since all reads and writes need to be exclusive.  Internally, owned data for the
@@ -59,11 +111,22 @@ pub struct Conn {
     /// replaced on commit.
     metadata: Mutex<Metadata>,
 
-    // TODO: maintain set of change listeners or handles to transaction report queues. #298.
+    /// Translated queries, shared by every reader and invalidated when the schema changes.
+    query_plan_cache: QueryPlanCache,
 
-    // TODO: maintain cache of query plans that could be shared across threads and invalidated when
-    // the schema changes. #315.
     pub(crate) tx_observer_service: Mutex<TxObservationService>,
+
+    /// Queries re-run whenever a commit touches the attributes they depend on.
+    pub(crate) live_queries: Mutex<LiveQueryService>,
+
+    /// Bounded queues of committed transactions, each owned by its consumer.
+    tx_report_queues: Mutex<TxReportQueues>,
+
+    /// Recorded cache registrations being rebuilt in the background, if any.
+    cache_warmer: Mutex<Option<CacheWarmer>>,
+
+    /// Used to notice writes made by other connections, which our metadata doesn't reflect.
+    data_version: Mutex<Option<DataVersion>>,
 }
 
 impl Conn {
@@ -76,13 +139,21 @@ impl Conn {
                 Arc::new(schema),
                 Default::default(),
             )),
+            query_plan_cache: QueryPlanCache::default(),
             tx_observer_service: Mutex::new(TxObservationService::new()),
+            live_queries: Mutex::new(LiveQueryService::new()),
+            tx_report_queues: Mutex::new(TxReportQueues::new()),
+            cache_warmer: Mutex::new(None),
+            data_version: Mutex::new(None),
         }
     }
 
     pub fn connect(sqlite: &mut rusqlite::Connection) -> Result<Conn> {
         let db = db::ensure_current_version(sqlite)?;
-        Ok(Conn::new(db.partition_map, db.schema))
+        let conn = Conn::new(db.partition_map, db.schema);
+        conn.metadata.lock().unwrap().history_horizon = history::history_horizon(sqlite)?;
+        *conn.data_version.lock().unwrap() = Some(DataVersion::of(sqlite)?);
+        Ok(conn)
     }
 
     /// Yield a clone of the current `Schema` instance.
@@ -106,6 +177,44 @@ impl Conn {
         self.metadata.lock().unwrap().attribute_cache.clone()
     }
 
+    /// Return the size and hit rate of the cache of each cached attribute.
+    pub fn attribute_cache_stats(&self) -> BTreeMap<Entid, AttributeCacheStats> {
+        self.metadata.lock().unwrap().attribute_cache.stats()
+    }
+
+    /// Hold the attribute caches to roughly `budget` bytes, or lift the limit with `None`. Over
+    /// budget, the least recently used entities are evicted from forward caches; looking them up
+    /// again reads from the store and loads them back in.
+    pub fn set_attribute_cache_budget(&self, budget: Option<usize>) {
+        self.metadata
+            .lock()
+            .unwrap()
+            .attribute_cache
+            .set_memory_budget(budget);
+    }
+
+    /// Load the partially cached entities that recent lookups missed. This is only an
+    /// optimization -- those lookups were answered from the store -- so failures are logged
+    /// rather than returned.
+    fn fault_in_missed(metadata: &mut Metadata, sqlite: &rusqlite::Connection) {
+        if metadata.attribute_cache.memory_budget().is_some() {
+            let schema = metadata.schema.clone();
+            if let Err(e) = metadata.attribute_cache.fault_in_missed(&schema, sqlite) {
+                log::warn!("Failed to load missed attribute cache entries: {}", e);
+            }
+        }
+    }
+
+    /// Return hit, miss, and occupancy counts for the shared query plan cache.
+    pub fn query_plan_cache_stats(&self) -> QueryPlanCacheStats {
+        self.query_plan_cache.stats()
+    }
+
+    /// Bound the number of query plans retained. Zero disables plan caching.
+    pub fn set_query_plan_cache_capacity(&self, capacity: usize) {
+        self.query_plan_cache.set_capacity(capacity);
+    }
+
     pub fn last_tx_id(&self) -> Entid {
         // The mutex is taken during this entire method.
         let metadata = self.metadata.lock().unwrap();
@@ -124,9 +233,35 @@ impl Conn {
         T: Into<Option<QueryInputs>>,
     {
         // Doesn't clone, unlike `current_schema`.
-        let metadata = self.metadata.lock().unwrap();
-        let known = Known::new(&*metadata.schema, Some(&metadata.attribute_cache));
-        q_once(sqlite, known, query, inputs)
+        let mut metadata = self.metadata.lock().unwrap();
+        let output = {
+            let known = metadata.known();
+            q_once_with_plan_cache(
+                sqlite,
+                known,
+                &self.query_plan_cache,
+                metadata.generation,
+                query,
+                inputs,
+            )
+        };
+        Conn::fault_in_missed(&mut metadata, sqlite);
+        output
+    }
+
+    /// Like `q_once`, but give up with `MentatError::Interrupted` if `interrupt` fires before the
+    /// query completes.
+    pub fn q_once_with_interrupt<T>(
+        &self,
+        sqlite: &rusqlite::Connection,
+        query: &str,
+        inputs: T,
+        interrupt: &Interrupt,
+    ) -> Result<QueryOutput>
+    where
+        T: Into<Option<QueryInputs>>,
+    {
+        interruptible(sqlite, interrupt, || self.q_once(sqlite, query, inputs))
     }
 
     /// Query the Mentat store, using the given connection and the current metadata,
@@ -159,7 +294,7 @@ impl Conn {
         T: Into<Option<QueryInputs>>,
     {
         let metadata = self.metadata.lock().unwrap();
-        let known = Known::new(&*metadata.schema, Some(&metadata.attribute_cache));
+        let known = metadata.known();
         q_prepare(sqlite, known, query, inputs)
     }
 
@@ -173,7 +308,7 @@ impl Conn {
         T: Into<Option<QueryInputs>>,
     {
         let metadata = self.metadata.lock().unwrap();
-        let known = Known::new(&*metadata.schema, Some(&metadata.attribute_cache));
+        let known = metadata.known();
         q_explain(sqlite, known, query, inputs)
     }
 
@@ -212,9 +347,13 @@ impl Conn {
         entity: Entid,
         attribute: &edn::Keyword,
     ) -> Result<Vec<TypedValue>> {
-        let metadata = self.metadata.lock().unwrap();
-        let known = Known::new(&*metadata.schema, Some(&metadata.attribute_cache));
-        lookup_values_for_attribute(sqlite, known, entity, attribute)
+        let mut metadata = self.metadata.lock().unwrap();
+        let values = {
+            let known = metadata.known();
+            lookup_values_for_attribute(sqlite, known, entity, attribute)
+        };
+        Conn::fault_in_missed(&mut metadata, sqlite);
+        values
     }
 
     pub fn lookup_value_for_attribute(
@@ -223,9 +362,13 @@ impl Conn {
         entity: Entid,
         attribute: &edn::Keyword,
     ) -> Result<Option<TypedValue>> {
-        let metadata = self.metadata.lock().unwrap();
-        let known = Known::new(&*metadata.schema, Some(&metadata.attribute_cache));
-        lookup_value_for_attribute(sqlite, known, entity, attribute)
+        let mut metadata = self.metadata.lock().unwrap();
+        let value = {
+            let known = metadata.known();
+            lookup_value_for_attribute(sqlite, known, entity, attribute)
+        };
+        Conn::fault_in_missed(&mut metadata, sqlite);
+        value
     }
 
     /// Take a SQLite transaction.
@@ -234,10 +377,39 @@ impl Conn {
         sqlite: &'conn mut rusqlite::Connection,
         behavior: TransactionBehavior,
     ) -> Result<InProgress<'m, 'conn>> {
+        self.begin_shared(sqlite, behavior, true)
+    }
+
+    /// Take a SQLite transaction without exclusive access to this `Conn`, for use when several
+    /// connections share it. Each of them must only be written to through this `Conn`; if
+    /// `notice_external_writes` is false, the store mustn't have been written to at all since
+    /// this `Conn` last noticed.
+    pub(crate) fn begin_shared<'m, 'conn>(
+        &'m self,
+        sqlite: &'conn mut rusqlite::Connection,
+        behavior: TransactionBehavior,
+        notice_external_writes: bool,
+    ) -> Result<InProgress<'m, 'conn>> {
+        self.finish_cache_warming(sqlite, false)?;
+
         let tx = sqlite.transaction_with_behavior(behavior)?;
-        let (current_generation, current_partition_map, current_schema, cache_cow) = {
-            // The mutex is taken during this block.
-            let current: &Metadata = &(*self.metadata.lock().unwrap());
+
+        // Reading the data version starts the SQLite transaction. Commits hold the metadata mutex
+        // while they write, so if nobody else has written, the metadata we take below matches what
+        // this transaction sees. Otherwise we can't trust our schema or partition map --
+        // transacting would allocate entids that are already taken -- so reload them first.
+        let mut metadata = self.metadata.lock().unwrap();
+        let data_version = DataVersion::of(&tx)?;
+        if notice_external_writes && *self.data_version.lock().unwrap() != Some(data_version) {
+            drop(metadata);
+            let db = db::read_db(&tx)?;
+            self.replace_metadata(&tx, db)?;
+            *self.data_version.lock().unwrap() = Some(data_version);
+            metadata = self.metadata.lock().unwrap();
+        }
+
+        let (current_generation, current_partition_map, current_schema, cache_cow, history_horizon) = {
+            let current: &Metadata = &metadata;
             (
                 current.generation,
                 // Expensive, but the partition map is updated after every committed transaction.
@@ -245,8 +417,10 @@ impl Conn {
                 // Cheap.
                 current.schema.clone(),
                 current.attribute_cache.clone(),
+                current.history_horizon,
             )
         };
+        drop(metadata);
 
         Ok(InProgress {
             mutex: &self.metadata,
@@ -256,8 +430,14 @@ impl Conn {
             schema: (*current_schema).clone(),
             cache: InProgressSQLiteAttributeCache::from_cache(cache_cow),
             use_caching: true,
+            history_horizon,
             tx_observer: &self.tx_observer_service,
             tx_observer_watcher: InProgressObserverTransactWatcher::new(),
+            query_plan_cache: &self.query_plan_cache,
+            live_queries: &self.live_queries,
+            tx_report_queues: &self.tx_report_queues,
+            tx_reports: vec![],
+            metadata_replaced: false,
         })
     }
 
@@ -321,40 +501,170 @@ impl Conn {
     /// `cache_action` determines if the attribute should be added or removed from the cache.
     /// CacheAction::Add is idempotent - each attribute is only added once.
     /// CacheAction::Remove throws an error if the attribute does not currently exist in the cache.
+    /// `schema` is no longer consulted: the attribute is looked up in, and cached against, the
+    /// schema of the transaction that registers it.
     pub fn cache(
         &mut self,
         sqlite: &mut rusqlite::Connection,
-        schema: &Schema,
+        _schema: &Schema,
         attribute: &Keyword,
         cache_direction: CacheDirection,
         cache_action: CacheAction,
     ) -> Result<()> {
+        // Change the cache and persist the registration in one transaction: neither happens
+        // unless both do.
+        let mut in_progress = self.begin_transaction(sqlite)?;
+        let attribute_entid = in_progress
+            .schema
+            .get_entid(attribute)
+            .ok_or_else(|| MentatError::UnknownAttribute(attribute.to_string()))?
+            .0;
+        in_progress.cache(attribute, cache_direction, cache_action)?;
+        in_progress.commit()?;
+
+        // Anything a background rebuild produces for this attribute is now out of date.
+        if let Some(warmer) = self.cache_warmer.lock().unwrap().as_mut() {
+            warmer.forget(attribute_entid);
+        }
+        Ok(())
+    }
+
+    /// Rebuild the attribute caches registered the last time this store was used.
+    pub fn restore_caches(&self, sqlite: &rusqlite::Connection) -> Result<()> {
+        let registrations = cache_registrations(sqlite)?;
         let mut metadata = self.metadata.lock().unwrap();
-        let attribute_entid: Entid;
+        let schema = metadata.schema.clone();
+        register_caches(
+            &mut metadata.attribute_cache,
+            &schema,
+            sqlite,
+            &registrations,
+        )
+    }
 
-        // Immutable borrow of metadata.
+    /// Replace the schema, partition map, and attribute caches with what's stored in `sqlite`,
+    /// whose contents have been replaced wholesale -- by restoring a backup, for example. Any
+    /// caches being rebuilt in the background are abandoned, and prepared queries are invalidated.
+    pub fn reload_metadata(&mut self, sqlite: &mut rusqlite::Connection) -> Result<()> {
+        let db = db::ensure_current_version(sqlite)?;
+        self.replace_metadata(sqlite, db)?;
+        *self.data_version.lock().unwrap() = Some(DataVersion::of(sqlite)?);
+        Ok(())
+    }
+
+    fn replace_metadata(&self, sqlite: &rusqlite::Connection, db: DB) -> Result<()> {
+        *self.cache_warmer.lock().unwrap() = None;
         {
-            attribute_entid = metadata
-                .schema
-                .attribute_for_ident(&attribute)
-                .ok_or_else(|| MentatError::UnknownAttribute(attribute.to_string()))?
-                .1
-                .into();
+            let mut metadata = self.metadata.lock().unwrap();
+            let generation = metadata.generation + 1;
+            let mut attribute_cache = SQLiteAttributeCache::default();
+            attribute_cache.set_memory_budget(metadata.attribute_cache.memory_budget());
+            *metadata = Metadata::new(
+                generation,
+                db.partition_map,
+                Arc::new(db.schema),
+                attribute_cache,
+            );
+            metadata.history_horizon = history::history_horizon(sqlite)?;
+            self.query_plan_cache.invalidate(generation);
         }
+        self.restore_caches(sqlite)?;
+        self.metadata_replaced(sqlite);
+        Ok(())
+    }
 
-        let cache = &mut metadata.attribute_cache;
-        match cache_action {
-            CacheAction::Register => match cache_direction {
-                CacheDirection::Both => cache.register(schema, sqlite, attribute_entid),
-                CacheDirection::Forward => cache.register_forward(schema, sqlite, attribute_entid),
-                CacheDirection::Reverse => cache.register_reverse(schema, sqlite, attribute_entid),
-            }
-            .map_err(|e| e.into()),
-            CacheAction::Deregister => {
-                cache.unregister(attribute_entid);
-                Ok(())
+    /// The store's metadata was replaced wholesale, by restoring a backup or noticing another
+    /// connection's writes: bring everything watching the store up to date with `sqlite`.
+    /// `InProgress` does the same on commit after undo, redo, or switching branches.
+    fn metadata_replaced(&self, sqlite: &rusqlite::Connection) {
+        self.tx_report_queues.lock().unwrap().replaced();
+        let last_tx_id = self.last_tx_id();
+        self.tx_observer_service
+            .lock()
+            .unwrap()
+            .store_replaced(last_tx_id);
+
+        // Don't hold either mutex while live queries run.
+        let stale = self
+            .live_queries
+            .lock()
+            .unwrap()
+            .stale(&AttributeSet::new(), true);
+        if stale.is_empty() {
+            return;
+        }
+        let (schema, cache, history_horizon) = {
+            let metadata = self.metadata.lock().unwrap();
+            (
+                metadata.schema.clone(),
+                metadata.attribute_cache.clone(),
+                metadata.history_horizon,
+            )
+        };
+        let known = Known {
+            schema: &schema,
+            cache: Some(&cache),
+            history_horizon,
+        };
+        let refreshed = stale
+            .into_iter()
+            .map(|live| live.refresh(sqlite, known))
+            .collect();
+        self.live_queries.lock().unwrap().did_commit(refreshed);
+    }
+
+    /// Like `restore_caches`, but rebuild on a background thread, using a second connection to
+    /// the same store made by `open`. Until the caches are installed -- at the start of the first
+    /// read or write after they're ready -- queries don't use them.
+    pub fn restore_caches_in_background<F>(
+        &mut self,
+        sqlite: &rusqlite::Connection,
+        open: F,
+    ) -> Result<()>
+    where
+        F: Fn() -> Result<rusqlite::Connection> + Send + Sync + 'static,
+    {
+        let registrations = cache_registrations(sqlite)?;
+        if registrations.is_empty() {
+            return Ok(());
+        }
+        let (schema, generation) = {
+            let metadata = self.metadata.lock().unwrap();
+            (metadata.schema.clone(), metadata.generation)
+        };
+        *self.cache_warmer.lock().unwrap() =
+            Some(CacheWarmer::spawn(open, schema, generation, registrations));
+        Ok(())
+    }
+
+    /// Install the caches being rebuilt in the background if they're ready, or, if `wait` is
+    /// true, once they are. Returns true if no rebuild remains in progress.
+    pub fn finish_cache_warming(&self, sqlite: &rusqlite::Connection, wait: bool) -> Result<bool> {
+        let mut cache_warmer = self.cache_warmer.lock().unwrap();
+        while let Some(warmer) = cache_warmer.take() {
+            let mut metadata = self.metadata.lock().unwrap();
+            let schema = metadata.schema.clone();
+            match warmer.finish(wait, &schema, metadata.generation) {
+                CacheWarmerState::Warming(warmer) => {
+                    *cache_warmer = Some(warmer);
+                    if !wait {
+                        return Ok(false);
+                    }
+                }
+                CacheWarmerState::Warmed(cache) => {
+                    metadata.attribute_cache.absorb_from(cache);
+                }
+                CacheWarmerState::Abandoned(registrations) => {
+                    register_caches(
+                        &mut metadata.attribute_cache,
+                        &schema,
+                        sqlite,
+                        &registrations,
+                    )?;
+                }
             }
         }
+        Ok(true)
     }
 
     pub fn register_observer(&mut self, key: String, observer: Arc<TxObserver>) {
@@ -367,6 +677,64 @@ impl Conn {
     pub fn unregister_observer(&mut self, key: &str) {
         self.tx_observer_service.lock().unwrap().deregister(key);
     }
+
+    /// Run `query` and return its results, then keep running it: whenever a commit touches an
+    /// attribute the query depends on, `callback` is given the rows that were added and removed.
+    /// An error re-running the query is passed to `callback`, and the query stays registered.
+    /// Registering a live query with an existing key replaces it.
+    pub fn register_live_query<T, F>(
+        &mut self,
+        sqlite: &mut rusqlite::Connection,
+        key: String,
+        query: &str,
+        inputs: T,
+        callback: F,
+    ) -> Result<Vec<Vec<Binding>>>
+    where
+        T: Into<Option<QueryInputs>>,
+        F: Fn(&str, Result<LiveQueryDiff>) + Send + Sync + 'static,
+    {
+        let inputs = inputs.into();
+        let (dependencies, rows) = {
+            let read = self.begin_read(sqlite)?;
+            let ip = &read.in_progress;
+            let known = ip.known();
+            let dependencies = q_dependencies(known, query, inputs.clone())?;
+            let rows = read.q_once(query, inputs.clone())?.results.into_rows();
+            (dependencies, rows)
+        };
+
+        self.live_queries.lock().unwrap().register(
+            key,
+            query.to_string(),
+            inputs,
+            dependencies,
+            rows.clone(),
+            callback,
+        );
+        Ok(rows)
+    }
+
+    pub fn unregister_live_query(&mut self, key: &str) {
+        self.live_queries.lock().unwrap().deregister(key);
+    }
+
+    /// Open a queue that receives every transaction committed from now on, with its datoms, in
+    /// commit order. If `attributes` is given, only transactions that touch one of them are
+    /// queued, and only their datoms are included.
+    ///
+    /// At most `capacity`, which must be non-zero, transactions are held. Committing never waits
+    /// for the consumer: if the queue is full, the oldest transaction is dropped and the receiver
+    /// reports the lag.
+    pub fn tx_report_queue<T>(&self, capacity: usize, attributes: T) -> Result<TxReportReceiver>
+    where
+        T: Into<Option<AttributeSet>>,
+    {
+        self.tx_report_queues
+            .lock()
+            .unwrap()
+            .subscribe(capacity, attributes.into())
+    }
 }
 
 #[cfg(test)]
@@ -902,4 +1270,193 @@ mod tests {
         assert!(conn.current_cache().is_attribute_cached_forward(db_ident));
         assert!(conn.current_cache().is_attribute_cached_forward(db_type));
     }
+
+    #[test]
+    fn test_query_plan_cache() {
+        let mut sqlite = db::new_connection("").unwrap();
+        let mut conn = Conn::connect(&mut sqlite).unwrap();
+
+        let query = "[:find ?ident . :in ?e :where [?e :db/ident ?ident]]";
+        let run = |conn: &Conn, sqlite: &rusqlite::Connection, e| {
+            let inputs =
+                QueryInputs::with_value_sequence(vec![(Variable::from_valid_name("?e"), e)]);
+            conn.q_once(sqlite, query, inputs)
+                .into_scalar_result()
+                .expect("query")
+        };
+
+        let ident = run(&conn, &sqlite, TypedValue::Ref(1));
+        assert_eq!(
+            ident,
+            Some(Binding::Scalar(TypedValue::typed_ns_keyword("db", "ident")))
+        );
+        assert_eq!(run(&conn, &sqlite, TypedValue::Ref(1)), ident);
+
+        // Input values are bound when the plan runs, so every value shares a plan.
+        let other = run(&conn, &sqlite, TypedValue::Ref(2));
+        assert!(other.is_some());
+        assert_ne!(other, ident);
+
+        let stats = conn.query_plan_cache_stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (2, 1, 1));
+
+        // Input types are part of the key.
+        assert_eq!(run(&conn, &sqlite, TypedValue::Long(1)), None);
+        let stats = conn.query_plan_cache_stats();
+        assert_eq!((stats.misses, stats.entries), (2, 2));
+
+        // Reads share plans with the `Conn`.
+        {
+            let read = conn.begin_read(&mut sqlite).expect("read");
+            let inputs = QueryInputs::with_value_sequence(vec![(
+                Variable::from_valid_name("?e"),
+                TypedValue::Ref(1),
+            )]);
+            assert_eq!(
+                read.q_once(query, inputs)
+                    .into_scalar_result()
+                    .expect("query"),
+                ident
+            );
+        }
+        assert_eq!(conn.query_plan_cache_stats().hits, 3);
+
+        // Data changes don't invalidate plans…
+        conn.transact(&mut sqlite, "[[:db/add \"a\" :db.schema/attribute \"b\"]]")
+            .expect("transacted");
+        assert_eq!(conn.query_plan_cache_stats().invalidations, 0);
+        assert!(run(&conn, &sqlite, TypedValue::Ref(1)).is_some());
+        assert_eq!(conn.query_plan_cache_stats().hits, 4);
+
+        // … but schema changes do.
+        conn.transact(
+            &mut sqlite,
+            r#"[{:db/ident :foo/baz
+                 :db/valueType :db.type/long
+                 :db/cardinality :db.cardinality/one}]"#,
+        )
+        .expect("transacted");
+        let stats = conn.query_plan_cache_stats();
+        assert_eq!((stats.invalidations, stats.entries), (1, 0));
+        assert_eq!(run(&conn, &sqlite, TypedValue::Ref(1)), ident);
+        assert_eq!(conn.query_plan_cache_stats().misses, 3);
+
+        // A keyword input might be an ident, so a query taking one is planned for each value,
+        // taking up a single entry.
+        let by_ident = |conn: &Conn, sqlite: &rusqlite::Connection, name| {
+            let inputs = QueryInputs::with_value_sequence(vec![(
+                Variable::from_valid_name("?ident"),
+                TypedValue::typed_ns_keyword("db", name),
+            )]);
+            conn.q_once(
+                sqlite,
+                "[:find ?e . :in ?ident :where [?e :db/ident ?ident]]",
+                inputs,
+            )
+            .into_scalar_result()
+            .expect("query")
+        };
+        assert_eq!(
+            by_ident(&conn, &sqlite, "ident"),
+            Some(Binding::Scalar(TypedValue::Ref(1)))
+        );
+        assert_ne!(
+            by_ident(&conn, &sqlite, "txInstant"),
+            Some(Binding::Scalar(TypedValue::Ref(1)))
+        );
+        assert_eq!(conn.query_plan_cache_stats().entries, 2);
+
+        // A capacity of zero disables plan caching altogether.
+        conn.set_query_plan_cache_capacity(0);
+        assert_eq!(conn.query_plan_cache_stats().entries, 0);
+        assert_eq!(run(&conn, &sqlite, TypedValue::Ref(1)), ident);
+        assert_eq!(conn.query_plan_cache_stats().entries, 0);
+    }
+
+    #[test]
+    fn test_interrupt() {
+        use std::thread;
+        use std::time::Duration;
+
+        use mentat_transaction::{CancellationToken, Pullable};
+
+        use public_traits::errors::InterruptReason;
+
+        let mut sqlite = db::new_connection("").unwrap();
+        let mut conn = Conn::connect(&mut sqlite).unwrap();
+
+        // Every bootstrap datom, four times over: far too many rows to finish counting.
+        let runaway = "[:find (count ?a) . :where [?a _ _] [?b _ _] [?c _ _] [?d _ _]]";
+        let ident = "[:find ?e . :where [?e :db/ident :db/ident]]";
+
+        // A deadline fires mid-query, and leaves the read usable.
+        let read = conn.begin_read(&mut sqlite).expect("read");
+        let interrupt = Interrupt::after(Duration::from_millis(20));
+        match read.with_interrupt(&interrupt, |read| read.q_once(runaway, None)) {
+            Err(MentatError::Interrupted(InterruptReason::DeadlineExceeded)) => {}
+            x => panic!("expected deadline to be exceeded, got {:?}", x.map(|_| ())),
+        }
+        assert_eq!(
+            read.q_once(ident, None)
+                .into_scalar_result()
+                .expect("query"),
+            Some(Binding::Scalar(TypedValue::Ref(1)))
+        );
+
+        // Once the deadline has passed, nothing more runs under it.
+        match read.with_interrupt(&interrupt, |read| read.q_once(ident, None)) {
+            Err(MentatError::Interrupted(InterruptReason::DeadlineExceeded)) => {}
+            x => panic!("expected deadline to be exceeded, got {:?}", x.map(|_| ())),
+        }
+
+        // Pulls and transaction log reads are interruptible too.
+        let generous = Interrupt::after(Duration::from_secs(60));
+        let pulled = read
+            .with_interrupt(&generous, |read| {
+                read.pull_attributes_for_entity(1, vec![1])
+            })
+            .expect("pulled");
+        assert!(!pulled.0.is_empty());
+        let txs = read
+            .with_interrupt(&generous, |read| {
+                mentat_db::debug::transactions_after(
+                    &read.in_progress.transaction,
+                    &read.in_progress.schema,
+                    0,
+                )
+                .map_err(|e| e.into())
+            })
+            .expect("transactions");
+        assert!(!txs.0.is_empty());
+
+        let token = CancellationToken::new();
+        token.cancel();
+        match read.with_interrupt(&Interrupt::on_cancel(token), |read| {
+            read.pull_attributes_for_entity(1, vec![1])
+        }) {
+            Err(MentatError::Interrupted(InterruptReason::Cancelled)) => {}
+            x => panic!("expected cancellation, got {:?}", x.map(|_| ())),
+        }
+        drop(read);
+
+        // Another thread can cancel a query that's underway.
+        let token = CancellationToken::new();
+        let canceller = {
+            let token = token.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(20));
+                token.cancel();
+            })
+        };
+        match conn.q_once_with_interrupt(&sqlite, runaway, None, &Interrupt::on_cancel(token)) {
+            Err(MentatError::Interrupted(InterruptReason::Cancelled)) => {}
+            x => panic!("expected cancellation, got {:?}", x.map(|_| ())),
+        }
+        canceller.join().expect("joined");
+
+        // The progress handler doesn't outlive the interruptible call.
+        assert!(conn.q_once(&sqlite, ident, None).is_ok());
+        conn.transact(&mut sqlite, "[[:db/add \"a\" :db.schema/attribute \"b\"]]")
+            .expect("transacted");
+    }
 }
@@ -0,0 +1,545 @@
+// Copyright 2018 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Whole-store export and import as an EDN transaction log.
+//!
+//! An export is a sequence of EDN forms, one per line. The first is a header map:
+//!
+//! ```edn
+//! {:mentat.export/version      1
+//!  :mentat.export/entids       :mentat.export/preserve
+//!  :mentat.export/transactions 2
+//!  :mentat.export/datoms       7
+//!  :mentat.export/schema       [{:db/ident :foo/name …} …]
+//!  :mentat.export/vocabularies {:org.example/foo 1}}
+//! ```
+//!
+//! and each following form is one transaction from the main timeline, oldest first:
+//!
+//! ```edn
+//! {:mentat.export/tx      268435457
+//!  :mentat.export/tx-data [[:db/add (transaction-tx) :db/txInstant #inst "2018-01-01T00:00:00Z"]
+//!                          [:db/add 65536 :foo/name "Alice"]]}
+//! ```
+//!
+//! The transaction data is ordinary transaction EDN. Entities are named by the ident they had when
+//! the transaction ran, if any; otherwise by entid, with `:mentat.export/preserve`, or by a tempid
+//! made from the entid, with `:mentat.export/tempids`. Importing with tempids lets the target store
+//! allocate its own entids, so tempids from one transaction are carried over to the next by the
+//! importer, not the transactor.
+//!
+//! The bootstrap transaction isn't exported: the importing store makes its own. Neither are other
+//! timelines. History that has been pruned is gone, but what is left replays to the same datoms.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{BufRead, Write};
+
+use rusqlite;
+
+use core_traits::{Entid, TypedValue, ValueType};
+
+use edn::symbols::PlainSymbol;
+use mentat_core::{HasSchema, Keyword};
+
+use mentat_db::{branches, db, entids};
+use mentat_db::{TypedSQLValue, TX0, USER0};
+
+use mentat_transaction::{InProgress, InProgressRead};
+
+use public_traits::errors::{MentatError, Result};
+
+use crate::vocabulary::HasVocabularies;
+
+/// The version of the export format written by `export`.
+pub const EXPORT_FORMAT_VERSION: i64 = 1;
+
+/// How an export names entities that don't have an ident.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ExportEntids {
+    /// By entid. Importing reproduces the store exactly, transaction IDs included.
+    Preserve,
+    /// By tempid. Importing allocates new entids, in the order the entities first appeared.
+    Tempids,
+}
+
+impl ExportEntids {
+    fn to_edn(self) -> edn::Value {
+        match self {
+            ExportEntids::Preserve => export_keyword("preserve"),
+            ExportEntids::Tempids => export_keyword("tempids"),
+        }
+    }
+
+    fn from_edn(value: &edn::Value) -> Option<ExportEntids> {
+        match value.as_keyword() {
+            Some(k) if k.namespace() == Some("mentat.export") && k.name() == "preserve" => {
+                Some(ExportEntids::Preserve)
+            }
+            Some(k) if k.namespace() == Some("mentat.export") && k.name() == "tempids" => {
+                Some(ExportEntids::Tempids)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// What an export wrote, or what an import replayed and verified.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ExportReport {
+    /// The number of transactions, not counting the bootstrap transaction.
+    pub transactions: usize,
+    /// The number of datoms asserted by those transactions that are still in the store.
+    pub datoms: usize,
+}
+
+fn export_keyword(name: &str) -> edn::Value {
+    edn::Value::Keyword(Keyword::namespaced("mentat.export", name))
+}
+
+fn transaction_tx() -> edn::Value {
+    edn::Value::List(
+        vec![edn::Value::PlainSymbol(PlainSymbol::plain(
+            "transaction-tx",
+        ))]
+        .into_iter()
+        .collect(),
+    )
+}
+
+fn count(conn: &rusqlite::Connection, sql: &str) -> Result<usize> {
+    let count: i64 = conn.query_row(sql, &[&TX0], |row| row.get(0))?;
+    Ok(count as usize)
+}
+
+fn count_transactions(conn: &rusqlite::Connection) -> Result<usize> {
+    count(
+        conn,
+        "SELECT COUNT(DISTINCT tx) FROM transactions WHERE tx > ?",
+    )
+}
+
+fn count_datoms(conn: &rusqlite::Connection) -> Result<usize> {
+    count(conn, "SELECT COUNT(*) FROM datoms WHERE tx > ?")
+}
+
+fn vocabulary_versions<T: HasVocabularies>(store: &T) -> Result<BTreeMap<edn::Value, edn::Value>> {
+    Ok(store
+        .read_vocabularies()?
+        .iter()
+        .map(|(name, vocabulary)| {
+            (
+                edn::Value::Keyword(name.clone()),
+                edn::Value::Integer(vocabulary.version.into()),
+            )
+        })
+        .collect())
+}
+
+fn schema_set(schema: &edn::Value) -> BTreeSet<edn::Value> {
+    schema
+        .as_vector()
+        .map(|attributes| attributes.iter().cloned().collect())
+        .unwrap_or_default()
+}
+
+/// Turns transaction log rows into transaction EDN, tracking idents as they change.
+struct Renderer {
+    entids: ExportEntids,
+    idents: BTreeMap<Entid, Keyword>,
+}
+
+impl Renderer {
+    fn entity(&self, e: Entid, tx: Entid) -> edn::Value {
+        if e == tx {
+            return transaction_tx();
+        }
+        if let Some(ident) = self.idents.get(&e) {
+            return edn::Value::Keyword(ident.clone());
+        }
+        match self.entids {
+            // Bootstrapped entities and transaction are the same in every store.
+            ExportEntids::Tempids if e >= USER0 && e != TX0 => edn::Value::Text(e.to_string()),
+            _ => edn::Value::Integer(e),
+        }
+    }
+
+    fn attribute(&self, a: Entid) -> Result<edn::Value> {
+        match (self.idents.get(&a), self.entids) {
+            (Some(ident), _) => Ok(edn::Value::Keyword(ident.clone())),
+            (None, ExportEntids::Tempids) if a >= USER0 => bail!(MentatError::BadExport(format!(
+                "attribute {} has no ident, so can't be exported with tempids",
+                a
+            ))),
+            (None, _) => Ok(edn::Value::Integer(a)),
+        }
+    }
+
+    fn value(&self, v: TypedValue, tx: Entid) -> edn::Value {
+        match v {
+            TypedValue::Ref(e) => self.entity(e, tx),
+            v => v.to_edn_value_pair().0,
+        }
+    }
+
+    /// Render one datom, and note any change it makes to an ident.
+    fn datom(
+        &self,
+        e: Entid,
+        a: Entid,
+        v: TypedValue,
+        tx: Entid,
+        added: bool,
+        ident_changes: &mut Vec<(Entid, Keyword, bool)>,
+    ) -> Result<edn::Value> {
+        if a == entids::DB_IDENT {
+            if let TypedValue::Keyword(ref ident) = v {
+                ident_changes.push((e, (**ident).clone(), added));
+            }
+        }
+        let op = if added { "add" } else { "retract" };
+        Ok(edn::Value::Vector(vec![
+            edn::Value::Keyword(Keyword::namespaced("db", op)),
+            self.entity(e, tx),
+            self.attribute(a)?,
+            self.value(v, tx),
+        ]))
+    }
+
+    fn write_transaction<W: Write>(
+        &mut self,
+        writer: &mut W,
+        tx: Entid,
+        data: Vec<edn::Value>,
+        ident_changes: Vec<(Entid, Keyword, bool)>,
+    ) -> Result<()> {
+        let mut form = BTreeMap::new();
+        form.insert(export_keyword("tx"), edn::Value::Integer(tx));
+        form.insert(export_keyword("tx-data"), edn::Value::Vector(data));
+        writeln!(writer, "{}", edn::Value::Map(form))?;
+
+        // Retractions sort first, so a renamed entity ends up with its new ident.
+        for (e, ident, added) in ident_changes {
+            if added {
+                self.idents.insert(e, ident);
+            } else if self.idents.get(&e) == Some(&ident) {
+                self.idents.remove(&e);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Write every transaction visible to `read` to `writer`, as described in the module docs.
+pub fn export<W: Write>(
+    read: &InProgressRead<'_, '_>,
+    mut writer: W,
+    entids: ExportEntids,
+) -> Result<ExportReport> {
+    let in_progress = &read.in_progress;
+    let conn: &rusqlite::Connection = &in_progress.transaction;
+
+    // Exports hold the main branch's log; other branches' transactions aren't included.
+    branches::ensure_main_checked_out(conn)?;
+
+    let report = ExportReport {
+        transactions: count_transactions(conn)?,
+        datoms: count_datoms(conn)?,
+    };
+
+    let mut header = BTreeMap::new();
+    header.insert(
+        export_keyword("version"),
+        edn::Value::Integer(EXPORT_FORMAT_VERSION),
+    );
+    header.insert(export_keyword("entids"), entids.to_edn());
+    header.insert(
+        export_keyword("transactions"),
+        edn::Value::Integer(report.transactions as i64),
+    );
+    header.insert(
+        export_keyword("datoms"),
+        edn::Value::Integer(report.datoms as i64),
+    );
+    header.insert(export_keyword("schema"), in_progress.schema.to_edn_value());
+    header.insert(
+        export_keyword("vocabularies"),
+        edn::Value::Map(vocabulary_versions(in_progress)?),
+    );
+    writeln!(writer, "{}", edn::Value::Map(header))?;
+
+    // Start from the idents the bootstrap transaction made; every store has those.
+    let mut renderer = Renderer {
+        entids,
+        idents: BTreeMap::new(),
+    };
+    {
+        let mut stmt = conn.prepare(
+            "SELECT e, v, value_type_tag FROM transactions \
+             WHERE tx <= ? AND a = ? AND added = 1",
+        )?;
+        let mut rows = stmt.query(&[&TX0, &entids::DB_IDENT])?;
+        while let Some(row) = rows.next()? {
+            let e: Entid = row.get(0)?;
+            let v: rusqlite::types::Value = row.get(1)?;
+            let value_type_tag: i32 = row.get(2)?;
+            if let TypedValue::Keyword(ident) = TypedValue::from_sql_value_pair(v, value_type_tag)?
+            {
+                renderer.idents.insert(e, (*ident).clone());
+            }
+        }
+    }
+
+    let mut stmt = conn.prepare(
+        "SELECT e, a, v, value_type_tag, tx, added FROM transactions \
+         WHERE tx > ? \
+         ORDER BY tx ASC, added ASC, e ASC, a ASC, value_type_tag ASC, v ASC",
+    )?;
+    let mut rows = stmt.query(&[&TX0])?;
+
+    let mut current: Option<Entid> = None;
+    let mut data = Vec::new();
+    let mut ident_changes = Vec::new();
+    while let Some(row) = rows.next()? {
+        let e: Entid = row.get(0)?;
+        let a: Entid = row.get(1)?;
+        let v: rusqlite::types::Value = row.get(2)?;
+        let value_type_tag: i32 = row.get(3)?;
+        let tx: Entid = row.get(4)?;
+        let added: bool = row.get(5)?;
+
+        if let Some(previous) = current {
+            if previous != tx {
+                let data = ::std::mem::take(&mut data);
+                let changes = ::std::mem::take(&mut ident_changes);
+                renderer.write_transaction(&mut writer, previous, data, changes)?;
+            }
+        }
+        current = Some(tx);
+
+        if in_progress.schema.attribute_for_entid(a).is_none() {
+            bail!(MentatError::UnknownAttribute(a.to_string()));
+        }
+        let value = db::datom_value(conn, &in_progress.schema, a, v, value_type_tag)?;
+        data.push(renderer.datom(e, a, value, tx, added, &mut ident_changes)?);
+    }
+    if let Some(tx) = current {
+        renderer.write_transaction(&mut writer, tx, data, ident_changes)?;
+    }
+
+    writer.flush()?;
+    Ok(report)
+}
+
+/// Replays an export into an `InProgress`, one transaction at a time.
+struct Importer {
+    entids: ExportEntids,
+    /// Exported tempids, and the entids they were given by earlier transactions.
+    tempids: BTreeMap<String, Entid>,
+}
+
+impl Importer {
+    fn is_ref(in_progress: &InProgress<'_, '_>, a: &edn::Value) -> bool {
+        let attribute = match *a {
+            edn::Value::Keyword(ref ident) => {
+                in_progress.attribute_for_ident(ident).map(|(a, _)| a)
+            }
+            edn::Value::Integer(entid) => in_progress.attribute_for_entid(entid),
+            _ => None,
+        };
+        match attribute {
+            Some(a) => a.value_type == ValueType::Ref,
+            None => false,
+        }
+    }
+
+    /// Make sure `e`, a plain entid, is allocated in the partition that holds it.
+    fn allocate(in_progress: &mut InProgress<'_, '_>, e: Entid) {
+        for partition in in_progress.partition_map.values_mut() {
+            if partition.allows_entid(e) && !partition.contains_entid(e) {
+                if partition.allows_entid(e + 1) {
+                    partition.set_next_entid(e + 1);
+                }
+                return;
+            }
+        }
+    }
+
+    fn entity(&self, in_progress: &mut InProgress<'_, '_>, value: &mut edn::Value) {
+        match (*value).clone() {
+            edn::Value::Integer(e) if self.entids == ExportEntids::Preserve => {
+                Importer::allocate(in_progress, e);
+            }
+            edn::Value::Text(ref tempid) => {
+                if let Some(e) = self.tempids.get(tempid) {
+                    *value = edn::Value::Integer(*e);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn transact(
+        &mut self,
+        in_progress: &mut InProgress<'_, '_>,
+        tx: Entid,
+        mut data: Vec<edn::Value>,
+    ) -> Result<()> {
+        for datom in data.iter_mut() {
+            match *datom {
+                edn::Value::Vector(ref mut parts) if parts.len() == 4 => {
+                    let is_ref = Importer::is_ref(in_progress, &parts[2]);
+                    self.entity(in_progress, &mut parts[1]);
+                    if is_ref {
+                        self.entity(in_progress, &mut parts[3]);
+                    }
+                }
+                _ => bail!(MentatError::BadExport(format!(
+                    "expected [op e a v] in transaction {}, got {}",
+                    tx, datom
+                ))),
+            }
+        }
+
+        if self.entids == ExportEntids::Preserve {
+            let next = in_progress
+                .partition_map
+                .get_mut(":db.part/tx")
+                .ok_or_else(|| MentatError::BadExport("no transaction partition".to_string()))?;
+            if !next.allows_entid(tx) || tx < next.next_entid() {
+                bail!(MentatError::BadExport(format!(
+                    "transaction {} is out of order",
+                    tx
+                )));
+            }
+            next.set_next_entid(tx);
+        }
+
+        // Transaction data is written the same way a user would write it, so it's read the same
+        // way too.
+        let entities = edn::parse::entities(&edn::Value::Vector(data).to_string())?;
+        let report = in_progress.transact_entities(entities)?;
+
+        match self.entids {
+            ExportEntids::Preserve => {
+                if report.tx_id != tx {
+                    bail!(MentatError::ImportMismatch(format!(
+                        "transaction {} was imported as {}",
+                        tx, report.tx_id
+                    )));
+                }
+            }
+            ExportEntids::Tempids => {
+                self.tempids.insert(tx.to_string(), report.tx_id);
+                self.tempids.extend(report.tempids);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn header_value<'v>(header: &'v edn::Value, name: &str) -> Result<&'v edn::Value> {
+    header
+        .as_map()
+        .and_then(|map| map.get(&export_keyword(name)))
+        .ok_or_else(|| MentatError::BadExport(format!("header has no :mentat.export/{}", name)))
+}
+
+fn header_count(header: &edn::Value, name: &str) -> Result<usize> {
+    match header_value(header, name)?.as_integer() {
+        Some(n) if n >= 0 => Ok(n as usize),
+        _ => bail!(MentatError::BadExport(format!(
+            "header has a bad :mentat.export/{}",
+            name
+        ))),
+    }
+}
+
+fn check<T: PartialEq + ::std::fmt::Debug>(what: &str, expected: T, got: T) -> Result<()> {
+    if expected == got {
+        Ok(())
+    } else {
+        bail!(MentatError::ImportMismatch(format!(
+            "expected {} {:?}, got {:?}",
+            what, expected, got
+        )))
+    }
+}
+
+/// Replay an export read from `reader` into `in_progress`, which must be an empty store, and check
+/// that the result has the schema, vocabularies and counts the export says it should.
+pub fn import<R: BufRead>(in_progress: &mut InProgress<'_, '_>, reader: R) -> Result<ExportReport> {
+    let last_tx = in_progress.last_tx_id();
+    if last_tx != TX0 {
+        bail!(MentatError::ImportIntoNonEmptyStore(last_tx));
+    }
+
+    let mut forms = reader.lines().filter(|line| match *line {
+        Ok(ref line) => !line.trim().is_empty(),
+        Err(_) => true,
+    });
+    let mut read_form = || -> Result<Option<edn::Value>> {
+        match forms.next() {
+            None => Ok(None),
+            Some(line) => Ok(Some(edn::parse::value(&line?)?.without_spans())),
+        }
+    };
+
+    let header = read_form()?.ok_or_else(|| MentatError::BadExport("empty export".to_string()))?;
+    match header_value(&header, "version")?.as_integer() {
+        Some(EXPORT_FORMAT_VERSION) => {}
+        _ => bail!(MentatError::BadExport(format!(
+            "unsupported version {}",
+            header_value(&header, "version")?
+        ))),
+    }
+    let entids = ExportEntids::from_edn(header_value(&header, "entids")?).ok_or_else(|| {
+        MentatError::BadExport("header has bad :mentat.export/entids".to_string())
+    })?;
+
+    let mut importer = Importer {
+        entids,
+        tempids: BTreeMap::new(),
+    };
+    let mut transactions = 0;
+    while let Some(form) = read_form()? {
+        let tx = header_value(&form, "tx")?
+            .as_integer()
+            .ok_or_else(|| MentatError::BadExport(format!("bad transaction {}", form)))?;
+        let data = header_value(&form, "tx-data")?
+            .as_vector()
+            .cloned()
+            .ok_or_else(|| MentatError::BadExport(format!("bad transaction {}", form)))?;
+        importer.transact(in_progress, tx, data)?;
+        transactions += 1;
+    }
+
+    let report = ExportReport {
+        transactions: count_transactions(&in_progress.transaction)?,
+        datoms: count_datoms(&in_progress.transaction)?,
+    };
+    check(
+        "transactions",
+        header_count(&header, "transactions")?,
+        transactions,
+    )?;
+    check("transactions", transactions, report.transactions)?;
+    check("datoms", header_count(&header, "datoms")?, report.datoms)?;
+    check(
+        "schema",
+        schema_set(header_value(&header, "schema")?),
+        schema_set(&in_progress.schema.to_edn_value()),
+    )?;
+    check(
+        "vocabularies",
+        header_value(&header, "vocabularies")?.as_map().cloned(),
+        Some(vocabulary_versions(in_progress)?),
+    )?;
+    Ok(report)
+}
@@ -0,0 +1,244 @@
+// Copyright 2018 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! A JSON mapping for transactions and query results, for clients that don't speak EDN.
+//!
+//! # Values
+//!
+//! Values that JSON can represent directly are written as JSON. Everything else is a tagged
+//! object with a single key:
+//!
+//! | Mentat type  | JSON                                           |
+//! |--------------|------------------------------------------------|
+//! | `:db.type/boolean` | `true`                                   |
+//! | `:db.type/long`    | `42`                                     |
+//! | `:db.type/double`  | `4.2`, or `{"double": "NaN"}`, `{"double": "Infinity"}` or `{"double": "-Infinity"}` |
+//! | `:db.type/string`  | `"hello"`                                |
+//! | `:db.type/instant` | `{"instant": "2018-01-01T12:00:00.000000Z"}`, in RFC 3339 |
+//! | `:db.type/uuid`    | `{"uuid": "550e8400-e29b-41d4-a716-446655440000"}` |
+//! | `:db.type/keyword` | `{"keyword": ":foo/bar"}`                |
+//! | `:db.type/ref`     | `{"ref": 65536}`                         |
+//!
+//! Query results use these encodings: a scalar is a value or `null`, a tuple is an array or `null`,
+//! a collection is an array, and a relation is an array of arrays. Pulled entities are objects
+//! keyed by keyword strings. `QueryOutput`, `QueryResults`, `Binding`, and `TxReport` implement
+//! serde's `Serialize` this way, so `serde_json::to_string(&output)` writes the mapping.
+//! `TypedValue`'s own `Serialize` is the format transactions are synced in; wrap a value in
+//! `JsonValue` to write it in this mapping instead.
+//!
+//! # Transactions
+//!
+//! A transaction is an array of entity maps, just like EDN map notation. Keys are keyword strings,
+//! such as `":foo/bar"` or `":foo/_bar"`. Values are encoded as above, and because the type of each
+//! attribute is known, a few more forms are accepted:
+//!
+//! - a long for a double attribute;
+//! - for a ref attribute, or `":db/id"`: a string is a tempid, a number is an entid,
+//!   `{"keyword": ":foo/bar"}` is an ident, and `{"lookup-ref": [":foo/name", "Alice"]}` is a
+//!   lookup ref;
+//! - for a ref attribute, an untagged object is a nested entity map;
+//! - for a cardinality-many attribute, an array is a set of values.
+//!
+//! ```json
+//! [{":db/id": "a", ":person/name": "Alice", ":person/born": {"instant": "1990-01-01T00:00:00Z"}},
+//!  {":person/name": "Bob", ":person/friend": ["a", {"lookup-ref": [":person/name", "Carol"]}]}]
+//! ```
+
+use chrono::{DateTime, Utc};
+use serde_json::{self, Map, Value};
+
+use core_traits::{TypedValue, ValueType};
+
+pub use core_traits::json::JsonValue;
+
+use edn::entities::{
+    AttributePlace, EntidOrIdent, Entity, LookupRef, MapNotation, TempId, ValuePlace,
+};
+use mentat_core::{HasSchema, Keyword, Uuid};
+
+use public_traits::errors::{MentatError, Result};
+
+const DOUBLE: &str = "double";
+const INSTANT: &str = "instant";
+const KEYWORD: &str = "keyword";
+const LOOKUP_REF: &str = "lookup-ref";
+const REF: &str = "ref";
+const UUID: &str = "uuid";
+
+fn bad_json<T>(message: String) -> Result<T> {
+    bail!(MentatError::BadJson(message))
+}
+
+/// If `value` is a tagged object, return its tag and content.
+fn as_tagged(value: &Value) -> Option<(&str, &Value)> {
+    match *value {
+        Value::Object(ref map) if map.len() == 1 => map
+            .iter()
+            .next()
+            .filter(|(k, _)| !k.starts_with(':'))
+            .map(|(k, v)| (k.as_str(), v)),
+        _ => None,
+    }
+}
+
+fn parse_keyword(s: &str) -> Result<Keyword> {
+    match edn::parse::value(s).map(|v| v.without_spans()) {
+        Ok(edn::Value::Keyword(k)) => Ok(k),
+        _ => bad_json(format!(
+            "expected a keyword string like \":foo/bar\", got {:?}",
+            s
+        )),
+    }
+}
+
+fn tagged_keyword(value: &Value) -> Result<Keyword> {
+    match value.as_str() {
+        Some(s) => parse_keyword(s),
+        None => bad_json(format!("expected a keyword string, got {}", value)),
+    }
+}
+
+/// Converts JSON transactions into entities, using the schema to decide what each value means.
+struct EntityBuilder<'s, S: HasSchema> {
+    schema: &'s S,
+}
+
+impl<'s, S: HasSchema> EntityBuilder<'s, S> {
+    fn value_type(&self, attribute: &Keyword) -> Result<(ValueType, bool)> {
+        match self.schema.attribute_for_ident(attribute) {
+            Some((attribute, _)) => Ok((attribute.value_type, attribute.multival)),
+            None => bail!(MentatError::UnknownAttribute(attribute.to_string())),
+        }
+    }
+
+    fn typed_value(&self, value_type: ValueType, value: &Value) -> Result<TypedValue> {
+        let typed = match (value_type, value, as_tagged(value)) {
+            (ValueType::Boolean, Value::Bool(b), _) => Some(TypedValue::Boolean(*b)),
+            (ValueType::Long, Value::Number(n), _) => n.as_i64().map(TypedValue::Long),
+            (ValueType::Double, Value::Number(n), _) => n.as_f64().map(TypedValue::from),
+            (ValueType::Double, _, Some((DOUBLE, Value::String(s)))) => match s.as_str() {
+                "NaN" => Some(TypedValue::from(f64::NAN)),
+                "Infinity" => Some(TypedValue::from(f64::INFINITY)),
+                "-Infinity" => Some(TypedValue::from(f64::NEG_INFINITY)),
+                _ => None,
+            },
+            (ValueType::String, Value::String(s), _) => Some(TypedValue::typed_string(s)),
+            (ValueType::Instant, _, Some((INSTANT, Value::String(s)))) => {
+                DateTime::parse_from_rfc3339(s)
+                    .ok()
+                    .map(|t| t.with_timezone(&Utc).into())
+            }
+            (ValueType::Uuid, _, Some((UUID, Value::String(s)))) => {
+                Uuid::parse_str(s).ok().map(TypedValue::Uuid)
+            }
+            (ValueType::Keyword, _, Some((KEYWORD, k))) => Some(tagged_keyword(k)?.into()),
+            (ValueType::Ref, Value::Number(n), _)
+            | (ValueType::Ref, _, Some((REF, Value::Number(n)))) => n.as_i64().map(TypedValue::Ref),
+            _ => None,
+        };
+        match typed {
+            Some(typed) => Ok(typed),
+            None => bad_json(format!("expected {}, got {}", value_type, value)),
+        }
+    }
+
+    fn lookup_ref(&self, value: &Value) -> Result<LookupRef<TypedValue>> {
+        match value.as_array().map(|pair| pair.as_slice()) {
+            Some([a, v]) => {
+                let a = tagged_keyword(a)?;
+                let (value_type, _) = self.value_type(&a)?;
+                Ok(LookupRef {
+                    a: AttributePlace::Entid(EntidOrIdent::Ident(a)),
+                    v: self.typed_value(value_type, v)?,
+                })
+            }
+            _ => bad_json(format!(
+                "expected [attribute, value] in a lookup ref, got {}",
+                value
+            )),
+        }
+    }
+
+    /// Something that names an entity: the value of `:db/id` or of a ref attribute.
+    fn entity(&self, value: &Value) -> Result<ValuePlace<TypedValue>> {
+        match (value, as_tagged(value)) {
+            (Value::String(s), _) => Ok(ValuePlace::TempId(TempId::External(s.clone()).into())),
+            (_, Some((KEYWORD, k))) => {
+                Ok(ValuePlace::Entid(EntidOrIdent::Ident(tagged_keyword(k)?)))
+            }
+            (_, Some((LOOKUP_REF, pair))) => Ok(ValuePlace::LookupRef(self.lookup_ref(pair)?)),
+            _ => match self.typed_value(ValueType::Ref, value)? {
+                TypedValue::Ref(e) => Ok(ValuePlace::Entid(EntidOrIdent::Entid(e))),
+                _ => unreachable!(),
+            },
+        }
+    }
+
+    fn value(&self, value_type: ValueType, value: &Value) -> Result<ValuePlace<TypedValue>> {
+        match (value_type, value) {
+            (ValueType::Ref, Value::Object(map)) if as_tagged(value).is_none() => {
+                Ok(ValuePlace::MapNotation(self.map_notation(map)?))
+            }
+            (ValueType::Ref, _) => self.entity(value),
+            _ => Ok(ValuePlace::Atom(self.typed_value(value_type, value)?)),
+        }
+    }
+
+    fn values(&self, attribute: &Keyword, value: &Value) -> Result<ValuePlace<TypedValue>> {
+        if attribute.is_backward() {
+            return match *value {
+                Value::Array(ref vs) => Ok(ValuePlace::Vector(
+                    vs.iter().map(|v| self.entity(v)).collect::<Result<_>>()?,
+                )),
+                _ => self.entity(value),
+            };
+        }
+
+        let (value_type, multival) = self.value_type(attribute)?;
+        match *value {
+            Value::Array(ref vs) if multival => Ok(ValuePlace::Vector(
+                vs.iter()
+                    .map(|v| self.value(value_type, v))
+                    .collect::<Result<_>>()?,
+            )),
+            _ => self.value(value_type, value),
+        }
+    }
+
+    fn map_notation(&self, map: &Map<String, Value>) -> Result<MapNotation<TypedValue>> {
+        map.iter()
+            .map(|(key, value)| {
+                let attribute = parse_keyword(key)?;
+                let value = if attribute.namespace() == Some("db") && attribute.name() == "id" {
+                    self.entity(value)?
+                } else {
+                    self.values(&attribute, value)?
+                };
+                Ok((EntidOrIdent::Ident(attribute), value))
+            })
+            .collect()
+    }
+}
+
+/// Turn a JSON transaction, as described in the module docs, into entities that can be transacted
+/// against a store with the given schema.
+pub fn entities_from_json<S: HasSchema>(schema: &S, json: &str) -> Result<Vec<Entity<TypedValue>>> {
+    let builder = EntityBuilder { schema };
+    match serde_json::from_str(json)? {
+        Value::Array(entities) => entities
+            .iter()
+            .map(|entity| match *entity {
+                Value::Object(ref map) => Ok(Entity::MapNotation(builder.map_notation(map)?)),
+                _ => bad_json(format!("expected an entity map, got {}", entity)),
+            })
+            .collect(),
+        other => bad_json(format!("expected an array of entity maps, got {}", other)),
+    }
+}
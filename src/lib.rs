@@ -20,14 +20,18 @@ pub use core_traits::{
     now, Attribute, Binding, Entid, KnownEntid, StructuredMap, TypedValue, ValueType,
 };
 
-pub use mentat_core::{DateTime, HasSchema, Keyword, Schema, TxReport, Utc, Uuid};
+pub use mentat_core::{DateTime, DatomsView, HasSchema, Keyword, Schema, TxReport, Utc, Uuid};
 
-pub use edn::query::FindSpec;
+pub use edn::query::{FindSpec, SrcVar};
 
 pub use mentat_db::{
-    new_connection, AttributeSet, TxObserver, CORE_SCHEMA_VERSION, DB_SCHEMA_CORE,
+    new_connection, AttributeSet, Problem, TxObserver, VerifyReport, CORE_SCHEMA_VERSION,
+    DB_SCHEMA_CORE,
 };
 
+pub use mentat_db::branches::{Branch, MAIN_BRANCH};
+pub use mentat_db::cache::AttributeCacheStats;
+
 #[cfg(feature = "sqlcipher")]
 pub use mentat_db::{change_encryption_key, new_connection_with_key};
 
@@ -82,7 +86,7 @@ macro_rules! kw {
 }
 
 pub use public_traits::errors;
-pub use public_traits::errors::{MentatError, Result};
+pub use public_traits::errors::{InterruptReason, MentatError, Result};
 
 pub use edn::{FromMicros, FromMillis, ParseError, ToMicros, ToMillis};
 pub use mentat_query_projector::BindingTuple;
@@ -102,6 +106,10 @@ pub use mentat_transaction::query::{
 };
 
 pub mod conn;
+pub mod export;
+#[cfg(feature = "json")]
+pub mod json;
+pub mod pool;
 pub mod query_builder;
 pub mod store;
 pub mod vocabulary;
@@ -113,15 +121,31 @@ mod sync;
 pub use sync::Syncable;
 
 #[cfg(feature = "syncable")]
-pub use mentat_tolstoy::SyncReport;
+pub use mentat_tolstoy::{
+    Conflict, ConflictResolver, DirectoryLog, GlobalTransactionLog, LastWriterWins, LocalWins,
+    LogStorage, PerAttribute, RemoteClient, RemoteWins, Resolution, ResolvedConflict, SyncFilter,
+    SyncReport,
+};
+
+#[cfg(feature = "sync_encryption")]
+pub use mentat_tolstoy::{EncryptedLog, SyncKeys};
 
 pub use query_builder::QueryBuilder;
 
 pub use conn::Conn;
 
-pub use mentat_transaction::{CacheAction, CacheDirection, InProgress, Pullable, Queryable};
+pub use mentat_transaction::{
+    interruptible, CacheAction, CacheDirection, CacheWarming, CancellationToken,
+    CommittedTransaction, Diff, DiffDatom, HistoryCutoff, InProgress, InProgressRead,
+    InProgressView, Interrupt, PruneReport, Pullable, QueryPlanCacheStats, Queryable, TxDatom,
+    TxReportQueueError, TxReportReceiver,
+};
 
-pub use store::Store;
+pub use export::{ExportEntids, ExportReport};
+#[cfg(feature = "json")]
+pub use json::JsonValue;
+pub use pool::{PooledReader, PooledWriter, StorePool};
+pub use store::{BackupProgress, Store};
 
 #[cfg(test)]
 mod tests {
@@ -0,0 +1,152 @@
+// Copyright 2018 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! A pool of SQLite connections to one store, sharing one `Conn`.
+//!
+//! A `Store` has a single connection, so reads and writes take turns. A `StorePool` opens
+//! several read connections and one write connection to the same file. SQLite's WAL mode lets
+//! the readers work alongside each other and alongside the writer, each seeing a snapshot of the
+//! store as of the start of its read, paired with the metadata -- schema, partition map, and
+//! attribute caches -- of that snapshot. Writes queue up for the single writer, which moves the
+//! shared metadata forward as each commits.
+//!
+//! The pool assumes that it does all the writing. Writes made by other processes are noticed at
+//! the start of the next write through the pool, which reloads the metadata; until then, reads
+//! pair their snapshots with the metadata of the last write the pool knows about.
+
+use std::sync::{Condvar, Mutex, MutexGuard};
+
+use rusqlite::{self, TransactionBehavior};
+
+use mentat_core::TxReport;
+
+use mentat_transaction::{InProgress, InProgressRead};
+
+use public_traits::errors::{MentatError, Result};
+
+use crate::conn::Conn;
+
+/// Several read connections and one write connection to the same store. Share it between
+/// threads by reference, or in an `Arc`.
+pub struct StorePool {
+    conn: Conn,
+    writer: Mutex<rusqlite::Connection>,
+    readers: Mutex<Vec<rusqlite::Connection>>,
+    reader_returned: Condvar,
+}
+
+impl StorePool {
+    /// Open the store at `path` with `readers` read connections, at least one, and a write
+    /// connection, and rebuild the attribute caches it had registered. The store must be in a
+    /// file: in-memory stores can't be shared between connections.
+    pub fn open(path: &str, readers: usize) -> Result<StorePool> {
+        if path.is_empty() {
+            bail!(MentatError::InMemoryStorePool);
+        }
+        let mut writer = crate::new_connection(path)?;
+        let conn = Conn::connect(&mut writer)?;
+        conn.restore_caches(&writer)?;
+        let readers = (0..readers.max(1))
+            .map(|_| crate::new_connection(path))
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(StorePool {
+            conn,
+            writer: Mutex::new(writer),
+            readers: Mutex::new(readers),
+            reader_returned: Condvar::new(),
+        })
+    }
+
+    pub fn conn(&self) -> &Conn {
+        &self.conn
+    }
+
+    /// Take a read connection, waiting for one to be returned to the pool if they're all in use.
+    pub fn reader(&self) -> PooledReader<'_> {
+        let mut readers = self.readers.lock().unwrap();
+        loop {
+            if let Some(sqlite) = readers.pop() {
+                return PooledReader {
+                    pool: self,
+                    sqlite: Some(sqlite),
+                };
+            }
+            readers = self.reader_returned.wait(readers).unwrap();
+        }
+    }
+
+    /// Take the write connection, waiting for any other writer to finish with it.
+    pub fn writer(&self) -> PooledWriter<'_> {
+        PooledWriter {
+            conn: &self.conn,
+            sqlite: self.writer.lock().unwrap(),
+        }
+    }
+
+    /// Run `f` against a consistent read of the store.
+    pub fn read<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&InProgressRead) -> Result<T>,
+    {
+        let mut reader = self.reader();
+        let read = reader.begin_read()?;
+        f(&read)
+    }
+
+    pub fn transact(&self, transaction: &str) -> Result<TxReport> {
+        self.writer().transact(transaction)
+    }
+}
+
+/// A read connection taken from a `StorePool`, returned to it when dropped.
+pub struct PooledReader<'p> {
+    pool: &'p StorePool,
+    sqlite: Option<rusqlite::Connection>,
+}
+
+impl<'p> PooledReader<'p> {
+    /// Begin a read, pinned to the store and metadata as they are now.
+    pub fn begin_read<'r>(&'r mut self) -> Result<InProgressRead<'p, 'r>> {
+        let sqlite = self.sqlite.as_mut().expect("connection taken from pool");
+        self.pool
+            .conn
+            .begin_shared(sqlite, TransactionBehavior::Deferred, false)
+            .map(|in_progress| InProgressRead { in_progress })
+    }
+}
+
+impl<'p> Drop for PooledReader<'p> {
+    fn drop(&mut self) {
+        if let Some(sqlite) = self.sqlite.take() {
+            self.pool.readers.lock().unwrap().push(sqlite);
+            self.pool.reader_returned.notify_one();
+        }
+    }
+}
+
+/// The write connection of a `StorePool`, held exclusively until dropped.
+pub struct PooledWriter<'p> {
+    conn: &'p Conn,
+    sqlite: MutexGuard<'p, rusqlite::Connection>,
+}
+
+impl<'p> PooledWriter<'p> {
+    pub fn begin_transaction<'w>(&'w mut self) -> Result<InProgress<'p, 'w>> {
+        self.conn
+            .begin_shared(&mut self.sqlite, TransactionBehavior::Immediate, true)
+    }
+
+    pub fn transact(&mut self, transaction: &str) -> Result<TxReport> {
+        let mut in_progress = self.begin_transaction()?;
+        let report = in_progress.transact(transaction)?;
+        in_progress.commit()?;
+        Ok(report)
+    }
+}
@@ -11,11 +11,16 @@
 #![macro_use]
 use std::collections::BTreeMap;
 
+use std::time::Duration;
+
 pub use core_traits::{Binding, Entid, TypedValue, ValueType};
 
 use mentat_core::{DateTime, Keyword, Utc};
 
-use super::{HasSchema, QueryInputs, QueryOutput, Queryable, RelResult, Store, Variable};
+use super::{
+    CancellationToken, HasSchema, Interrupt, QueryInputs, QueryOutput, Queryable, RelResult, Store,
+    Variable,
+};
 
 use public_traits::errors::{MentatError, Result};
 
@@ -23,6 +28,8 @@ pub struct QueryBuilder<'a> {
     query: String,
     values: BTreeMap<Variable, TypedValue>,
     types: BTreeMap<Variable, ValueType>,
+    interrupt: Interrupt,
+    timeout: Option<Duration>,
     store: &'a mut Store,
 }
 
@@ -35,6 +42,8 @@ impl<'a> QueryBuilder<'a> {
             query: query.into(),
             values: BTreeMap::new(),
             types: BTreeMap::new(),
+            interrupt: Interrupt::none(),
+            timeout: None,
             store,
         }
     }
@@ -98,12 +107,30 @@ impl<'a> QueryBuilder<'a> {
         self
     }
 
+    /// Give up with `MentatError::Interrupted` if the query is still running `timeout` after it
+    /// is executed.
+    pub fn timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Give up with `MentatError::Interrupted` once `token` is cancelled.
+    pub fn cancel_on(&mut self, token: CancellationToken) -> &mut Self {
+        self.interrupt = ::std::mem::take(&mut self.interrupt).with_token(token);
+        self
+    }
+
     pub fn execute(&mut self) -> Result<QueryOutput> {
         let values = ::std::mem::take(&mut self.values);
         let types = ::std::mem::take(&mut self.types);
         let query_inputs = QueryInputs::new(types, values)?;
+        let interrupt = match self.timeout {
+            Some(timeout) => self.interrupt.clone().with_timeout(timeout),
+            None => self.interrupt.clone(),
+        };
         let read = self.store.begin_read()?;
-        read.q_once(&self.query, query_inputs).map_err(|e| e)
+        let query = &self.query;
+        read.with_interrupt(&interrupt, |read| read.q_once(query, query_inputs))
     }
 
     pub fn execute_scalar(&mut self) -> Result<Option<Binding>> {
@@ -129,7 +156,9 @@ impl<'a> QueryBuilder<'a> {
 
 #[cfg(test)]
 mod test {
-    use super::{QueryBuilder, Store, TypedValue};
+    use super::{CancellationToken, Duration, QueryBuilder, Store, TypedValue};
+
+    use public_traits::errors::{InterruptReason, MentatError};
 
     #[test]
     fn test_scalar_query() {
@@ -538,4 +567,49 @@ mod test {
             25
         );
     }
+
+    #[test]
+    fn test_interrupted_query() {
+        let mut store = Store::open("").expect("store connection");
+        let runaway = "[:find (count ?a) . :where [?a _ _] [?b _ _] [?c _ _] [?d _ _]]";
+
+        match QueryBuilder::new(&mut store, runaway)
+            .timeout(Duration::from_millis(20))
+            .execute_scalar()
+        {
+            Err(MentatError::Interrupted(InterruptReason::DeadlineExceeded)) => {}
+            x => panic!("expected deadline to be exceeded, got {:?}", x),
+        }
+
+        let token = CancellationToken::new();
+        token.cancel();
+        match QueryBuilder::new(&mut store, runaway)
+            .cancel_on(token)
+            .execute_scalar()
+        {
+            Err(MentatError::Interrupted(InterruptReason::Cancelled)) => {}
+            x => panic!("expected cancellation, got {:?}", x),
+        }
+
+        // The clock starts when the query is executed, not when the builder is configured.
+        let mut builder =
+            QueryBuilder::new(&mut store, "[:find ?e . :where [?e :db/ident :db/ident]]");
+        builder.timeout(Duration::from_millis(20));
+        ::std::thread::sleep(Duration::from_millis(40));
+        assert_eq!(
+            builder
+                .execute_scalar()
+                .expect("ScalarResult")
+                .and_then(|t| t.into_entid()),
+            Some(1)
+        );
+
+        // Queries that finish in time are unaffected.
+        let entid = QueryBuilder::new(&mut store, "[:find ?e . :where [?e :db/ident :db/ident]]")
+            .timeout(Duration::from_secs(60))
+            .execute_scalar()
+            .expect("ScalarResult")
+            .and_then(|t| t.into_entid());
+        assert_eq!(entid, Some(1));
+    }
 }
@@ -12,47 +12,157 @@
 
 use std::collections::BTreeMap;
 
+use std::io::{self, BufReader, Read, Write};
+
+use std::path::Path;
+
 use std::sync::Arc;
 
-use core_traits::{Entid, StructuredMap, TypedValue};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rusqlite::backup::{Backup, StepResult};
+
+use core_traits::{Binding, Entid, StructuredMap, TypedValue};
 
 use mentat_core::{Keyword, TxReport, ValueRc};
-use mentat_db::TxObserver;
+use mentat_db::branches::{self, Branch};
+use mentat_db::cache::AttributeCacheStats;
+use mentat_db::db;
+use mentat_db::{AttributeSet, TxObserver, VerifyReport};
 
 use mentat_transaction::{
-    CacheAction, CacheDirection, InProgress, InProgressRead, Pullable, Queryable,
+    CacheAction, CacheDirection, CacheWarming, Diff, HistoryCutoff, InProgress, InProgressRead,
+    LiveQueryDiff, PruneReport, Pullable, Queryable, TxReportReceiver,
 };
 
 use crate::conn::Conn;
+use crate::export::{self, ExportEntids, ExportReport};
+#[cfg(feature = "json")]
+use crate::json;
 
-use public_traits::errors::Result;
+use public_traits::errors::{MentatError, Result};
 
 use mentat_transaction::query::{PreparedResult, QueryExplanation, QueryInputs, QueryOutput};
 
 #[cfg(feature = "syncable")]
-use mentat_tolstoy::{SyncFollowup, SyncReport, SyncResult};
+use uuid::Uuid;
+
+#[cfg(feature = "syncable")]
+use mentat_tolstoy::{
+    ConflictResolver, GlobalTransactionLog, LocalWins, RemoteClient, SyncFilter, SyncFollowup,
+    SyncReport, SyncResult,
+};
+
+#[cfg(feature = "sync_encryption")]
+use mentat_tolstoy::{EncryptedLog, SyncKeys};
 
 #[cfg(feature = "syncable")]
 use crate::sync::Syncable;
 
+/// The number of pages a backup or restore copies at a time. Other connections can read and write
+/// between steps.
+const BACKUP_PAGES_PER_STEP: i32 = 256;
+
+/// How long to wait before retrying a backup step that couldn't get the locks it needed.
+const BACKUP_RETRY_DELAY: Duration = Duration::from_millis(10);
+
+/// How long a backup or restore keeps retrying a step that can't get the locks it needed before
+/// giving up.
+const BACKUP_BUSY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How far a backup has got, in database pages.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct BackupProgress {
+    pub remaining: usize,
+    pub total: usize,
+}
+
+/// Copy the database in `from` over the one in `to`, a few pages at a time. If another connection
+/// writes to `from` in the meantime SQLite starts the copy again, and writes made through `from`
+/// itself are carried across, so `to` always ends up with a consistent snapshot. If a step can't
+/// get the locks it needs for `BACKUP_BUSY_TIMEOUT`, the copy is abandoned and `to` is untouched.
+fn copy_database<F>(
+    from: &rusqlite::Connection,
+    to: &mut rusqlite::Connection,
+    mut progress: F,
+) -> Result<()>
+where
+    F: FnMut(BackupProgress),
+{
+    let backup = Backup::new(from, to)?;
+    let mut busy_since: Option<Instant> = None;
+    loop {
+        match backup.step(BACKUP_PAGES_PER_STEP)? {
+            StepResult::Done => break,
+            StepResult::More => busy_since = None,
+            _ => {
+                let since = *busy_since.get_or_insert_with(Instant::now);
+                if since.elapsed() >= BACKUP_BUSY_TIMEOUT {
+                    bail!(MentatError::BackupBusy(BACKUP_BUSY_TIMEOUT));
+                }
+                thread::sleep(BACKUP_RETRY_DELAY);
+            }
+        }
+        let step = backup.progress();
+        progress(BackupProgress {
+            remaining: step.remaining as usize,
+            total: step.pagecount as usize,
+        });
+    }
+    progress(BackupProgress {
+        remaining: 0,
+        total: backup.progress().pagecount as usize,
+    });
+    Ok(())
+}
+
 /// A convenience wrapper around a single SQLite connection and a Conn. This is suitable
 /// for applications that don't require complex connection management.
 pub struct Store {
     conn: Conn,
     sqlite: rusqlite::Connection,
+
+    /// The key the store was opened with, which its backups share.
+    #[cfg(feature = "sqlcipher")]
+    encryption_key: Option<String>,
 }
 
 impl Store {
-    /// Open a store at the supplied path, ensuring that it includes the bootstrap schema.
+    /// Open a store at the supplied path, ensuring that it includes the bootstrap schema, and
+    /// rebuild the attribute caches it had registered.
     pub fn open(path: &str) -> Result<Store> {
+        Store::open_with_cache_warming(path, CacheWarming::Synchronous)
+    }
+
+    /// Variant of `open` that can rebuild attribute caches in the background. In-memory stores
+    /// always rebuild synchronously, since a second connection can't see them.
+    pub fn open_with_cache_warming(path: &str, warming: CacheWarming) -> Result<Store> {
         let mut connection = crate::new_connection(path)?;
-        let conn = Conn::connect(&mut connection)?;
+        let mut conn = Conn::connect(&mut connection)?;
+        if warming == CacheWarming::Background && !path.is_empty() {
+            let path = path.to_string();
+            conn.restore_caches_in_background(&connection, move || {
+                crate::new_connection(&path).map_err(|e| e.into())
+            })?;
+        } else {
+            conn.restore_caches(&connection)?;
+        }
         Ok(Store {
             conn,
             sqlite: connection,
+            #[cfg(feature = "sqlcipher")]
+            encryption_key: None,
         })
     }
 
+    /// Block until attribute caches being rebuilt in the background are in use.
+    pub fn wait_for_caches(&mut self) -> Result<()> {
+        self.conn
+            .finish_cache_warming(&self.sqlite, true)
+            .map(|_| ())
+    }
+
     pub fn transact(&mut self, transaction: &str) -> Result<TxReport> {
         let mut ip = self.begin_transaction()?;
         let report = ip.transact(transaction)?;
@@ -60,16 +170,164 @@ impl Store {
         Ok(report)
     }
 
+    /// Transact entity maps written in JSON. See `mentat::json` for the mapping.
+    #[cfg(feature = "json")]
+    pub fn transact_json(&mut self, transaction: &str) -> Result<TxReport> {
+        let mut ip = self.begin_transaction()?;
+        let entities = json::entities_from_json(&ip, transaction)?;
+        let report = ip.transact_entities(entities)?;
+        ip.commit()?;
+        Ok(report)
+    }
+
+    /// Remove history before `cutoff` from the transaction log. See `InProgress::prune_history`.
+    pub fn prune_history<C>(&mut self, cutoff: C) -> Result<PruneReport>
+    where
+        C: Into<HistoryCutoff>,
+    {
+        let mut ip = self.begin_transaction()?;
+        let report = ip.prune_history(cutoff)?;
+        ip.commit()?;
+        Ok(report)
+    }
+
+    /// The net change in the store's datoms from transaction `from` to transaction `to`. See
+    /// `InProgress::diff`.
+    pub fn diff(&mut self, from: Entid, to: Entid) -> Result<Diff> {
+        let read = self.begin_read()?;
+        read.diff(from, to)
+    }
+
+    /// Take the datoms in `diff` back to how they were after `diff.from`, in a new transaction.
+    /// See `InProgress::revert`.
+    pub fn revert(&mut self, diff: &Diff) -> Result<TxReport> {
+        let mut ip = self.begin_transaction()?;
+        let report = ip.revert(diff)?;
+        ip.commit()?;
+        Ok(report)
+    }
+
+    /// Undo the last `n` transactions. See `InProgress::undo`.
+    pub fn undo(&mut self, n: usize) -> Result<Vec<Entid>> {
+        let mut ip = self.begin_transaction()?;
+        let undone = ip.undo(n)?;
+        ip.commit()?;
+        Ok(undone)
+    }
+
+    /// Redo the last `n` undone transactions. See `InProgress::redo`.
+    pub fn redo(&mut self, n: usize) -> Result<Vec<Entid>> {
+        let mut ip = self.begin_transaction()?;
+        let redone = ip.redo(n)?;
+        ip.commit()?;
+        Ok(redone)
+    }
+
+    /// Fork a branch called `name` from the main branch at transaction `base`. See
+    /// `InProgress::fork_branch`.
+    pub fn fork_branch(&mut self, name: &str, base: Entid) -> Result<()> {
+        let mut ip = self.begin_transaction()?;
+        ip.fork_branch(name, base)?;
+        ip.commit()
+    }
+
+    /// Check out the branch called `name`. See `InProgress::switch_branch`.
+    pub fn switch_branch(&mut self, name: &str) -> Result<()> {
+        let mut ip = self.begin_transaction()?;
+        ip.switch_branch(name)?;
+        ip.commit()
+    }
+
+    /// Merge the branch called `name` into the main branch. See `InProgress::merge_branch`.
+    pub fn merge_branch(&mut self, name: &str) -> Result<Vec<TxReport>> {
+        let mut ip = self.begin_transaction()?;
+        let reports = ip.merge_branch(name)?;
+        ip.commit()?;
+        Ok(reports)
+    }
+
+    /// Delete the branch called `name`. See `InProgress::delete_branch`.
+    pub fn delete_branch(&mut self, name: &str) -> Result<()> {
+        let mut ip = self.begin_transaction()?;
+        ip.delete_branch(name)?;
+        ip.commit()
+    }
+
+    /// List the store's branches, other than the main branch.
+    pub fn branches(&mut self) -> Result<Vec<Branch>> {
+        let read = self.begin_read()?;
+        Ok(branches::branches(&read.in_progress.transaction)?)
+    }
+
+    /// The name of the checked out branch.
+    pub fn current_branch(&mut self) -> Result<String> {
+        let read = self.begin_read()?;
+        Ok(branches::current_branch(&read.in_progress.transaction)?)
+    }
+
     #[cfg(feature = "syncable")]
     pub fn sync(&mut self, server_uri: &str, user_uuid: &str) -> Result<SyncResult> {
+        let mut remote_client =
+            RemoteClient::new(server_uri.to_string(), Uuid::parse_str(user_uuid)?)?;
+        self.sync_with(&mut remote_client)
+    }
+
+    /// Sync against the server at `server_uri` as `sync` does, sealing everything uploaded with
+    /// `keys` so that the server never sees a datom.
+    #[cfg(feature = "sync_encryption")]
+    pub fn sync_encrypted(
+        &mut self,
+        server_uri: &str,
+        user_uuid: &str,
+        keys: SyncKeys,
+    ) -> Result<SyncResult> {
+        let remote_client = RemoteClient::new(server_uri.to_string(), Uuid::parse_str(user_uuid)?)?;
+        self.sync_with(&mut EncryptedLog::new(remote_client, keys))
+    }
+
+    /// Sync against `log`, which need not be a sync server: a `mentat_tolstoy::DirectoryLog`
+    /// syncs through a shared directory.
+    #[cfg(feature = "syncable")]
+    pub fn sync_with<R>(&mut self, log: &mut R) -> Result<SyncResult>
+    where
+        R: GlobalTransactionLog,
+    {
+        self.sync_with_filter(log, &SyncFilter::new())
+    }
+
+    /// Sync against `log`, keeping the attributes `filter` excludes, such as device-local
+    /// preferences, in this store only.
+    ///
+    /// Local changes are replayed over remote ones, so where both changed a value, the local one
+    /// is kept. Use `sync_with_resolver` to resolve conflicts another way.
+    #[cfg(feature = "syncable")]
+    pub fn sync_with_filter<R>(&mut self, log: &mut R, filter: &SyncFilter) -> Result<SyncResult>
+    where
+        R: GlobalTransactionLog,
+    {
+        self.sync_with_resolver(log, filter, &LocalWins)
+    }
+
+    /// Sync against `log` as `sync_with_filter` does, resolving conflicts between local and
+    /// remote changes with `resolver`. Each merge reports the conflicts it resolved.
+    #[cfg(feature = "syncable")]
+    pub fn sync_with_resolver<R>(
+        &mut self,
+        log: &mut R,
+        filter: &SyncFilter,
+        resolver: &dyn ConflictResolver,
+    ) -> Result<SyncResult>
+    where
+        R: GlobalTransactionLog,
+    {
         let mut reports = vec![];
         loop {
             let mut ip = self.begin_transaction()?;
-            let report = ip.sync(server_uri, user_uuid)?;
+            let report = ip.sync_with_resolver(log, filter, resolver)?;
             ip.commit()?;
 
             match report {
-                SyncReport::Merge(SyncFollowup::FullSync) => {
+                SyncReport::Merge(SyncFollowup::FullSync, _) => {
                     reports.push(report);
                     continue;
                 }
@@ -94,10 +352,12 @@ impl Store {
     /// supports the Sqlite Encryption Extension).
     pub fn open_with_key(path: &str, encryption_key: &str) -> Result<Store> {
         let mut connection = crate::new_connection_with_key(path, encryption_key)?;
-        let conn = Conn::connect(&mut connection)?;
+        let mut conn = Conn::connect(&mut connection)?;
+        conn.restore_caches(&connection)?;
         Ok(Store {
             conn,
             sqlite: connection,
+            encryption_key: Some(encryption_key.to_string()),
         })
     }
 
@@ -106,6 +366,7 @@ impl Store {
     /// Encryption Extension).
     pub fn change_encryption_key(&mut self, new_encryption_key: &str) -> Result<()> {
         crate::change_encryption_key(&self.sqlite, new_encryption_key)?;
+        self.encryption_key = Some(new_encryption_key.to_string());
         Ok(())
     }
 }
@@ -127,6 +388,84 @@ impl Store {
 }
 
 impl Store {
+    /// Open the backup at `path` with this store's key, if it has one.
+    fn open_backup(&self, path: &Path) -> Result<rusqlite::Connection> {
+        #[cfg(feature = "sqlcipher")]
+        {
+            if let Some(ref key) = self.encryption_key {
+                return Ok(crate::new_connection_with_key(path, key)?);
+            }
+        }
+        Ok(rusqlite::Connection::open(path)?)
+    }
+
+    /// Write a consistent copy of this store to `path`, replacing anything already there, using
+    /// SQLite's online backup API. Other connections to the store can keep reading and writing
+    /// while the copy is made. `progress` is called after each step. A store opened with a key is
+    /// backed up encrypted with the same key.
+    pub fn backup_to<P, F>(&self, path: P, progress: F) -> Result<()>
+    where
+        P: AsRef<Path>,
+        F: FnMut(BackupProgress),
+    {
+        let mut destination = self.open_backup(path.as_ref())?;
+        copy_database(&self.sqlite, &mut destination, progress)
+    }
+
+    /// Replace the contents of this store with the backup at `path`, then reload the schema,
+    /// partition map, and attribute caches from it. Nothing changes unless `path` holds a Mentat
+    /// store of the current version.
+    pub fn restore_from<P>(&mut self, path: P) -> Result<()>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no backup at {}", path.display()),
+            )
+            .into());
+        }
+
+        let source = self.open_backup(path)?;
+        // Older stores are upgraded once restored.
+        let version = db::get_user_version(&source)?;
+        if !(1..=db::CURRENT_VERSION).contains(&version) {
+            bail!(MentatError::UnexpectedStoreVersion(
+                db::CURRENT_VERSION,
+                version
+            ));
+        }
+        // Make sure we can make sense of the backup before we overwrite anything.
+        db::read_db(&source)?;
+
+        copy_database(&source, &mut self.sqlite, |_| ())?;
+        self.conn.reload_metadata(&mut self.sqlite)
+    }
+
+    /// Write every transaction in this store to `writer` as an EDN transaction log, from a single
+    /// consistent read. See `mentat::export` for the format.
+    pub fn export<W: Write>(&mut self, writer: W, entids: ExportEntids) -> Result<ExportReport> {
+        let read = self.begin_read()?;
+        export::export(&read, writer, entids)
+    }
+
+    /// Replay an export made by `export` into this store, which must be empty. Either all of it is
+    /// imported and matches what the export says it holds, or none of it is.
+    pub fn import<R: Read>(&mut self, reader: R) -> Result<ExportReport> {
+        let mut in_progress = self.begin_transaction()?;
+        let report = export::import(&mut in_progress, BufReader::new(reader))?;
+        in_progress.commit()?;
+        Ok(report)
+    }
+
+    /// Check the integrity of this store from a single consistent read. See `mentat_db::verify`.
+    pub fn verify(&mut self) -> Result<VerifyReport> {
+        let read = self.begin_read()?;
+        Ok(mentat_db::verify(&read.in_progress.transaction)?)
+    }
+
     pub fn dismantle(self) -> (rusqlite::Connection, Conn) {
         (self.sqlite, self.conn)
     }
@@ -154,6 +493,16 @@ impl Store {
         )
     }
 
+    /// Return the size and hit rate of the cache of each cached attribute.
+    pub fn attribute_cache_stats(&self) -> BTreeMap<Entid, AttributeCacheStats> {
+        self.conn.attribute_cache_stats()
+    }
+
+    /// Bound the memory used by attribute caches. See `Conn::set_attribute_cache_budget`.
+    pub fn set_attribute_cache_budget(&self, budget: Option<usize>) {
+        self.conn.set_attribute_cache_budget(budget);
+    }
+
     pub fn register_observer(&mut self, key: String, observer: Arc<TxObserver>) {
         self.conn.register_observer(key, observer);
     }
@@ -162,6 +511,35 @@ impl Store {
         self.conn.unregister_observer(key);
     }
 
+    /// Run `query`, returning its results, and call `callback` with the rows added and removed
+    /// by each later commit that changes them. See `Conn::register_live_query`.
+    pub fn register_live_query<T, F>(
+        &mut self,
+        key: String,
+        query: &str,
+        inputs: T,
+        callback: F,
+    ) -> Result<Vec<Vec<Binding>>>
+    where
+        T: Into<Option<QueryInputs>>,
+        F: Fn(&str, Result<LiveQueryDiff>) + Send + Sync + 'static,
+    {
+        self.conn
+            .register_live_query(&mut self.sqlite, key, query, inputs, callback)
+    }
+
+    pub fn unregister_live_query(&mut self, key: &str) {
+        self.conn.unregister_live_query(key);
+    }
+
+    /// Receive every transaction committed from now on. See `Conn::tx_report_queue`.
+    pub fn tx_report_queue<T>(&self, capacity: usize, attributes: T) -> Result<TxReportReceiver>
+    where
+        T: Into<Option<AttributeSet>>,
+    {
+        self.conn.tx_report_queue(capacity, attributes)
+    }
+
     pub fn last_tx_id(&self) -> Entid {
         self.conn.last_tx_id()
     }
@@ -253,7 +631,9 @@ mod tests {
 
     use core_traits::{TypedValue, ValueType};
 
-    use mentat_core::{CachedAttributes, HasSchema};
+    use mentat_core::{CachedAttributes, DatomsView, HasSchema};
+
+    use edn::query::SrcVar;
 
     use mentat_transaction::entity_builder::BuildTerms;
 
@@ -261,6 +641,15 @@ mod tests {
 
     use mentat_query_algebrizer::QueryInputs;
 
+    use query_algebrizer_traits::errors::AlgebrizerError;
+
+    use futures::executor::block_on;
+    use futures::stream::StreamExt;
+
+    use mentat_transaction::{TxDatom, TxReportQueueError};
+
+    use rusqlite::TransactionBehavior;
+
     use crate::vocabulary::{AttributeBuilder, Definition, VersionedStore};
 
     use core_traits::attribute::Unique;
@@ -270,6 +659,26 @@ mod tests {
         fixtures.join(Path::new(rest))
     }
 
+    /// A store file in the temporary directory, removed along with its WAL files when dropped,
+    /// even if the test fails.
+    struct TempStore(String);
+
+    impl TempStore {
+        fn new(name: &str) -> TempStore {
+            let path =
+                ::std::env::temp_dir().join(format!("mentat-{}-{}.db", name, Uuid::new_v4()));
+            TempStore(path.to_str().expect("path").to_string())
+        }
+    }
+
+    impl Drop for TempStore {
+        fn drop(&mut self) {
+            for suffix in &["", "-wal", "-shm"] {
+                let _ = ::std::fs::remove_file(format!("{}{}", self.0, suffix));
+            }
+        }
+    }
+
     #[test]
     fn test_prepared_query_with_cache() {
         let mut store = Store::open("").expect("opened");
@@ -862,4 +1271,1221 @@ mod tests {
         assert_eq!(o.txids, tx_ids);
         assert_eq!(o.changes, changesets);
     }
+
+    #[test]
+    fn test_live_query() {
+        let mut store = Store::open("").unwrap();
+        add_schema(&mut store);
+
+        let (tx, rx) = mpsc::channel();
+        let thread_tx = Mutex::new(tx);
+        let initial = store
+            .register_live_query(
+                "colors".to_string(),
+                "[:find ?name ?color :where [?l :label/name ?name] [?l :label/color ?color]]",
+                None,
+                move |key, diff| {
+                    let diff = diff.expect("re-ran live query");
+                    thread_tx
+                        .lock()
+                        .unwrap()
+                        .send((key.to_string(), diff))
+                        .unwrap();
+                },
+            )
+            .expect("registered");
+        assert!(initial.is_empty());
+
+        let delay = Duration::from_millis(500);
+        let row = |name: &str, color: &str| -> Vec<Binding> {
+            vec![
+                TypedValue::typed_string(name).into(),
+                TypedValue::typed_string(color).into(),
+            ]
+        };
+
+        let work = *store
+            .transact(r#"[[:db/add "w" :label/name "Work"] [:db/add "w" :label/color "red"]]"#)
+            .expect("transacted")
+            .tempids
+            .get("w")
+            .expect("tempid");
+        let (key, diff) = rx.recv_timeout(delay).expect("notified");
+        assert_eq!(key, "colors");
+        assert_eq!(diff.added, vec![row("Work", "red")]);
+        assert!(diff.removed.is_empty());
+
+        // Attributes the query doesn't depend on don't trigger it…
+        store
+            .transact(r#"[{:todo/name "Shop"}]"#)
+            .expect("transacted");
+        // … and nor do changes that leave the results unchanged, or that are rolled back.
+        store
+            .transact(r#"[[:db/add "x" :label/color "green"]]"#)
+            .expect("transacted");
+        {
+            let mut in_progress = store.begin_transaction().expect("began");
+            in_progress
+                .transact(r#"[{:label/name "Home" :label/color "blue"}]"#)
+                .expect("transacted");
+            in_progress.rollback().expect("rolled back");
+        }
+        assert!(rx.recv_timeout(delay).is_err());
+
+        store
+            .transact(format!(r#"[[:db/add {} :label/color "orange"]]"#, work).as_str())
+            .expect("transacted");
+        let (_, diff) = rx.recv_timeout(delay).expect("notified");
+        assert_eq!(diff.added, vec![row("Work", "orange")]);
+        assert_eq!(diff.removed, vec![row("Work", "red")]);
+
+        store.unregister_live_query("colors");
+        store
+            .transact(r#"[{:label/name "Home" :label/color "blue"}]"#)
+            .expect("transacted");
+        assert!(rx.recv_timeout(delay).is_err());
+    }
+
+    #[test]
+    fn test_live_query_after_metadata_replaced() {
+        let path = TempStore::new("live");
+
+        let mut ours = Store::open(&path.0).expect("opened");
+        add_schema(&mut ours);
+        let mut theirs = Store::open(&path.0).expect("opened");
+
+        let (tx, rx) = mpsc::channel();
+        let thread_tx = Mutex::new(tx);
+        ours.register_live_query(
+            "names".to_string(),
+            "[:find [?name ...] :where [_ :label/name ?name]]",
+            None,
+            move |_, diff| {
+                let diff = diff.expect("re-ran live query");
+                thread_tx.lock().unwrap().send(diff).unwrap();
+            },
+        )
+        .expect("registered");
+
+        let delay = Duration::from_millis(500);
+        let name = |name: &str| -> Vec<Binding> { vec![TypedValue::typed_string(name).into()] };
+
+        // Noticing another connection's writes re-runs live queries.
+        theirs
+            .transact(r#"[{:label/name "Theirs"}]"#)
+            .expect("transacted");
+        assert!(rx.recv_timeout(delay).is_err());
+        drop(ours.begin_read().expect("began"));
+        assert_eq!(
+            rx.recv_timeout(delay).expect("notified").added,
+            vec![name("Theirs")]
+        );
+
+        // So does undo.
+        ours.transact(r#"[{:label/name "Ours"}]"#)
+            .expect("transacted");
+        assert_eq!(
+            rx.recv_timeout(delay).expect("notified").added,
+            vec![name("Ours")]
+        );
+        ours.undo(1).expect("undone");
+        assert_eq!(
+            rx.recv_timeout(delay).expect("notified").removed,
+            vec![name("Ours")]
+        );
+    }
+
+    #[test]
+    fn test_live_query_diff() {
+        let row = |v: i64| -> Vec<Binding> { vec![TypedValue::Long(v).into()] };
+        let diff =
+            LiveQueryDiff::between(&[row(1), row(2), row(2), row(3)], &[row(3), row(2), row(4)]);
+        assert_eq!(diff.added, vec![row(4)]);
+        assert_eq!(diff.removed, vec![row(1), row(2)]);
+    }
+
+    #[test]
+    fn test_tx_report_queue() {
+        let mut store = Store::open("").unwrap();
+        add_schema(&mut store);
+
+        let color = store
+            .conn()
+            .current_schema()
+            .get_entid(&kw!(:label/color))
+            .expect("entid")
+            .0;
+        let everything = store.tx_report_queue(2, None).expect("queue");
+        let mut colors = store
+            .tx_report_queue(10, Some(vec![color].into_iter().collect()))
+            .expect("queue");
+        match store.tx_report_queue(0, None) {
+            Err(MentatError::ZeroQueueCapacity) => {}
+            _ => panic!("expected a zero-capacity queue to be refused"),
+        }
+
+        let report = store
+            .transact(r#"[[:db/add "w" :label/name "Work"] [:db/add "w" :label/color "red"]]"#)
+            .expect("transacted");
+        let work = *report.tempids.get("w").expect("tempid");
+
+        let committed = everything.try_recv().expect("queued");
+        assert_eq!(committed.report, report);
+        assert_eq!(committed.datoms.len(), 3); // Including the :db/txInstant.
+        assert!(committed.datoms.contains(&TxDatom {
+            e: work,
+            a: color,
+            v: TypedValue::typed_string("red"),
+            added: true,
+        }));
+
+        let committed = colors.try_recv().expect("queued");
+        assert_eq!(committed.report.tx_id, report.tx_id);
+        assert_eq!(committed.datoms.len(), 1);
+
+        // Unrelated and rolled back transactions are filtered out.
+        store
+            .transact(r#"[{:todo/name "Shop"}]"#)
+            .expect("transacted");
+        {
+            let mut in_progress = store.begin_transaction().expect("began");
+            in_progress
+                .transact(r#"[{:label/name "Home" :label/color "blue"}]"#)
+                .expect("transacted");
+            in_progress.rollback().expect("rolled back");
+        }
+        assert_eq!(colors.try_recv(), Err(TxReportQueueError::Empty));
+
+        // Each transaction in a commit is queued separately, in order, with implied retractions.
+        let (first, second) = {
+            let mut in_progress = store.begin_transaction().expect("began");
+            let first = in_progress
+                .transact(format!(r#"[[:db/add {} :label/color "orange"]]"#, work))
+                .expect("transacted");
+            let second = in_progress
+                .transact(format!(r#"[[:db/add {} :label/color "green"]]"#, work))
+                .expect("transacted");
+            in_progress.commit().expect("committed");
+            (first, second)
+        };
+        let committed = colors.try_recv().expect("queued");
+        assert_eq!(committed.report, first);
+        assert_eq!(
+            committed.datoms,
+            vec![
+                TxDatom {
+                    e: work,
+                    a: color,
+                    v: TypedValue::typed_string("orange"),
+                    added: true,
+                },
+                TxDatom {
+                    e: work,
+                    a: color,
+                    v: TypedValue::typed_string("red"),
+                    added: false,
+                },
+            ]
+        );
+        let committed = block_on(colors.next()).expect("streamed").expect("queued");
+        assert_eq!(committed.report, second);
+
+        // The unfiltered queue only holds two transactions, and says how many it dropped.
+        assert_eq!(everything.try_recv(), Err(TxReportQueueError::Lagged(1)));
+        assert_eq!(everything.try_recv().expect("queued").report, first);
+        assert_eq!(everything.try_recv().expect("queued").report, second);
+        assert_eq!(everything.try_recv(), Err(TxReportQueueError::Empty));
+
+        // Queues opened while a transaction is in progress receive it once it commits.
+        let late = {
+            let mut in_progress = store
+                .conn
+                .begin_shared(&mut store.sqlite, TransactionBehavior::Immediate, true)
+                .expect("began");
+            in_progress
+                .transact(format!(r#"[[:db/add {} :label/color "pink"]]"#, work))
+                .expect("transacted");
+            let late = store.conn.tx_report_queue(1, None).expect("queue");
+            in_progress.commit().expect("committed");
+            late
+        };
+        assert!(late.try_recv().is_ok());
+        assert!(colors.try_recv().is_ok());
+        assert!(everything.try_recv().is_ok());
+
+        // Undo isn't a transaction: queues are told the store's contents were replaced.
+        store.undo(1).expect("undone");
+        assert_eq!(late.try_recv(), Err(TxReportQueueError::Replaced));
+        assert_eq!(late.try_recv(), Err(TxReportQueueError::Empty));
+        assert_eq!(colors.try_recv(), Err(TxReportQueueError::Replaced));
+        assert_eq!(everything.try_recv(), Err(TxReportQueueError::Replaced));
+
+        // Queues close when the store goes away.
+        let waiting = ::std::thread::spawn(move || block_on(colors.next()));
+        drop(store);
+        assert!(waiting.join().expect("joined").is_none());
+        assert_eq!(everything.recv(), Err(TxReportQueueError::Closed));
+    }
+
+    #[test]
+    fn test_cache_registrations_persist() {
+        let store_file = TempStore::new("caches");
+        let path = &store_file.0;
+
+        let (foo_bar, foo_baz, foo_x) = {
+            let mut store = Store::open(path).expect("opened");
+            store
+                .transact(
+                    r#"[
+                {  :db/ident       :foo/bar
+                   :db/cardinality :db.cardinality/one
+                   :db/index       true
+                   :db/unique      :db.unique/identity
+                   :db/valueType   :db.type/long },
+                {  :db/ident       :foo/baz
+                   :db/cardinality :db.cardinality/one
+                   :db/valueType   :db.type/boolean }
+                {  :db/ident       :foo/x
+                   :db/cardinality :db.cardinality/many
+                   :db/valueType   :db.type/long }]"#,
+                )
+                .expect("transact");
+            store
+                .transact(r#"[{:foo/bar 1 :foo/baz true :foo/x 7}]"#)
+                .expect("transact");
+
+            store
+                .cache(&kw!(:foo/bar), CacheDirection::Forward)
+                .expect("cache done");
+            store
+                .cache(&kw!(:foo/baz), CacheDirection::Both)
+                .expect("cache done");
+            {
+                let mut in_progress = store.begin_transaction().expect("began");
+                in_progress
+                    .cache(
+                        &kw!(:foo/bar),
+                        CacheDirection::Reverse,
+                        CacheAction::Register,
+                    )
+                    .expect("cache done");
+                in_progress.commit().expect("committed");
+            }
+            {
+                // Rolled back, so never recorded.
+                let mut in_progress = store.begin_transaction().expect("began");
+                in_progress
+                    .cache(&kw!(:foo/x), CacheDirection::Forward, CacheAction::Register)
+                    .expect("cache done");
+                in_progress.rollback().expect("rolled back");
+            }
+            let schema = store.conn.current_schema();
+            store
+                .conn
+                .cache(
+                    &mut store.sqlite,
+                    &schema,
+                    &kw!(:foo/baz),
+                    CacheDirection::Both,
+                    CacheAction::Deregister,
+                )
+                .expect("uncached");
+
+            (
+                schema.get_entid(&kw!(:foo/bar)).expect("foo/bar").0,
+                schema.get_entid(&kw!(:foo/baz)).expect("foo/baz").0,
+                schema.get_entid(&kw!(:foo/x)).expect("foo/x").0,
+            )
+        };
+
+        let check = |store: &Store| {
+            assert!(store.is_attribute_cached_forward(foo_bar));
+            assert!(store.is_attribute_cached_reverse(foo_bar));
+            assert!(!store.is_attribute_cached_forward(foo_baz));
+            assert!(!store.is_attribute_cached_forward(foo_x));
+            assert_eq!(
+                store.conn.current_cache().get_value_for_entid(
+                    &store.conn.current_schema(),
+                    foo_bar,
+                    store
+                        .conn
+                        .current_cache()
+                        .get_entid_for_value(foo_bar, &TypedValue::Long(1))
+                        .expect("entity"),
+                ),
+                Some(&TypedValue::Long(1))
+            );
+        };
+
+        check(&Store::open(path).expect("reopened"));
+
+        let mut store =
+            Store::open_with_cache_warming(path, CacheWarming::Background).expect("reopened");
+        // Queries work whether or not the caches are ready.
+        assert_eq!(
+            store
+                .q_once("[:find ?x . :where [_ :foo/bar 1] [_ :foo/x ?x]]", None)
+                .expect("query")
+                .into_scalar()
+                .expect("scalar"),
+            Some(TypedValue::Long(7).into())
+        );
+        store.wait_for_caches().expect("warmed");
+        check(&store);
+    }
+
+    #[test]
+    fn test_backup_and_restore() {
+        let backup = TempStore::new("backup");
+        let count = |store: &Store| {
+            store
+                .q_once("[:find (count ?e) . :where [?e :foo/bar _]]", None)
+                .expect("query")
+                .into_scalar()
+                .expect("scalar")
+        };
+
+        let mut store = Store::open("").expect("opened");
+        store
+            .transact(
+                r#"[{:db/ident       :foo/bar
+                     :db/cardinality :db.cardinality/one
+                     :db/index       true
+                     :db/unique      :db.unique/identity
+                     :db/valueType   :db.type/long}]"#,
+            )
+            .expect("transact");
+        store
+            .transact(r#"[{:foo/bar 1} {:foo/bar 2}]"#)
+            .expect("transact");
+        store
+            .cache(&kw!(:foo/bar), CacheDirection::Reverse)
+            .expect("cache done");
+
+        let mut steps = vec![];
+        store
+            .backup_to(&backup.0, |progress| steps.push(progress))
+            .expect("backed up");
+        let last = steps.last().cloned().expect("progress");
+        assert_eq!(last.remaining, 0);
+        assert!(last.total > 0);
+
+        // Move on past the backup: new data, new schema, no caches.
+        store
+            .transact(
+                r#"[{:db/ident       :foo/baz
+                     :db/cardinality :db.cardinality/one
+                     :db/valueType   :db.type/boolean}
+                    {:foo/bar 3}]"#,
+            )
+            .expect("transact");
+        let schema = store.conn.current_schema();
+        store
+            .conn
+            .cache(
+                &mut store.sqlite,
+                &schema,
+                &kw!(:foo/bar),
+                CacheDirection::Reverse,
+                CacheAction::Deregister,
+            )
+            .expect("uncached");
+        assert_eq!(count(&store), Some(TypedValue::Long(3).into()));
+
+        let foo_bar = store
+            .conn
+            .current_schema()
+            .get_entid(&kw!(:foo/bar))
+            .expect("foo/bar")
+            .0;
+        let (sender, receiver) = mpsc::channel();
+        let sender = Mutex::new(sender);
+        store.register_observer(
+            "restore".to_string(),
+            Arc::new(TxObserver::new(
+                ::std::iter::once(foo_bar).collect(),
+                move |_, _| sender.lock().unwrap().send(()).unwrap(),
+            )),
+        );
+
+        store.restore_from(&backup.0).expect("restored");
+        receiver
+            .recv_timeout(Duration::from_secs(1))
+            .expect("notified of restore");
+        assert_eq!(count(&store), Some(TypedValue::Long(2).into()));
+        let schema = store.conn.current_schema();
+        assert!(schema.get_entid(&kw!(:foo/baz)).is_none());
+        assert_eq!(
+            schema.get_entid(&kw!(:foo/bar)).expect("foo/bar").0,
+            foo_bar
+        );
+        assert!(store.is_attribute_cached_reverse(foo_bar));
+
+        // The partition map came back too, so new entities don't collide with restored ones.
+        store.transact(r#"[{:foo/bar 3}]"#).expect("transact");
+        assert_eq!(count(&store), Some(TypedValue::Long(3).into()));
+
+        // Missing files and files that aren't Mentat stores are refused, and change nothing.
+        let missing = TempStore::new("missing");
+        match store.restore_from(&missing.0).expect_err("no backup") {
+            MentatError::IoError(_) => {}
+            e => panic!("expected an IO error, got {:?}", e),
+        }
+        assert!(!Path::new(&missing.0).exists());
+
+        let other = TempStore::new("other");
+        rusqlite::Connection::open(&other.0)
+            .expect("opened")
+            .execute_batch("CREATE TABLE t (x INTEGER)")
+            .expect("created");
+        match store.restore_from(&other.0).expect_err("not a store") {
+            MentatError::UnexpectedStoreVersion(expected, 0) if expected == db::CURRENT_VERSION => {
+            }
+            e => panic!("expected a version mismatch, got {:?}", e),
+        }
+        assert_eq!(count(&store), Some(TypedValue::Long(3).into()));
+    }
+
+    #[test]
+    fn test_prune_history() {
+        let store_file = TempStore::new("prune");
+        let path = &store_file.0;
+
+        let tx_data = |store: &Store, tx: Entid| {
+            let inputs = QueryInputs::with_value_sequence(vec![(var!(?tx), TypedValue::Ref(tx))]);
+            store
+                .q_once(
+                    "[:find ?e ?a ?v ?added :in ?tx :where [(tx-data $ ?tx) [[?e ?a ?v _ ?added]]]]",
+                    inputs,
+                )
+                .and_then(|output| Ok(output.into_rel()?))
+                .map(|rel| rel.row_count())
+        };
+
+        let (first, horizon) = {
+            let mut store = Store::open(path).expect("opened");
+            store
+                .transact(
+                    r#"[{:db/ident       :foo/bar
+                         :db/cardinality :db.cardinality/one
+                         :db/valueType   :db.type/long}]"#,
+                )
+                .expect("transact");
+            let first = store
+                .transact(r#"[{:db/id "e" :db/ident :foo/thing :foo/bar 1}]"#)
+                .expect("transact")
+                .tx_id;
+            store
+                .transact(r#"[{:db/id :foo/thing :foo/bar 2}]"#)
+                .expect("transact");
+            let horizon = store
+                .transact(r#"[{:db/id :foo/thing :foo/bar 3}]"#)
+                .expect("transact")
+                .tx_id;
+
+            // Plans made before pruning don't get to see pruned transactions.
+            assert_eq!(tx_data(&store, first).expect("tx-data"), 3);
+
+            let report = store.prune_history(horizon).expect("pruned");
+            assert_eq!(report.horizon, horizon);
+            // The assertion of 1, and its retraction.
+            assert_eq!(report.removed, 2);
+
+            assert_eq!(
+                store
+                    .q_once("[:find ?v . :where [:foo/thing :foo/bar ?v]]", None)
+                    .expect("query")
+                    .into_scalar()
+                    .expect("scalar"),
+                Some(TypedValue::Long(3).into())
+            );
+            (first, horizon)
+        };
+
+        let mut store = Store::open(path).expect("reopened");
+        match tx_data(&store, first).expect_err("pruned") {
+            MentatError::AlgebrizerError(AlgebrizerError::HistoryPruned(_, tx, h)) => {
+                assert_eq!((tx, h), (first, horizon));
+            }
+            e => panic!("expected pruned history, got {:?}", e),
+        }
+        // Retracting 2 and asserting 3.
+        assert_eq!(tx_data(&store, horizon).expect("tx-data"), 3);
+
+        // Transactions found while the query runs are limited to those after the horizon.
+        let results = store
+            .q_once(
+                "[:find [?tx ...] :where [(tx-ids $ 0 10000000000) [?tx ...]] [(tx-data $ ?tx) [[?e ?a]]] [?a :db/ident :foo/bar]]",
+                None,
+            )
+            .expect("query")
+            .into_coll()
+            .expect("coll");
+        assert_eq!(results, vec![TypedValue::Ref(horizon).into()]);
+
+        // The horizon doesn't move backwards.
+        let report = store.prune_history(first).expect("pruned");
+        assert_eq!(report.horizon, horizon);
+        assert_eq!(report.removed, 0);
+
+        // Pruning up to an instant after everything prunes everything.
+        let report = store
+            .prune_history(::core_traits::now() + ::chrono::Duration::days(1))
+            .expect("pruned");
+        assert_eq!(report.horizon, store.last_tx_id() + 1);
+        let horizon = report.horizon;
+        match tx_data(&store, horizon - 1).expect_err("pruned") {
+            MentatError::AlgebrizerError(AlgebrizerError::HistoryPruned(..)) => {}
+            e => panic!("expected pruned history, got {:?}", e),
+        }
+
+        let mut in_progress = store.begin_transaction().expect("began");
+        in_progress
+            .transact(r#"[{:db/id :foo/thing :foo/bar 4}]"#)
+            .expect("transact");
+        let tx = in_progress.last_tx_id();
+        assert_eq!(tx, horizon);
+        in_progress.commit().expect("committed");
+        assert_eq!(tx_data(&store, tx).expect("tx-data"), 3);
+    }
+
+    #[test]
+    fn test_export_and_import() {
+        let export = |store: &mut Store, entids: ExportEntids| {
+            let mut out = Vec::new();
+            let report = store.export(&mut out, entids).expect("exported");
+            (report, String::from_utf8(out).expect("utf-8"))
+        };
+
+        let mut store = Store::open("").expect("opened");
+        store
+            .transact(
+                r#"[{:db/ident       :foo/name
+                     :db/cardinality :db.cardinality/one
+                     :db/index       true
+                     :db/unique      :db.unique/identity
+                     :db/valueType   :db.type/string}
+                    {:db/ident       :foo/note
+                     :db/cardinality :db.cardinality/many
+                     :db/fulltext    true
+                     :db/index       true
+                     :db/valueType   :db.type/string}
+                    {:db/ident       :foo/weight
+                     :db/cardinality :db.cardinality/one
+                     :db/valueType   :db.type/double}
+                    {:db/ident       :foo/friend
+                     :db/cardinality :db.cardinality/many
+                     :db/valueType   :db.type/ref}
+                    {:db/ident       :foo/source
+                     :db/cardinality :db.cardinality/one
+                     :db/valueType   :db.type/ref}]"#,
+            )
+            .expect("transact");
+        store
+            .transact(
+                r#"[{:db/ident            :org.test/people
+                     :db.schema/version   1
+                     :db.schema/attribute [:foo/name :foo/note :foo/weight :foo/friend]}]"#,
+            )
+            .expect("transact");
+        let first = store
+            .transact(
+                r#"[{:db/id "a" :foo/name "Alice \"A\"\nSmith" :foo/weight 1.0 :foo/note "likes\ttabs"}
+                    {:db/id "b" :foo/name "Bob" :foo/friend "a" :foo/weight 72.5}]"#,
+            )
+            .expect("transact")
+            .tx_id;
+        store
+            .transact(
+                format!(
+                    r#"[[:db/retract (lookup-ref :foo/name "Bob") :foo/weight 72.5]
+                    [:db/add (lookup-ref :foo/name "Bob") :foo/source {}]
+                    [:db/add (transaction-tx) :foo/note "tx notes"]
+                    {{:db/id :foo/weight :db/ident :foo/mass}}]"#,
+                    first
+                )
+                .as_str(),
+            )
+            .expect("transact");
+        store
+            .transact(
+                r#"[{:foo/name "Carol" :foo/mass 60.0 :foo/friend (lookup-ref :foo/name "Bob")}]"#,
+            )
+            .expect("transact");
+
+        for &entids in &[ExportEntids::Preserve, ExportEntids::Tempids] {
+            let (report, exported) = export(&mut store, entids);
+            assert_eq!(report.transactions, 5);
+            assert_eq!(exported.lines().count(), 6);
+
+            let mut imported = Store::open("").expect("opened");
+            assert_eq!(
+                imported.import(exported.as_bytes()).expect("imported"),
+                report
+            );
+
+            // Exporting again gives back exactly what we imported.
+            assert_eq!(export(&mut imported, entids).1, exported);
+
+            let name = imported
+                .q_once(
+                    r#"[:find ?name . :where [?b :foo/name "Bob"] [?b :foo/friend ?a] [?a :foo/name ?name]]"#,
+                    None,
+                )
+                .expect("query")
+                .into_scalar()
+                .expect("scalar");
+            assert_eq!(
+                name,
+                Some(TypedValue::typed_string("Alice \"A\"\nSmith").into())
+            );
+            // References to transactions, and notes about them, survive.
+            let source = imported
+                .q_once(
+                    r#"[:find ?name . :where [?b :foo/name "Bob"] [?b :foo/source ?tx] [?a :foo/name ?name ?tx] [?b :foo/friend ?a]]"#,
+                    None,
+                )
+                .expect("query");
+            assert_eq!(source.into_scalar().expect("scalar"), name);
+            let note = imported
+                .q_once(
+                    r#"[:find ?note . :where [?tx :db/txInstant _] [?tx :foo/note ?note]]"#,
+                    None,
+                )
+                .expect("query");
+            assert_eq!(
+                note.into_scalar().expect("scalar"),
+                Some(TypedValue::typed_string("tx notes").into())
+            );
+            assert!(imported
+                .conn
+                .current_schema()
+                .get_entid(&kw!(:foo/weight))
+                .is_none());
+            assert_eq!(
+                imported
+                    .q_once(r#"[:find (sum ?m) . :where [_ :foo/mass ?m]]"#, None)
+                    .expect("query")
+                    .into_scalar()
+                    .expect("scalar"),
+                Some(TypedValue::Double(61.0.into()).into())
+            );
+
+            // Imports only go into empty stores.
+            match imported.import(exported.as_bytes()) {
+                Err(MentatError::ImportIntoNonEmptyStore(_)) => {}
+                x => panic!("expected a non-empty store, got {:?}", x),
+            }
+        }
+
+        // A mismatched count rolls the whole import back.
+        let (_, exported) = export(&mut store, ExportEntids::Preserve);
+        let tampered = exported.replacen(
+            ":mentat.export/transactions 5",
+            ":mentat.export/transactions 6",
+            1,
+        );
+        assert_ne!(tampered, exported);
+        let mut imported = Store::open("").expect("opened");
+        match imported.import(tampered.as_bytes()) {
+            Err(MentatError::ImportMismatch(_)) => {}
+            x => panic!("expected a mismatch, got {:?}", x),
+        }
+        assert_eq!(
+            imported.begin_read().expect("read").last_tx_id(),
+            ::mentat_db::TX0
+        );
+    }
+
+    #[test]
+    fn test_verify() {
+        let mut store = Store::open("").expect("opened");
+        store
+            .transact(
+                r#"[{:db/ident       :foo/count
+                     :db/cardinality :db.cardinality/one
+                     :db/valueType   :db.type/long}]"#,
+            )
+            .expect("transacted");
+        store.transact(r#"[{:foo/count 1}]"#).expect("transacted");
+        assert!(store.verify().expect("verified").is_ok());
+
+        let a = store
+            .conn()
+            .current_schema()
+            .get_entid(&kw!(:foo/count))
+            .expect("entid")
+            .0;
+        store
+            .sqlite_mut()
+            .execute("UPDATE datoms SET index_avet = 1 WHERE a = ?", &[&a])
+            .expect("corrupted");
+        let report = store.verify().expect("verified");
+        assert_eq!(
+            report.problems,
+            vec![mentat_db::Problem::IndexFlagMismatch {
+                a,
+                flag: mentat_db::verify::IndexFlag::AVET,
+                expected: false,
+                datoms: 1,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_undo_redo() {
+        let mut store = Store::open("").expect("opened");
+        store
+            .transact(
+                r#"[{:db/ident       :foo/count
+                     :db/cardinality :db.cardinality/one
+                     :db/valueType   :db.type/long}]"#,
+            )
+            .expect("transacted");
+        store
+            .cache(&kw!(:foo/count), CacheDirection::Forward)
+            .expect("cached");
+        let e = *store
+            .transact(r#"[{:db/id "e" :foo/count 1}]"#)
+            .expect("transacted")
+            .tempids
+            .get("e")
+            .expect("e");
+        let second = store
+            .transact(format!("[[:db/add {} :foo/count 2]]", e).as_str())
+            .expect("transacted")
+            .tx_id;
+
+        let a = store
+            .conn()
+            .current_schema()
+            .get_entid(&kw!(:foo/count))
+            .expect("entid")
+            .0;
+        let (sender, receiver) = mpsc::channel();
+        let sender = Mutex::new(sender);
+        store.register_observer(
+            "undo".to_string(),
+            Arc::new(TxObserver::new(
+                ::std::iter::once(a).collect(),
+                move |_, _| sender.lock().unwrap().send(()).unwrap(),
+            )),
+        );
+
+        let count = |store: &Store| store.lookup_value_for_attribute(e, &kw!(:foo/count));
+
+        assert_eq!(store.undo(1).expect("undone"), vec![second]);
+        assert_eq!(count(&store).expect("count"), Some(TypedValue::Long(1)));
+        assert_eq!(store.last_tx_id(), second - 1);
+        receiver
+            .recv_timeout(Duration::from_secs(1))
+            .expect("notified of undo");
+
+        assert_eq!(store.redo(1).expect("redone"), vec![second]);
+        assert_eq!(count(&store).expect("count"), Some(TypedValue::Long(2)));
+        assert_eq!(store.last_tx_id(), second);
+        receiver
+            .recv_timeout(Duration::from_secs(1))
+            .expect("notified of redo");
+
+        // Undoing the schema leaves nothing to refer to it.
+        assert_eq!(store.undo(3).expect("undone").len(), 3);
+        assert!(store
+            .conn()
+            .current_schema()
+            .get_entid(&kw!(:foo/count))
+            .is_none());
+        assert!(store.transact(r#"[{:foo/count 1}]"#).is_err());
+        assert_eq!(store.redo(3).expect("redone").len(), 3);
+        assert_eq!(count(&store).expect("count"), Some(TypedValue::Long(2)));
+        assert!(store.verify().expect("verified").is_ok());
+    }
+
+    #[test]
+    fn test_branches() {
+        let mut store = Store::open("").expect("opened");
+        store
+            .transact(
+                r#"[{:db/ident       :foo/count
+                     :db/cardinality :db.cardinality/one
+                     :db/valueType   :db.type/long}]"#,
+            )
+            .expect("transacted");
+        store
+            .cache(&kw!(:foo/count), CacheDirection::Forward)
+            .expect("cached");
+        let report = store
+            .transact(r#"[{:db/id "e" :db/ident :foo/e :foo/count 1}]"#)
+            .expect("transacted");
+        let e = report.tempids["e"];
+
+        store.fork_branch("draft", report.tx_id).expect("forked");
+        store.switch_branch("draft").expect("switched");
+        assert_eq!(store.current_branch().expect("current"), "draft");
+        store
+            .transact(r#"[[:db/add :foo/e :foo/count 2]]"#)
+            .expect("transacted");
+
+        let count = |store: &Store| {
+            store
+                .q_once("[:find ?c . :where [:foo/e :foo/count ?c]]", None)
+                .and_then(|output| Ok(output.into_scalar()?))
+                .expect("queried")
+        };
+        assert_eq!(count(&store), Some(Binding::Scalar(TypedValue::Long(2))));
+        assert_eq!(
+            store
+                .lookup_value_for_attribute(e, &kw!(:foo/count))
+                .expect("looked up"),
+            Some(TypedValue::Long(2))
+        );
+
+        store
+            .switch_branch(branches::MAIN_BRANCH)
+            .expect("switched");
+        assert_eq!(count(&store), Some(Binding::Scalar(TypedValue::Long(1))));
+        assert_eq!(
+            store
+                .lookup_value_for_attribute(e, &kw!(:foo/count))
+                .expect("looked up"),
+            Some(TypedValue::Long(1))
+        );
+
+        assert_eq!(store.merge_branch("draft").expect("merged").len(), 1);
+        assert_eq!(count(&store), Some(Binding::Scalar(TypedValue::Long(2))));
+        assert!(store.branches().expect("branches").is_empty());
+        assert!(store.verify().expect("verified").is_ok());
+    }
+
+    #[test]
+    fn test_as_of_and_since() {
+        let mut store = Store::open("").expect("opened");
+        store
+            .transact(
+                r#"[{:db/ident       :foo/count
+                     :db/cardinality :db.cardinality/one
+                     :db/valueType   :db.type/long}
+                    {:db/ident       :foo/text
+                     :db/cardinality :db.cardinality/one
+                     :db/valueType   :db.type/string
+                     :db/fulltext    true
+                     :db/index       true}]"#,
+            )
+            .expect("transacted");
+        store
+            .cache(&kw!(:foo/count), CacheDirection::Forward)
+            .expect("cached");
+        let first = store
+            .transact(r#"[{:db/id "e" :db/ident :foo/e :foo/count 1 :foo/text "one"}]"#)
+            .expect("transacted");
+        let e = first.tempids["e"];
+        store
+            .transact(r#"[[:db/add :foo/e :foo/count 2] [:db/add :foo/e :foo/text "two"]]"#)
+            .expect("transacted");
+
+        let count = store
+            .conn()
+            .current_schema()
+            .get_entid(&kw!(:foo/count))
+            .expect("count");
+        let text = store
+            .conn()
+            .current_schema()
+            .get_entid(&kw!(:foo/text))
+            .expect("text");
+
+        // Look up by name, so that the current schema resolves `:foo/e`.
+        let named = |o: Result<QueryOutput>| o.and_then(|o| Ok(o.into_scalar()?)).expect("queried");
+        let read = store.begin_read().expect("began read");
+        let then = read.as_of(first.tx_id);
+        assert_eq!(
+            named(then.q_once("[:find ?c . :where [:foo/e :foo/count ?c]]", None)),
+            Some(Binding::Scalar(TypedValue::Long(1)))
+        );
+        assert_eq!(
+            named(then.q_once("[:find ?t . :where [:foo/e :foo/text ?t]]", None)),
+            Some(Binding::Scalar(TypedValue::typed_string("one")))
+        );
+        assert_eq!(
+            named(then.q_once(
+                r#"[:find ?x . :where [(fulltext $ :foo/text "one") [[?x]]]]"#,
+                None
+            )),
+            Some(Binding::Scalar(TypedValue::Ref(e)))
+        );
+        assert_eq!(
+            named(read.q_once(
+                r#"[:find ?x . :where [(fulltext $ :foo/text "one") [[?x]]]]"#,
+                None
+            )),
+            None
+        );
+        assert_eq!(
+            then.lookup_value_for_attribute(e, &kw!(:foo/count))
+                .expect("looked up"),
+            Some(TypedValue::Long(1))
+        );
+
+        // Pulls see the view too, whether made directly or from a query.
+        let pulled = then
+            .pull_attributes_for_entity(e, vec![count.0, text.0])
+            .expect("pulled");
+        assert_eq!(
+            pulled.0.get(&ValueRc::new(kw!(:foo/count))),
+            Some(&Binding::Scalar(TypedValue::Long(1)))
+        );
+        assert_eq!(
+            pulled.0.get(&ValueRc::new(kw!(:foo/text))),
+            Some(&Binding::Scalar(TypedValue::typed_string("one")))
+        );
+        let pulled = named(then.q_once(
+            "[:find (pull ?x [:foo/count]) . :where [?x :foo/text _]]",
+            None,
+        ));
+        match pulled {
+            Some(Binding::Map(m)) => assert_eq!(
+                m.0.get(&ValueRc::new(kw!(:foo/count))),
+                Some(&Binding::Scalar(TypedValue::Long(1)))
+            ),
+            x => panic!("expected a map, got {:?}", x),
+        }
+
+        // Since the first transaction, only the new values are visible.
+        let since = read.since(first.tx_id);
+        let values = since
+            .q_once("[:find [?v ...] :where [:foo/e _ ?v]]", None)
+            .and_then(|o| Ok(o.into_coll()?))
+            .expect("queried");
+        assert_eq!(
+            values
+                .into_iter()
+                .map(|v| v.into_scalar().expect("scalar"))
+                .collect::<BTreeSet<_>>(),
+            vec![TypedValue::Long(2), TypedValue::typed_string("two")]
+                .into_iter()
+                .collect()
+        );
+
+        // Named sources compare views within one query.
+        let inputs = QueryInputs::default().with_source(
+            SrcVar::NamedSrc("before".to_string()),
+            DatomsView::AsOf(first.tx_id),
+        );
+        let changed = read
+            .q_once(
+                "[:find [?old ?new] :in $ $before :where [?x :foo/count ?new] [$before ?x :foo/count ?old]]",
+                inputs,
+            )
+            .and_then(|o| Ok(o.into_tuple()?))
+            .expect("queried");
+        assert_eq!(
+            changed,
+            Some(vec![
+                Binding::Scalar(TypedValue::Long(1)),
+                Binding::Scalar(TypedValue::Long(2)),
+            ])
+        );
+        match read.q_once(
+            "[:find ?x :in $before :where [$before ?x :foo/count _]]",
+            None,
+        ) {
+            Err(MentatError::AlgebrizerError(AlgebrizerError::UnboundSource(_))) => {}
+            x => panic!("expected UnboundSource, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_history() {
+        let mut store = Store::open("").expect("opened");
+        store
+            .transact(
+                r#"[{:db/ident       :foo/count
+                     :db/cardinality :db.cardinality/one
+                     :db/valueType   :db.type/long}]"#,
+            )
+            .expect("transacted");
+        let first = store
+            .transact(r#"[{:db/ident :foo/e :foo/count 1}]"#)
+            .expect("transacted");
+        let second = store
+            .transact(r#"[[:db/add :foo/e :foo/count 2]]"#)
+            .expect("transacted");
+
+        let rows = |o: Result<QueryOutput>| -> BTreeSet<Vec<TypedValue>> {
+            o.and_then(|o| Ok(o.into_rel()?))
+                .expect("queried")
+                .into_iter()
+                .map(|row| {
+                    row.into_iter()
+                        .map(|v| v.into_scalar().expect("scalar"))
+                        .collect()
+                })
+                .collect()
+        };
+        let expected: BTreeSet<Vec<TypedValue>> = vec![
+            vec![
+                TypedValue::Long(1),
+                TypedValue::Ref(first.tx_id),
+                TypedValue::Boolean(true),
+            ],
+            vec![
+                TypedValue::Long(1),
+                TypedValue::Ref(second.tx_id),
+                TypedValue::Boolean(false),
+            ],
+            vec![
+                TypedValue::Long(2),
+                TypedValue::Ref(second.tx_id),
+                TypedValue::Boolean(true),
+            ],
+        ]
+        .into_iter()
+        .collect();
+
+        // Through a named history source.
+        let read = store.begin_read().expect("began read");
+        let inputs = QueryInputs::default()
+            .with_source(SrcVar::NamedSrc("h".to_string()), DatomsView::History);
+        assert_eq!(
+            rows(read.q_once(
+                "[:find ?v ?tx ?added :in $h :where [$h :foo/e :foo/count ?v ?tx ?added]]",
+                inputs,
+            )),
+            expected
+        );
+
+        // Through the history handle, constraining `added` to find retractions.
+        let history = read.history();
+        assert_eq!(
+            rows(history.q_once(
+                "[:find ?v ?tx ?added :where [:foo/e :foo/count ?v ?tx ?added]]",
+                None
+            )),
+            expected
+        );
+        assert_eq!(
+            rows(history.q_once(
+                "[:find ?v ?tx :where [:foo/e :foo/count ?v ?tx false]]",
+                None
+            )),
+            vec![vec![TypedValue::Long(1), TypedValue::Ref(second.tx_id)]]
+                .into_iter()
+                .collect()
+        );
+
+        // Only a history source has an `added` place.
+        match read.q_once("[:find ?v :where [:foo/e :foo/count ?v _ ?added]]", None) {
+            Err(MentatError::AlgebrizerError(AlgebrizerError::NonHistorySource(_))) => {}
+            x => panic!("expected NonHistorySource, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_diff_and_revert() {
+        let mut store = Store::open("").expect("opened");
+        store
+            .transact(
+                r#"[{:db/ident       :foo/count
+                     :db/cardinality :db.cardinality/one
+                     :db/valueType   :db.type/long}
+                    {:db/ident       :foo/tag
+                     :db/cardinality :db.cardinality/many
+                     :db/valueType   :db.type/keyword}]"#,
+            )
+            .expect("transacted");
+        let yesterday = store
+            .transact(r#"[{:db/ident :foo/e :foo/count 1 :foo/tag :foo/old}]"#)
+            .expect("transacted")
+            .tx_id;
+        store
+            .transact(r#"[[:db/add :foo/e :foo/count 2] [:db/add :foo/e :foo/tag :foo/new]]"#)
+            .expect("transacted");
+        let today = store
+            .transact(r#"[[:db/add :foo/e :foo/count 3] [:db/retract :foo/e :foo/tag :foo/old]]"#)
+            .expect("transacted")
+            .tx_id;
+
+        let diff = store.diff(yesterday, today).expect("diffed");
+        let schema = store.conn().current_schema();
+        assert_eq!(
+            diff.to_edn(&schema),
+            edn::parse::value(&format!(
+                "{{:from {} :to {} \
+                  :added [[:foo/e :foo/count 3] [:foo/e :foo/tag :foo/new]] \
+                  :retracted [[:foo/e :foo/count 1] [:foo/e :foo/tag :foo/old]]}}",
+                yesterday, today
+            ))
+            .expect("parsed")
+            .without_spans()
+        );
+
+        // Reverting is a new transaction, after which the store is as it was.
+        let report = store.revert(&diff).expect("reverted");
+        assert!(report.tx_id > today);
+        assert!(store
+            .diff(yesterday, report.tx_id)
+            .expect("diffed")
+            .is_empty());
+        assert_eq!(
+            store
+                .q_once("[:find ?c . :where [:foo/e :foo/count ?c]]", None)
+                .and_then(|o| Ok(o.into_scalar()?))
+                .expect("queried"),
+            Some(Binding::Scalar(TypedValue::Long(1)))
+        );
+    }
+
+    #[test]
+    fn test_reload_metadata_after_external_writes() {
+        let store_file = TempStore::new("external");
+        let path = &store_file.0;
+
+        let mut ours = Store::open(path).expect("opened");
+        let mut theirs = Store::open(path).expect("opened");
+
+        theirs
+            .transact(
+                r#"[{:db/ident       :foo/bar
+                     :db/cardinality :db.cardinality/one
+                     :db/valueType   :db.type/long}]"#,
+            )
+            .expect("transacted");
+        let a = theirs
+            .transact(r#"[{:db/id "a" :foo/bar 1}]"#)
+            .expect("transacted")
+            .tempids["a"];
+        assert!(ours
+            .conn()
+            .current_schema()
+            .get_entid(&kw!(:foo/bar))
+            .is_none());
+
+        // We pick up their schema, and don't reuse their entids.
+        let b = ours
+            .transact(r#"[{:db/id "b" :foo/bar 2}]"#)
+            .expect("transacted")
+            .tempids["b"];
+        assert_ne!(a, b);
+        assert!(ours
+            .conn()
+            .current_schema()
+            .get_entid(&kw!(:foo/bar))
+            .is_some());
+        assert_eq!(ours.last_tx_id(), theirs.last_tx_id() + 1);
+
+        let c = theirs
+            .transact(r#"[{:db/id "c" :foo/bar 3}]"#)
+            .expect("transacted")
+            .tempids["c"];
+        assert!(c != a && c != b);
+
+        let read = ours.begin_read().expect("began");
+        assert_eq!(
+            read.q_once("[:find (count ?e) . :where [?e :foo/bar _]]", None)
+                .expect("query")
+                .into_scalar()
+                .expect("scalar"),
+            Some(Binding::Scalar(TypedValue::Long(3)))
+        );
+    }
 }
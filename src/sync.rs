@@ -14,22 +14,72 @@ use mentat_transaction::InProgress;
 
 use super::errors::Result;
 
-use mentat_tolstoy::{RemoteClient, SyncReport, Syncer};
+use mentat_tolstoy::{
+    ConflictResolver, GlobalTransactionLog, LocalWins, RemoteClient, SyncFilter, SyncReport, Syncer,
+};
 
 pub trait Syncable {
     fn sync(&mut self, server_uri: &str, user_uuid: &str) -> Result<SyncReport>;
+
+    /// Sync against any global transaction log, such as a `mentat_tolstoy::DirectoryLog`, rather
+    /// than a sync server.
+    fn sync_with<R>(&mut self, log: &mut R) -> Result<SyncReport>
+    where
+        R: GlobalTransactionLog;
+
+    /// Sync against `log`, keeping the attributes `filter` excludes local.
+    fn sync_with_filter<R>(&mut self, log: &mut R, filter: &SyncFilter) -> Result<SyncReport>
+    where
+        R: GlobalTransactionLog;
+
+    /// Sync against `log`, keeping the attributes `filter` excludes local, and resolving conflicts
+    /// between local and remote changes with `resolver` rather than by last writer wins.
+    fn sync_with_resolver<R>(
+        &mut self,
+        log: &mut R,
+        filter: &SyncFilter,
+        resolver: &dyn ConflictResolver,
+    ) -> Result<SyncReport>
+    where
+        R: GlobalTransactionLog;
 }
 
 impl<'a, 'c> Syncable for InProgress<'a, 'c> {
     fn sync(&mut self, server_uri: &str, user_uuid: &str) -> Result<SyncReport> {
+        let mut remote_client =
+            RemoteClient::new(server_uri.to_string(), Uuid::parse_str(user_uuid)?)?;
+        self.sync_with(&mut remote_client)
+    }
+
+    fn sync_with<R>(&mut self, log: &mut R) -> Result<SyncReport>
+    where
+        R: GlobalTransactionLog,
+    {
+        self.sync_with_filter(log, &SyncFilter::new())
+    }
+
+    fn sync_with_filter<R>(&mut self, log: &mut R, filter: &SyncFilter) -> Result<SyncReport>
+    where
+        R: GlobalTransactionLog,
+    {
+        self.sync_with_resolver(log, filter, &LocalWins)
+    }
+
+    fn sync_with_resolver<R>(
+        &mut self,
+        log: &mut R,
+        filter: &SyncFilter,
+        resolver: &dyn ConflictResolver,
+    ) -> Result<SyncReport>
+    where
+        R: GlobalTransactionLog,
+    {
         // Syncer behaves as if it's part of InProgress.
         // This split into a separate crate is segment synchronization functionality
         // in a single crate which can be easily disabled by consumers,
         // and to separate concerns.
         // But for all intents and purposes, Syncer operates over a "mentat transaction",
         // which is exactly what InProgress represents.
-        let mut remote_client =
-            RemoteClient::new(server_uri.to_string(), Uuid::parse_str(&user_uuid)?);
-        Syncer::sync(self, &mut remote_client).map_err(|e| e)
+        Syncer::sync_with_resolver(self, log, filter, resolver)
     }
 }
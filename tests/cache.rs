@@ -9,7 +9,10 @@
 // specific language governing permissions and limitations under the License.
 
 //extern crate mentat;
-use mentat::{self, kw, Binding, Entid, HasSchema, Queryable, Schema, Store, TypedValue};
+use mentat::{
+    self, kw, Binding, CacheDirection, Entid, HasSchema, QueryExplanation, Queryable, Schema,
+    Store, TypedValue,
+};
 use mentat_core::{self, CachedAttributes};
 
 use std::collections::BTreeSet;
@@ -360,3 +363,244 @@ fn test_fetch_attribute_values_for_entid() {
         ]
     );
 }
+
+fn populate_numbered(store: &mut Store, count: i64) -> Vec<Entid> {
+    store
+        .transact(
+            r#"[{:db/ident       :num/value
+                 :db/valueType   :db.type/long
+                 :db/cardinality :db.cardinality/one}
+                {:db/ident       :num/name
+                 :db/valueType   :db.type/string
+                 :db/cardinality :db.cardinality/one
+                 :db/unique      :db.unique/identity
+                 :db/index       true}]"#,
+        )
+        .expect("transacted schema");
+    let entities: Vec<String> = (0..count)
+        .map(|i| format!(r#"{{:db/id "e{0}" :num/value {0} :num/name "n{0}"}}"#, i))
+        .collect();
+    let report = store
+        .transact(&format!("[{}]", entities.join(" ")))
+        .expect("transacted entities");
+    (0..count)
+        .map(|i| report.tempids[&format!("e{}", i)])
+        .collect()
+}
+
+#[test]
+fn test_memory_budget_evicts_and_faults_in() {
+    let mut store = Store::open("").expect("opened");
+    let entities = populate_numbered(&mut store, 20);
+    let value = store
+        .conn()
+        .current_schema()
+        .get_entid(&kw!(:num/value))
+        .expect("attribute")
+        .0;
+    let name = store
+        .conn()
+        .current_schema()
+        .get_entid(&kw!(:num/name))
+        .expect("attribute")
+        .0;
+
+    store
+        .cache(&kw!(:num/value), CacheDirection::Forward)
+        .expect("cached");
+    store
+        .cache(&kw!(:num/name), CacheDirection::Both)
+        .expect("cached");
+
+    let stats = store.attribute_cache_stats();
+    assert_eq!(stats[&value].entries, 20);
+    assert!(!stats[&value].partial);
+    assert_eq!(stats[&name].entries, 40);
+
+    // Leave room for about five `:num/value` entries and nothing else.
+    let budget = stats[&value].approximate_bytes / 4;
+    store.set_attribute_cache_budget(Some(budget));
+
+    let stats = store.attribute_cache_stats();
+    assert!(stats[&value].partial || stats[&name].partial);
+    assert!(stats[&name].reverse_suspended);
+    assert!(stats.values().map(|s| s.approximate_bytes).sum::<usize>() <= budget);
+
+    // Every lookup is still answered correctly, whether or not the entity is resident.
+    for (i, &e) in entities.iter().enumerate() {
+        assert_eq!(
+            store
+                .lookup_value_for_attribute(e, &kw!(:num/value))
+                .expect("looked up"),
+            Some(TypedValue::Long(i as i64))
+        );
+    }
+    let stats = store.attribute_cache_stats();
+    assert!(stats[&value].misses > 0);
+    assert!(stats.values().map(|s| s.approximate_bytes).sum::<usize>() <= budget);
+
+    // The last miss was loaded back in, so looking it up again hits.
+    let last = *entities.last().unwrap();
+    let hits = stats[&value].hits;
+    store
+        .lookup_value_for_attribute(last, &kw!(:num/value))
+        .expect("looked up");
+    assert_eq!(store.attribute_cache_stats()[&value].hits, hits + 1);
+
+    // Writes to resident and evicted entities alike are visible.
+    let first = entities[0];
+    store
+        .transact(&format!(
+            "[[:db/add {} :num/value 100] [:db/add {} :num/value 200]]",
+            first, last
+        ))
+        .expect("transacted");
+    assert_eq!(
+        store
+            .lookup_value_for_attribute(first, &kw!(:num/value))
+            .expect("looked up"),
+        Some(TypedValue::Long(100))
+    );
+    assert_eq!(
+        store
+            .lookup_value_for_attribute(last, &kw!(:num/value))
+            .expect("looked up"),
+        Some(TypedValue::Long(200))
+    );
+    let results = store
+        .q_once("[:find ?e . :where [?e :num/name \"n19\"]]", None)
+        .expect("queried")
+        .into_scalar()
+        .expect("scalar");
+    assert_eq!(results, Some(Binding::Scalar(TypedValue::Ref(last))));
+
+    // Lifting the budget leaves partial caches partial, but stops evicting.
+    store.set_attribute_cache_budget(None);
+    for &e in entities.iter() {
+        store
+            .lookup_value_for_attribute(e, &kw!(:num/value))
+            .expect("looked up");
+    }
+    store
+        .cache(&kw!(:num/value), CacheDirection::Forward)
+        .expect("cached");
+    let stats = store.attribute_cache_stats();
+    assert!(!stats[&value].partial);
+    assert_eq!(stats[&value].entries, 20);
+}
+
+fn rel_entities(store: &Store, query: &str) -> BTreeSet<Entid> {
+    store
+        .q_once(query, None)
+        .expect("query")
+        .into_coll()
+        .expect("coll")
+        .into_iter()
+        .map(|binding| match binding {
+            Binding::Scalar(TypedValue::Ref(entid)) => entid,
+            x => panic!("expected Ref, got {:?}", x),
+        })
+        .collect()
+}
+
+#[test]
+fn test_cache_substitutes_for_patterns() {
+    let mut store = populate_db();
+    store
+        .cache(&kw!(:foo/bar), CacheDirection::Both)
+        .expect("cached");
+    store
+        .cache(&kw!(:foo/bap), CacheDirection::Both)
+        .expect("cached");
+    {
+        let mut in_progress = store.begin_transaction().expect("began");
+        in_progress
+            .transact(r#"[{:db/ident :item/three :foo/bar 100}]"#)
+            .expect("transacted");
+        in_progress.commit().expect("committed");
+    }
+
+    let schema = store.conn().current_schema();
+    let one = schema.get_entid(&kw!(:item/one)).expect("one").0;
+    let two = schema.get_entid(&kw!(:item/two)).expect("two").0;
+    let three = schema.get_entid(&kw!(:item/three)).expect("three").0;
+
+    // A reverse lookup with a single result binds the entity, and a forward lookup of a
+    // constant checks it, so there's nothing left to ask the store.
+    let query = r#"[:find ?e . :where [?e :foo/bap "four"] [?e :foo/bar 200]]"#;
+    match store.q_explain(query, None).expect("explained") {
+        QueryExplanation::KnownConstant => {}
+        _ => panic!("expected a constant query"),
+    }
+    assert_eq!(
+        store
+            .q_once(query, None)
+            .expect("query")
+            .into_scalar()
+            .expect("scalar"),
+        Some(Binding::Scalar(TypedValue::Ref(two)))
+    );
+
+    // Multi-valued forward lookups become computed tables.
+    let query = r#"[:find [?v ...] :where [:item/one :foo/bap ?v]]"#;
+    match store.q_explain(query, None).expect("explained") {
+        QueryExplanation::ExecutionPlan { query, .. } => {
+            assert!(!query.sql.contains("datoms"), "{}", query.sql);
+        }
+        _ => panic!("expected an execution plan"),
+    }
+    let values: BTreeSet<String> = store
+        .q_once(query, None)
+        .expect("query")
+        .into_coll()
+        .expect("coll")
+        .into_iter()
+        .map(|binding| binding.into_string().expect("string").to_string())
+        .collect();
+    let expected: BTreeSet<String> = ["one", "two", "buckle my shoe"]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+    assert_eq!(values, expected);
+
+    // So do reverse lookups that find more than one entity.
+    let entities = rel_entities(&store, r#"[:find [?e ...] :where [?e :foo/bar 100]]"#);
+    assert_eq!(entities, vec![one, three].into_iter().collect());
+
+    // Cached values bound by a later pattern are used by earlier ones.
+    let entities = rel_entities(
+        &store,
+        r#"[:find [?e ...] :where [?e :foo/bar ?n] [:item/two :foo/bar ?n]]"#,
+    );
+    assert_eq!(entities, vec![two].into_iter().collect());
+
+    // The cache can show that a query has no results.
+    let query = r#"[:find ?e . :where [?e :foo/bap "one"] [?e :foo/bar 200]]"#;
+    match store.q_explain(query, None).expect("explained") {
+        QueryExplanation::KnownEmpty(because) => {
+            assert_eq!(
+                format!("{:?}", because),
+                format!(
+                    "({}, {}, Long(200), _) not present in store",
+                    one,
+                    schema.get_entid(&kw!(:foo/bar)).expect("bar").0
+                )
+            );
+        }
+        _ => panic!("expected an empty query"),
+    }
+    assert_eq!(
+        store
+            .q_once(query, None)
+            .expect("query")
+            .into_scalar()
+            .expect("scalar"),
+        None
+    );
+
+    let query = r#"[:find ?e . :where [?e :foo/bar 300]]"#;
+    match store.q_explain(query, None).expect("explained") {
+        QueryExplanation::KnownEmpty(_) => {}
+        _ => panic!("expected an empty query"),
+    }
+}
@@ -0,0 +1,188 @@
+// Copyright 2018 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+#![cfg(feature = "json")]
+
+#[macro_use]
+extern crate serde_json;
+
+use serde_json::to_value;
+
+use mentat::{JsonValue, MentatError, Queryable, Store, TypedValue};
+
+fn store_with_schema() -> Store {
+    let mut store = Store::open("").expect("opened");
+    store
+        .transact(
+            r#"[{:db/ident       :person/name
+                 :db/valueType   :db.type/string
+                 :db/cardinality :db.cardinality/one
+                 :db/index       true
+                 :db/unique      :db.unique/identity}
+                {:db/ident       :person/height
+                 :db/valueType   :db.type/double
+                 :db/cardinality :db.cardinality/one}
+                {:db/ident       :person/born
+                 :db/valueType   :db.type/instant
+                 :db/cardinality :db.cardinality/one}
+                {:db/ident       :person/id
+                 :db/valueType   :db.type/uuid
+                 :db/cardinality :db.cardinality/one}
+                {:db/ident       :person/mood
+                 :db/valueType   :db.type/keyword
+                 :db/cardinality :db.cardinality/one}
+                {:db/ident       :person/friend
+                 :db/valueType   :db.type/ref
+                 :db/cardinality :db.cardinality/many}]"#,
+        )
+        .expect("transacted schema");
+    store
+}
+
+#[test]
+fn test_transact_json() {
+    let mut store = store_with_schema();
+    let report = store
+        .transact_json(
+            r#"[{":db/id": "a",
+                 ":person/name": "Alice",
+                 ":person/height": 170,
+                 ":person/born": {"instant": "1990-01-02T03:04:05.678Z"},
+                 ":person/id": {"uuid": "550e8400-e29b-41d4-a716-446655440000"},
+                 ":person/mood": {"keyword": ":mood/happy"}},
+                {":person/name": "Bob",
+                 ":person/friend": ["a", {":person/name": "Carol"}]}]"#,
+        )
+        .expect("transacted");
+    let alice = report.tempids["a"];
+    store
+        .transact_json(
+            r#"[{":db/id": {"lookup-ref": [":person/name", "Carol"]},
+                 ":person/_friend": {"lookup-ref": [":person/name", "Alice"]}}]"#,
+        )
+        .expect("transacted");
+
+    let json = to_value(&report).expect("serialized");
+    assert_eq!(json["tx_id"], json!(report.tx_id));
+    assert_eq!(json["tempids"], json!({ "a": alice }));
+
+    let output = store
+        .q_once(
+            r#"[:find ?height ?born ?id ?mood
+                :where [?a :person/name "Alice"]
+                       [?a :person/height ?height]
+                       [?a :person/born ?born]
+                       [?a :person/id ?id]
+                       [?a :person/mood ?mood]]"#,
+            None,
+        )
+        .expect("query");
+    assert_eq!(
+        to_value(&output).expect("serialized"),
+        json!({
+            "find": ["?height", "?born", "?id", "?mood"],
+            "results": [[170.0,
+                         {"instant": "1990-01-02T03:04:05.678000Z"},
+                         {"uuid": "550e8400-e29b-41d4-a716-446655440000"},
+                         {"keyword": ":mood/happy"}]]
+        })
+    );
+    // Doubles keep their decimal point, so they read back as doubles.
+    assert!(to_value(&output)
+        .expect("serialized")
+        .to_string()
+        .contains("170.0"));
+
+    let friends = store
+        .q_once(
+            r#"[:find [?name ...]
+                :where [?b :person/name "Bob"] [?b :person/friend ?f] [?f :person/name ?name]]"#,
+            None,
+        )
+        .expect("query");
+    let mut names = to_value(&friends.results).expect("serialized");
+    names
+        .as_array_mut()
+        .expect("array")
+        .sort_by_key(|v| v.to_string());
+    assert_eq!(names, json!(["Alice", "Carol"]));
+
+    let carol_friends = store
+        .q_once(
+            r#"[:find (count ?a) .
+                :where [?c :person/name "Carol"] [?a :person/friend ?c]]"#,
+            None,
+        )
+        .expect("query");
+    assert_eq!(
+        to_value(&carol_friends.results).expect("serialized"),
+        json!(2)
+    );
+
+    // Refs come out tagged, and go back in as entids.
+    let entity = store
+        .q_once(r#"[:find ?a . :where [?a :person/name "Alice"]]"#, None)
+        .expect("query")
+        .results;
+    let entity = to_value(&entity).expect("serialized");
+    assert_eq!(entity, json!({ "ref": alice }));
+    store
+        .transact_json(&json!([{":db/id": entity, ":person/height": 171.5}]).to_string())
+        .expect("transacted");
+
+    let pulled = store
+        .q_once(
+            r#"[:find (pull ?a [:person/name :person/height]) .
+                :where [?a :person/name "Alice"]]"#,
+            None,
+        )
+        .expect("query");
+    assert_eq!(
+        to_value(&pulled.results).expect("serialized"),
+        json!({":person/name": "Alice", ":person/height": 171.5})
+    );
+
+    let empty = store
+        .q_once(r#"[:find ?a . :where [?a :person/name "Nobody"]]"#, None)
+        .expect("query");
+    assert_eq!(to_value(&empty.results).expect("serialized"), json!(null));
+
+    // Doubles JSON can't represent are tagged.
+    assert_eq!(
+        to_value(JsonValue(&TypedValue::from(f64::NAN))).expect("serialized"),
+        json!({"double": "NaN"})
+    );
+}
+
+#[test]
+fn test_transact_json_errors() {
+    let mut store = store_with_schema();
+
+    match store.transact_json(r#"[{":person/nickname": "Al"}]"#) {
+        Err(MentatError::UnknownAttribute(a)) => assert_eq!(a, ":person/nickname"),
+        x => panic!("expected an unknown attribute, got {:?}", x),
+    }
+    match store.transact_json(r#"[{":person/name": 5}]"#) {
+        Err(MentatError::BadJson(_)) => {}
+        x => panic!("expected bad JSON, got {:?}", x),
+    }
+    match store.transact_json(r#"[{":person/born": "1990-01-01T00:00:00Z"}]"#) {
+        Err(MentatError::BadJson(_)) => {}
+        x => panic!("expected bad JSON, got {:?}", x),
+    }
+    match store.transact_json(r#"{":person/name": "Alice"}"#) {
+        Err(MentatError::BadJson(_)) => {}
+        x => panic!("expected bad JSON, got {:?}", x),
+    }
+    match store.transact_json(r#"[{":person/name": "Alice""#) {
+        Err(MentatError::SerializationError(_)) => {}
+        x => panic!("expected a parse error, got {:?}", x),
+    }
+}
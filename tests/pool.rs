@@ -0,0 +1,121 @@
+// Copyright 2018 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+use std::sync::Arc;
+use std::thread;
+
+use mentat::{Binding, MentatError, Queryable, Store, StorePool, TypedValue, Uuid};
+
+struct TempStore(String);
+
+impl TempStore {
+    fn new() -> TempStore {
+        let path = ::std::env::temp_dir().join(format!("mentat-pool-{}.db", Uuid::new_v4()));
+        TempStore(path.to_str().expect("path").to_string())
+    }
+}
+
+impl Drop for TempStore {
+    fn drop(&mut self) {
+        for suffix in &["", "-wal", "-shm"] {
+            let _ = ::std::fs::remove_file(format!("{}{}", self.0, suffix));
+        }
+    }
+}
+
+const SCHEMA: &str = r#"[{:db/ident       :foo/n
+                          :db/cardinality :db.cardinality/one
+                          :db/valueType   :db.type/long}]"#;
+
+fn count(pool: &StorePool) -> (i64, i64) {
+    pool.read(|read| {
+        let count = read
+            .q_once("[:find (count ?e) . :where [?e :foo/n _]]", None)?
+            .into_scalar()?;
+        let count = match count {
+            Some(Binding::Scalar(TypedValue::Long(count))) => count,
+            None => 0,
+            x => panic!("expected a count, got {:?}", x),
+        };
+        Ok((count, read.last_tx_id()))
+    })
+    .expect("read")
+}
+
+#[test]
+fn test_pool_reads_and_writes() {
+    let path = TempStore::new();
+    let pool = Arc::new(StorePool::open(&path.0, 3).expect("opened"));
+    pool.transact(SCHEMA).expect("transacted");
+    let (_, schema_tx) = count(&pool);
+
+    // Each write adds one entity, so every consistent read sees as many entities as it sees
+    // transactions since the schema.
+    let mut threads = vec![];
+    for _ in 0..4 {
+        let pool = pool.clone();
+        threads.push(thread::spawn(move || {
+            for _ in 0..50 {
+                let (count, last_tx) = count(&pool);
+                assert_eq!(count, last_tx - schema_tx);
+            }
+        }));
+    }
+    let writer = pool.clone();
+    threads.push(thread::spawn(move || {
+        for n in 0..50 {
+            writer
+                .transact(&format!("[{{:foo/n {}}}]", n))
+                .expect("transacted");
+        }
+    }));
+    for thread in threads {
+        thread.join().expect("joined");
+    }
+    assert_eq!(count(&pool), (50, schema_tx + 50));
+
+    // Readers taken out of the pool keep their snapshot until they're done.
+    let mut reader = pool.reader();
+    let read = reader.begin_read().expect("began");
+    pool.transact("[{:foo/n 50}]").expect("transacted");
+    assert_eq!(read.last_tx_id(), schema_tx + 50);
+    drop(read);
+    drop(reader);
+    assert_eq!(count(&pool), (51, schema_tx + 51));
+}
+
+#[test]
+fn test_pool_notices_external_writes() {
+    let path = TempStore::new();
+    let pool = StorePool::open(&path.0, 1).expect("opened");
+    let mut store = Store::open(&path.0).expect("opened");
+    store.transact(SCHEMA).expect("transacted");
+    let a = store
+        .transact(r#"[{:db/id "a" :foo/n 1}]"#)
+        .expect("transacted")
+        .tempids["a"];
+
+    // The pool's writer reloads the schema and partition map rather than clashing.
+    let b = pool
+        .transact(r#"[{:db/id "b" :foo/n 2}]"#)
+        .expect("transacted")
+        .tempids["b"];
+    assert_ne!(a, b);
+    assert_eq!(count(&pool), (2, store.last_tx_id() + 1));
+}
+
+#[test]
+fn test_pool_needs_a_file() {
+    match StorePool::open("", 2) {
+        Err(MentatError::InMemoryStorePool) => {}
+        Err(e) => panic!("expected an in-memory error, got {:?}", e),
+        Ok(_) => panic!("expected an in-memory error"),
+    }
+}
@@ -800,8 +800,8 @@ fn test_aggregates_type_handling() {
         .expect("results")
         .unwrap();
 
-    // Yes, the current version is in the store as a Long!
-    let total = 30i64 + 20i64 + 10i64 + ::mentat_db::db::CURRENT_VERSION as i64;
+    // Yes, the core schema version is in the store as a Long!
+    let total = 30i64 + 20i64 + 10i64 + ::mentat::CORE_SCHEMA_VERSION as i64;
     assert_eq!(Binding::Scalar(TypedValue::Long(total)), r);
 
     let r = store
@@ -1971,3 +1971,129 @@ fn test_encrypted() {
     // so the specific test we use doesn't matter that much.
     run_tx_data_test(Store::open_with_key("", "secret").expect("opened"));
 }
+
+#[test]
+fn test_stream() {
+    let mut store = Store::open("").expect("opened");
+    store
+        .transact(
+            r#"[
+        [:db/add "s" :db/ident :foo/count]
+        [:db/add "s" :db/valueType :db.type/long]
+        [:db/add "s" :db/cardinality :db.cardinality/one]
+    ]"#,
+        )
+        .expect("transacted schema");
+    store
+        .transact(
+            r#"[
+        {:foo/count 1}
+        {:foo/count 2}
+        {:foo/count 3}
+        {:foo/count 4}
+        {:foo/count 5}
+    ]"#,
+        )
+        .expect("transacted data");
+
+    let query = "[:find ?e ?c :where [?e :foo/count ?c] :order ?c]";
+
+    // Streaming yields the same rows as collecting.
+    let collected = store.q_once(query, None).into_rel_result().expect("rel");
+    let mut streamed = vec![];
+    store
+        .q_stream(query, None, |row| {
+            streamed.push(row);
+            Ok(true)
+        })
+        .expect("streamed");
+    assert_eq!(streamed, collected.into_iter().collect::<Vec<_>>());
+
+    // Streams respect `:limit`.
+    let mut counts = vec![];
+    store
+        .q_stream(
+            "[:find [?c ...] :where [_ :foo/count ?c] :order (desc ?c) :limit 2]",
+            None,
+            |row| {
+                counts.push(row);
+                Ok(true)
+            },
+        )
+        .expect("streamed");
+    assert_eq!(
+        counts,
+        vec![
+            vec![Binding::Scalar(TypedValue::Long(5))],
+            vec![Binding::Scalar(TypedValue::Long(4))],
+        ]
+    );
+
+    // Consumers can stop midway.
+    let mut seen = 0;
+    store
+        .q_stream(query, None, |_| {
+            seen += 1;
+            Ok(seen < 3)
+        })
+        .expect("streamed");
+    assert_eq!(seen, 3);
+
+    // Errors from the consumer are propagated.
+    let result = store.q_stream(query, None, |_| {
+        Err(MentatError::UnknownAttribute("nope".to_string()))
+    });
+    match result {
+        Err(MentatError::UnknownAttribute(_)) => {}
+        x => panic!("expected error, got {:?}", x),
+    }
+
+    // Prepared queries can be streamed and dropped without reading every row.
+    let mut prepared = store.q_prepare(query, None).expect("prepared");
+    {
+        let mut stream = prepared.stream().expect("stream");
+        assert_eq!(stream.spec().expected_column_count(), 2);
+        let first = stream.next().expect("a row").expect("no error");
+        assert_eq!(first[1], Binding::Scalar(TypedValue::Long(1)));
+    }
+    assert_eq!(prepared.stream().expect("stream").count(), 5);
+
+    // Pulls are expanded for each page of rows, and each row gets its own entity's map.
+    let mut pulled = vec![];
+    store
+        .q_stream(
+            "[:find ?c (pull ?e [:foo/count]) :where [?e :foo/count ?c] :order ?c]",
+            None,
+            |row| {
+                pulled.push(row);
+                Ok(true)
+            },
+        )
+        .expect("streamed");
+    assert_eq!(pulled.len(), 5);
+    let count = kw!(:foo/count);
+    for row in pulled.iter() {
+        match row[1] {
+            Binding::Map(ref m) => assert_eq!(m.get(&count), Some(&row[0])),
+            ref x => panic!("expected map, got {:?}", x),
+        }
+    }
+
+    // Queries known to be empty and constant queries stream too.
+    let mut rows = 0;
+    store
+        .q_stream("[:find ?e :where [?e :foo/count \"nope\"]]", None, |_| {
+            rows += 1;
+            Ok(true)
+        })
+        .expect("streamed");
+    assert_eq!(rows, 0);
+    store
+        .q_stream("[:find ?x . :where [(ground 7) ?x]]", None, |row| {
+            assert_eq!(row, vec![Binding::Scalar(TypedValue::Long(7))]);
+            rows += 1;
+            Ok(true)
+        })
+        .expect("streamed");
+    assert_eq!(rows, 1);
+}
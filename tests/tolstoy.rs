@@ -20,14 +20,20 @@ mod tolstoy_tests {
 
     use uuid::Uuid;
 
-    use mentat::{conn::Conn, new_connection};
+    use mentat::{conn::Conn, kw, new_connection, Binding, Queryable, Store};
 
     use mentat_db::{assert_matches, TX0};
 
     use mentat_tolstoy::{
-        debug::parts_to_datoms, GlobalTransactionLog, SyncFollowup, SyncReport, Syncer, Tx, TxPart,
+        debug::parts_to_datoms, ConflictResolver, DirectoryLog, GlobalTransactionLog,
+        LastWriterWins, LocalWins, PerAttribute, RemoteClient, RemoteWins, Resolution,
+        ResolvedConflict, SyncFilter, SyncFollowup, SyncReport, SyncResult, SyncServer, Syncer, Tx,
+        TxPart,
     };
 
+    #[cfg(feature = "sync_encryption")]
+    use mentat_tolstoy::{EncryptedLog, SyncKeys};
+
     use mentat_tolstoy::debug::txs_after;
 
     use core_traits::{Entid, TypedValue, ValueType};
@@ -176,11 +182,14 @@ mod tolstoy_tests {
 
     macro_rules! assert_sync {
         ( $report: pat, $conn: expr, $sqlite: expr, $remote: expr ) => {{
+            assert_sync!($report if true, $conn, $sqlite, $remote)
+        }};
+        ( $report: pat if $guard: expr, $conn: expr, $sqlite: expr, $remote: expr ) => {{
             let mut ip = $conn
                 .begin_transaction(&mut $sqlite)
                 .expect("begun successfully");
             match Syncer::sync(&mut ip, &mut $remote).expect("sync report") {
-                $report => (),
+                $report if $guard => (),
                 wr => panic!("Wrong sync report: {:?}", wr),
             }
             ip.commit().expect("committed");
@@ -455,7 +464,7 @@ mod tolstoy_tests {
 
         // Merge 1 and 2 bootstrap transactions.
         assert_sync!(
-            SyncReport::Merge(SyncFollowup::None),
+            SyncReport::Merge(SyncFollowup::None, _),
             conn_2,
             sqlite_2,
             remote_client
@@ -503,7 +512,7 @@ mod tolstoy_tests {
 
         // Merge bootstrap+schema transactions from 1 into 2.
         assert_sync!(
-            SyncReport::Merge(SyncFollowup::None),
+            SyncReport::Merge(SyncFollowup::None, _),
             conn_2,
             sqlite_2,
             remote_client
@@ -563,7 +572,7 @@ mod tolstoy_tests {
 
         // Merge bootstrap+schema transactions from 1 into 2.
         assert_sync!(
-            SyncReport::Merge(SyncFollowup::None),
+            SyncReport::Merge(SyncFollowup::None, _),
             conn_2,
             sqlite_2,
             remote_client
@@ -628,7 +637,7 @@ mod tolstoy_tests {
 
         // Merge bootstrap+schema transactions from 1 into 2.
         assert_sync!(
-            SyncReport::Merge(SyncFollowup::None),
+            SyncReport::Merge(SyncFollowup::None, _),
             conn_2,
             sqlite_2,
             remote_client
@@ -695,7 +704,7 @@ mod tolstoy_tests {
 
         // Merge bootstrap+schema transactions from 1 into 2.
         assert_sync!(
-            SyncReport::Merge(SyncFollowup::None),
+            SyncReport::Merge(SyncFollowup::None, _),
             conn_2,
             sqlite_2,
             remote_client
@@ -771,7 +780,7 @@ mod tolstoy_tests {
 
         // Merge bootstrap+schema transactions from 1 into 2.
         assert_sync!(
-            SyncReport::Merge(SyncFollowup::None),
+            SyncReport::Merge(SyncFollowup::None, _),
             conn_2,
             sqlite_2,
             remote_client
@@ -849,7 +858,7 @@ mod tolstoy_tests {
 
         // Merge bootstrap+schema transactions from 1 into 2.
         assert_sync!(
-            SyncReport::Merge(SyncFollowup::None),
+            SyncReport::Merge(SyncFollowup::None, _),
             conn_2,
             sqlite_2,
             remote_client
@@ -884,7 +893,7 @@ mod tolstoy_tests {
 
         // And now, merge!
         assert_sync!(
-            SyncReport::Merge(SyncFollowup::FullSync),
+            SyncReport::Merge(SyncFollowup::FullSync, _),
             conn_2,
             sqlite_2,
             remote_client
@@ -960,7 +969,7 @@ mod tolstoy_tests {
 
         // Merge bootstrap+schema transactions from 1 into 2.
         assert_sync!(
-            SyncReport::Merge(SyncFollowup::None),
+            SyncReport::Merge(SyncFollowup::None, _),
             conn_2,
             sqlite_2,
             remote_client
@@ -995,7 +1004,7 @@ mod tolstoy_tests {
 
         // And now, merge!
         assert_sync!(
-            SyncReport::Merge(SyncFollowup::None),
+            SyncReport::Merge(SyncFollowup::None, _),
             conn_1,
             sqlite_1,
             remote_client
@@ -1068,7 +1077,7 @@ mod tolstoy_tests {
 
         // Merge bootstrap+schema transactions from 1 into 2.
         assert_sync!(
-            SyncReport::Merge(SyncFollowup::None),
+            SyncReport::Merge(SyncFollowup::None, _),
             conn_2,
             sqlite_2,
             remote_client
@@ -1101,17 +1110,17 @@ mod tolstoy_tests {
             remote_client
         );
 
-        // And now, merge!
+        // And now, merge! The renames conflict, and first's own rename is kept.
         assert_sync!(
-            SyncReport::Merge(SyncFollowup::FullSync),
+            SyncReport::Merge(SyncFollowup::FullSync, ref resolved)
+                if resolved.len() == 1 && resolved[0].resolution == Resolution::Local,
             conn_1,
             sqlite_1,
             remote_client
         );
 
         // These hard-coded entids are brittle but deterministic.
-        // They signify that we end up with a new entity Vanechka, separate from the one
-        // that was renamed.
+        // They signify that the entity is renamed twice, rather than split in two.
         assert_transactions!(
             sqlite_1,
             conn_1,
@@ -1120,8 +1129,8 @@ mod tolstoy_tests {
             r#"[[65537 :person/name "Ivan" ?tx false]
             [65537 :person/name "Vanya" ?tx true]
             [?tx :db/txInstant ?ms ?tx true]]"#,
-            // A new entity is created for the second rename.
-            r#"[[65538 :person/name "Vanechka" ?tx true]
+            r#"[[65537 :person/name "Vanechka" ?tx true]
+            [65537 :person/name "Vanya" ?tx false]
             [?tx :db/txInstant ?ms ?tx true]]"#
         );
     }
@@ -1214,7 +1223,7 @@ mod tolstoy_tests {
             remote_client
         );
         assert_sync!(
-            SyncReport::Merge(SyncFollowup::FullSync),
+            SyncReport::Merge(SyncFollowup::FullSync, _),
             conn_2,
             sqlite_2,
             remote_client
@@ -1301,7 +1310,7 @@ mod tolstoy_tests {
         // Merge bootstrap+schema transactions from 1 into 2.
         // Will result in two Ivans.
         assert_sync!(
-            SyncReport::Merge(SyncFollowup::FullSync),
+            SyncReport::Merge(SyncFollowup::FullSync, _),
             conn_2,
             sqlite_2,
             remote_client
@@ -1352,7 +1361,7 @@ mod tolstoy_tests {
 
         // And now, merge!
         assert_sync!(
-            SyncReport::Merge(SyncFollowup::FullSync),
+            SyncReport::Merge(SyncFollowup::FullSync, _),
             conn_2,
             sqlite_2,
             remote_client
@@ -1429,7 +1438,7 @@ mod tolstoy_tests {
         // Merge bootstrap+schema transactions from 1 into 2.
         // Merge will result in two Ivans.
         assert_sync!(
-            SyncReport::Merge(SyncFollowup::FullSync),
+            SyncReport::Merge(SyncFollowup::FullSync, _),
             conn_2,
             sqlite_2,
             remote_client
@@ -1480,7 +1489,7 @@ mod tolstoy_tests {
 
         // First merges its changes with second's.
         assert_sync!(
-            SyncReport::Merge(SyncFollowup::None),
+            SyncReport::Merge(SyncFollowup::None, _),
             conn_1,
             sqlite_1,
             remote_client
@@ -1558,7 +1567,7 @@ mod tolstoy_tests {
 
         // Since :world/city is not unique, we elect not to smush these entities.
         assert_sync!(
-            SyncReport::Merge(SyncFollowup::FullSync),
+            SyncReport::Merge(SyncFollowup::FullSync, _),
             conn_2,
             sqlite_2,
             remote_client
@@ -1667,7 +1676,7 @@ mod tolstoy_tests {
 
         // Merge bootstrap+schema transactions from 1 into 2.
         assert_sync!(
-            SyncReport::Merge(SyncFollowup::FullSync),
+            SyncReport::Merge(SyncFollowup::FullSync, _),
             conn_2,
             sqlite_2,
             remote_client
@@ -1775,7 +1784,7 @@ mod tolstoy_tests {
 
         // Merge bootstrap+schema transactions from 1 into 2.
         assert_sync!(
-            SyncReport::Merge(SyncFollowup::FullSync),
+            SyncReport::Merge(SyncFollowup::FullSync, _),
             conn_2,
             sqlite_2,
             remote_client
@@ -1882,4 +1891,518 @@ mod tolstoy_tests {
             conn_2, sqlite_2, remote_client
         );
     }
+
+    #[test]
+    fn test_sync_through_directory() {
+        let root = ::std::env::temp_dir().join(format!("mentat-sync-{}", Uuid::new_v4()));
+        let mut log = DirectoryLog::open(&root).expect("opened");
+
+        let mut store_1 = Store::open("").expect("opened");
+        let mut store_2 = Store::open("").expect("opened");
+
+        store_1
+            .transact(
+                "[{:db/ident :person/name
+                   :db/valueType :db.type/string
+                   :db/cardinality :db.cardinality/one}]",
+            )
+            .expect("transacted");
+        store_1
+            .transact(r#"[{:person/name "Ivan"}]"#)
+            .expect("transacted");
+
+        let report = |result: Result<SyncResult>| match result.expect("synced") {
+            SyncResult::Atomic(report) => report,
+            SyncResult::NonAtomic(reports) => panic!("expected one report, got {:?}", reports),
+        };
+        let names = |store: &mut Store| {
+            store
+                .q_once("[:find [?name ...] :where [_ :person/name ?name]]", None)
+                .and_then(|o| Ok(o.into_coll()?))
+                .expect("queried")
+                .len()
+        };
+
+        assert_eq!(
+            report(store_1.sync_with(&mut log)),
+            SyncReport::RemoteFastForward
+        );
+
+        // A second store, with its own handle on the directory, adopts the first's transactions.
+        let mut other_log = DirectoryLog::open(&root).expect("opened");
+        assert_eq!(
+            report(store_2.sync_with(&mut other_log)),
+            SyncReport::Merge(SyncFollowup::None, vec![])
+        );
+        assert_eq!(names(&mut store_2), 1);
+
+        store_2
+            .transact(r#"[{:person/name "Vanya"}]"#)
+            .expect("transacted");
+        assert_eq!(
+            report(store_2.sync_with(&mut other_log)),
+            SyncReport::RemoteFastForward
+        );
+        assert_eq!(
+            report(store_1.sync_with(&mut log)),
+            SyncReport::LocalFastForward
+        );
+        assert_eq!(names(&mut store_1), 2);
+        assert_eq!(report(store_1.sync_with(&mut log)), SyncReport::NoChanges);
+
+        ::std::fs::remove_dir_all(&root).expect("removed");
+    }
+
+    #[test]
+    fn test_selective_sync() {
+        let root = ::std::env::temp_dir().join(format!("mentat-sync-{}", Uuid::new_v4()));
+        let mut log_1 = DirectoryLog::open(&root).expect("opened");
+        let mut log_2 = DirectoryLog::open(&root).expect("opened");
+        let filter = SyncFilter::new()
+            .exclude_attribute(kw!(:device/theme))
+            .exclude_vocabulary(kw!(:org.example/device));
+
+        let mut store_1 = Store::open("").expect("opened");
+        let mut store_2 = Store::open("").expect("opened");
+
+        store_1
+            .transact(
+                "[{:db/ident :person/name
+                   :db/valueType :db.type/string
+                   :db/cardinality :db.cardinality/one}
+                  {:db/ident :device/theme
+                   :db/valueType :db.type/string
+                   :db/cardinality :db.cardinality/one}
+                  {:db/ident :device/cache
+                   :db/valueType :db.type/string
+                   :db/cardinality :db.cardinality/one}]",
+            )
+            .expect("transacted");
+        store_1
+            .transact(
+                "[{:db/ident :org.example/device
+                   :db.schema/version 1
+                   :db.schema/attribute :device/cache}]",
+            )
+            .expect("transacted");
+        store_1
+            .transact(
+                r#"[{:person/name "Ivan" :device/theme "dark" :device/cache "cached"}
+                    {:device/theme "light"}]"#,
+            )
+            .expect("transacted");
+
+        let values = |store: &mut Store, attribute: &str| {
+            let mut values: Vec<String> = store
+                .q_once(
+                    &format!("[:find [?v ...] :where [_ {} ?v]]", attribute),
+                    None,
+                )
+                .and_then(|o| Ok(o.into_coll()?))
+                .expect("queried")
+                .into_iter()
+                .map(|v| v.into_string().expect("string").to_string())
+                .collect();
+            values.sort();
+            values
+        };
+
+        store_1
+            .sync_with_filter(&mut log_1, &filter)
+            .expect("synced");
+        store_2
+            .sync_with_filter(&mut log_2, &filter)
+            .expect("synced");
+
+        // The second store gets the names, but not the first store's device-local values.
+        assert_eq!(values(&mut store_2, ":person/name"), vec!["Ivan"]);
+        assert!(values(&mut store_2, ":device/theme").is_empty());
+        assert!(values(&mut store_2, ":device/cache").is_empty());
+
+        // Which never left the first store.
+        let uploaded: String = ::std::fs::read_dir(root.join("chunks"))
+            .expect("listed")
+            .map(|entry| ::std::fs::read_to_string(entry.expect("entry").path()).expect("read"))
+            .collect();
+        assert!(uploaded.contains("Ivan"));
+        assert!(!uploaded.contains("dark"));
+        assert!(!uploaded.contains("light"));
+        assert!(!uploaded.contains("cached"));
+
+        // Both stores keep their device-local values as they sync back and forth, and entities
+        // the second store creates don't collide with the first store's local-only entity.
+        store_2
+            .transact(r#"[{:person/name "Vanya" :device/theme "blue"}]"#)
+            .expect("transacted");
+        store_1
+            .transact(r#"[{:person/name "Petya"}]"#)
+            .expect("transacted");
+        store_2
+            .sync_with_filter(&mut log_2, &filter)
+            .expect("synced");
+        store_1
+            .sync_with_filter(&mut log_1, &filter)
+            .expect("synced");
+        store_2
+            .sync_with_filter(&mut log_2, &filter)
+            .expect("synced");
+
+        assert_eq!(
+            values(&mut store_1, ":person/name"),
+            vec!["Ivan", "Petya", "Vanya"]
+        );
+        assert_eq!(
+            values(&mut store_2, ":person/name"),
+            vec!["Ivan", "Petya", "Vanya"]
+        );
+        assert_eq!(values(&mut store_1, ":device/theme"), vec!["dark", "light"]);
+        assert_eq!(values(&mut store_1, ":device/cache"), vec!["cached"]);
+        assert_eq!(values(&mut store_2, ":device/theme"), vec!["blue"]);
+        assert_eq!(
+            store_1
+                .q_once(
+                    r#"[:find [?name ...] :where [?e :device/theme "light"] [?e :person/name ?name]]"#,
+                    None
+                )
+                .and_then(|o| Ok(o.into_coll()?))
+                .expect("queried"),
+            vec![]
+        );
+
+        // Core attributes always sync.
+        match store_1.sync_with_filter(
+            &mut log_1,
+            &SyncFilter::new().exclude_attribute(kw!(:db/ident)),
+        ) {
+            Err(MentatError::TolstoyError(TolstoyError::CoreAttributeExcluded(_))) => {}
+            x => panic!("expected CoreAttributeExcluded, got {:?}", x.map(|_| ())),
+        }
+
+        ::std::fs::remove_dir_all(&root).expect("removed");
+    }
+
+    /// Two stores share a person, then rename them and change their age concurrently, the first
+    /// store before the second if `first_is_earlier`. The second store syncs first, so the first
+    /// store merges, resolving conflicts with `resolver`. Returns the conflicts it resolved, and
+    /// each store's people once both have synced again.
+    /// Have two stores edit the same entity, then merge, resolving conflicts with `resolver`, or
+    /// as a plain sync does without one.
+    fn concurrent_edits(
+        resolver: Option<&dyn ConflictResolver>,
+        first_is_earlier: bool,
+    ) -> (Vec<ResolvedConflict>, Vec<String>, Vec<String>) {
+        let root = ::std::env::temp_dir().join(format!("mentat-sync-{}", Uuid::new_v4()));
+        let mut log_1 = DirectoryLog::open(&root).expect("opened");
+        let mut log_2 = DirectoryLog::open(&root).expect("opened");
+
+        let mut store_1 = Store::open("").expect("opened");
+        let mut store_2 = Store::open("").expect("opened");
+
+        store_1
+            .transact(
+                "[{:db/ident :person/name
+                   :db/valueType :db.type/string
+                   :db/cardinality :db.cardinality/one}
+                  {:db/ident :person/age
+                   :db/valueType :db.type/long
+                   :db/cardinality :db.cardinality/one}]",
+            )
+            .expect("transacted");
+        store_1
+            .transact(r#"[{:person/name "Ivan" :person/age 29}]"#)
+            .expect("transacted");
+        store_1.sync_with(&mut log_1).expect("synced");
+        store_2.sync_with(&mut log_2).expect("synced");
+
+        let edit_1 = |store: &mut Store| {
+            store
+                .transact(
+                    r#"[[:db/add 65538 :person/name "Vanechka"] [:db/add 65538 :person/age 30]]"#,
+                )
+                .expect("transacted");
+        };
+        let edit_2 = |store: &mut Store| {
+            store
+                .transact(
+                    r#"[[:db/add 65538 :person/name "Vanya"] [:db/add 65538 :person/age 31]]"#,
+                )
+                .expect("transacted");
+        };
+        if first_is_earlier {
+            edit_1(&mut store_1);
+            edit_2(&mut store_2);
+        } else {
+            edit_2(&mut store_2);
+            edit_1(&mut store_1);
+        }
+
+        store_2.sync_with(&mut log_2).expect("synced");
+        // Where local changes survive the merge, a follow-up sync uploads them.
+        let synced = match resolver {
+            Some(resolver) => store_1.sync_with_resolver(&mut log_1, &SyncFilter::new(), resolver),
+            None => store_1.sync_with(&mut log_1),
+        };
+        let resolved = match synced.expect("synced") {
+            SyncResult::Atomic(SyncReport::Merge(SyncFollowup::None, resolved)) => resolved,
+            SyncResult::NonAtomic(reports) => match &reports[..] {
+                [SyncReport::Merge(SyncFollowup::FullSync, resolved), SyncReport::RemoteFastForward] => {
+                    resolved.clone()
+                }
+                _ => panic!("expected a merge and an upload, got {:?}", reports),
+            },
+            x => panic!("expected a merge, got {}", x),
+        };
+        store_2.sync_with(&mut log_2).expect("synced");
+
+        let people = |store: &mut Store| {
+            let mut people: Vec<String> = store
+                .q_once(
+                    "[:find ?e ?name ?age :where [?e :person/name ?name] [?e :person/age ?age]]",
+                    None,
+                )
+                .and_then(|o| Ok(o.into_rel()?))
+                .expect("queried")
+                .into_iter()
+                .map(|row| {
+                    format!(
+                        "{} {} {}",
+                        row[0].clone().into_entid().expect("entid"),
+                        row[1].clone().into_string().expect("string"),
+                        row[2].clone().into_long().expect("long")
+                    )
+                })
+                .collect();
+            people.sort();
+            people
+        };
+        let people_1 = people(&mut store_1);
+        let people_2 = people(&mut store_2);
+
+        ::std::fs::remove_dir_all(&root).expect("removed");
+        (resolved, people_1, people_2)
+    }
+
+    fn resolutions(resolved: &[ResolvedConflict]) -> Vec<(String, Resolution)> {
+        let mut resolutions: Vec<(String, Resolution)> = resolved
+            .iter()
+            .map(|r| {
+                (
+                    format!("{:?} {:?}", r.conflict.local, r.conflict.remote),
+                    r.resolution.clone(),
+                )
+            })
+            .collect();
+        resolutions.sort_by(|a, b| a.0.cmp(&b.0));
+        resolutions
+    }
+
+    #[test]
+    fn test_sync_keeps_local_changes() {
+        // A plain sync replays local changes over remote ones, even remote ones made later.
+        let (resolved, people_1, people_2) = concurrent_edits(None, true);
+        assert_eq!(
+            resolutions(&resolved),
+            vec![
+                ("Long(30) Long(31)".to_string(), Resolution::Local),
+                (
+                    r#"String("Vanechka") String("Vanya")"#.to_string(),
+                    Resolution::Local
+                )
+            ]
+        );
+        assert_eq!(people_1, vec!["65538 Vanechka 30"]);
+        assert_eq!(people_2, people_1);
+    }
+
+    #[test]
+    fn test_conflict_resolution() {
+        let names = r#"String("Vanechka") String("Vanya")"#.to_string();
+        let ages = "Long(30) Long(31)".to_string();
+
+        // The last writer wins, whichever side it's on.
+        let (resolved, people_1, people_2) = concurrent_edits(Some(&LastWriterWins), true);
+        assert_eq!(
+            resolutions(&resolved),
+            vec![
+                (ages.clone(), Resolution::Remote),
+                (names.clone(), Resolution::Remote)
+            ]
+        );
+        assert!(resolved
+            .iter()
+            .all(|r| r.conflict.local_tx_instant < r.conflict.remote_tx_instant));
+        assert_eq!(people_1, vec!["65538 Vanya 31"]);
+        assert_eq!(people_2, people_1);
+
+        let (resolved, people_1, people_2) = concurrent_edits(Some(&LastWriterWins), false);
+        assert_eq!(
+            resolutions(&resolved),
+            vec![
+                (ages.clone(), Resolution::Local),
+                (names.clone(), Resolution::Local)
+            ]
+        );
+        assert_eq!(people_1, vec!["65538 Vanechka 30"]);
+        assert_eq!(people_2, people_1);
+
+        // Or one side always wins.
+        let (_, people_1, people_2) = concurrent_edits(Some(&RemoteWins), false);
+        assert_eq!(people_1, vec!["65538 Vanya 31"]);
+        assert_eq!(people_2, people_1);
+
+        let (_, people_1, people_2) = concurrent_edits(Some(&LocalWins), true);
+        assert_eq!(people_1, vec!["65538 Vanechka 30"]);
+        assert_eq!(people_2, people_1);
+
+        // Or a resolver of our own picks another value for some attributes.
+        let resolver = PerAttribute::new(RemoteWins).with(kw!(:person/name), |conflict| {
+            match (&conflict.local, &conflict.remote) {
+                (TypedValue::String(local), TypedValue::String(remote)) => {
+                    Resolution::Value(TypedValue::typed_string(format!("{} ({})", local, remote)))
+                }
+                _ => Resolution::Remote,
+            }
+        });
+
+        // It can be shared between the threads that sync.
+        fn assert_send_sync<T: Send + Sync>(_: &T) {}
+        assert_send_sync(&resolver);
+
+        let (resolved, people_1, people_2) = concurrent_edits(Some(&resolver), false);
+        assert_eq!(
+            resolutions(&resolved),
+            vec![
+                (ages, Resolution::Remote),
+                (
+                    names,
+                    Resolution::Value(TypedValue::typed_string("Vanechka (Vanya)"))
+                )
+            ]
+        );
+        assert_eq!(people_1, vec!["65538 Vanechka (Vanya) 31"]);
+        assert_eq!(people_2, people_1);
+    }
+
+    #[test]
+    fn test_sync_through_server() {
+        let server =
+            SyncServer::start("", "127.0.0.1:0".parse().expect("address")).expect("started");
+        let uri = format!("http://{}/api/0.1", server.address());
+        let user = Uuid::new_v4().to_string();
+
+        let mut store_1 = Store::open("").expect("opened");
+        let mut store_2 = Store::open("").expect("opened");
+
+        store_1
+            .transact(
+                "[{:db/ident :person/name
+                   :db/valueType :db.type/string
+                   :db/cardinality :db.cardinality/one}]",
+            )
+            .expect("transacted");
+        store_1
+            .transact(r#"[{:person/name "Ivan"}]"#)
+            .expect("transacted");
+
+        let report = |result: Result<SyncResult>| match result.expect("synced") {
+            SyncResult::Atomic(report) => report,
+            SyncResult::NonAtomic(reports) => panic!("expected one report, got {:?}", reports),
+        };
+
+        assert_eq!(
+            report(store_1.sync(&uri, &user)),
+            SyncReport::RemoteFastForward
+        );
+        assert_eq!(
+            report(store_2.sync(&uri, &user)),
+            SyncReport::Merge(SyncFollowup::None, vec![])
+        );
+        assert_eq!(
+            store_2
+                .q_once("[:find ?name . :where [_ :person/name ?name]]", None)
+                .and_then(|o| Ok(o.into_scalar()?))
+                .expect("queried"),
+            Some(Binding::Scalar(TypedValue::typed_string("Ivan")))
+        );
+        assert_eq!(report(store_2.sync(&uri, &user)), SyncReport::NoChanges);
+
+        // The head only moves forward: a transaction that doesn't descend from it is refused.
+        let mut client =
+            RemoteClient::new(uri.clone(), Uuid::parse_str(&user).expect("uuid")).expect("client");
+        let head = client.head().expect("head");
+        let stray = Uuid::new_v4();
+        client
+            .put_transaction(&stray, &Uuid::nil(), &[])
+            .expect("put");
+        match client.set_head(&stray) {
+            Err(MentatError::TolstoyError(TolstoyError::BadRemoteResponse(_))) => {}
+            x => panic!("expected BadRemoteResponse, got {:?}", x),
+        }
+        assert_eq!(client.head().expect("head"), head);
+    }
+
+    #[test]
+    #[cfg(feature = "sync_encryption")]
+    fn test_sync_encrypted_through_server() {
+        let path = ::std::env::temp_dir().join(format!("tolstoy-server-{}.db", Uuid::new_v4()));
+        let server = SyncServer::start(
+            path.to_str().expect("path"),
+            "127.0.0.1:0".parse().expect("address"),
+        )
+        .expect("started");
+        let uri = format!("http://{}/api/0.1", server.address());
+        let user = Uuid::new_v4();
+        let keys = || SyncKeys::from_passphrase(1, "correct horse", &user).expect("derived");
+
+        let mut store_1 = Store::open("").expect("opened");
+        let mut store_2 = Store::open("").expect("opened");
+        store_1
+            .transact(
+                "[{:db/ident :person/name
+                   :db/valueType :db.type/string
+                   :db/cardinality :db.cardinality/one}]",
+            )
+            .expect("transacted");
+        store_1
+            .transact(r#"[{:person/name "Ivan"}]"#)
+            .expect("transacted");
+
+        store_1
+            .sync_encrypted(&uri, &user.to_string(), keys())
+            .expect("synced");
+        store_2
+            .sync_encrypted(&uri, &user.to_string(), keys())
+            .expect("synced");
+        assert_eq!(
+            store_2
+                .q_once("[:find ?name . :where [_ :person/name ?name]]", None)
+                .and_then(|o| Ok(o.into_scalar()?))
+                .expect("queried"),
+            Some(Binding::Scalar(TypedValue::typed_string("Ivan")))
+        );
+
+        // The server never saw a datom.
+        drop(server);
+        let stored = ::std::fs::read(&path).expect("read");
+        assert!(!String::from_utf8_lossy(&stored).contains("Ivan"));
+        assert!(!String::from_utf8_lossy(&stored).contains("person/name"));
+
+        // Without the key, or with another, the log can't be read.
+        let server = SyncServer::start(
+            path.to_str().expect("path"),
+            "127.0.0.1:0".parse().expect("address"),
+        )
+        .expect("started");
+        let uri = format!("http://{}/api/0.1", server.address());
+        let plain = RemoteClient::new(uri.clone(), user).expect("client");
+        assert!(plain.transactions_after(&Uuid::nil()).is_err());
+        let wrong = EncryptedLog::new(
+            RemoteClient::new(uri, user).expect("client"),
+            SyncKeys::from_passphrase(1, "battery staple", &user).expect("derived"),
+        );
+        assert!(wrong.transactions_after(&Uuid::nil()).is_err());
+
+        drop(server);
+        ::std::fs::remove_file(&path).expect("removed");
+    }
 }
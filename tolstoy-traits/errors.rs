@@ -41,6 +41,18 @@ pub enum TolstoyError {
     #[fail(display = "not yet implemented: {}", _0)]
     NotYetImplemented(String),
 
+    #[fail(display = "no sync key with version {}", _0)]
+    UnknownKeyVersion(u32),
+
+    #[fail(display = "couldn't encrypt or decrypt sync data: {}", _0)]
+    EncryptionFailed(String),
+
+    #[fail(
+        display = "{} can't be excluded from sync: core attributes always sync",
+        _0
+    )]
+    CoreAttributeExcluded(String),
+
     #[fail(display = "{}", _0)]
     DbError(#[cause] DbError),
 
@@ -0,0 +1,139 @@
+// Copyright 2018 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Resolving conflicts between local and remote changes during a merge.
+//!
+//! A conflict is a cardinality-one attribute of an entity that both sides already shared, which
+//! local and remote transactions since then assert different values for. When rebasing local
+//! transactions onto remote ones, the syncer asks a `ConflictResolver` which value to keep, and
+//! reports every conflict and its resolution in `SyncReport::Merge`.
+
+use std::collections::BTreeMap;
+
+use core_traits::{Entid, TypedValue};
+use edn::Keyword;
+use mentat_core::{DateTime, HasSchema, Schema, Utc};
+
+/// Local and remote values for the same cardinality-one attribute of the same entity.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Conflict {
+    pub e: Entid,
+    pub a: Entid,
+    pub local: TypedValue,
+
+    /// When the local transaction asserting `local` was made.
+    pub local_tx_instant: DateTime<Utc>,
+
+    pub remote: TypedValue,
+
+    /// When the remote transaction asserting `remote` was made.
+    pub remote_tx_instant: DateTime<Utc>,
+}
+
+/// Which value a conflict ends up with.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Resolution {
+    Local,
+    Remote,
+
+    /// Neither: some other value, such as a combination of the two.
+    Value(TypedValue),
+}
+
+/// A conflict, and how it was resolved.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ResolvedConflict {
+    pub conflict: Conflict,
+    pub resolution: Resolution,
+}
+
+pub trait ConflictResolver {
+    fn resolve(&self, schema: &Schema, conflict: &Conflict) -> Resolution;
+}
+
+/// Keep whichever value was asserted last, going by the transactions' `:db/txInstant`s. Ties go to
+/// the remote, which other clients may already have.
+///
+/// `:db/txInstant`s come from each device's own clock, and nothing keeps those clocks in step, so a
+/// device whose clock runs fast wins conflicts it should lose.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LastWriterWins;
+
+impl ConflictResolver for LastWriterWins {
+    fn resolve(&self, _schema: &Schema, conflict: &Conflict) -> Resolution {
+        if conflict.local_tx_instant > conflict.remote_tx_instant {
+            Resolution::Local
+        } else {
+            Resolution::Remote
+        }
+    }
+}
+
+/// Always keep the remote value.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RemoteWins;
+
+impl ConflictResolver for RemoteWins {
+    fn resolve(&self, _schema: &Schema, _conflict: &Conflict) -> Resolution {
+        Resolution::Remote
+    }
+}
+
+/// Always keep the local value. This is how syncing resolves conflicts unless told otherwise.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LocalWins;
+
+impl ConflictResolver for LocalWins {
+    fn resolve(&self, _schema: &Schema, _conflict: &Conflict) -> Resolution {
+        Resolution::Local
+    }
+}
+
+type ResolverFn = Box<dyn Fn(&Conflict) -> Resolution + Send + Sync>;
+
+/// Resolve conflicts over particular attributes with functions of their own, and everything else
+/// with a fallback resolver.
+pub struct PerAttribute {
+    resolvers: BTreeMap<Keyword, ResolverFn>,
+    fallback: Box<dyn ConflictResolver + Send + Sync>,
+}
+
+impl PerAttribute {
+    pub fn new<R>(fallback: R) -> PerAttribute
+    where
+        R: ConflictResolver + Send + Sync + 'static,
+    {
+        PerAttribute {
+            resolvers: BTreeMap::new(),
+            fallback: Box::new(fallback),
+        }
+    }
+
+    /// Resolve conflicts over `attribute` with `resolver`.
+    pub fn with<F>(mut self, attribute: Keyword, resolver: F) -> PerAttribute
+    where
+        F: Fn(&Conflict) -> Resolution + Send + Sync + 'static,
+    {
+        self.resolvers.insert(attribute, Box::new(resolver));
+        self
+    }
+}
+
+impl ConflictResolver for PerAttribute {
+    fn resolve(&self, schema: &Schema, conflict: &Conflict) -> Resolution {
+        match schema
+            .get_ident(conflict.a)
+            .and_then(|ident| self.resolvers.get(ident))
+        {
+            Some(resolver) => resolver(conflict),
+            None => self.fallback.resolve(schema, conflict),
+        }
+    }
+}
@@ -0,0 +1,288 @@
+// Copyright 2018 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! A global transaction log kept in a directory, so that stores can sync without a server: over a
+//! shared folder, a USB drive, or a temporary directory in tests.
+//!
+//! The directory holds the same JSON documents the sync server serves:
+//!
+//! - `chunks/<uuid>.json`: one `TxPart`;
+//! - `transactions/<uuid>.json`: a transaction's parent and its chunks, in order;
+//! - `head`: the last transaction in the log;
+//! - `head.lock`: present only while a store is moving the head.
+//!
+//! Every file is written to a temporary name and then renamed into place, so readers never see a
+//! partial write, and the head only moves once the transaction it names is complete. The log is
+//! the chain of parents leading back from the head; transactions left behind by an interrupted
+//! upload aren't part of it. Like the sync server, the log only moves the head to a transaction
+//! that descends from it, so a store that raced another to upload is told to sync again rather
+//! than overwriting what the other uploaded. A store that dies while moving the head leaves
+//! `head.lock` behind, and it has to be removed by hand.
+//!
+//! Wrapped in an `EncryptedLog`, chunks and each transaction's list of chunks are written sealed.
+
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use uuid::Uuid;
+
+use public_traits::errors::Result;
+use tolstoy_traits::errors::TolstoyError;
+
+use crate::logger::d;
+use crate::types::{LogStorage, StoredTx};
+
+#[derive(Serialize, Deserialize)]
+struct SerializedHead {
+    head: Uuid,
+}
+
+pub struct DirectoryLog {
+    root: PathBuf,
+}
+
+impl DirectoryLog {
+    /// Use the log in the directory `root`, creating the directory if it doesn't exist.
+    pub fn open<P>(root: P) -> Result<DirectoryLog>
+    where
+        P: AsRef<Path>,
+    {
+        let root = root.as_ref().to_path_buf();
+        fs::create_dir_all(root.join("chunks"))?;
+        fs::create_dir_all(root.join("transactions"))?;
+        Ok(DirectoryLog { root })
+    }
+
+    fn head_path(&self) -> PathBuf {
+        self.root.join("head")
+    }
+
+    fn head_lock_path(&self) -> PathBuf {
+        self.root.join("head.lock")
+    }
+
+    pub(crate) fn transaction_path(&self, tx: &Uuid) -> PathBuf {
+        self.root.join("transactions").join(format!("{}.json", tx))
+    }
+
+    pub(crate) fn chunk_path(&self, chunk: &Uuid) -> PathBuf {
+        self.root.join("chunks").join(format!("{}.json", chunk))
+    }
+
+    fn read<T>(&self, path: &Path) -> Result<T>
+    where
+        T: DeserializeOwned,
+    {
+        d(&format!("reading {:?}", path));
+        let json = fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&json)?)
+    }
+
+    /// Write `json` to `path` by writing it elsewhere and renaming it into place.
+    fn write_json(&self, path: &Path, json: &str) -> Result<()> {
+        d(&format!("writing {:?}", path));
+        let temporary = path.with_extension(format!("{}.tmp", Uuid::new_v4()));
+        {
+            let mut file = fs::File::create(&temporary)?;
+            file.write_all(json.as_bytes())?;
+            file.sync_all()?;
+        }
+        fs::rename(&temporary, path)?;
+        Ok(())
+    }
+
+    fn write<T>(&self, path: &Path, value: &T) -> Result<()>
+    where
+        T: Serialize,
+    {
+        self.write_json(path, &serde_json::to_string(value)?)
+    }
+}
+
+/// Held while moving the head; removes the lock file when dropped.
+struct HeadLock {
+    path: PathBuf,
+}
+
+impl HeadLock {
+    fn acquire(path: PathBuf) -> Result<HeadLock> {
+        match fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+        {
+            Ok(_) => Ok(HeadLock { path }),
+            Err(ref e) if e.kind() == ErrorKind::AlreadyExists => {
+                bail!(TolstoyError::BadRemoteState(format!(
+                    "another store is moving the head: {:?}",
+                    path
+                )))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl Drop for HeadLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+impl LogStorage for DirectoryLog {
+    fn read_head(&self) -> Result<Uuid> {
+        let path = self.head_path();
+        if !path.exists() {
+            return Ok(Uuid::nil());
+        }
+        self.read::<SerializedHead>(&path).map(|h| h.head)
+    }
+
+    fn write_head(&mut self, tx: &Uuid) -> Result<()> {
+        let _lock = HeadLock::acquire(self.head_lock_path())?;
+        let head = self.read_head()?;
+        let mut ancestor = *tx;
+        while ancestor != head {
+            if ancestor.is_nil() {
+                bail!(TolstoyError::BadRemoteState(format!(
+                    "transaction {} doesn't descend from the head {}",
+                    tx, head
+                )));
+            }
+            let transaction: StoredTx = self.read(&self.transaction_path(&ancestor))?;
+            ancestor = transaction.parent;
+        }
+        self.write(&self.head_path(), &SerializedHead { head: *tx })
+    }
+
+    /// Walk back from the head to `tx`, then return the transactions in between in order.
+    fn read_transactions_after(&self, tx: &Uuid) -> Result<Vec<(Uuid, StoredTx)>> {
+        let mut transactions = vec![];
+        let mut next = self.read_head()?;
+        while next != *tx {
+            if next.is_nil() {
+                bail!(TolstoyError::BadRemoteState(format!(
+                    "transaction {} is not in the log",
+                    tx
+                )));
+            }
+            let transaction: StoredTx = self.read(&self.transaction_path(&next))?;
+            let parent = transaction.parent;
+            transactions.push((next, transaction));
+            next = parent;
+        }
+        transactions.reverse();
+        Ok(transactions)
+    }
+
+    fn read_chunk(&self, chunk: &Uuid) -> Result<String> {
+        let path = self.chunk_path(chunk);
+        d(&format!("reading {:?}", path));
+        Ok(fs::read_to_string(path)?)
+    }
+
+    fn write_transaction(&mut self, tx: &Uuid, transaction: &StoredTx) -> Result<()> {
+        self.write(&self.transaction_path(tx), transaction)
+    }
+
+    fn write_chunk(&mut self, chunk: &Uuid, json: &str) -> Result<()> {
+        self.write_json(&self.chunk_path(chunk), json)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use core_traits::TypedValue;
+
+    use crate::types::{GlobalTransactionLog, Tx, TxPart};
+
+    fn part(e: i64) -> TxPart {
+        TxPart {
+            partitions: None,
+            e,
+            a: 1,
+            v: TypedValue::Long(e),
+            tx: 2,
+            added: true,
+        }
+    }
+
+    #[test]
+    fn test_directory_log() {
+        let root = ::std::env::temp_dir().join(format!("tolstoy-log-{}", Uuid::new_v4()));
+        let mut log = DirectoryLog::open(&root).expect("opened");
+        assert_eq!(log.head().expect("head"), Uuid::nil());
+        assert!(log
+            .transactions_after(&Uuid::nil())
+            .expect("transactions")
+            .is_empty());
+
+        let (first, second, orphan) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let chunks: Vec<Uuid> = (0..3).map(|_| Uuid::new_v4()).collect();
+        for (i, chunk) in chunks.iter().enumerate() {
+            log.put_chunk(chunk, &part(i as i64)).expect("put chunk");
+        }
+        log.put_transaction(&first, &Uuid::nil(), &chunks[..1])
+            .expect("put transaction");
+        log.put_transaction(&second, &first, &chunks[1..])
+            .expect("put transaction");
+        log.set_head(&second).expect("set head");
+
+        // A transaction that never became the head isn't in the log.
+        log.put_transaction(&orphan, &second, &chunks[..1])
+            .expect("put transaction");
+
+        // The head only moves forward: a transaction that doesn't descend from it can't replace it,
+        // and nothing can while another store holds the lock.
+        let sibling = Uuid::new_v4();
+        log.put_transaction(&sibling, &first, &chunks[..1])
+            .expect("put transaction");
+        assert!(log.set_head(&sibling).is_err());
+        fs::write(root.join("head.lock"), "").expect("locked");
+        assert!(log.set_head(&orphan).is_err());
+        fs::remove_file(root.join("head.lock")).expect("unlocked");
+
+        // Another handle on the same directory sees the same log.
+        let log = DirectoryLog::open(&root).expect("opened");
+        assert_eq!(log.head().expect("head"), second);
+        assert_eq!(
+            log.transactions_after(&Uuid::nil()).expect("transactions"),
+            vec![
+                Tx {
+                    tx: first,
+                    parts: vec![part(0)],
+                },
+                Tx {
+                    tx: second,
+                    parts: vec![part(1), part(2)],
+                },
+            ]
+        );
+        assert_eq!(
+            log.transactions_after(&first).expect("transactions"),
+            vec![Tx {
+                tx: second,
+                parts: vec![part(1), part(2)],
+            }]
+        );
+        assert!(log
+            .transactions_after(&second)
+            .expect("transactions")
+            .is_empty());
+        assert!(log.transactions_after(&orphan).is_err());
+
+        fs::remove_dir_all(&root).expect("removed");
+    }
+}
@@ -0,0 +1,410 @@
+// Copyright 2018 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! End-to-end encryption of what a store uploads when it syncs.
+//!
+//! An `EncryptedLog` wraps the storage of a global transaction log and, with `SyncKeys`, seals each
+//! chunk, and each transaction's list of chunks, with AES-256-GCM before it leaves the device, so whatever holds the log never sees
+//! datoms. A transaction's parent stays in the clear, since the log is the chain of parents. Each
+//! sealed payload names the version of the key that sealed it and is bound to the chunk or
+//! transaction it belongs to, so it can't be moved elsewhere in the log undetected.
+//!
+//! Keys are rotated by sealing with a new version while keeping the old ones around to open what
+//! they sealed.
+
+use std::collections::BTreeMap;
+
+use openssl::hash::MessageDigest;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+use uuid::Uuid;
+
+use public_traits::errors::Result;
+use tolstoy_traits::errors::TolstoyError;
+
+use crate::types::{GlobalTransactionLog, LogStorage, StoredTx, Tx, TxPart};
+
+const KEY_LENGTH: usize = 32;
+const NONCE_LENGTH: usize = 12;
+const TAG_LENGTH: usize = 16;
+
+/// Iterations of PBKDF2 when deriving a key from a passphrase.
+const PBKDF2_ITERATIONS: usize = 100_000;
+
+/// An encrypted payload, as it's stored in the log.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Sealed {
+    key_version: u32,
+    nonce: String,
+    ciphertext: String,
+}
+
+/// The keys a store syncs with: the current one, which seals, and older ones, which only open.
+#[derive(Clone)]
+pub struct SyncKeys {
+    current: u32,
+    keys: BTreeMap<u32, [u8; KEY_LENGTH]>,
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Result<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        bail!(TolstoyError::EncryptionFailed("odd-length hex".to_string()));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&hex[i..i + 2], 16)
+                .map_err(|e| TolstoyError::EncryptionFailed(e.to_string()).into())
+        })
+        .collect()
+}
+
+fn failed(e: openssl::error::ErrorStack) -> TolstoyError {
+    TolstoyError::EncryptionFailed(e.to_string())
+}
+
+fn chunk_aad(chunk: &Uuid) -> Vec<u8> {
+    [&b"chunk:"[..], chunk.as_bytes()].concat()
+}
+
+fn transaction_aad(tx: &Uuid, parent: &Uuid) -> Vec<u8> {
+    [&b"transaction:"[..], tx.as_bytes(), parent.as_bytes()].concat()
+}
+
+impl SyncKeys {
+    /// Seal with `key`, calling it `version`.
+    pub fn new(version: u32, key: [u8; KEY_LENGTH]) -> SyncKeys {
+        SyncKeys {
+            current: version,
+            keys: vec![(version, key)].into_iter().collect(),
+        }
+    }
+
+    /// Seal with a key derived from `passphrase` for the user `user`, calling it `version`. The
+    /// same passphrase gives the same key on every device.
+    pub fn from_passphrase(version: u32, passphrase: &str, user: &Uuid) -> Result<SyncKeys> {
+        let mut key = [0; KEY_LENGTH];
+        openssl::pkcs5::pbkdf2_hmac(
+            passphrase.as_bytes(),
+            user.as_bytes(),
+            PBKDF2_ITERATIONS,
+            MessageDigest::sha256(),
+            &mut key,
+        )
+        .map_err(failed)?;
+        Ok(SyncKeys::new(version, key))
+    }
+
+    /// Also open what was sealed with an older `key`, called `version`.
+    pub fn with_old_key(mut self, version: u32, key: [u8; KEY_LENGTH]) -> SyncKeys {
+        self.keys.insert(version, key);
+        self
+    }
+
+    fn seal(&self, aad: &[u8], plaintext: &[u8]) -> Result<Sealed> {
+        let mut nonce = [0; NONCE_LENGTH];
+        openssl::rand::rand_bytes(&mut nonce).map_err(failed)?;
+        let mut tag = [0; TAG_LENGTH];
+        let mut ciphertext = encrypt_aead(
+            Cipher::aes_256_gcm(),
+            &self.keys[&self.current],
+            Some(&nonce),
+            aad,
+            plaintext,
+            &mut tag,
+        )
+        .map_err(failed)?;
+        ciphertext.extend_from_slice(&tag);
+        Ok(Sealed {
+            key_version: self.current,
+            nonce: to_hex(&nonce),
+            ciphertext: to_hex(&ciphertext),
+        })
+    }
+
+    fn open(&self, aad: &[u8], sealed: &Sealed) -> Result<Vec<u8>> {
+        let key = self
+            .keys
+            .get(&sealed.key_version)
+            .ok_or(TolstoyError::UnknownKeyVersion(sealed.key_version))?;
+        let nonce = from_hex(&sealed.nonce)?;
+        let ciphertext = from_hex(&sealed.ciphertext)?;
+        if ciphertext.len() < TAG_LENGTH {
+            bail!(TolstoyError::EncryptionFailed(
+                "ciphertext is too short".to_string()
+            ));
+        }
+        let (ciphertext, tag) = ciphertext.split_at(ciphertext.len() - TAG_LENGTH);
+        Ok(decrypt_aead(
+            Cipher::aes_256_gcm(),
+            key,
+            Some(&nonce),
+            aad,
+            ciphertext,
+            tag,
+        )
+        .map_err(failed)?)
+    }
+
+    pub(crate) fn seal_chunk(&self, chunk: &Uuid, part: &TxPart) -> Result<Sealed> {
+        self.seal(&chunk_aad(chunk), serde_json::to_string(part)?.as_bytes())
+    }
+
+    pub(crate) fn open_chunk(&self, chunk: &Uuid, sealed: &Sealed) -> Result<TxPart> {
+        let json = self.open(&chunk_aad(chunk), sealed)?;
+        Ok(serde_json::from_slice(&json)?)
+    }
+
+    pub(crate) fn seal_chunks(&self, tx: &Uuid, parent: &Uuid, chunks: &[Uuid]) -> Result<Sealed> {
+        self.seal(
+            &transaction_aad(tx, parent),
+            serde_json::to_string(chunks)?.as_bytes(),
+        )
+    }
+
+    /// Open the list of chunks of the transaction `tx`, or fail if it wasn't sealed.
+    pub(crate) fn open_chunks(
+        &self,
+        tx: &Uuid,
+        parent: &Uuid,
+        sealed: Option<&Sealed>,
+    ) -> Result<Vec<Uuid>> {
+        let sealed = sealed.ok_or_else(|| {
+            TolstoyError::EncryptionFailed(format!("transaction {} isn't encrypted", tx))
+        })?;
+        let json = self.open(&transaction_aad(tx, parent), sealed)?;
+        Ok(serde_json::from_slice(&json)?)
+    }
+}
+
+/// A global transaction log that seals what it writes to `storage` and opens what it reads.
+pub struct EncryptedLog<S> {
+    storage: S,
+    keys: SyncKeys,
+}
+
+impl<S> EncryptedLog<S>
+where
+    S: LogStorage,
+{
+    pub fn new(storage: S, keys: SyncKeys) -> EncryptedLog<S> {
+        EncryptedLog { storage, keys }
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.storage
+    }
+
+    pub fn into_inner(self) -> S {
+        self.storage
+    }
+}
+
+impl<S> GlobalTransactionLog for EncryptedLog<S>
+where
+    S: LogStorage,
+{
+    fn head(&self) -> Result<Uuid> {
+        self.storage.read_head()
+    }
+
+    fn transactions_after(&self, tx: &Uuid) -> Result<Vec<Tx>> {
+        self.storage
+            .read_transactions_after(tx)?
+            .into_iter()
+            .map(|(tx, transaction)| {
+                let sealed = match transaction.sealed {
+                    Some(sealed) => Some(serde_json::from_value::<Sealed>(sealed)?),
+                    None => None,
+                };
+                let parts = self
+                    .keys
+                    .open_chunks(&tx, &transaction.parent, sealed.as_ref())?
+                    .iter()
+                    .map(|chunk| {
+                        let sealed: Sealed =
+                            serde_json::from_str(&self.storage.read_chunk(chunk)?)?;
+                        self.keys.open_chunk(chunk, &sealed)
+                    })
+                    .collect::<Result<Vec<TxPart>>>()?;
+                Ok(Tx { tx, parts })
+            })
+            .collect()
+    }
+
+    fn set_head(&mut self, tx: &Uuid) -> Result<()> {
+        self.storage.write_head(tx)
+    }
+
+    fn put_transaction(&mut self, tx: &Uuid, parent_tx: &Uuid, chunk_txs: &[Uuid]) -> Result<()> {
+        let sealed = self.keys.seal_chunks(tx, parent_tx, chunk_txs)?;
+        let transaction = StoredTx {
+            parent: *parent_tx,
+            chunks: vec![],
+            sealed: Some(serde_json::to_value(&sealed)?),
+        };
+        self.storage.write_transaction(tx, &transaction)
+    }
+
+    fn put_chunk(&mut self, tx: &Uuid, payload: &TxPart) -> Result<()> {
+        let sealed = self.keys.seal_chunk(tx, payload)?;
+        self.storage
+            .write_chunk(tx, &serde_json::to_string(&sealed)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+    use std::path::PathBuf;
+
+    use core_traits::TypedValue;
+
+    use public_traits::errors::MentatError;
+
+    use crate::directory_log::DirectoryLog;
+
+    fn part() -> TxPart {
+        TxPart {
+            partitions: None,
+            e: 65536,
+            a: 100,
+            v: TypedValue::typed_string("secret"),
+            tx: 268435457,
+            added: true,
+        }
+    }
+
+    #[test]
+    fn test_seal_and_open() {
+        let keys = SyncKeys::new(1, [7; KEY_LENGTH]);
+        let (chunk, other) = (Uuid::new_v4(), Uuid::new_v4());
+
+        let sealed = keys.seal_chunk(&chunk, &part()).expect("sealed");
+        assert_eq!(sealed.key_version, 1);
+        assert!(!serde_json::to_string(&sealed)
+            .expect("serialized")
+            .contains("secret"));
+        assert_eq!(keys.open_chunk(&chunk, &sealed).expect("opened"), part());
+
+        // Sealing twice gives different ciphertexts.
+        assert_ne!(keys.seal_chunk(&chunk, &part()).expect("sealed"), sealed);
+
+        // A sealed chunk can't be passed off as another, or tampered with.
+        assert!(keys.open_chunk(&other, &sealed).is_err());
+        let mut tampered = sealed.clone();
+        let flipped = if tampered.ciphertext.starts_with('0') {
+            "1"
+        } else {
+            "0"
+        };
+        tampered.ciphertext.replace_range(0..1, flipped);
+        assert!(keys.open_chunk(&chunk, &tampered).is_err());
+
+        // Nor can a transaction's chunks be moved to another transaction.
+        let (tx, parent) = (Uuid::new_v4(), Uuid::new_v4());
+        let sealed = keys
+            .seal_chunks(&tx, &parent, &[chunk, other])
+            .expect("sealed");
+        assert_eq!(
+            keys.open_chunks(&tx, &parent, Some(&sealed))
+                .expect("opened"),
+            vec![chunk, other]
+        );
+        assert!(keys.open_chunks(&tx, &Uuid::nil(), Some(&sealed)).is_err());
+        assert!(keys.open_chunks(&tx, &parent, None).is_err());
+    }
+
+    #[test]
+    fn test_key_versions() {
+        let chunk = Uuid::new_v4();
+        let old = SyncKeys::new(1, [1; KEY_LENGTH]);
+        let sealed_with_old = old.seal_chunk(&chunk, &part()).expect("sealed");
+
+        // After rotating, new chunks are sealed with the new key, and old ones still open.
+        let new = SyncKeys::new(2, [2; KEY_LENGTH]).with_old_key(1, [1; KEY_LENGTH]);
+        let sealed_with_new = new.seal_chunk(&chunk, &part()).expect("sealed");
+        assert_eq!(sealed_with_new.key_version, 2);
+        assert_eq!(
+            new.open_chunk(&chunk, &sealed_with_old).expect("opened"),
+            part()
+        );
+
+        match old.open_chunk(&chunk, &sealed_with_new) {
+            Err(MentatError::TolstoyError(TolstoyError::UnknownKeyVersion(2))) => {}
+            x => panic!("expected UnknownKeyVersion, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_from_passphrase() {
+        let user = Uuid::new_v4();
+        let chunk = Uuid::new_v4();
+        let here = SyncKeys::from_passphrase(1, "correct horse", &user).expect("derived");
+        let there = SyncKeys::from_passphrase(1, "correct horse", &user).expect("derived");
+        let wrong = SyncKeys::from_passphrase(1, "battery staple", &user).expect("derived");
+
+        let sealed = here.seal_chunk(&chunk, &part()).expect("sealed");
+        assert_eq!(there.open_chunk(&chunk, &sealed).expect("opened"), part());
+        assert!(wrong.open_chunk(&chunk, &sealed).is_err());
+    }
+
+    #[test]
+    fn test_encrypted_directory_log() {
+        let root = ::std::env::temp_dir().join(format!("tolstoy-log-{}", Uuid::new_v4()));
+        let keys = SyncKeys::new(1, [3; KEY_LENGTH]);
+        let mut log = EncryptedLog::new(DirectoryLog::open(&root).expect("opened"), keys);
+
+        let (tx, chunk) = (Uuid::new_v4(), Uuid::new_v4());
+        log.put_chunk(&chunk, &part()).expect("put chunk");
+        log.put_transaction(&tx, &Uuid::nil(), &[chunk])
+            .expect("put transaction");
+        log.set_head(&tx).expect("set head");
+
+        // Neither the chunk's datom nor the transaction's chunks are written in the clear.
+        let written = |path: PathBuf| fs::read_to_string(path).expect("read");
+        assert!(!written(log.inner().chunk_path(&chunk)).contains("secret"));
+        assert!(!written(log.inner().transaction_path(&tx)).contains(&chunk.to_string()));
+
+        let expected = vec![Tx {
+            tx,
+            parts: vec![part()],
+        }];
+        assert_eq!(
+            log.transactions_after(&Uuid::nil()).expect("transactions"),
+            expected
+        );
+
+        // Without the right key, or without any key, the log can't be read.
+        let wrong = EncryptedLog::new(
+            DirectoryLog::open(&root).expect("opened"),
+            SyncKeys::new(1, [4; KEY_LENGTH]),
+        );
+        assert!(wrong.transactions_after(&Uuid::nil()).is_err());
+        let mut plain = DirectoryLog::open(&root).expect("opened");
+        assert!(plain.transactions_after(&Uuid::nil()).is_err());
+
+        // Nor can a log with a key read what was written without one.
+        let (other_tx, other_chunk) = (Uuid::new_v4(), Uuid::new_v4());
+        plain.put_chunk(&other_chunk, &part()).expect("put chunk");
+        plain
+            .put_transaction(&other_tx, &tx, &[other_chunk])
+            .expect("put transaction");
+        plain.set_head(&other_tx).expect("set head");
+        assert!(log.transactions_after(&tx).is_err());
+
+        fs::remove_dir_all(&root).expect("removed");
+    }
+}
@@ -0,0 +1,106 @@
+// Copyright 2018 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Selective sync: keeping some attributes out of the global transaction log.
+//!
+//! Datoms of an excluded attribute stay in the local store. They aren't uploaded, and any that
+//! arrive from the log are ignored. Everything else syncs as usual, including the definitions of
+//! excluded attributes, so that every store agrees on what the attributes are.
+//!
+//! Every local transaction is still uploaded, even one left with nothing but its `:db/txInstant`,
+//! and entids used only by excluded datoms are still reserved in the uploaded partitions, so that
+//! other stores never allocate them to something else.
+
+use std::collections::{BTreeSet, HashSet};
+
+use core_traits::{Entid, TypedValue};
+use edn::Keyword;
+use mentat_core::HasSchema;
+use mentat_transaction::query::{QueryInputs, Variable};
+use mentat_transaction::{InProgress, Queryable};
+
+use public_traits::errors::Result;
+use tolstoy_traits::errors::TolstoyError;
+
+/// Which attributes don't take part in sync: named ones, and those of named vocabularies.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct SyncFilter {
+    attributes: BTreeSet<Keyword>,
+    vocabularies: BTreeSet<Keyword>,
+}
+
+/// Core attributes, such as `:db/ident` and `:db/txInstant`, describe the store itself, and
+/// always sync.
+fn is_core(keyword: &Keyword) -> bool {
+    keyword
+        .namespace()
+        .map_or(false, |ns| ns == "db" || ns.starts_with("db."))
+}
+
+impl SyncFilter {
+    /// A filter that syncs everything.
+    pub fn new() -> SyncFilter {
+        SyncFilter::default()
+    }
+
+    /// Keep the attribute `attribute` out of sync.
+    pub fn exclude_attribute(mut self, attribute: Keyword) -> SyncFilter {
+        self.attributes.insert(attribute);
+        self
+    }
+
+    /// Keep every attribute of the vocabulary named `vocabulary` out of sync.
+    pub fn exclude_vocabulary(mut self, vocabulary: Keyword) -> SyncFilter {
+        self.vocabularies.insert(vocabulary);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.attributes.is_empty() && self.vocabularies.is_empty()
+    }
+
+    /// The entids of the excluded attributes the store knows about. Attributes and vocabularies
+    /// the store hasn't defined yet are skipped.
+    pub(crate) fn excluded_attributes(&self, ip: &InProgress<'_, '_>) -> Result<HashSet<Entid>> {
+        if let Some(core) = self.attributes.iter().find(|a| is_core(a)) {
+            bail!(TolstoyError::CoreAttributeExcluded(core.to_string()));
+        }
+
+        let mut excluded: HashSet<Entid> = self
+            .attributes
+            .iter()
+            .filter_map(|a| ip.get_entid(a))
+            .map(|e| e.0)
+            .collect();
+
+        for vocabulary in &self.vocabularies {
+            let attributes = ip
+                .q_once(
+                    "[:find [?a ...] :in ?vocabulary
+                      :where [?v :db/ident ?vocabulary] [?v :db.schema/attribute ?a]]",
+                    QueryInputs::with_value_sequence(vec![(
+                        Variable::from_valid_name("?vocabulary"),
+                        TypedValue::Keyword(vocabulary.clone().into()),
+                    )]),
+                )?
+                .into_coll()?;
+            for attribute in attributes {
+                if let Some(e) = attribute.into_entid() {
+                    if ip.get_ident(e).map_or(false, is_core) {
+                        bail!(TolstoyError::CoreAttributeExcluded(vocabulary.to_string()));
+                    }
+                    excluded.insert(e);
+                }
+            }
+        }
+
+        Ok(excluded)
+    }
+}
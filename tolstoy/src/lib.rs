@@ -21,13 +21,28 @@ extern crate serde_derive;
 extern crate core_traits;
 
 pub mod bootstrap;
+pub mod conflicts;
+pub use crate::conflicts::{
+    Conflict, ConflictResolver, LastWriterWins, LocalWins, PerAttribute, RemoteWins, Resolution,
+    ResolvedConflict,
+};
 pub mod metadata;
 pub use crate::metadata::{PartitionsTable, SyncMetadata};
 mod datoms;
 pub mod debug;
+pub mod directory_log;
+pub use crate::directory_log::DirectoryLog;
+#[cfg(feature = "encryption")]
+pub mod encryption;
+#[cfg(feature = "encryption")]
+pub use crate::encryption::{EncryptedLog, SyncKeys};
+pub mod filter;
+pub use crate::filter::SyncFilter;
 pub mod remote_client;
 pub use crate::remote_client::RemoteClient;
 pub mod schema;
+pub mod server;
+pub use crate::server::SyncServer;
 pub mod syncer;
 pub use crate::syncer::{SyncFollowup, SyncReport, SyncResult, Syncer};
 pub mod logger;
@@ -36,4 +51,4 @@ mod tx_uploader;
 pub use crate::tx_mapper::TxMapper;
 pub mod tx_processor;
 pub mod types;
-pub use crate::types::{GlobalTransactionLog, Tx, TxPart};
+pub use crate::types::{GlobalTransactionLog, LogStorage, StoredTx, Tx, TxPart};
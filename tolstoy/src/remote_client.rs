@@ -10,37 +10,27 @@
 
 #![allow(dead_code)]
 
+use std::sync::Mutex;
+
+use hyper::client::HttpConnector;
 use hyper::{body, header, Body, Client, Method, Request, StatusCode};
 use hyper_tls::HttpsConnector;
+use tokio::runtime::Runtime;
 // TODO: https://github.com/mozilla/mentat/issues/570
 // use serde_cbor;
-use futures::executor::block_on;
 use uuid::Uuid;
 
 use crate::logger::d;
 use public_traits::errors::Result;
+use tolstoy_traits::errors::TolstoyError;
 
-use crate::types::{GlobalTransactionLog, Tx, TxPart};
+use crate::types::{LogStorage, StoredTx};
 
 #[derive(Serialize, Deserialize)]
 struct SerializedHead {
     head: Uuid,
 }
 
-#[derive(Serialize)]
-struct SerializedTransaction<'a> {
-    parent: &'a Uuid,
-    chunks: &'a [Uuid],
-}
-
-#[derive(Deserialize)]
-struct DeserializableTransaction {
-    parent: Uuid,
-    chunks: Vec<Uuid>,
-    id: Uuid,
-    seq: i64,
-}
-
 #[derive(Deserialize)]
 struct SerializedTransactions {
     limit: i64,
@@ -51,14 +41,35 @@ struct SerializedTransactions {
 pub struct RemoteClient {
     base_uri: String,
     user_uuid: Uuid,
+    client: Client<HttpsConnector<HttpConnector>, Body>,
+    /// hyper's connections need a tokio runtime to drive them. Requests are made one at a time,
+    /// but running a runtime needs `&mut`.
+    runtime: Mutex<Runtime>,
 }
 
 impl RemoteClient {
-    pub fn new(base_uri: String, user_uuid: Uuid) -> Self {
-        RemoteClient {
+    pub fn new(base_uri: String, user_uuid: Uuid) -> Result<Self> {
+        // Setting up TLS is slow, so share one client, and the runtime its pooled connections live
+        // on, between requests.
+        let runtime = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .enable_all()
+            .build()?;
+        let client = Client::builder().build::<_, Body>(HttpsConnector::new());
+        Ok(RemoteClient {
             base_uri,
             user_uuid,
-        }
+            client,
+            runtime: Mutex::new(runtime),
+        })
+    }
+
+    /// Run `work` to completion on this client's runtime.
+    fn block_on<F, T>(&self, work: F) -> Result<T>
+    where
+        F: std::future::Future<Output = Result<T>>,
+    {
+        self.runtime.lock().unwrap().block_on(work)
     }
 
     fn bound_base_uri(&self) -> String {
@@ -71,8 +82,7 @@ impl RemoteClient {
     // into borrow issues doing that - probably need to restructure this and use PhantomData markers
     // or somesuch. But for now, we get code duplication.
     fn get_uuid(&self, uri: String) -> Result<Uuid> {
-        let https = HttpsConnector::new();
-        let client = Client::builder().build::<_, Body>(https);
+        let client = &self.client;
 
         d(&"client".to_string());
 
@@ -91,15 +101,14 @@ impl RemoteClient {
                 .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
             Ok(json.head)
         };
-        block_on(work)
+        self.block_on(work)
     }
 
     fn put<T>(&self, uri: String, payload: T, expected: StatusCode) -> Result<()>
     where
         hyper::Body: std::convert::From<T>,
     {
-        let https = HttpsConnector::new();
-        let client = Client::builder().build::<_, Body>(https);
+        let client = &self.client;
 
         d(&format!("PUT {:?}", uri));
 
@@ -116,15 +125,18 @@ impl RemoteClient {
 
             if status_code != expected {
                 d(&format!("bad put response: {:?}", status_code));
+                bail!(TolstoyError::BadRemoteResponse(format!(
+                    "expected {}, got {}",
+                    expected, status_code
+                )));
             }
             Ok(())
         };
-        block_on(work)
+        self.block_on(work)
     }
 
     fn get_transactions(&self, parent_uuid: &Uuid) -> Result<Vec<Uuid>> {
-        let https = HttpsConnector::new();
-        let client = Client::builder().build::<_, Body>(https);
+        let client = &self.client;
 
         d(&"client".to_string());
 
@@ -149,12 +161,11 @@ impl RemoteClient {
             d(&format!("got transactions: {:?}", &json.transactions));
             Ok(json.transactions)
         };
-        block_on(work)
+        self.block_on(work)
     }
 
-    fn get_chunks(&self, transaction_uuid: &Uuid) -> Result<Vec<Uuid>> {
-        let https = HttpsConnector::new();
-        let client = Client::builder().build::<_, Body>(https);
+    fn get_transaction(&self, transaction_uuid: &Uuid) -> Result<StoredTx> {
+        let client = &self.client;
 
         d(&"client".to_string());
 
@@ -174,18 +185,16 @@ impl RemoteClient {
             let body_bytes = body::to_bytes(res.into_body()).await.unwrap(); // TODO use '?' fix From hyper::Error to MentatError;
             let body =
                 String::from_utf8(body_bytes.to_vec()).expect("response was not valid utf-8");
-            let json: DeserializableTransaction = serde_json::from_str(&body)
+            let json: StoredTx = serde_json::from_str(&body)
                 .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
-
-            d(&format!("got transaction chunks: {:?}", &json.chunks));
-            Ok(json.chunks)
+            d(&format!("got transaction: {:?}", &json));
+            Ok(json)
         };
-        block_on(work)
+        self.block_on(work)
     }
 
-    fn get_chunk(&self, chunk_uuid: &Uuid) -> Result<TxPart> {
-        let https = HttpsConnector::new();
-        let client = Client::builder().build::<_, Body>(https);
+    fn get_chunk(&self, chunk_uuid: &Uuid) -> Result<String> {
+        let client = &self.client;
 
         d(&"client".to_string());
 
@@ -201,22 +210,20 @@ impl RemoteClient {
             let body_bytes = body::to_bytes(res.into_body()).await.unwrap(); // TODO use '?' fix From hyper::Error to MentatError;
             let body =
                 String::from_utf8(body_bytes.to_vec()).expect("response was not valid utf-8");
-            let json: TxPart = serde_json::from_str(&body)
-                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
-            d(&format!("got transaction chunk: {:?}", &json));
-            Ok(json)
+            d(&format!("got transaction chunk: {:?}", &body));
+            Ok(body)
         };
-        block_on(work)
+        self.block_on(work)
     }
 }
 
-impl GlobalTransactionLog for RemoteClient {
-    fn head(&self) -> Result<Uuid> {
+impl LogStorage for RemoteClient {
+    fn read_head(&self) -> Result<Uuid> {
         let uri = format!("{}/head", self.bound_base_uri());
         self.get_uuid(uri)
     }
 
-    fn set_head(&mut self, uuid: &Uuid) -> Result<()> {
+    fn write_head(&mut self, uuid: &Uuid) -> Result<()> {
         // {"head": uuid}
         let head = SerializedHead { head: *uuid };
 
@@ -226,64 +233,36 @@ impl GlobalTransactionLog for RemoteClient {
         self.put(uri, json, StatusCode::NO_CONTENT)
     }
 
-    /// Slurp transactions and datoms after `tx`, returning them as owned data.
+    /// Slurp the transactions after `tx`, returning them as owned data.
     ///
     /// This is inefficient but convenient for development.
-    fn transactions_after(&self, tx: &Uuid) -> Result<Vec<Tx>> {
-        let new_txs = self.get_transactions(tx)?;
-        let mut tx_list = Vec::new();
-
-        for tx in new_txs {
-            let mut tx_parts = Vec::new();
-            let chunks = self.get_chunks(&tx)?;
-
-            // We pass along all of the downloaded parts, including transaction's
-            // metadata datom. Transactor is expected to do the right thing, and
-            // use txInstant from one of our datoms.
-            for chunk in chunks {
-                let part = self.get_chunk(&chunk)?;
-                tx_parts.push(part);
-            }
-
-            tx_list.push(Tx {
-                tx,
-                parts: tx_parts,
-            });
-        }
-
-        d(&format!("got tx list: {:?}", &tx_list));
+    fn read_transactions_after(&self, tx: &Uuid) -> Result<Vec<(Uuid, StoredTx)>> {
+        self.get_transactions(tx)?
+            .into_iter()
+            .map(|tx| Ok((tx, self.get_transaction(&tx)?)))
+            .collect()
+    }
 
-        Ok(tx_list)
+    fn read_chunk(&self, chunk_uuid: &Uuid) -> Result<String> {
+        self.get_chunk(chunk_uuid)
     }
 
-    fn put_transaction(
-        &mut self,
-        transaction_uuid: &Uuid,
-        parent_uuid: &Uuid,
-        chunks: &[Uuid],
-    ) -> Result<()> {
+    fn write_transaction(&mut self, transaction_uuid: &Uuid, transaction: &StoredTx) -> Result<()> {
         // {"parent": uuid, "chunks": [chunk1, chunk2...]}
-        let transaction = SerializedTransaction {
-            parent: parent_uuid,
-            chunks,
-        };
-
         let uri = format!(
             "{}/transactions/{}",
             self.bound_base_uri(),
             transaction_uuid
         );
-        let json = serde_json::to_string(&transaction)?;
+        let json = serde_json::to_string(transaction)?;
         d(&format!("serialized transaction: {:?}", json));
         self.put(uri, json, StatusCode::CREATED)
     }
 
-    fn put_chunk(&mut self, chunk_uuid: &Uuid, payload: &TxPart) -> Result<()> {
-        let payload: String = serde_json::to_string(payload)?;
+    fn write_chunk(&mut self, chunk_uuid: &Uuid, payload: &str) -> Result<()> {
         let uri = format!("{}/chunks/{}", self.bound_base_uri(), chunk_uuid);
         d(&format!("serialized chunk: {:?}", payload));
-        // TODO don't want to clone every datom!
-        self.put(uri, payload, StatusCode::CREATED)
+        self.put(uri, payload.to_string(), StatusCode::CREATED)
     }
 }
 
@@ -296,7 +275,7 @@ mod tests {
     fn test_remote_client_bound_uri() {
         let user_uuid = Uuid::from_str(&"316ea470-ce35-4adf-9c61-e0de6e289c59").expect("uuid");
         let server_uri = String::from("https://example.com/api/0.1");
-        let remote_client = RemoteClient::new(server_uri, user_uuid);
+        let remote_client = RemoteClient::new(server_uri, user_uuid).expect("client");
         assert_eq!(
             "https://example.com/api/0.1/316ea470-ce35-4adf-9c61-e0de6e289c59",
             remote_client.bound_base_uri()
@@ -0,0 +1,655 @@
+// Copyright 2018 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! A reference sync server: the other end of the HTTP protocol `RemoteClient` speaks, keeping each
+//! user's global transaction log in SQLite.
+//!
+//! Routes are relative to any prefix, so a client's base URI can include one:
+//!
+//! - `GET {user}/head`, `PUT {user}/head`: the last transaction in the log, as `{"head": uuid}`;
+//! - `GET {user}/transactions?from={uuid}`: the transactions after `from`, in order;
+//! - `GET {user}/transactions/{uuid}`, `PUT {user}/transactions/{uuid}`: a transaction's parent
+//!   and chunks;
+//! - `GET {user}/chunks/{uuid}`, `PUT {user}/chunks/{uuid}`: one `TxPart`.
+//!
+//! When clients encrypt what they sync, a chunk is a sealed `TxPart`, and a transaction's chunks
+//! are replaced by a `sealed` list of them. The server keeps both as they're sent, and never needs
+//! to look inside them: only parents matter to it.
+//!
+//! Transactions and chunks can't be changed once put. The head moves with compare-and-set
+//! semantics: the new head must descend from the current one, so when two clients race to extend
+//! the log from the same head, the second is refused with `409 Conflict` and has to sync again.
+
+use std::net::{SocketAddr, TcpListener};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use futures::channel::oneshot;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{header, Body, Method, Request, Response, Server, StatusCode};
+use rusqlite;
+use uuid::Uuid;
+
+use public_traits::errors::Result;
+use tolstoy_traits::errors::TolstoyError;
+
+use crate::logger::d;
+
+#[derive(Serialize, Deserialize)]
+struct SerializedHead {
+    head: Uuid,
+}
+
+#[derive(Deserialize)]
+struct DeserializableTransaction {
+    parent: Uuid,
+    chunks: Vec<Uuid>,
+    #[serde(default)]
+    sealed: Option<serde_json::Value>,
+}
+
+#[derive(Serialize)]
+struct SerializedTransaction<'a> {
+    parent: Uuid,
+    chunks: &'a [Uuid],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sealed: Option<serde_json::Value>,
+    id: Uuid,
+    seq: i64,
+}
+
+#[derive(Serialize)]
+struct SerializedTransactions<'a> {
+    limit: i64,
+    from: Uuid,
+    transactions: &'a [Uuid],
+}
+
+/// What became of a request to change the log.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PutOutcome {
+    Done,
+
+    /// The change conflicts with what's already in the log.
+    Conflict,
+
+    /// The change refers to a transaction that isn't in the log.
+    Unknown,
+}
+
+/// A transaction as the server records it.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct StoredTransaction {
+    pub parent: Uuid,
+    pub chunks: Vec<Uuid>,
+
+    /// The transaction's chunks, encrypted, as JSON.
+    pub sealed: Option<String>,
+
+    /// The transaction's distance from the start of the log.
+    pub seq: i64,
+}
+
+/// Every user's global transaction log, in one SQLite database.
+pub struct ServerLog {
+    conn: Mutex<rusqlite::Connection>,
+}
+
+fn uuid_column(row: &rusqlite::Row<'_>, index: usize) -> rusqlite::Result<Uuid> {
+    let text: String = row.get(index)?;
+    Uuid::parse_str(&text).map_err(|e| {
+        rusqlite::Error::FromSqlConversionFailure(index, rusqlite::types::Type::Text, Box::new(e))
+    })
+}
+
+impl ServerLog {
+    /// Open the logs in the SQLite database at `path`, creating it if need be. An empty path opens
+    /// a temporary database.
+    pub fn open(path: &str) -> Result<ServerLog> {
+        let conn = rusqlite::Connection::open(path)?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS heads (user TEXT NOT NULL PRIMARY KEY, head TEXT NOT NULL);
+             CREATE TABLE IF NOT EXISTS transactions (user TEXT NOT NULL, id TEXT NOT NULL,
+                 parent TEXT NOT NULL, chunks TEXT NOT NULL, sealed TEXT, seq INTEGER NOT NULL,
+                 PRIMARY KEY (user, id));
+             CREATE TABLE IF NOT EXISTS chunks (user TEXT NOT NULL, id TEXT NOT NULL,
+                 payload TEXT NOT NULL, PRIMARY KEY (user, id));",
+        )?;
+        Ok(ServerLog {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    fn head_in(conn: &rusqlite::Connection, user: &Uuid) -> Result<Uuid> {
+        let mut stmt = conn.prepare_cached("SELECT head FROM heads WHERE user = ?")?;
+        let mut rows = stmt.query_and_then(&[&user.to_string()], |row| uuid_column(row, 0))?;
+        Ok(rows.next().transpose()?.unwrap_or_else(Uuid::nil))
+    }
+
+    fn transaction_in(
+        conn: &rusqlite::Connection,
+        user: &Uuid,
+        tx: &Uuid,
+    ) -> Result<Option<StoredTransaction>> {
+        let mut stmt = conn.prepare_cached(
+            "SELECT parent, chunks, sealed, seq FROM transactions WHERE user = ? AND id = ?",
+        )?;
+        let mut rows = stmt.query_and_then(
+            &[&user.to_string(), &tx.to_string()],
+            |row| -> Result<StoredTransaction> {
+                let chunks: String = row.get(1)?;
+                Ok(StoredTransaction {
+                    parent: uuid_column(row, 0)?,
+                    chunks: serde_json::from_str(&chunks)?,
+                    sealed: row.get(2)?,
+                    seq: row.get(3)?,
+                })
+            },
+        )?;
+        rows.next().transpose()
+    }
+
+    pub fn head(&self, user: &Uuid) -> Result<Uuid> {
+        ServerLog::head_in(&self.conn.lock().unwrap(), user)
+    }
+
+    /// Move the head to `tx`, but only if `tx` descends from the current head.
+    pub fn set_head(&self, user: &Uuid, tx: &Uuid) -> Result<PutOutcome> {
+        let mut conn = self.conn.lock().unwrap();
+        let transaction = conn.transaction()?;
+        let head = ServerLog::head_in(&transaction, user)?;
+        let mut ancestor = *tx;
+        while ancestor != head {
+            if ancestor.is_nil() {
+                return Ok(PutOutcome::Conflict);
+            }
+            match ServerLog::transaction_in(&transaction, user, &ancestor)? {
+                Some(transaction) => ancestor = transaction.parent,
+                None => return Ok(PutOutcome::Unknown),
+            }
+        }
+        transaction.execute(
+            "INSERT OR REPLACE INTO heads (user, head) VALUES (?, ?)",
+            &[&user.to_string(), &tx.to_string()],
+        )?;
+        transaction.commit()?;
+        Ok(PutOutcome::Done)
+    }
+
+    /// The transactions after `from` leading up to the head, in order, or `None` if `from` isn't
+    /// one of them.
+    pub fn transactions_after(&self, user: &Uuid, from: &Uuid) -> Result<Option<Vec<Uuid>>> {
+        let conn = self.conn.lock().unwrap();
+        let mut transactions = vec![];
+        let mut next = ServerLog::head_in(&conn, user)?;
+        while next != *from {
+            if next.is_nil() {
+                return Ok(None);
+            }
+            transactions.push(next);
+            next = match ServerLog::transaction_in(&conn, user, &next)? {
+                Some(transaction) => transaction.parent,
+                None => bail!(TolstoyError::UnexpectedState(format!(
+                    "head descends from missing transaction {}",
+                    next
+                ))),
+            };
+        }
+        transactions.reverse();
+        Ok(Some(transactions))
+    }
+
+    pub fn transaction(&self, user: &Uuid, tx: &Uuid) -> Result<Option<StoredTransaction>> {
+        ServerLog::transaction_in(&self.conn.lock().unwrap(), user, tx)
+    }
+
+    /// Record the transaction `tx`. Its parent must already be recorded, unless it's the first.
+    pub fn put_transaction(
+        &self,
+        user: &Uuid,
+        tx: &Uuid,
+        parent: &Uuid,
+        chunks: &[Uuid],
+        sealed: Option<&str>,
+    ) -> Result<PutOutcome> {
+        let conn = self.conn.lock().unwrap();
+        if let Some(existing) = ServerLog::transaction_in(&conn, user, tx)? {
+            return Ok(
+                if existing.parent == *parent
+                    && existing.chunks == chunks
+                    && existing.sealed.as_deref() == sealed
+                {
+                    PutOutcome::Done
+                } else {
+                    PutOutcome::Conflict
+                },
+            );
+        }
+        let seq = if parent.is_nil() {
+            0
+        } else {
+            match ServerLog::transaction_in(&conn, user, parent)? {
+                Some(transaction) => transaction.seq + 1,
+                None => return Ok(PutOutcome::Unknown),
+            }
+        };
+        conn.execute(
+            "INSERT INTO transactions (user, id, parent, chunks, sealed, seq) VALUES (?, ?, ?, ?, ?, ?)",
+            &[
+                &user.to_string() as &dyn rusqlite::ToSql,
+                &tx.to_string(),
+                &parent.to_string(),
+                &serde_json::to_string(chunks)?,
+                &sealed,
+                &seq,
+            ],
+        )?;
+        Ok(PutOutcome::Done)
+    }
+
+    fn chunk_in(conn: &rusqlite::Connection, user: &Uuid, chunk: &Uuid) -> Result<Option<String>> {
+        let mut stmt =
+            conn.prepare_cached("SELECT payload FROM chunks WHERE user = ? AND id = ?")?;
+        let mut rows = stmt.query_and_then(
+            &[&user.to_string(), &chunk.to_string()],
+            |row| -> Result<String> { Ok(row.get(0)?) },
+        )?;
+        rows.next().transpose()
+    }
+
+    pub fn chunk(&self, user: &Uuid, chunk: &Uuid) -> Result<Option<String>> {
+        ServerLog::chunk_in(&self.conn.lock().unwrap(), user, chunk)
+    }
+
+    /// Record the chunk `chunk`, a JSON-serialized `TxPart`, or a sealed one.
+    pub fn put_chunk(&self, user: &Uuid, chunk: &Uuid, payload: &str) -> Result<PutOutcome> {
+        let conn = self.conn.lock().unwrap();
+        if let Some(existing) = ServerLog::chunk_in(&conn, user, chunk)? {
+            return Ok(if existing == payload {
+                PutOutcome::Done
+            } else {
+                PutOutcome::Conflict
+            });
+        }
+        conn.execute(
+            "INSERT INTO chunks (user, id, payload) VALUES (?, ?, ?)",
+            &[&user.to_string(), &chunk.to_string(), &payload.to_string()],
+        )?;
+        Ok(PutOutcome::Done)
+    }
+
+    /// Answer one request, given its method, path, query string, and body.
+    pub fn respond(
+        &self,
+        method: &Method,
+        path: &str,
+        query: Option<&str>,
+        body: &[u8],
+    ) -> Response<Body> {
+        d(&format!("{} {}", method, path));
+        match self.route(method, path, query, body) {
+            Ok(response) => response,
+            Err(e) => status(StatusCode::BAD_REQUEST, &e.to_string()),
+        }
+    }
+
+    fn route(
+        &self,
+        method: &Method,
+        path: &str,
+        query: Option<&str>,
+        body: &[u8],
+    ) -> Result<Response<Body>> {
+        // Route on the last few segments of the path, counting back from the end.
+        let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+        let last = |i: usize| {
+            segments
+                .len()
+                .checked_sub(i)
+                .map_or("", |index| segments[index])
+        };
+        let uuid = |i: usize| -> Result<Uuid> { Ok(Uuid::parse_str(last(i))?) };
+
+        match (method, last(2), last(1)) {
+            (&Method::GET, _, "head") => {
+                let head = self.head(&uuid(2)?)?;
+                json(StatusCode::OK, &SerializedHead { head })
+            }
+            (&Method::PUT, _, "head") => {
+                let head: SerializedHead = serde_json::from_slice(body)?;
+                let outcome = self.set_head(&uuid(2)?, &head.head)?;
+                Ok(put_status(outcome, StatusCode::NO_CONTENT))
+            }
+            (&Method::GET, _, "transactions") => {
+                let from = query
+                    .unwrap_or_default()
+                    .split('&')
+                    .find(|pair| pair.starts_with("from="))
+                    .map(|pair| &pair["from=".len()..])
+                    .map_or(Ok(Uuid::nil()), Uuid::parse_str)?;
+                match self.transactions_after(&uuid(2)?, &from)? {
+                    Some(transactions) => json(
+                        StatusCode::OK,
+                        &SerializedTransactions {
+                            limit: transactions.len() as i64,
+                            from,
+                            transactions: &transactions,
+                        },
+                    ),
+                    None => Ok(status(StatusCode::NOT_FOUND, "unknown transaction")),
+                }
+            }
+            (&Method::GET, "transactions", _) => {
+                let id = uuid(1)?;
+                match self.transaction(&uuid(3)?, &id)? {
+                    Some(transaction) => json(
+                        StatusCode::OK,
+                        &SerializedTransaction {
+                            parent: transaction.parent,
+                            chunks: &transaction.chunks,
+                            sealed: transaction
+                                .sealed
+                                .as_deref()
+                                .map(serde_json::from_str)
+                                .transpose()?,
+                            id,
+                            seq: transaction.seq,
+                        },
+                    ),
+                    None => Ok(status(StatusCode::NOT_FOUND, "unknown transaction")),
+                }
+            }
+            (&Method::PUT, "transactions", _) => {
+                let transaction: DeserializableTransaction = serde_json::from_slice(body)?;
+                let sealed = transaction
+                    .sealed
+                    .as_ref()
+                    .map(serde_json::to_string)
+                    .transpose()?;
+                let outcome = self.put_transaction(
+                    &uuid(3)?,
+                    &uuid(1)?,
+                    &transaction.parent,
+                    &transaction.chunks,
+                    sealed.as_deref(),
+                )?;
+                Ok(put_status(outcome, StatusCode::CREATED))
+            }
+            (&Method::GET, "chunks", _) => match self.chunk(&uuid(3)?, &uuid(1)?)? {
+                Some(payload) => Ok(Response::builder()
+                    .status(StatusCode::OK)
+                    .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.to_string())
+                    .body(payload.into())
+                    .unwrap()),
+                None => Ok(status(StatusCode::NOT_FOUND, "unknown chunk")),
+            },
+            (&Method::PUT, "chunks", _) => {
+                // Chunks are kept as they're sent, but they must at least be JSON.
+                serde_json::from_slice::<serde_json::Value>(body)?;
+                let payload = String::from_utf8_lossy(body);
+                let outcome = self.put_chunk(&uuid(3)?, &uuid(1)?, &payload)?;
+                Ok(put_status(outcome, StatusCode::CREATED))
+            }
+            _ => Ok(status(StatusCode::NOT_FOUND, "no such route")),
+        }
+    }
+}
+
+fn status(code: StatusCode, message: &str) -> Response<Body> {
+    Response::builder()
+        .status(code)
+        .body(message.to_string().into())
+        .unwrap()
+}
+
+fn json<T>(code: StatusCode, value: &T) -> Result<Response<Body>>
+where
+    T: serde::Serialize,
+{
+    Ok(Response::builder()
+        .status(code)
+        .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.to_string())
+        .body(serde_json::to_string(value)?.into())
+        .unwrap())
+}
+
+fn put_status(outcome: PutOutcome, done: StatusCode) -> Response<Body> {
+    match outcome {
+        PutOutcome::Done => status(done, ""),
+        PutOutcome::Conflict => status(StatusCode::CONFLICT, "conflicts with the log"),
+        PutOutcome::Unknown => status(StatusCode::NOT_FOUND, "unknown transaction"),
+    }
+}
+
+/// A sync server running on a background thread until it's dropped.
+pub struct SyncServer {
+    address: SocketAddr,
+    shutdown: Option<oneshot::Sender<()>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl SyncServer {
+    /// Serve the logs in the SQLite database at `path` on `address`. Use port 0 to pick any free
+    /// port, and `address()` to find out which.
+    pub fn start(path: &str, address: SocketAddr) -> Result<SyncServer> {
+        let log = Arc::new(ServerLog::open(path)?);
+        let listener = TcpListener::bind(address)?;
+        let address = listener.local_addr()?;
+        let (shutdown, stopped) = oneshot::channel::<()>();
+        let mut runtime = tokio::runtime::Runtime::new()?;
+        let thread = thread::spawn(move || {
+            let served = runtime.block_on(async move {
+                let service = make_service_fn(move |_| {
+                    let log = log.clone();
+                    async move {
+                        Ok::<_, hyper::Error>(service_fn(move |request| {
+                            respond(log.clone(), request)
+                        }))
+                    }
+                });
+                Server::from_tcp(listener)?
+                    .serve(service)
+                    .with_graceful_shutdown(async {
+                        stopped.await.ok();
+                    })
+                    .await
+            });
+            if let Err(e) = served {
+                d(&format!("sync server failed: {}", e));
+            }
+        });
+        Ok(SyncServer {
+            address,
+            shutdown: Some(shutdown),
+            thread: Some(thread),
+        })
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Block until the server stops, which it only does if it fails.
+    pub fn wait(mut self) {
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+impl Drop for SyncServer {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            shutdown.send(()).ok();
+        }
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+async fn respond(
+    log: Arc<ServerLog>,
+    request: Request<Body>,
+) -> ::std::result::Result<Response<Body>, hyper::Error> {
+    let (parts, body) = request.into_parts();
+    let body = hyper::body::to_bytes(body).await?;
+    Ok(log.respond(&parts.method, parts.uri.path(), parts.uri.query(), &body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_head_compare_and_set() {
+        let log = ServerLog::open("").expect("opened");
+        let user = Uuid::new_v4();
+        let (a, b, c) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+
+        assert_eq!(log.head(&user).expect("head"), Uuid::nil());
+        assert_eq!(
+            log.put_transaction(&user, &b, &a, &[], None).expect("put"),
+            PutOutcome::Unknown
+        );
+        assert_eq!(
+            log.put_transaction(&user, &a, &Uuid::nil(), &[], None)
+                .expect("put"),
+            PutOutcome::Done
+        );
+        assert_eq!(
+            log.put_transaction(&user, &a, &Uuid::nil(), &[c], None)
+                .expect("put"),
+            PutOutcome::Conflict
+        );
+
+        // Two clients extend the log from the same head; only the first can move it.
+        assert_eq!(
+            log.put_transaction(&user, &b, &a, &[], None).expect("put"),
+            PutOutcome::Done
+        );
+        assert_eq!(
+            log.put_transaction(&user, &c, &a, &[], None).expect("put"),
+            PutOutcome::Done
+        );
+        assert_eq!(log.set_head(&user, &a).expect("set"), PutOutcome::Done);
+        assert_eq!(log.set_head(&user, &b).expect("set"), PutOutcome::Done);
+        assert_eq!(log.set_head(&user, &c).expect("set"), PutOutcome::Conflict);
+        assert_eq!(
+            log.set_head(&user, &Uuid::new_v4()).expect("set"),
+            PutOutcome::Unknown
+        );
+
+        assert_eq!(log.head(&user).expect("head"), b);
+        assert_eq!(
+            log.transactions_after(&user, &Uuid::nil()).expect("txs"),
+            Some(vec![a, b])
+        );
+        assert_eq!(
+            log.transactions_after(&user, &a).expect("txs"),
+            Some(vec![b])
+        );
+        assert_eq!(log.transactions_after(&user, &c).expect("txs"), None);
+        assert_eq!(
+            log.transaction(&user, &b).expect("tx"),
+            Some(StoredTransaction {
+                parent: a,
+                chunks: vec![],
+                sealed: None,
+                seq: 1,
+            })
+        );
+
+        // Users don't share logs.
+        assert_eq!(log.head(&Uuid::new_v4()).expect("head"), Uuid::nil());
+    }
+
+    #[test]
+    fn test_routes() {
+        let log = ServerLog::open("").expect("opened");
+        let user = Uuid::new_v4();
+        let (tx, chunk) = (Uuid::new_v4(), Uuid::new_v4());
+        let base = format!("/api/0.1/{}", user);
+        let body = |response: Response<Body>| -> (StatusCode, String) {
+            let status = response.status();
+            let bytes = futures::executor::block_on(hyper::body::to_bytes(response.into_body()))
+                .expect("body");
+            (status, String::from_utf8(bytes.to_vec()).expect("utf-8"))
+        };
+
+        let put = Method::PUT;
+        let get = Method::GET;
+        let chunk_path = format!("{}/chunks/{}", base, chunk);
+        let tx_path = format!("{}/transactions/{}", base, tx);
+        let head_path = format!("{}/head", base);
+        assert_eq!(
+            body(log.respond(&put, &chunk_path, None, br#"{"e": 1}"#)).0,
+            StatusCode::CREATED
+        );
+        assert_eq!(
+            body(log.respond(&get, &chunk_path, None, b"")),
+            (StatusCode::OK, r#"{"e": 1}"#.to_string())
+        );
+        let transaction = format!(
+            r#"{{"parent": "{}", "chunks": ["{}"]}}"#,
+            Uuid::nil(),
+            chunk
+        );
+        assert_eq!(
+            body(log.respond(&put, &tx_path, None, transaction.as_bytes())).0,
+            StatusCode::CREATED
+        );
+        assert_eq!(
+            body(log.respond(&get, &tx_path, None, b"")),
+            (
+                StatusCode::OK,
+                format!(
+                    r#"{{"parent":"{}","chunks":["{}"],"id":"{}","seq":0}}"#,
+                    Uuid::nil(),
+                    chunk,
+                    tx
+                )
+            )
+        );
+        let head = format!(r#"{{"head": "{}"}}"#, tx);
+        assert_eq!(
+            body(log.respond(&put, &head_path, None, head.as_bytes())).0,
+            StatusCode::NO_CONTENT
+        );
+        assert_eq!(
+            body(log.respond(&get, &head_path, None, b"")),
+            (StatusCode::OK, format!(r#"{{"head":"{}"}}"#, tx))
+        );
+        assert_eq!(
+            body(log.respond(
+                &get,
+                &format!("{}/transactions", base),
+                Some(&format!("from={}", Uuid::nil())),
+                b""
+            )),
+            (
+                StatusCode::OK,
+                format!(
+                    r#"{{"limit":1,"from":"{}","transactions":["{}"]}}"#,
+                    Uuid::nil(),
+                    tx
+                )
+            )
+        );
+        assert_eq!(
+            body(log.respond(&get, &format!("{}/nothing", base), None, b"")).0,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            body(log.respond(&put, &head_path, None, b"not json")).0,
+            StatusCode::BAD_REQUEST
+        );
+    }
+}
@@ -10,7 +10,7 @@
 
 use std::fmt;
 
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 
 use uuid::Uuid;
 
@@ -18,7 +18,8 @@ use core_traits::{Entid, KnownEntid, TypedValue};
 
 use edn::entities::{EntityPlace, LookupRef, TxFunction};
 use edn::PlainSymbol;
-use mentat_db::{entids, timelines, PartitionMap, CORE_SCHEMA_VERSION};
+use mentat_core::{DateTime, HasSchema, Utc};
+use mentat_db::{branches, entids, timelines, PartitionMap, CORE_SCHEMA_VERSION};
 use mentat_transaction::{InProgress, Queryable, TermBuilder};
 
 use mentat_transaction::entity_builder::BuildTerms;
@@ -26,6 +27,8 @@ use mentat_transaction::entity_builder::BuildTerms;
 use mentat_transaction::query::{QueryInputs, Variable};
 
 use crate::bootstrap::BootstrapHelper;
+use crate::conflicts::{Conflict, ConflictResolver, LocalWins, Resolution, ResolvedConflict};
+use crate::filter::SyncFilter;
 
 use public_traits::errors::Result;
 
@@ -64,7 +67,8 @@ pub enum SyncReport {
     NoChanges,
     RemoteFastForward,
     LocalFastForward,
-    Merge(SyncFollowup),
+    /// Merged local and remote changes, resolving any conflicts between them.
+    Merge(SyncFollowup, Vec<ResolvedConflict>),
 }
 
 pub enum SyncResult {
@@ -84,9 +88,10 @@ impl fmt::Display for SyncReport {
             SyncReport::NoChanges => write!(f, "Neither local nor remote have any new changes"),
             SyncReport::RemoteFastForward => write!(f, "Fast-forwarded remote"),
             SyncReport::LocalFastForward => write!(f, "Fast-forwarded local"),
-            SyncReport::Merge(follow_up) => write!(
+            SyncReport::Merge(follow_up, resolved) => write!(
                 f,
-                "Merged local and remote, requesting a follow-up: {}",
+                "Merged local and remote, resolving {} conflicts, requesting a follow-up: {}",
+                resolved.len(),
                 follow_up
             ),
         }
@@ -226,6 +231,7 @@ impl Syncer {
         from_tx: Option<Entid>,
         remote_client: &mut R,
         remote_head: &Uuid,
+        excluded_attributes: &HashSet<Entid>,
     ) -> Result<()>
     where
         R: GlobalTransactionLog,
@@ -241,6 +247,7 @@ impl Syncer {
                 remote_client,
                 remote_head,
                 SyncMetadata::get_partitions(db_tx, PartitionsTable::Tolstoy)?,
+                excluded_attributes,
             );
             // Walk the local transactions in the database and upload them.
             report = Processor::process(db_tx, from_tx, uploader)?;
@@ -275,8 +282,17 @@ impl Syncer {
         }
     }
 
-    fn remote_parts_to_builder(builder: &mut TermBuilder, parts: Vec<TxPart>) -> Result<()> {
+    fn remote_parts_to_builder(
+        builder: &mut TermBuilder,
+        parts: Vec<TxPart>,
+        excluded_attributes: &HashSet<Entid>,
+    ) -> Result<()> {
         for part in parts {
+            // Another client might sync what we don't.
+            if excluded_attributes.contains(&part.a) {
+                continue;
+            }
+
             let e: EntityPlace<TypedValue>;
             let a = KnownEntid(part.a);
             let v = part.v;
@@ -303,6 +319,19 @@ impl Syncer {
         Ok(())
     }
 
+    /// The `:db/txInstant` of the transaction made of `parts`.
+    fn tx_instant(parts: &[TxPart]) -> Result<DateTime<Utc>> {
+        match parts.iter().find(|part| part.a == entids::DB_TX_INSTANT) {
+            Some(TxPart {
+                v: TypedValue::Instant(instant),
+                ..
+            }) => Ok(*instant),
+            _ => bail!(TolstoyError::UnexpectedState(
+                "transaction without a txInstant".to_string()
+            )),
+        }
+    }
+
     /// In context of a "transaction to be applied", a PartitionMap supplied here
     /// represents what a PartitionMap will be once this transaction is applied.
     /// This works well for regular assertions: entids are supplied, and we need
@@ -327,6 +356,7 @@ impl Syncer {
     fn fast_forward_local<'a, 'c>(
         in_progress: &mut InProgress<'a, 'c>,
         txs: Vec<Tx>,
+        excluded_attributes: &HashSet<Entid>,
     ) -> Result<SyncReport> {
         let mut last_tx = None;
 
@@ -349,7 +379,7 @@ impl Syncer {
             // Make space in the provided tx partition for the transaction we're about to create.
             // See function's notes for details.
             Syncer::rewind_tx_partition_by_one(&mut partition_map)?;
-            Syncer::remote_parts_to_builder(&mut builder, tx.parts)?;
+            Syncer::remote_parts_to_builder(&mut builder, tx.parts, excluded_attributes)?;
 
             // Allocate space for the incoming entids.
             in_progress.partition_map = partition_map;
@@ -373,6 +403,8 @@ impl Syncer {
         ip: &mut InProgress<'_, '_>,
         incoming_txs: Vec<Tx>,
         mut local_txs_to_merge: Vec<LocalTx>,
+        excluded_attributes: &HashSet<Entid>,
+        resolver: &dyn ConflictResolver,
     ) -> Result<SyncReport> {
         d(&"Rewinding local transactions.".to_string());
 
@@ -391,6 +423,15 @@ impl Syncer {
         if let Some(schema) = new_schema {
             ip.schema = schema
         };
+
+        // Entities allocated before the shared root are known to local and remote by the same
+        // entids. They're the only ones local and remote can both have changed.
+        let shared_partitions = new_partition_map.clone();
+        let is_shared = |e: Entid| {
+            shared_partitions
+                .values()
+                .any(|p| p.allows_entid(e) && e < p.next_entid())
+        };
         ip.partition_map = new_partition_map;
 
         // 2) Transact incoming.
@@ -398,6 +439,7 @@ impl Syncer {
         // a remote transaction, its global identifier and partitions after it's applied.
         d(&"Transacting incoming...".to_string());
         let mut builders = vec![];
+        let mut remote_shared_parts = vec![];
         for remote_tx in incoming_txs {
             let mut builder = TermBuilder::new();
 
@@ -410,7 +452,21 @@ impl Syncer {
                 }
             };
 
-            Syncer::remote_parts_to_builder(&mut builder, remote_tx.parts)?;
+            remote_shared_parts.push((
+                Syncer::tx_instant(&remote_tx.parts)?,
+                remote_tx
+                    .parts
+                    .iter()
+                    .filter(|part| {
+                        part.a != entids::DB_TX_INSTANT
+                            && is_shared(part.e)
+                            && !excluded_attributes.contains(&part.a)
+                    })
+                    .cloned()
+                    .collect::<Vec<TxPart>>(),
+            ));
+
+            Syncer::remote_parts_to_builder(&mut builder, remote_tx.parts, excluded_attributes)?;
 
             builders.push((builder, partition_map, remote_tx.tx));
         }
@@ -427,12 +483,67 @@ impl Syncer {
             remote_report = Some((ip.transact_builder(builder)?.tx_id, remote_tx));
         }
 
+        // 2.2) Note the values remote left for cardinality-one attributes of shared entities, and
+        // when it asserted them. Those are what local changes might conflict with.
+        let mut remote_values: HashMap<(Entid, Entid), (TypedValue, DateTime<Utc>)> =
+            HashMap::new();
+        for (tx_instant, parts) in remote_shared_parts {
+            for part in parts.iter().filter(|part| !part.added) {
+                if remote_values
+                    .get(&(part.e, part.a))
+                    .map_or(false, |(v, _)| *v == part.v)
+                {
+                    remote_values.remove(&(part.e, part.a));
+                }
+            }
+            for part in parts.into_iter().filter(|part| part.added) {
+                if ip
+                    .schema
+                    .attribute_for_entid(part.a)
+                    .map_or(false, |attribute| !attribute.multival)
+                {
+                    remote_values.insert((part.e, part.a), (part.v, tx_instant));
+                }
+            }
+        }
+
         d(&"Transacting local on top of incoming...".to_string());
         // 3) Rebase local transactions on top of remote.
         let mut clean_rebase = true;
+        let mut resolved = vec![];
         for local_tx in local_txs_to_merge {
             let mut builder = TermBuilder::new();
 
+            // Resolve this transaction's conflicts with remote.
+            let local_tx_instant = Syncer::tx_instant(&local_tx.parts)?;
+            let mut resolutions = HashMap::new();
+            for part in local_tx.parts.iter().filter(|part| part.added) {
+                match remote_values.get(&(part.e, part.a)) {
+                    Some((remote, remote_tx_instant)) if *remote != part.v => {
+                        let conflict = Conflict {
+                            e: part.e,
+                            a: part.a,
+                            local: part.v.clone(),
+                            local_tx_instant,
+                            remote: remote.clone(),
+                            remote_tx_instant: *remote_tx_instant,
+                        };
+                        let resolution = resolver.resolve(&ip.schema, &conflict);
+                        d(&format!("resolved {:?} as {:?}", conflict, resolution));
+                        resolutions.insert((part.e, part.a), resolution.clone());
+                        resolved.push(ResolvedConflict {
+                            conflict,
+                            resolution,
+                        });
+                    }
+                    _ => {}
+                }
+            }
+
+            // Entities with conflicts are referred to verbatim, so that local changes to them
+            // apply to them rather than to copies.
+            let conflicted: HashSet<Entid> = resolutions.keys().map(|&(e, _)| e).collect();
+
             // This is the beginnings of entity merging.
 
             // An entid might be already known to the Schema, or it
@@ -505,7 +616,7 @@ impl Syncer {
                     // Non-unique entities are "duplicated". Unique entities are upserted.
                     _ => {
                         // Retractions never allocated tempids in the transactor.
-                        if part.added {
+                        if part.added && !conflicted.contains(&part.e) {
                             entids_that_will_allocate.insert(part.e);
                         }
                     }
@@ -534,6 +645,12 @@ impl Syncer {
                     continue;
                 }
 
+                // Where remote's value stays, or gives way to another, drop local's changes.
+                match resolutions.get(&(part.e, part.a)) {
+                    Some(Resolution::Remote) | Some(Resolution::Value(_)) => continue,
+                    _ => {}
+                }
+
                 let e: EntityPlace<TypedValue>;
                 let a = KnownEntid(part.a);
                 let v = part.v;
@@ -560,7 +677,9 @@ impl Syncer {
                 match part.added {
                     true => builder.add(e, a, v)?,
                     false => {
-                        if entids_that_will_allocate.contains(&part.e) {
+                        if entids_that_will_allocate.contains(&part.e)
+                            || conflicted.contains(&part.e)
+                        {
                             builder.retract(e, a, v)?;
                             continue;
                         }
@@ -616,6 +735,12 @@ impl Syncer {
                 }
             }
 
+            for (&(e, a), resolution) in &resolutions {
+                if let Resolution::Value(ref v) = *resolution {
+                    builder.add(KnownEntid(e), KnownEntid(a), v.clone())?;
+                }
+            }
+
             // After all these checks, our builder might be empty: short-circuit.
             if builder.is_empty() {
                 continue;
@@ -676,9 +801,9 @@ impl Syncer {
 
         // If necessary, request a full sync as a follow-up to fast-forward remote.
         if clean_rebase {
-            Ok(SyncReport::Merge(SyncFollowup::None))
+            Ok(SyncReport::Merge(SyncFollowup::None, resolved))
         } else {
-            Ok(SyncReport::Merge(SyncFollowup::FullSync))
+            Ok(SyncReport::Merge(SyncFollowup::FullSync, resolved))
         }
     }
 
@@ -686,6 +811,8 @@ impl Syncer {
         ip: &mut InProgress<'_, '_>,
         remote_client: &R,
         local_metadata: &SyncMetadata,
+        excluded_attributes: &HashSet<Entid>,
+        resolver: &dyn ConflictResolver,
     ) -> Result<SyncReport>
     where
         R: GlobalTransactionLog,
@@ -741,13 +868,14 @@ impl Syncer {
         // Since we've "merged" with the remote bootstrap, the "no-op" and
         // "local fast-forward" cases are reported as merges.
         match Syncer::what_do(remote_state, local_state) {
-            SyncAction::NoOp => {
-                Ok(SyncReport::Merge(SyncFollowup::None))
-            }
+            SyncAction::NoOp => Ok(SyncReport::Merge(SyncFollowup::None, vec![])),
 
             SyncAction::PopulateRemote => {
                 // This is a programming error.
-                bail!(TolstoyError::UnexpectedState("Remote state can't be empty on first sync against non-empty remote".to_string()))
+                bail!(TolstoyError::UnexpectedState(
+                    "Remote state can't be empty on first sync against non-empty remote"
+                        .to_string()
+                ))
             }
 
             SyncAction::RemoteFastForward => {
@@ -755,30 +883,69 @@ impl Syncer {
             }
 
             SyncAction::LocalFastForward => {
-                Syncer::fast_forward_local(ip, incoming_txs[1..].to_vec())?;
-                Ok(SyncReport::Merge(SyncFollowup::None))
+                Syncer::fast_forward_local(ip, incoming_txs[1..].to_vec(), excluded_attributes)?;
+                Ok(SyncReport::Merge(SyncFollowup::None, vec![]))
             }
 
             SyncAction::CombineChanges => {
                 let local_txs = Processor::process(
-                    &ip.transaction, Some(local_metadata.root), LocalTxSet::new())?;
+                    &ip.transaction,
+                    Some(local_metadata.root),
+                    LocalTxSet::new(),
+                )?;
                 Syncer::merge(
                     ip,
                     incoming_txs[1..].to_vec(),
                     local_txs,
+                    excluded_attributes,
+                    resolver,
                 )
             }
         }
     }
 
     pub fn sync<R>(ip: &mut InProgress<'_, '_>, remote_client: &mut R) -> Result<SyncReport>
+    where
+        R: GlobalTransactionLog,
+    {
+        Syncer::sync_with_filter(ip, remote_client, &SyncFilter::new())
+    }
+
+    /// Sync, keeping the attributes `filter` excludes out of the global transaction log.
+    ///
+    /// Local changes are replayed over remote ones, so where both changed a value, the local one
+    /// is kept.
+    pub fn sync_with_filter<R>(
+        ip: &mut InProgress<'_, '_>,
+        remote_client: &mut R,
+        filter: &SyncFilter,
+    ) -> Result<SyncReport>
+    where
+        R: GlobalTransactionLog,
+    {
+        Syncer::sync_with_resolver(ip, remote_client, filter, &LocalWins)
+    }
+
+    /// Sync, keeping the attributes `filter` excludes out of the global transaction log, and
+    /// resolving conflicts between local and remote changes with `resolver`.
+    pub fn sync_with_resolver<R>(
+        ip: &mut InProgress<'_, '_>,
+        remote_client: &mut R,
+        filter: &SyncFilter,
+        resolver: &dyn ConflictResolver,
+    ) -> Result<SyncReport>
     where
         R: GlobalTransactionLog,
     {
         d(&"sync flowing".to_string());
 
+        // Only the main branch is shared; other branches stay local until they're merged.
+        branches::ensure_main_checked_out(&ip.transaction)?;
+
         ensure_current_version(&mut ip.transaction)?;
 
+        let excluded_attributes = filter.excluded_attributes(ip)?;
+
         let remote_head = remote_client.head()?;
         d(&format!("remote head {:?}", remote_head));
 
@@ -794,7 +961,13 @@ impl Syncer {
 
         // Currently, first sync against a non-empty remote is special.
         if locally_known_remote_head == Uuid::nil() && remote_head != Uuid::nil() {
-            return Syncer::first_sync_against_non_empty(ip, remote_client, &local_metadata);
+            return Syncer::first_sync_against_non_empty(
+                ip,
+                remote_client,
+                &local_metadata,
+                &excluded_attributes,
+                resolver,
+            );
         }
 
         match Syncer::what_do(remote_state, local_state) {
@@ -810,6 +983,7 @@ impl Syncer {
                     None,
                     remote_client,
                     &remote_head,
+                    &excluded_attributes,
                 )?;
                 Ok(SyncReport::RemoteFastForward)
             }
@@ -829,6 +1003,7 @@ impl Syncer {
                     Some(upload_from_tx),
                     remote_client,
                     &remote_head,
+                    &excluded_attributes,
                 )?;
                 Ok(SyncReport::RemoteFastForward)
             }
@@ -838,6 +1013,7 @@ impl Syncer {
                 Syncer::fast_forward_local(
                     ip,
                     remote_client.transactions_after(&locally_known_remote_head)?,
+                    &excluded_attributes,
                 )?;
                 Ok(SyncReport::LocalFastForward)
             }
@@ -859,6 +1035,8 @@ impl Syncer {
                     remote_client.transactions_after(&locally_known_remote_head)?,
                     // ... with the local txs.
                     local_txs,
+                    &excluded_attributes,
+                    resolver,
                 )
             }
         }
@@ -9,6 +9,7 @@
 // specific language governing permissions and limitations under the License.
 use std::iter::Peekable;
 
+use mentat_db::history::ensure_history_from;
 use mentat_db::TypedSQLValue;
 
 use core_traits::{Entid, TypedValue};
@@ -124,6 +125,9 @@ impl Processor {
         from_tx: Option<Entid>,
         mut receiver: R,
     ) -> Result<RR> {
+        // Transactions before the history horizon are incomplete, and mustn't be replayed.
+        ensure_history_from(sqlite, from_tx.map_or(mentat_db::TX0, |tx| tx + 1))?;
+
         let tx_filter = match from_tx {
             Some(tx) => format!(" WHERE timeline = 0 AND tx > {} ", tx),
             None => "WHERE timeline = 0".to_string(),
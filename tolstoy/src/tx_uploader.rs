@@ -8,7 +8,7 @@
 // CONDITIONS OF ANY KIND, either express or implied. See the License for the
 // specific language governing permissions and limitations under the License.
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use uuid::Uuid;
 
@@ -35,6 +35,7 @@ pub(crate) struct TxUploader<'c> {
     remote_head: &'c Uuid,
     rolling_temp_head: Option<Uuid>,
     local_partitions: PartitionMap,
+    excluded_attributes: &'c HashSet<Entid>,
 }
 
 impl<'c> TxUploader<'c> {
@@ -42,6 +43,7 @@ impl<'c> TxUploader<'c> {
         client: &'c mut dyn GlobalTransactionLog,
         remote_head: &'c Uuid,
         local_partitions: PartitionMap,
+        excluded_attributes: &'c HashSet<Entid>,
     ) -> TxUploader<'c> {
         TxUploader {
             tx_temp_uuids: HashMap::new(),
@@ -49,6 +51,7 @@ impl<'c> TxUploader<'c> {
             remote_head,
             rolling_temp_head: None,
             local_partitions,
+            excluded_attributes,
         }
     }
 }
@@ -102,12 +105,16 @@ impl<'c> TxReceiver<UploaderReport> for TxUploader<'c> {
 
         let mut datoms: Vec<TxPart> = datoms.collect();
 
+        // Entids used only by datoms that don't sync are still reserved, so that no other
+        // client allocates them.
+        let partitions =
+            allocate_partition_map_for_entids(datoms.iter().map(|d| d.e), &self.local_partitions);
+        datoms.retain(|d| !self.excluded_attributes.contains(&d.a));
+
         // TODO this should live within a transaction, once server support is in place.
         // For now, we're uploading the PartitionMap in transaction's first chunk.
-        datoms[0].partitions = Some(allocate_partition_map_for_entids(
-            datoms.iter().map(|d| d.e),
-            &self.local_partitions,
-        ));
+        // There's always at least the transaction's txInstant left.
+        datoms[0].partitions = Some(partitions);
 
         // Upload all chunks.
         for datom in &datoms {
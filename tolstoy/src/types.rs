@@ -16,6 +16,7 @@ use core_traits::{Entid, TypedValue};
 use mentat_db::PartitionMap;
 
 use public_traits::errors::Result;
+use tolstoy_traits::errors::TolstoyError;
 
 pub struct LocalGlobalTxMapping<'a> {
     pub local: Entid,
@@ -87,3 +88,81 @@ pub trait GlobalTransactionLog {
     fn put_transaction(&mut self, tx: &Uuid, parent_tx: &Uuid, chunk_txs: &[Uuid]) -> Result<()>;
     fn put_chunk(&mut self, tx: &Uuid, payload: &TxPart) -> Result<()>;
 }
+
+/// A transaction as a global transaction log stores it: its parent, and the chunks it's made of.
+/// When what's synced is encrypted, the list of chunks is sealed instead; see `EncryptedLog`.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct StoredTx {
+    pub parent: Uuid,
+    #[serde(default)]
+    pub chunks: Vec<Uuid>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sealed: Option<serde_json::Value>,
+}
+
+/// Where a global transaction log keeps its head, transactions and chunks. Chunks are kept as the
+/// JSON documents they're written as, whether those are `TxPart`s or sealed ones.
+///
+/// Storage is a `GlobalTransactionLog` that reads and writes everything in the clear. Wrap it in
+/// an `EncryptedLog` to seal what's written and open what's read.
+pub trait LogStorage {
+    fn read_head(&self) -> Result<Uuid>;
+
+    /// Move the head to `tx`, but only if `tx` descends from the current head.
+    fn write_head(&mut self, tx: &Uuid) -> Result<()>;
+
+    /// The transactions after `tx` leading up to the head, in order.
+    fn read_transactions_after(&self, tx: &Uuid) -> Result<Vec<(Uuid, StoredTx)>>;
+
+    fn read_chunk(&self, chunk: &Uuid) -> Result<String>;
+
+    fn write_transaction(&mut self, tx: &Uuid, transaction: &StoredTx) -> Result<()>;
+
+    fn write_chunk(&mut self, chunk: &Uuid, json: &str) -> Result<()>;
+}
+
+impl<S> GlobalTransactionLog for S
+where
+    S: LogStorage,
+{
+    fn head(&self) -> Result<Uuid> {
+        self.read_head()
+    }
+
+    fn transactions_after(&self, tx: &Uuid) -> Result<Vec<Tx>> {
+        self.read_transactions_after(tx)?
+            .into_iter()
+            .map(|(tx, transaction)| {
+                if transaction.sealed.is_some() {
+                    bail!(TolstoyError::EncryptionFailed(format!(
+                        "transaction {} is encrypted",
+                        tx
+                    )));
+                }
+                let parts = transaction
+                    .chunks
+                    .iter()
+                    .map(|chunk| Ok(serde_json::from_str(&self.read_chunk(chunk)?)?))
+                    .collect::<Result<Vec<TxPart>>>()?;
+                Ok(Tx { tx, parts })
+            })
+            .collect()
+    }
+
+    fn set_head(&mut self, tx: &Uuid) -> Result<()> {
+        self.write_head(tx)
+    }
+
+    fn put_transaction(&mut self, tx: &Uuid, parent_tx: &Uuid, chunk_txs: &[Uuid]) -> Result<()> {
+        let transaction = StoredTx {
+            parent: *parent_tx,
+            chunks: chunk_txs.to_vec(),
+            sealed: None,
+        };
+        self.write_transaction(tx, &transaction)
+    }
+
+    fn put_chunk(&mut self, tx: &Uuid, payload: &TxPart) -> Result<()> {
+        self.write_chunk(tx, &serde_json::to_string(payload)?)
+    }
+}
@@ -22,6 +22,7 @@ use combine::error::StringStreamError;
 use mentat::CacheDirection;
 
 pub static COMMAND_CACHE: &str = &"cache";
+pub static COMMAND_CHECK: &str = "check";
 pub static COMMAND_CLOSE: &str = &"close";
 pub static COMMAND_EXIT_LONG: &str = &"exit";
 pub static COMMAND_EXIT_SHORT: &str = &"e";
@@ -44,6 +45,9 @@ pub static COMMAND_TRANSACT_SHORT: &str = &"t";
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum Command {
     Cache(String, CacheDirection),
+    CacheBudget(Option<usize>),
+    CacheStats,
+    Check,
     Close,
     Exit,
     Help(Vec<String>),
@@ -71,6 +75,9 @@ impl Command {
             | &Command::QueryPrepared(ref args)
             | &Command::Transact(ref args) => edn::parse::value(&args).is_ok(),
             &Command::Cache(_, _)
+            | &Command::CacheBudget(_)
+            | &Command::CacheStats
+            | &Command::Check
             | &Command::Close
             | &Command::Exit
             | &Command::Help(_)
@@ -85,12 +92,15 @@ impl Command {
 
     pub fn is_timed(&self) -> bool {
         match self {
-            &Command::Import(_)
+            &Command::Check
+            | &Command::Import(_)
             | &Command::Query(_)
             | &Command::QueryPrepared(_)
             | &Command::Transact(_) => true,
 
             &Command::Cache(_, _)
+            | &Command::CacheBudget(_)
+            | &Command::CacheStats
             | &Command::Close
             | &Command::Exit
             | &Command::Help(_)
@@ -108,6 +118,10 @@ impl Command {
             Command::Cache(ref attr, ref direction) => {
                 format!(".{} {} {:?}", COMMAND_CACHE, attr, direction)
             }
+            Command::CacheBudget(Some(budget)) => format!(".{} budget {}", COMMAND_CACHE, budget),
+            Command::CacheBudget(None) => format!(".{} budget off", COMMAND_CACHE),
+            Command::CacheStats => format!(".{}", COMMAND_CACHE),
+            Command::Check => format!(".{}", COMMAND_CHECK),
             Command::Close => format!(".{}", COMMAND_CLOSE),
             Command::Exit => format!(".{}", COMMAND_EXIT_LONG),
             Command::Help(ref args) => format!(".{} {:?}", COMMAND_HELP, args),
@@ -188,6 +202,58 @@ pub fn command(s: &str) -> Result<Command, Error> {
             .map(|(arg, direction)| Ok(Command::Cache(arg, direction))),
     );
 
+    let cache_budget_parser = string(COMMAND_CACHE)
+        .with(spaces())
+        .with(string("budget"))
+        .with(spaces())
+        .with(arguments())
+        .map(|args: Vec<String>| {
+            if args.is_empty() {
+                bail!(CliError::CommandParse(
+                    "Missing required argument".to_string()
+                ));
+            }
+            if args.len() > 1 {
+                bail!(CliError::CommandParse(format!(
+                    "Unrecognized argument {:?}",
+                    args[1]
+                )));
+            }
+            if args[0] == "off" {
+                return Ok(Command::CacheBudget(None));
+            }
+            match args[0].parse::<usize>() {
+                Ok(budget) => Ok(Command::CacheBudget(Some(budget))),
+                Err(_) => bail!(CliError::CommandParse(format!(
+                    "Invalid cache budget {:?}",
+                    args[0]
+                ))),
+            }
+        });
+
+    let cache_stats_parser = string(COMMAND_CACHE)
+        .with(spaces())
+        .with(arguments())
+        .map(|args| {
+            if !args.is_empty() && args != ["stats"] {
+                bail!(CliError::CommandParse(format!(
+                    "Unrecognized argument {:?}",
+                    args[0]
+                )));
+            }
+            Ok(Command::CacheStats)
+        });
+
+    let check_parser = string(COMMAND_CHECK).with(no_arg_parser()).map(|args| {
+        if !args.is_empty() {
+            bail!(CliError::CommandParse(format!(
+                "Unrecognized argument {:?}",
+                args[0]
+            )));
+        }
+        Ok(Command::Check)
+    });
+
     let close_parser = string(COMMAND_CLOSE).with(no_arg_parser()).map(|args| {
         if !args.is_empty() {
             bail!(CliError::CommandParse(format!(
@@ -286,6 +352,9 @@ pub fn command(s: &str) -> Result<Command, Error> {
         attempt(import_parser),
         attempt(timer_parser),
         attempt(cache_parser),
+        attempt(cache_budget_parser),
+        attempt(cache_stats_parser),
+        attempt(check_parser),
         attempt(open_encrypted_parser),
         attempt(open_parser),
         attempt(close_parser),
@@ -452,6 +521,48 @@ mod tests {
         assert_eq!(err.to_string(), "Missing required argument");
     }
 
+    #[test]
+    fn test_cache_parser() {
+        let cmd = command(".cache :foo/bar reverse").expect("Expected cache command");
+        assert_eq!(
+            cmd,
+            Command::Cache(":foo/bar".to_string(), CacheDirection::Reverse)
+        );
+
+        let cmd = command(".cache").expect("Expected cache stats command");
+        assert_eq!(cmd, Command::CacheStats);
+
+        let cmd = command(".cache stats ").expect("Expected cache stats command");
+        assert_eq!(cmd, Command::CacheStats);
+    }
+
+    #[test]
+    fn test_cache_budget_parser() {
+        let cmd = command(".cache budget 1048576").expect("Expected cache budget command");
+        assert_eq!(cmd, Command::CacheBudget(Some(1048576)));
+        assert_eq!(cmd.output(), ".cache budget 1048576");
+
+        let cmd = command(".cache budget off").expect("Expected cache budget command");
+        assert_eq!(cmd, Command::CacheBudget(None));
+
+        let err = command(".cache budget").expect_err("Expected an error");
+        assert_eq!(err.to_string(), "Missing required argument");
+
+        let err = command(".cache budget lots").expect_err("Expected an error");
+        assert_eq!(err.to_string(), "Invalid cache budget \"lots\"");
+    }
+
+    #[test]
+    fn test_check_parser() {
+        let cmd = command(".check").expect("Expected check command");
+        assert_eq!(cmd, Command::Check);
+        assert_eq!(cmd.output(), ".check");
+
+        let input = ".check arg1";
+        let err = command(input).expect_err("Expected an error");
+        assert_eq!(err.to_string(), format!("Invalid command {:?}", input));
+    }
+
     #[test]
     fn test_close_parser_with_args() {
         let input = ".close arg1";
@@ -23,17 +23,17 @@ use time::{Duration, Instant};
 use core_traits::StructuredMap;
 
 use mentat::{
-    Binding, CacheDirection, Keyword, QueryExplanation, QueryOutput, QueryResults, Queryable,
-    Store, TxReport, TypedValue,
+    Binding, CacheDirection, HasSchema, Keyword, QueryExplanation, QueryOutput, QueryResults,
+    Queryable, Store, TxReport, TypedValue,
 };
 
 use command_parser::Command;
 
 use command_parser::{
-    COMMAND_CACHE, COMMAND_EXIT_LONG, COMMAND_EXIT_SHORT, COMMAND_HELP, COMMAND_IMPORT_LONG,
-    COMMAND_OPEN, COMMAND_QUERY_EXPLAIN_LONG, COMMAND_QUERY_EXPLAIN_SHORT, COMMAND_QUERY_LONG,
-    COMMAND_QUERY_PREPARED_LONG, COMMAND_QUERY_SHORT, COMMAND_SCHEMA, COMMAND_TIMER_LONG,
-    COMMAND_TRANSACT_LONG, COMMAND_TRANSACT_SHORT,
+    COMMAND_CACHE, COMMAND_CHECK, COMMAND_EXIT_LONG, COMMAND_EXIT_SHORT, COMMAND_HELP,
+    COMMAND_IMPORT_LONG, COMMAND_OPEN, COMMAND_QUERY_EXPLAIN_LONG, COMMAND_QUERY_EXPLAIN_SHORT,
+    COMMAND_QUERY_LONG, COMMAND_QUERY_PREPARED_LONG, COMMAND_QUERY_SHORT, COMMAND_SCHEMA,
+    COMMAND_TIMER_LONG, COMMAND_TRANSACT_LONG, COMMAND_TRANSACT_SHORT,
 };
 
 // These are still defined when this feature is disabled (so that we can
@@ -64,6 +64,8 @@ lazy_static! {
 
             (COMMAND_SCHEMA, "Output the schema for the current open database."),
 
+            (COMMAND_CHECK, "Check the integrity of the current open database, and list any problems found."),
+
             (COMMAND_IMPORT_LONG, "Transact the contents of a file against the current open database."),
 
             (COMMAND_QUERY_LONG, "Execute a query against the current open database."),
@@ -79,7 +81,7 @@ lazy_static! {
 
             (COMMAND_TIMER_LONG, "Enable or disable timing of query and transact operations."),
 
-            (COMMAND_CACHE, "Cache an attribute. Usage: `.cache :foo/bar reverse`"),
+            (COMMAND_CACHE, "Cache an attribute. Usage: `.cache :foo/bar reverse`. With no arguments, show what each cached attribute holds and how often lookups hit. `.cache budget 1048576` holds the caches to roughly that many bytes; `.cache budget off` lifts the limit."),
 
             #[cfg(feature = "syncable")]
             (COMMAND_SYNC, "Synchronize the database against a Mentat Sync Server URL for a provided user UUID."),
@@ -237,6 +239,51 @@ impl Repl {
         }
     }
 
+    fn cache_stats(&self) -> Result<(), Error> {
+        let stats = self.store.attribute_cache_stats();
+        if stats.is_empty() {
+            println!("No attributes are cached.");
+            return Ok(());
+        }
+
+        let schema = self.store.conn().current_schema();
+        let stdout = ::std::io::stdout();
+        let mut output = TabWriter::new(stdout.lock());
+        writeln!(
+            output,
+            "| Attribute\t| Entries\t| Bytes\t| Hits\t| Misses\t| Hit rate\t| Notes\t|"
+        )?;
+        writeln!(output, "---\t---\t---\t---\t---\t---\t---\t")?;
+        for (entid, stats) in stats {
+            let attribute = schema
+                .get_ident(entid)
+                .map_or_else(|| entid.to_string(), |ident| ident.to_string());
+            let hit_rate = stats
+                .hit_rate()
+                .map_or_else(|| "-".to_string(), |rate| format!("{:.1}%", rate * 100.0));
+            let mut notes = vec![];
+            if stats.partial {
+                notes.push("partial");
+            }
+            if stats.reverse_suspended {
+                notes.push("reverse suspended");
+            }
+            writeln!(
+                output,
+                "| {}\t| {}\t| {}\t| {}\t| {}\t| {}\t| {}\t|",
+                attribute,
+                stats.entries,
+                stats.approximate_bytes,
+                stats.hits,
+                stats.misses,
+                hit_rate,
+                notes.join(", ")
+            )?;
+        }
+        output.flush()?;
+        Ok(())
+    }
+
     /// Runs a single command input.
     fn handle_command(&mut self, cmd: Command) -> bool {
         let should_print_times = self.timer_on && cmd.is_timed();
@@ -248,6 +295,20 @@ impl Repl {
             Command::Cache(attr, direction) => {
                 self.cache(attr, direction);
             }
+            Command::CacheBudget(budget) => {
+                self.store.set_attribute_cache_budget(budget);
+            }
+            Command::CacheStats => {
+                if let Err(e) = self.cache_stats() {
+                    eprintln!("{}", e);
+                }
+            }
+            Command::Check => {
+                match self.store.verify() {
+                    Ok(report) => println!("{}", report),
+                    Err(e) => eprintln!("{}", e),
+                };
+            }
             Command::Close => {
                 self.close();
             }
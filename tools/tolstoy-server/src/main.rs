@@ -0,0 +1,76 @@
+// Copyright 2018 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Serve global transaction logs for `mentat_tolstoy::RemoteClient` to sync against. See
+//! `mentat_tolstoy::server`.
+
+use std::net::SocketAddr;
+
+use getopts::Options;
+
+use mentat_tolstoy::SyncServer;
+
+fn print_usage(arg0: &str, opts: &Options) {
+    print!("{}", opts.usage(&format!("Usage: {} [OPTIONS]", arg0)));
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let mut opts = Options::new();
+    opts.optopt(
+        "d",
+        "database",
+        "The path to the SQLite database holding the logs; temporary if omitted",
+        "DATABASE",
+    );
+    opts.optopt(
+        "a",
+        "address",
+        "The address to listen on, 127.0.0.1:3333 if omitted",
+        "ADDRESS",
+    );
+    opts.optflag("h", "help", "Print this help message and exit");
+
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(e) => {
+            eprintln!("{}: {}", args[0], e);
+            std::process::exit(2);
+        }
+    };
+    if matches.opt_present("h") {
+        print_usage(&args[0], &opts);
+        return;
+    }
+
+    let database = matches.opt_str("d").unwrap_or_default();
+    let address: SocketAddr = match matches
+        .opt_str("a")
+        .unwrap_or_else(|| "127.0.0.1:3333".to_string())
+        .parse()
+    {
+        Ok(address) => address,
+        Err(e) => {
+            eprintln!("{}: bad address: {}", args[0], e);
+            std::process::exit(2);
+        }
+    };
+
+    match SyncServer::start(&database, address) {
+        Ok(server) => {
+            println!("Serving on http://{}", server.address());
+            server.wait();
+        }
+        Err(e) => {
+            eprintln!("{}: {}", args[0], e);
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,237 @@
+// Copyright 2018 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Attribute cache registrations that outlive the process.
+//!
+//! Every registration made through `Conn::cache` or `InProgress::cache` is recorded in the
+//! `cached_attributes` table, so that a store can rebuild the same caches when it is reopened.
+//! Rebuilding can be slow for large attributes, so a `CacheWarmer` can do it on a thread of its
+//! own, using a separate SQLite connection. Until its caches are installed, queries simply
+//! don't use them and read from SQL instead.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::sync::Arc;
+
+use std::thread;
+
+use core_traits::Entid;
+
+use mentat_core::{HasSchema, Schema};
+
+use mentat_db::cache::SQLiteAttributeCache;
+
+use public_traits::errors::Result;
+
+use crate::{CacheAction, CacheDirection};
+
+/// How many times a `CacheWarmer` rebuilds in the background because the store changed under it,
+/// before giving up and building synchronously.
+const MAX_CACHE_WARMING_ATTEMPTS: usize = 3;
+
+/// Whether a reopened store rebuilds its attribute caches before returning, or in the background.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CacheWarming {
+    Synchronous,
+    Background,
+}
+
+/// Record that `attribute` has been registered or unregistered. Registering one direction and
+/// then the other amounts to registering both, just as it does for the caches themselves.
+pub fn record_cache_registration(
+    conn: &rusqlite::Connection,
+    attribute: Entid,
+    direction: CacheDirection,
+    action: CacheAction,
+) -> Result<()> {
+    match action {
+        CacheAction::Register => {
+            let forward = direction != CacheDirection::Reverse;
+            let reverse = direction != CacheDirection::Forward;
+            conn.execute(
+                "INSERT INTO cached_attributes (a, forward, reverse) VALUES (?, ?, ?)
+                 ON CONFLICT (a) DO UPDATE SET forward = forward OR excluded.forward, reverse = reverse OR excluded.reverse",
+                rusqlite::params![&attribute, &forward, &reverse],
+            )?;
+        }
+        CacheAction::Deregister => {
+            conn.execute(
+                "DELETE FROM cached_attributes WHERE a = ?",
+                rusqlite::params![&attribute],
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Return every recorded cache registration.
+pub fn cache_registrations(conn: &rusqlite::Connection) -> Result<BTreeMap<Entid, CacheDirection>> {
+    let mut stmt = conn.prepare("SELECT a, forward, reverse FROM cached_attributes")?;
+    let rows = stmt.query_and_then(rusqlite::params![], |row| -> Result<(Entid, bool, bool)> {
+        Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+    })?;
+
+    let mut registrations = BTreeMap::new();
+    for row in rows {
+        let direction = match row? {
+            (a, true, true) => (a, CacheDirection::Both),
+            (a, true, false) => (a, CacheDirection::Forward),
+            (a, false, true) => (a, CacheDirection::Reverse),
+            (_, false, false) => continue,
+        };
+        registrations.insert(direction.0, direction.1);
+    }
+    Ok(registrations)
+}
+
+/// Populate `cache` with each of `registrations`. Attributes that are no longer in the schema are
+/// skipped.
+pub fn register_caches(
+    cache: &mut SQLiteAttributeCache,
+    schema: &Schema,
+    sqlite: &rusqlite::Connection,
+    registrations: &BTreeMap<Entid, CacheDirection>,
+) -> Result<()> {
+    for (&a, &direction) in registrations {
+        if schema.attribute_for_entid(a).is_none() {
+            continue;
+        }
+        match direction {
+            CacheDirection::Both => cache.register(schema, sqlite, a),
+            CacheDirection::Forward => cache.register_forward(schema, sqlite, a),
+            CacheDirection::Reverse => cache.register_reverse(schema, sqlite, a),
+        }?;
+    }
+    Ok(())
+}
+
+type Opener = Arc<dyn Fn() -> Result<rusqlite::Connection> + Send + Sync>;
+
+/// Caches being built on a background thread.
+pub struct CacheWarmer {
+    open: Opener,
+    registrations: BTreeMap<Entid, CacheDirection>,
+    generation: u64,
+    attempts: usize,
+    /// The attributes the thread was asked to build.
+    requested: BTreeSet<Entid>,
+    receiver: Receiver<Result<SQLiteAttributeCache>>,
+}
+
+/// What became of a `CacheWarmer` when it was asked for its caches.
+pub enum CacheWarmerState {
+    /// Still building.
+    Warming(CacheWarmer),
+
+    /// Built, and up to date.
+    Warmed(SQLiteAttributeCache),
+
+    /// The thread failed, or the store kept changing under it. Build synchronously instead.
+    Abandoned(BTreeMap<Entid, CacheDirection>),
+}
+
+impl CacheWarmer {
+    /// Start building `registrations` against `schema` with a connection made by `open`.
+    /// `generation` identifies the state of the store the caches should reflect.
+    pub fn spawn<F>(
+        open: F,
+        schema: Arc<Schema>,
+        generation: u64,
+        registrations: BTreeMap<Entid, CacheDirection>,
+    ) -> CacheWarmer
+    where
+        F: Fn() -> Result<rusqlite::Connection> + Send + Sync + 'static,
+    {
+        CacheWarmer::start(Arc::new(open), schema, generation, registrations, 1)
+    }
+
+    fn start(
+        open: Opener,
+        schema: Arc<Schema>,
+        generation: u64,
+        registrations: BTreeMap<Entid, CacheDirection>,
+        attempts: usize,
+    ) -> CacheWarmer {
+        let (sender, receiver) = channel();
+        let thread_open = open.clone();
+        let thread_registrations = registrations.clone();
+        thread::spawn(move || {
+            let result = thread_open().and_then(|sqlite| {
+                let mut cache = SQLiteAttributeCache::default();
+                register_caches(&mut cache, &schema, &sqlite, &thread_registrations)?;
+                Ok(cache)
+            });
+            // The warmer might have been dropped; nobody is interested in the caches any more.
+            let _ = sender.send(result);
+        });
+        CacheWarmer {
+            open,
+            requested: registrations.keys().cloned().collect(),
+            registrations,
+            generation,
+            attempts,
+            receiver,
+        }
+    }
+
+    /// The attribute has been registered or unregistered explicitly, so what we build for it
+    /// would be out of date.
+    pub fn forget(&mut self, attribute: Entid) {
+        self.registrations.remove(&attribute);
+    }
+
+    /// Return the caches if they're ready, waiting for them if `wait` is true.
+    ///
+    /// If the store has moved past `generation` since the caches were started, they might be
+    /// stale, and are rebuilt against `schema` as of `current_generation`.
+    pub fn finish(
+        self,
+        wait: bool,
+        schema: &Arc<Schema>,
+        current_generation: u64,
+    ) -> CacheWarmerState {
+        let received = if wait {
+            self.receiver.recv().ok()
+        } else {
+            match self.receiver.try_recv() {
+                Ok(result) => Some(result),
+                Err(TryRecvError::Empty) => return CacheWarmerState::Warming(self),
+                Err(TryRecvError::Disconnected) => None,
+            }
+        };
+
+        // `None` means the thread panicked.
+        let mut cache = match received {
+            Some(Ok(cache)) => cache,
+            _ => return CacheWarmerState::Abandoned(self.registrations),
+        };
+
+        if self.generation != current_generation {
+            if self.attempts >= MAX_CACHE_WARMING_ATTEMPTS {
+                return CacheWarmerState::Abandoned(self.registrations);
+            }
+            return CacheWarmerState::Warming(CacheWarmer::start(
+                self.open,
+                schema.clone(),
+                current_generation,
+                self.registrations,
+                self.attempts + 1,
+            ));
+        }
+
+        for &a in self.requested.iter() {
+            if !self.registrations.contains_key(&a) {
+                cache.unregister(a);
+            }
+        }
+        CacheWarmerState::Warmed(cache)
+    }
+}
@@ -0,0 +1,202 @@
+// Copyright 2018 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Deadlines and cooperative cancellation for reads.
+//!
+//! A runaway query -- an accidental cartesian product, say -- can hold a connection for minutes.
+//! `interruptible` runs a closure with a SQLite progress handler installed; the handler polls an
+//! `Interrupt` every thousand or so virtual machine instructions and, once its deadline passes or
+//! its token is cancelled, makes the running statement fail with `SQLITE_INTERRUPT`. That failure
+//! surfaces as `MentatError::Interrupted`.
+//!
+//! Interrupting a `SELECT` leaves the enclosing SQLite transaction intact, so an `InProgressRead`
+//! remains usable afterwards. The handler is removed when the closure returns.
+
+use std::cell::Cell;
+
+use std::os::raw::{c_int, c_void};
+
+use std::ptr;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use std::time::{Duration, Instant};
+
+use rusqlite::ffi;
+
+use public_traits::errors::{InterruptReason, MentatError, Result};
+
+/// The number of SQLite virtual machine instructions between checks of an `Interrupt`.
+const PROGRESS_HANDLER_INSTRUCTIONS: c_int = 1000;
+
+/// A flag shared between the thread running an operation and any thread that wants to stop it.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    /// Ask every operation watching this token to stop. Cancellation is permanent.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+/// When to give up on an operation: at a deadline, when a token is cancelled, or both.
+#[derive(Clone, Debug, Default)]
+pub struct Interrupt {
+    deadline: Option<Instant>,
+    token: Option<CancellationToken>,
+}
+
+impl Interrupt {
+    /// An `Interrupt` that never fires.
+    pub fn none() -> Interrupt {
+        Interrupt::default()
+    }
+
+    /// Give up once `timeout` has elapsed from now.
+    pub fn after(timeout: Duration) -> Interrupt {
+        Interrupt::none().with_timeout(timeout)
+    }
+
+    /// Give up when `token` is cancelled.
+    pub fn on_cancel(token: CancellationToken) -> Interrupt {
+        Interrupt::none().with_token(token)
+    }
+
+    pub fn with_deadline(mut self, deadline: Instant) -> Interrupt {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    pub fn with_timeout(self, timeout: Duration) -> Interrupt {
+        self.with_deadline(Instant::now() + timeout)
+    }
+
+    pub fn with_token(mut self, token: CancellationToken) -> Interrupt {
+        self.token = Some(token);
+        self
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    pub fn token(&self) -> Option<&CancellationToken> {
+        self.token.as_ref()
+    }
+
+    /// Return the reason to stop now, if there is one. Cancellation takes precedence.
+    pub fn check(&self) -> Option<InterruptReason> {
+        if let Some(ref token) = self.token {
+            if token.is_cancelled() {
+                return Some(InterruptReason::Cancelled);
+            }
+        }
+        match self.deadline {
+            Some(deadline) if Instant::now() >= deadline => Some(InterruptReason::DeadlineExceeded),
+            _ => None,
+        }
+    }
+
+    fn is_unbounded(&self) -> bool {
+        self.deadline.is_none() && self.token.is_none()
+    }
+}
+
+/// What the progress handler sees. It lives on the stack of `interruptible` for as long as the
+/// handler is installed.
+struct ProgressState<'i> {
+    interrupt: &'i Interrupt,
+    fired: Cell<Option<InterruptReason>>,
+}
+
+// `interruptible` removes this handler before `state` goes out of scope, and SQLite only invokes
+// it on the thread that is stepping a statement on this connection.
+unsafe extern "C" fn progress_handler(state: *mut c_void) -> c_int {
+    let state = &*(state as *const ProgressState);
+    match state.interrupt.check() {
+        Some(reason) => {
+            state.fired.set(Some(reason));
+            1
+        }
+        None => 0,
+    }
+}
+
+/// Removes the progress handler from a connection, even if the guarded closure panics.
+struct ProgressHandlerGuard<'c> {
+    sqlite: &'c rusqlite::Connection,
+}
+
+impl<'c> Drop for ProgressHandlerGuard<'c> {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::sqlite3_progress_handler(self.sqlite.handle(), 0, None, ptr::null_mut());
+        }
+    }
+}
+
+/// Run `f`, which should only use `sqlite` to read, abandoning it if `interrupt` fires first.
+///
+/// If `interrupt` has already fired, `f` is not run at all. If it fires while `f` is stepping a
+/// statement, `f`'s error is replaced by `MentatError::Interrupted`. Progress handlers don't nest:
+/// don't call `interruptible` from inside `f` on the same connection.
+pub fn interruptible<T, E, F>(
+    sqlite: &rusqlite::Connection,
+    interrupt: &Interrupt,
+    f: F,
+) -> Result<T>
+where
+    F: FnOnce() -> ::std::result::Result<T, E>,
+    E: Into<MentatError>,
+{
+    if interrupt.is_unbounded() {
+        return f().map_err(|e| e.into());
+    }
+
+    if let Some(reason) = interrupt.check() {
+        bail!(MentatError::Interrupted(reason));
+    }
+
+    let state = ProgressState {
+        interrupt,
+        fired: Cell::new(None),
+    };
+
+    let result = {
+        let _guard = ProgressHandlerGuard { sqlite };
+        unsafe {
+            ffi::sqlite3_progress_handler(
+                sqlite.handle(),
+                PROGRESS_HANDLER_INSTRUCTIONS,
+                Some(progress_handler),
+                &state as *const ProgressState as *mut c_void,
+            );
+        }
+        f()
+    };
+
+    match (result, state.fired.get()) {
+        (Ok(v), _) => Ok(v),
+        (Err(_), Some(reason)) => Err(MentatError::Interrupted(reason)),
+        (Err(e), None) => Err(e.into()),
+    }
+}
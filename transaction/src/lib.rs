@@ -9,6 +9,9 @@
 // specific language governing permissions and limitations under the License.
 
 extern crate failure;
+#[macro_use]
+extern crate log;
+extern crate futures;
 extern crate rusqlite;
 
 extern crate edn;
@@ -21,12 +24,15 @@ extern crate mentat_db;
 extern crate mentat_query_algebrizer;
 extern crate mentat_query_projector;
 extern crate mentat_query_pull;
+extern crate mentat_query_sql;
 extern crate mentat_sql;
 
 use std::sync::{Arc, Mutex};
 
 use std::io::Read;
 
+use std::iter::once;
+
 use std::borrow::Borrow;
 
 use std::collections::BTreeMap;
@@ -36,36 +42,69 @@ use std::fs::File;
 use std::path::Path;
 
 use edn::entities::{OpType, TempId};
+use edn::query::{SrcVar, Variable};
 use edn::{InternSet, Keyword};
 
-use core_traits::{Attribute, Entid, KnownEntid, StructuredMap, TypedValue, ValueType};
+use core_traits::{Attribute, Binding, Entid, KnownEntid, StructuredMap, TypedValue, ValueType};
 
 use public_traits::errors::{MentatError, Result};
 
-use mentat_core::{HasSchema, Schema, TxReport, ValueRc};
+use mentat_core::{Cloned, DatomsView, HasSchema, Schema, TxReport, ValueRc};
 
-use mentat_query_pull::{pull_attributes_for_entities, pull_attributes_for_entity};
+use mentat_query_pull::{pull_attributes_for_entities, pull_attributes_for_entity, Puller};
 
 use mentat_db::{
-    transact, transact_terms, InProgressObserverTransactWatcher, PartitionMap, TransactWatcher,
-    TransactableValue, TxObservationService,
+    transact, transact_terms, AttributeSet, InProgressObserverTransactWatcher, PartitionMap,
+    TransactWatcher, TransactableValue, TxObservationService,
 };
 
 use mentat_db::internal_types::TermWithTempIds;
 
+use mentat_db::history::prune_history;
+
+use mentat_db::branches;
+
+use mentat_db::diff;
+pub use mentat_db::diff::{Diff, DiffDatom};
+
+pub use mentat_db::history::{HistoryCutoff, PruneReport};
+use mentat_db::timelines;
+
 use mentat_db::cache::{InProgressCacheTransactWatcher, InProgressSQLiteAttributeCache};
 
+pub mod cache_registrations;
 pub mod entity_builder;
+pub mod interrupt;
+pub mod live_query;
 pub mod metadata;
 pub mod query;
+pub mod query_plan_cache;
+pub mod tx_report_queue;
+
+pub use crate::cache_registrations::CacheWarming;
+
+use crate::cache_registrations::record_cache_registration;
 
 pub use crate::entity_builder::{InProgressBuilder, TermBuilder};
 
+pub use crate::interrupt::{interruptible, CancellationToken, Interrupt};
+
+pub use crate::live_query::{LiveQueryDiff, LiveQueryRefresh, LiveQueryService, StaleLiveQuery};
+
 pub use crate::metadata::Metadata;
 
+pub use crate::query_plan_cache::{QueryPlanCache, QueryPlanCacheStats};
+
+pub use crate::tx_report_queue::{
+    CommittedTransaction, TxDatom, TxReportQueueError, TxReportQueues, TxReportReceiver,
+};
+
+use crate::tx_report_queue::committed_transaction;
+
 use crate::query::{
-    lookup_value_for_attribute, lookup_values_for_attribute, q_explain, q_once, q_prepare,
-    q_uncached, Known, PreparedResult, QueryExplanation, QueryInputs, QueryOutput,
+    lookup_value_for_attribute, lookup_values_for_attribute, q_explain, q_once,
+    q_once_with_plan_cache, q_prepare, q_uncached, Known, PreparedResult, QueryExplanation,
+    QueryInputs, QueryOutput,
 };
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
@@ -93,8 +132,18 @@ pub struct InProgress<'a, 'c> {
     pub schema: Schema,
     pub cache: InProgressSQLiteAttributeCache,
     pub use_caching: bool,
+    pub history_horizon: Option<Entid>,
     pub tx_observer: &'a Mutex<TxObservationService>,
     pub tx_observer_watcher: InProgressObserverTransactWatcher,
+    pub query_plan_cache: &'a QueryPlanCache,
+    pub live_queries: &'a Mutex<LiveQueryService>,
+    pub tx_report_queues: &'a Mutex<TxReportQueues>,
+    /// Reports of the transactions made since the store's contents were last replaced, for any
+    /// transaction report queues.
+    pub tx_reports: Vec<TxReport>,
+    /// Whether the store's state was rewritten other than by transacting -- by undo, redo, or
+    /// switching branches -- so that everything watching it must catch up on commit.
+    pub metadata_replaced: bool,
 }
 
 /// Represents an in-progress set of reads to the store. Just like `InProgress`,
@@ -113,6 +162,24 @@ pub trait Queryable {
     fn q_prepare<T>(&self, query: &str, inputs: T) -> PreparedResult
     where
         T: Into<Option<QueryInputs>>;
+
+    /// Run a query, handing each row of results to `f` as it is read from SQLite, rather than
+    /// collecting every row first. Each row has one binding per element of the find spec.
+    /// Return `Ok(false)` from `f` to stop early; no further pages of rows will be read.
+    fn q_stream<T, F>(&self, query: &str, inputs: T, mut f: F) -> Result<()>
+    where
+        T: Into<Option<QueryInputs>>,
+        F: FnMut(Vec<Binding>) -> Result<bool>,
+    {
+        let mut prepared = self.q_prepare(query, inputs)?;
+        for row in prepared.stream()? {
+            if !f(row?)? {
+                break;
+            }
+        }
+        Ok(())
+    }
+
     fn lookup_values_for_attribute<E>(
         &self,
         entity: E,
@@ -153,6 +220,16 @@ impl<'a, 'c> InProgress<'a, 'c> {
         self.use_caching = yesno;
     }
 
+    /// What queries in this transaction can know without asking the store: like
+    /// `Metadata::known`, but with this transaction's changes.
+    pub fn known(&self) -> Known<'_, '_> {
+        Known {
+            schema: &self.schema,
+            cache: Some(&self.cache),
+            history_horizon: self.history_horizon,
+        }
+    }
+
     /// If you only have a reference to an `InProgress`, you can't use the easy builder.
     /// This exists so you can make your own.
     pub fn transact_builder(&mut self, builder: TermBuilder) -> Result<TxReport> {
@@ -182,6 +259,7 @@ impl<'a, 'c> InProgress<'a, 'c> {
         if let Some(schema) = next_schema {
             self.schema = schema;
         }
+        self.record_report(&report);
         Ok(report)
     }
 
@@ -213,9 +291,22 @@ impl<'a, 'c> InProgress<'a, 'c> {
         if let Some(schema) = next_schema {
             self.schema = schema;
         }
+        self.record_report(&report);
         Ok(report)
     }
 
+    fn record_report(&mut self, report: &TxReport) {
+        // A queue might be opened before we commit, so keep every report until then.
+        self.tx_reports.push(report.clone());
+    }
+
+    /// The store's state was rewritten other than by transacting. Reports of earlier transactions
+    /// are superseded: queues are told to start afresh instead.
+    fn replaced_metadata(&mut self) {
+        self.metadata_replaced = true;
+        self.tx_reports.clear();
+    }
+
     pub fn transact<B>(&mut self, transaction: B) -> Result<TxReport>
     where
         B: Borrow<str>,
@@ -239,7 +330,32 @@ impl<'a, 'c> InProgress<'a, 'c> {
     }
 
     pub fn commit(self) -> Result<()> {
-        // The mutex is taken during this entire method.
+        // Nobody else can write while we hold the SQLite write lock, so the schema we compare
+        // against can't change before we take the mutex again below.
+        let schema_changed = self.schema != *(self.mutex.lock().unwrap().schema);
+
+        // Bring live queries up to date while we can still read what we're about to commit.
+        // Neither the metadata mutex nor the live query mutex is held while they run.
+        let touched: AttributeSet = self
+            .tx_observer_watcher
+            .txes
+            .values()
+            .flat_map(|attributes| attributes.iter().cloned())
+            .collect();
+        let stale = self
+            .live_queries
+            .lock()
+            .unwrap()
+            .stale(&touched, schema_changed || self.metadata_replaced);
+        let live_query_refreshes: Vec<LiveQueryRefresh> = {
+            let known = self.known();
+            stale
+                .into_iter()
+                .map(|live| live.refresh(&self.transaction, known))
+                .collect()
+        };
+
+        // The mutex is taken during the rest of this method.
         let mut metadata = self.mutex.lock().unwrap();
 
         if self.generation != metadata.generation {
@@ -250,29 +366,67 @@ impl<'a, 'c> InProgress<'a, 'c> {
             bail!(MentatError::UnexpectedLostTransactRace);
         }
 
+        // Likewise collect the datoms each transaction wrote, if any transaction report queue
+        // might want them.
+        let mut committed = vec![];
+        if !self.tx_report_queues.lock().unwrap().is_empty() {
+            for report in self.tx_reports {
+                if let Some(transaction) =
+                    committed_transaction(&self.transaction, &self.schema, report)?
+                {
+                    committed.push(transaction);
+                }
+            }
+        }
+
         // Commit the SQLite transaction while we hold the mutex.
         self.transaction.commit()?;
 
         metadata.generation += 1;
         metadata.partition_map = self.partition_map;
 
+        if self.history_horizon != metadata.history_horizon {
+            metadata.history_horizon = self.history_horizon;
+            // Plans that read the transaction log were made against the old horizon.
+            self.query_plan_cache.invalidate(metadata.generation);
+        }
+
         // Update the conn's cache if we made any changes.
         self.cache.commit_to(&mut metadata.attribute_cache);
 
-        if self.schema != *(metadata.schema) {
+        if schema_changed {
             metadata.schema = Arc::new(self.schema);
+            self.query_plan_cache.invalidate(metadata.generation);
 
             // TODO: rebuild vocabularies and notify consumers that they've changed -- it's possible
             // that a change has arrived over the wire and invalidated some local module.
             // TODO: consider making vocabulary lookup lazy -- we won't need it much of the time.
         }
 
+        // The transaction might have pushed the cache past its memory budget.
+        metadata.attribute_cache.enforce_memory_budget();
+
         let txes = self.tx_observer_watcher.txes;
         self.tx_observer
             .lock()
             .unwrap()
             .in_progress_did_commit(txes);
 
+        self.live_queries
+            .lock()
+            .unwrap()
+            .did_commit(live_query_refreshes);
+
+        {
+            let mut queues = self.tx_report_queues.lock().unwrap();
+            if self.metadata_replaced {
+                queues.replaced();
+            }
+            if !committed.is_empty() {
+                queues.publish(&committed);
+            }
+        }
+
         Ok(())
     }
 
@@ -303,19 +457,160 @@ impl<'a, 'c> InProgress<'a, 'c> {
                     self.cache
                         .register_reverse(&self.schema, &self.transaction, attribute_entid)
                 }
-            }
-            .map_err(|e| e.into()),
+            }?,
             CacheAction::Deregister => {
                 self.cache.unregister(attribute_entid);
-                Ok(())
             }
         }
+
+        // Remember the registration if, and only if, this transaction commits.
+        record_cache_registration(
+            &self.transaction,
+            attribute_entid,
+            cache_direction,
+            cache_action,
+        )
     }
 
     pub fn last_tx_id(&self) -> Entid {
         self.partition_map[":db.part/tx"].next_entid() - 1
     }
 
+    /// Remove history before `cutoff` from the transaction log, keeping the store's current
+    /// state, and move the store's history horizon up to it. See `mentat_db::history`.
+    pub fn prune_history<C>(&mut self, cutoff: C) -> Result<PruneReport>
+    where
+        C: Into<HistoryCutoff>,
+    {
+        let report = prune_history(&self.transaction, cutoff.into())?;
+        self.history_horizon = Some(report.horizon);
+        Ok(report)
+    }
+
+    /// The net change in the store's datoms from transaction `from` to transaction `to`. See
+    /// `mentat_db::diff`.
+    pub fn diff(&self, from: Entid, to: Entid) -> Result<Diff> {
+        Ok(diff::diff(&self.transaction, &self.schema, from, to)?)
+    }
+
+    /// Transact the reverse of `diff`, taking its datoms back to how they were after `diff.from`.
+    /// Unlike `undo`, this is a new transaction: the timelines are left alone.
+    pub fn revert(&mut self, diff: &Diff) -> Result<TxReport> {
+        self.transact_terms(diff.reverting_terms(), InternSet::new())
+    }
+
+    /// Undo the last `n` transactions, most recent first, keeping them on the undo timeline so
+    /// that `redo` can re-apply them. Returns the IDs of the transactions undone, which might be
+    /// fewer than `n`: nothing at or before the base of a branch can be undone. See
+    /// `mentat_db::timelines::undo`.
+    pub fn undo(&mut self, n: usize) -> Result<Vec<Entid>> {
+        let w = InProgressTransactWatcher::new(
+            &mut self.tx_observer_watcher,
+            self.cache.transact_watcher(),
+        );
+        let moved = timelines::undo(
+            &self.transaction,
+            &self.schema,
+            self.partition_map.clone(),
+            n,
+            w,
+        )?;
+        self.partition_map = moved.partition_map;
+        if let Some(schema) = moved.schema {
+            self.schema = schema;
+        }
+        let txs = moved.txs;
+        drop(moved.watcher);
+        if !txs.is_empty() {
+            self.replaced_metadata();
+        }
+        Ok(txs)
+    }
+
+    /// Re-apply the last `n` undone transactions with their original IDs, in the order they were
+    /// first made. Returns the IDs of the transactions redone, which might be fewer than `n`: once
+    /// anything else is transacted, what was undone can no longer be redone.
+    pub fn redo(&mut self, n: usize) -> Result<Vec<Entid>> {
+        let w = InProgressTransactWatcher::new(
+            &mut self.tx_observer_watcher,
+            self.cache.transact_watcher(),
+        );
+        let moved = timelines::redo(
+            &self.transaction,
+            &self.schema,
+            self.partition_map.clone(),
+            n,
+            w,
+        )?;
+        self.partition_map = moved.partition_map;
+        if let Some(schema) = moved.schema {
+            self.schema = schema;
+        }
+        let txs = moved.txs;
+        drop(moved.watcher);
+        if !txs.is_empty() {
+            self.replaced_metadata();
+        }
+        Ok(txs)
+    }
+
+    /// Fork a branch called `name` from the main branch at transaction `base`. See
+    /// `mentat_db::branches`.
+    pub fn fork_branch(&mut self, name: &str, base: Entid) -> Result<()> {
+        Ok(branches::fork_branch(&self.transaction, name, base)?)
+    }
+
+    /// Check out the branch called `name`, or the main branch, `mentat_db::branches::MAIN_BRANCH`.
+    /// Each branch keeps what was undone on it, to be redone once it's checked out again.
+    pub fn switch_branch(&mut self, name: &str) -> Result<()> {
+        let w = InProgressTransactWatcher::new(
+            &mut self.tx_observer_watcher,
+            self.cache.transact_watcher(),
+        );
+        let (next_schema, next_partition_map, _watcher) = branches::switch_branch(
+            &self.transaction,
+            &self.schema,
+            self.partition_map.clone(),
+            name,
+            w,
+        )?;
+        self.partition_map = next_partition_map;
+        if let Some(schema) = next_schema {
+            self.schema = schema;
+        }
+        self.replaced_metadata();
+        Ok(())
+    }
+
+    /// Merge the branch called `name` into the main branch, which must be checked out, and delete
+    /// it. Returns a report for each of the branch's transactions, as transacted anew.
+    pub fn merge_branch(&mut self, name: &str) -> Result<Vec<TxReport>> {
+        let w = InProgressTransactWatcher::new(
+            &mut self.tx_observer_watcher,
+            self.cache.transact_watcher(),
+        );
+        let (reports, next_schema, next_partition_map, _watcher) = branches::merge_branch(
+            &self.transaction,
+            &self.schema,
+            self.partition_map.clone(),
+            name,
+            w,
+        )?;
+        self.partition_map = next_partition_map;
+        if let Some(schema) = next_schema {
+            self.schema = schema;
+        }
+        for report in &reports {
+            self.record_report(report);
+        }
+        Ok(reports)
+    }
+
+    /// Delete the branch called `name`, which must not be checked out, and its transactions.
+    pub fn delete_branch(&mut self, name: &str) -> Result<()> {
+        Ok(branches::delete_branch(&self.transaction, name)?)
+    }
+
     pub fn savepoint(&self, name: &str) -> Result<()> {
         self.transaction
             .execute(&format!("SAVEPOINT {}", name), rusqlite::params![])?;
@@ -339,14 +634,44 @@ impl<'a, 'c> InProgressRead<'a, 'c> {
     pub fn last_tx_id(&self) -> Entid {
         self.in_progress.last_tx_id()
     }
+
+    /// See `InProgress::diff`.
+    pub fn diff(&self, from: Entid, to: Entid) -> Result<Diff> {
+        self.in_progress.diff(from, to)
+    }
+
+    /// Run `f` against this read, giving up with `MentatError::Interrupted` if `interrupt` fires
+    /// while it is executing SQL. This read remains usable either way. Only reads can be
+    /// interrupted: SQLite rolls back the entire transaction if it interrupts a write.
+    pub fn with_interrupt<T, F>(&self, interrupt: &Interrupt, f: F) -> Result<T>
+    where
+        F: FnOnce(&Self) -> Result<T>,
+    {
+        interruptible(&self.in_progress.transaction, interrupt, || f(self))
+    }
 }
 
 impl<'a, 'c> Queryable for InProgressRead<'a, 'c> {
+    /// Reads can't change the schema or the attribute cache, so unlike `InProgress` they can
+    /// share query plans with other readers.
     fn q_once<T>(&self, query: &str, inputs: T) -> Result<QueryOutput>
     where
         T: Into<Option<QueryInputs>>,
     {
-        self.in_progress.q_once(query, inputs)
+        let ip = &self.in_progress;
+        if ip.use_caching {
+            let known = ip.known();
+            q_once_with_plan_cache(
+                &ip.transaction,
+                known,
+                ip.query_plan_cache,
+                ip.generation,
+                query,
+                inputs,
+            )
+        } else {
+            ip.q_once(query, inputs)
+        }
     }
 
     fn q_prepare<T>(&self, query: &str, inputs: T) -> PreparedResult
@@ -411,13 +736,157 @@ impl<'a, 'c> Pullable for InProgressRead<'a, 'c> {
     }
 }
 
+/// A read of the store as seen through a `DatomsView`: as it was after some transaction, or
+/// only the datoms asserted since one. Queries and pulls made through this handle see the
+/// default source `$` through the view; other sources can still be bound in `QueryInputs`.
+pub struct InProgressView<'r, 'a, 'c> {
+    read: &'r InProgressRead<'a, 'c>,
+    view: DatomsView,
+}
+
+impl<'a, 'c> InProgressRead<'a, 'c> {
+    /// Read the store as it was immediately after transaction `tx`.
+    pub fn as_of<'r>(&'r self, tx: Entid) -> InProgressView<'r, 'a, 'c> {
+        self.in_view(DatomsView::AsOf(tx))
+    }
+
+    /// Read only the datoms asserted after transaction `tx` that are still present.
+    pub fn since<'r>(&'r self, tx: Entid) -> InProgressView<'r, 'a, 'c> {
+        self.in_view(DatomsView::Since(tx))
+    }
+
+    /// Read every assertion and retraction in the log.  Patterns against the default source can
+    /// bind their `tx` and `added` places.
+    pub fn history<'r>(&'r self) -> InProgressView<'r, 'a, 'c> {
+        self.in_view(DatomsView::History)
+    }
+
+    pub fn in_view<'r>(&'r self, view: DatomsView) -> InProgressView<'r, 'a, 'c> {
+        InProgressView { read: self, view }
+    }
+}
+
+impl<'r, 'a, 'c> InProgressView<'r, 'a, 'c> {
+    pub fn view(&self) -> DatomsView {
+        self.view
+    }
+
+    fn inputs<T>(&self, inputs: T) -> QueryInputs
+    where
+        T: Into<Option<QueryInputs>>,
+    {
+        let inputs = inputs.into().unwrap_or_default();
+        if inputs.sources().contains_key(&SrcVar::DefaultSrc) {
+            inputs
+        } else {
+            inputs.with_source(SrcVar::DefaultSrc, self.view)
+        }
+    }
+
+    fn fetch_values(&self, entity: Entid, attribute: &Keyword, query: &str) -> Result<QueryOutput> {
+        let attribute = self
+            .read
+            .get_entid(attribute)
+            .ok_or_else(|| MentatError::UnknownAttribute(attribute.to_string()))?;
+        let inputs = QueryInputs::with_value_sequence(vec![
+            (Variable::from_valid_name("?e"), TypedValue::Ref(entity)),
+            (
+                Variable::from_valid_name("?a"),
+                TypedValue::Ref(attribute.into()),
+            ),
+        ]);
+        self.q_once(query, inputs)
+    }
+}
+
+impl<'r, 'a, 'c> Queryable for InProgressView<'r, 'a, 'c> {
+    fn q_once<T>(&self, query: &str, inputs: T) -> Result<QueryOutput>
+    where
+        T: Into<Option<QueryInputs>>,
+    {
+        self.read.q_once(query, self.inputs(inputs))
+    }
+
+    fn q_prepare<T>(&self, query: &str, inputs: T) -> PreparedResult<'_>
+    where
+        T: Into<Option<QueryInputs>>,
+    {
+        self.read.q_prepare(query, self.inputs(inputs))
+    }
+
+    fn q_explain<T>(&self, query: &str, inputs: T) -> Result<QueryExplanation>
+    where
+        T: Into<Option<QueryInputs>>,
+    {
+        self.read.q_explain(query, self.inputs(inputs))
+    }
+
+    fn lookup_values_for_attribute<E>(
+        &self,
+        entity: E,
+        attribute: &edn::Keyword,
+    ) -> Result<Vec<TypedValue>>
+    where
+        E: Into<Entid>,
+    {
+        let query = "[:find [?v ...] :in ?e ?a :where [?e ?a ?v]]";
+        self.fetch_values(entity.into(), attribute, query)?
+            .into_coll()
+            // Safe to unwrap: we never retrieve structure.
+            .map(|vs| vs.into_iter().map(|v| v.into_scalar().unwrap()).collect())
+            .map_err(|e| e.into())
+    }
+
+    fn lookup_value_for_attribute<E>(
+        &self,
+        entity: E,
+        attribute: &edn::Keyword,
+    ) -> Result<Option<TypedValue>>
+    where
+        E: Into<Entid>,
+    {
+        let query = "[:find ?v . :in ?e ?a :where [?e ?a ?v]]";
+        self.fetch_values(entity.into(), attribute, query)?
+            .into_scalar()
+            // Safe to unwrap: we never retrieve structure.
+            .map(|v| v.map(|v| v.into_scalar().unwrap()))
+            .map_err(|e| e.into())
+    }
+}
+
+impl<'r, 'a, 'c> Pullable for InProgressView<'r, 'a, 'c> {
+    fn pull_attributes_for_entities<E, A>(
+        &self,
+        entities: E,
+        attributes: A,
+    ) -> Result<BTreeMap<Entid, ValueRc<StructuredMap>>>
+    where
+        E: IntoIterator<Item = Entid>,
+        A: IntoIterator<Item = Entid>,
+    {
+        let ip = &self.read.in_progress;
+        Puller::for_attributes(&ip.schema, attributes)?
+            .in_view(self.view)
+            .pull(&ip.schema, &ip.transaction, entities)
+            .map_err(|e| e.into())
+    }
+
+    fn pull_attributes_for_entity<A>(&self, entity: Entid, attributes: A) -> Result<StructuredMap>
+    where
+        A: IntoIterator<Item = Entid>,
+    {
+        self.pull_attributes_for_entities(once(entity), attributes)
+            .map(|mut m| m.remove(&entity).map(|vs| vs.cloned()).unwrap_or_default())
+    }
+}
+
 impl<'a, 'c> Queryable for InProgress<'a, 'c> {
     fn q_once<T>(&self, query: &str, inputs: T) -> Result<QueryOutput>
     where
         T: Into<Option<QueryInputs>>,
     {
         if self.use_caching {
-            let known = Known::new(&self.schema, Some(&self.cache));
+            let known = self.known();
             q_once(&*(self.transaction), known, query, inputs)
         } else {
             q_uncached(&*(self.transaction), &self.schema, query, inputs)
@@ -428,7 +897,7 @@ impl<'a, 'c> Queryable for InProgress<'a, 'c> {
     where
         T: Into<Option<QueryInputs>>,
     {
-        let known = Known::new(&self.schema, Some(&self.cache));
+        let known = self.known();
         q_prepare(&*(self.transaction), known, query, inputs)
     }
 
@@ -436,7 +905,7 @@ impl<'a, 'c> Queryable for InProgress<'a, 'c> {
     where
         T: Into<Option<QueryInputs>>,
     {
-        let known = Known::new(&self.schema, Some(&self.cache));
+        let known = self.known();
         q_explain(&*(self.transaction), known, query, inputs)
     }
 
@@ -448,7 +917,7 @@ impl<'a, 'c> Queryable for InProgress<'a, 'c> {
     where
         E: Into<Entid>,
     {
-        let known = Known::new(&self.schema, Some(&self.cache));
+        let known = self.known();
         lookup_values_for_attribute(&*(self.transaction), known, entity, attribute)
     }
 
@@ -460,7 +929,7 @@ impl<'a, 'c> Queryable for InProgress<'a, 'c> {
     where
         E: Into<Entid>,
     {
-        let known = Known::new(&self.schema, Some(&self.cache));
+        let known = self.known();
         lookup_value_for_attribute(&*(self.transaction), known, entity, attribute)
     }
 }
@@ -0,0 +1,293 @@
+// Copyright 2018 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Queries that stay up to date.
+//!
+//! A live query is a Datalog query, its inputs, and a callback. When a transaction that touches
+//! any attribute the query depends on is about to commit, the query is re-run inside that
+//! transaction, so that it sees exactly the committed state. Once the commit succeeds, the
+//! callback is handed the rows that appeared and disappeared.
+//!
+//! Dependencies are computed by the algebrizer. Queries that read datoms of unknown attributes
+//! (`[?e _ ?v]`) or the transaction log depend on everything, and every query is re-run -- and
+//! its dependencies recomputed -- when the schema changes, or when the store's metadata is
+//! replaced wholesale: by undo, redo, or switching branches, or by restoring a backup or noticing
+//! another connection's writes.
+//!
+//! Like `TxObserver` notifications, callbacks run on a dedicated thread, in commit order.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+
+use std::hash::{Hash, Hasher};
+
+use std::sync::mpsc::{channel, Receiver, SendError, Sender};
+use std::sync::Arc;
+
+use std::thread;
+
+use core_traits::Binding;
+
+use mentat_db::AttributeSet;
+
+use public_traits::errors::Result;
+
+use crate::query::{q_dependencies, q_once, AttributeDependencies, Known, QueryInputs};
+
+/// How the results of a live query changed.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct LiveQueryDiff {
+    /// Rows that are now in the results but weren't before.
+    pub added: Vec<Vec<Binding>>,
+
+    /// Rows that were in the results but no longer are.
+    pub removed: Vec<Vec<Binding>>,
+}
+
+impl LiveQueryDiff {
+    /// Compute the difference between two sets of rows. Rows are compared as multisets: a row
+    /// that appears twice before and once after has been removed once.
+    pub fn between(before: &[Vec<Binding>], after: &[Vec<Binding>]) -> LiveQueryDiff {
+        let mut unmatched: HashMap<u64, Vec<usize>> = HashMap::new();
+        for (i, row) in before.iter().enumerate() {
+            unmatched.entry(hash_row(row)).or_default().push(i);
+        }
+
+        let mut kept = vec![false; before.len()];
+        let mut added = vec![];
+        for row in after {
+            let candidates = unmatched.entry(hash_row(row)).or_default();
+            match candidates.iter().position(|&i| before[i] == *row) {
+                Some(p) => kept[candidates.swap_remove(p)] = true,
+                None => added.push(row.clone()),
+            }
+        }
+
+        let removed = before
+            .iter()
+            .zip(kept)
+            .filter(|&(_, kept)| !kept)
+            .map(|(row, _)| row.clone())
+            .collect();
+
+        LiveQueryDiff { added, removed }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+    }
+}
+
+fn hash_binding<H: Hasher>(binding: &Binding, state: &mut H) {
+    match binding {
+        Binding::Scalar(v) => v.hash(state),
+        Binding::Vec(vs) => {
+            for v in vs.iter() {
+                hash_binding(v, state);
+            }
+        }
+        // Maps compare equal regardless of order, so we can't hash their contents in order.
+        Binding::Map(m) => m.len().hash(state),
+    }
+}
+
+fn hash_row(row: &[Binding]) -> u64 {
+    let mut state = DefaultHasher::new();
+    for binding in row {
+        hash_binding(binding, &mut state);
+    }
+    state.finish()
+}
+
+type LiveQueryCallback = Arc<dyn Fn(&str, Result<LiveQueryDiff>) + Send + Sync>;
+
+struct LiveQuery {
+    query: String,
+    inputs: Option<QueryInputs>,
+    dependencies: AttributeDependencies,
+    rows: Vec<Vec<Binding>>,
+    callback: LiveQueryCallback,
+}
+
+/// A live query whose results might have changed. See `LiveQueryService::stale`.
+pub struct StaleLiveQuery {
+    key: String,
+    query: String,
+    inputs: Option<QueryInputs>,
+    // `None` if they, too, need recomputing.
+    dependencies: Option<AttributeDependencies>,
+}
+
+impl StaleLiveQuery {
+    /// Re-run this query against `sqlite` -- inside a transaction that is about to commit, or
+    /// after the store's metadata was replaced -- using the schema and caches in `known`.
+    pub fn refresh(self, sqlite: &rusqlite::Connection, known: Known) -> LiveQueryRefresh {
+        let StaleLiveQuery {
+            key,
+            query,
+            inputs,
+            dependencies,
+        } = self;
+        let result = match dependencies {
+            Some(dependencies) => Ok(dependencies),
+            None => q_dependencies(known, &query, inputs.clone()),
+        }
+        .and_then(|dependencies| {
+            q_once(sqlite, known, &query, inputs)
+                .map(|output| (dependencies, output.results.into_rows()))
+        });
+        LiveQueryRefresh { key, result }
+    }
+}
+
+/// The outcome of re-running one live query.
+pub struct LiveQueryRefresh {
+    key: String,
+    result: Result<(AttributeDependencies, Vec<Vec<Binding>>)>,
+}
+
+struct LiveQueryNotification {
+    key: String,
+    callback: LiveQueryCallback,
+    diff: Result<LiveQueryDiff>,
+}
+
+#[derive(Default)]
+pub struct LiveQueryService {
+    queries: BTreeMap<String, LiveQuery>,
+    executor: Option<Sender<LiveQueryNotification>>,
+}
+
+impl LiveQueryService {
+    pub fn new() -> Self {
+        LiveQueryService::default()
+    }
+
+    pub fn is_registered(&self, key: &str) -> bool {
+        self.queries.contains_key(key)
+    }
+
+    /// Watch `query`, whose current results are `rows`. Replaces any live query with the same key.
+    pub fn register<F>(
+        &mut self,
+        key: String,
+        query: String,
+        inputs: Option<QueryInputs>,
+        dependencies: AttributeDependencies,
+        rows: Vec<Vec<Binding>>,
+        callback: F,
+    ) where
+        F: Fn(&str, Result<LiveQueryDiff>) + Send + Sync + 'static,
+    {
+        self.queries.insert(
+            key,
+            LiveQuery {
+                query,
+                inputs,
+                dependencies,
+                rows,
+                callback: Arc::new(callback),
+            },
+        );
+    }
+
+    pub fn deregister(&mut self, key: &str) {
+        self.queries.remove(key);
+    }
+
+    /// The live queries that depend on any of the `touched` attributes, or every live query if
+    /// `everything` changed. They're copied out so that they can be re-run, with `StaleLiveQuery::
+    /// refresh`, without holding on to this service. Nothing is recorded until `did_commit` is
+    /// called with the results.
+    pub fn stale(&self, touched: &AttributeSet, everything: bool) -> Vec<StaleLiveQuery> {
+        self.queries
+            .iter()
+            .filter(|&(_, live)| everything || live.dependencies.intersects(touched))
+            .map(|(key, live)| StaleLiveQuery {
+                key: key.clone(),
+                query: live.query.clone(),
+                inputs: live.inputs.clone(),
+                dependencies: if everything {
+                    None
+                } else {
+                    Some(live.dependencies.clone())
+                },
+            })
+            .collect()
+    }
+
+    /// The transaction that produced `refreshed` committed, or the store whose metadata was
+    /// replaced produced them: remember the new results, and tell each affected live query how
+    /// its results changed.
+    pub fn did_commit(&mut self, refreshed: Vec<LiveQueryRefresh>) {
+        let mut notifications = vec![];
+        for LiveQueryRefresh { key, result } in refreshed {
+            // The query might have been deregistered in the meantime.
+            let live = match self.queries.get_mut(&key) {
+                Some(live) => live,
+                None => continue,
+            };
+            let diff = match result {
+                Ok((dependencies, rows)) => {
+                    let diff = LiveQueryDiff::between(&live.rows, &rows);
+                    live.dependencies = dependencies;
+                    live.rows = rows;
+                    if diff.is_empty() {
+                        continue;
+                    }
+                    Ok(diff)
+                }
+                Err(e) => Err(e),
+            };
+            notifications.push(LiveQueryNotification {
+                key,
+                callback: live.callback.clone(),
+                diff,
+            });
+        }
+
+        if notifications.is_empty() {
+            return;
+        }
+
+        for notification in notifications {
+            self.notify(notification);
+        }
+    }
+
+    fn notify(&mut self, notification: LiveQueryNotification) {
+        let notification = match self.executor().send(notification) {
+            Ok(()) => return,
+            Err(SendError(notification)) => notification,
+        };
+
+        // The executor thread has gone away -- a callback panicked -- so start another.
+        self.executor = None;
+        if self.executor().send(notification).is_err() {
+            warn!("Unable to notify live query: executor thread exited");
+        }
+    }
+
+    fn executor(&mut self) -> &Sender<LiveQueryNotification> {
+        self.executor.get_or_insert_with(|| {
+            let (tx, rx): (
+                Sender<LiveQueryNotification>,
+                Receiver<LiveQueryNotification>,
+            ) = channel();
+            thread::spawn(move || {
+                // Exits once the service, and with it the sender, is dropped.
+                for notification in rx {
+                    (notification.callback)(&notification.key, notification.diff);
+                }
+            });
+            tx
+        })
+    }
+}
@@ -6,17 +6,24 @@
 /// See https://github.com/mozilla/mentat/wiki/Thoughts:-modeling-db-conn-in-Rust.
 use std::sync::Arc;
 
+use core_traits::Entid;
+
 use mentat_core::Schema;
 
 use mentat_db::PartitionMap;
 
 use mentat_db::cache::SQLiteAttributeCache;
 
+use mentat_query_algebrizer::Known;
+
 pub struct Metadata {
     pub generation: u64,
     pub partition_map: PartitionMap,
     pub schema: Arc<Schema>,
     pub attribute_cache: SQLiteAttributeCache,
+
+    /// Transactions before this one have had their history pruned. See `mentat_db::history`.
+    pub history_horizon: Option<Entid>,
 }
 
 impl Metadata {
@@ -32,6 +39,16 @@ impl Metadata {
             partition_map,
             schema,
             attribute_cache: cache,
+            history_horizon: None,
+        }
+    }
+
+    /// What queries against this metadata can know without asking the store.
+    pub fn known(&self) -> Known<'_, '_> {
+        Known {
+            schema: &self.schema,
+            cache: Some(&self.attribute_cache),
+            history_horizon: self.history_horizon,
         }
     }
 }
@@ -13,15 +13,19 @@ use rusqlite::types::ToSql;
 
 use std::rc::Rc;
 
-use core_traits::{Binding, Entid, KnownEntid, TypedValue};
+use std::sync::Arc;
+
+use core_traits::{Binding, Entid, KnownEntid, TypedValue, ValueType};
 
 use mentat_core::{HasSchema, Schema};
 
+use mentat_db::TypedSQLValue;
+
 use mentat_query_algebrizer::{
     algebrize_with_inputs, parse_find_string, AlgebraicQuery, EmptyBecause, FindQuery,
 };
 
-pub use mentat_query_algebrizer::QueryInputs;
+pub use mentat_query_algebrizer::{AttributeDependencies, QueryInputs};
 
 pub use edn::query::{Keyword, PlainSymbol, Variable};
 
@@ -35,6 +39,8 @@ use mentat_query_projector::translate::{query_to_select, ProjectedSelect};
 
 use mentat_sql::SQLQuery;
 
+use mentat_query_sql::parameter_name;
+
 pub use mentat_query_algebrizer::Known;
 
 pub use mentat_query_projector::{
@@ -45,12 +51,14 @@ pub use mentat_query_projector::{
 
 use public_traits::errors::{MentatError, Result};
 
+use crate::query_plan_cache::{CachedQuery, QueryPlan, QueryPlanCache, QueryPlanKey};
+
 pub type QueryExecutionResult = Result<QueryOutput>;
 pub type PreparedResult<'sqlite> = Result<PreparedQuery<'sqlite>>;
 
 pub enum PreparedQuery<'sqlite> {
     Empty {
-        find_spec: Rc<FindSpec>,
+        find_spec: Arc<FindSpec>,
     },
     Constant {
         select: ConstantProjector,
@@ -61,6 +69,7 @@ pub enum PreparedQuery<'sqlite> {
         connection: &'sqlite rusqlite::Connection,
         args: Vec<(String, Rc<rusqlite::types::Value>)>,
         projector: Box<dyn Projector>,
+        find_spec: Arc<FindSpec>,
     },
 }
 
@@ -80,6 +89,7 @@ impl<'sqlite> PreparedQuery<'sqlite> {
                 ref connection,
                 ref args,
                 ref projector,
+                ..
             } => {
                 let rows = run_statement(statement, args)?;
                 projector
@@ -90,6 +100,114 @@ impl<'sqlite> PreparedQuery<'sqlite> {
     }
 }
 
+impl<'sqlite> PreparedQuery<'sqlite> {
+    /// Run this query, producing its results a row at a time rather than collecting them.
+    /// Each row has one binding per element of the find spec. Rows are read from SQLite a page
+    /// at a time; dropping the stream abandons the query, so results can be consumed partially
+    /// without reading every row.
+    pub fn stream(&mut self) -> Result<QueryStream<'_>> {
+        match self {
+            PreparedQuery::Empty { ref find_spec } => Ok(QueryStream {
+                spec: find_spec.clone(),
+                source: QueryStreamSource::Constant(vec![].into_iter()),
+            }),
+            PreparedQuery::Constant { ref select } => {
+                let QueryOutput { spec, results } = select.project_without_rows()?;
+                Ok(QueryStream {
+                    spec,
+                    source: QueryStreamSource::Constant(results.into_rows().into_iter()),
+                })
+            }
+            PreparedQuery::Bound {
+                ref mut statement,
+                ref schema,
+                connection,
+                ref args,
+                ref projector,
+                ref find_spec,
+            } => {
+                let rows = run_statement(statement, args)?;
+                Ok(QueryStream {
+                    spec: find_spec.clone(),
+                    source: QueryStreamSource::Rows {
+                        rows,
+                        schema,
+                        connection,
+                        projector: projector.as_ref(),
+                        page: vec![].into_iter(),
+                        exhausted: false,
+                    },
+                })
+            }
+        }
+    }
+}
+
+/// How many rows a `QueryStream` reads from SQLite at a time. Pull expressions are run once for
+/// each page of rows.
+const QUERY_STREAM_PAGE_SIZE: usize = 64;
+
+enum QueryStreamSource<'stmt> {
+    Constant(::std::vec::IntoIter<Vec<Binding>>),
+    Rows {
+        rows: rusqlite::Rows<'stmt>,
+        schema: &'stmt Schema,
+        connection: &'stmt rusqlite::Connection,
+        projector: &'stmt dyn Projector,
+        page: ::std::vec::IntoIter<Vec<Binding>>,
+        exhausted: bool,
+    },
+}
+
+/// Results of a query, yielded one row at a time. See `PreparedQuery::stream`.
+pub struct QueryStream<'stmt> {
+    spec: Arc<FindSpec>,
+    source: QueryStreamSource<'stmt>,
+}
+
+impl<'stmt> QueryStream<'stmt> {
+    /// The find spec of the query producing these rows.
+    pub fn spec(&self) -> &Arc<FindSpec> {
+        &self.spec
+    }
+}
+
+impl<'stmt> Iterator for QueryStream<'stmt> {
+    type Item = Result<Vec<Binding>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.source {
+            QueryStreamSource::Constant(ref mut rows) => rows.next().map(Ok),
+            QueryStreamSource::Rows {
+                ref mut rows,
+                schema,
+                connection,
+                projector,
+                ref mut page,
+                ref mut exhausted,
+            } => {
+                if let Some(row) = page.next() {
+                    return Some(Ok(row));
+                }
+                if *exhausted {
+                    return None;
+                }
+                match projector.project_page(schema, connection, rows, QUERY_STREAM_PAGE_SIZE) {
+                    Ok(next) => {
+                        *exhausted = next.len() < QUERY_STREAM_PAGE_SIZE;
+                        *page = next.into_iter();
+                        page.next().map(Ok)
+                    }
+                    Err(e) => {
+                        *exhausted = true;
+                        Some(Err(e.into()))
+                    }
+                }
+            }
+        }
+    }
+}
+
 pub trait IntoResult {
     fn into_scalar_result(self) -> Result<Option<Binding>>;
     fn into_coll_result(self) -> Result<Vec<Binding>>;
@@ -214,7 +332,7 @@ where
     let entid = entity.into();
     let attrid = attribute.into();
 
-    if known.is_attribute_cached_forward(attrid) {
+    if known.is_entity_cached_forward(attrid, entid) {
         Ok(known
             .get_value_for_entid(known.schema, attrid, entid)
             .cloned())
@@ -239,7 +357,7 @@ where
     let entid = entity.into();
     let attrid = attribute.into();
 
-    if known.is_attribute_cached_forward(attrid) {
+    if known.is_entity_cached_forward(attrid, entid) {
         Ok(known
             .get_values_for_entid(known.schema, attrid, entid)
             .cloned()
@@ -282,18 +400,28 @@ where
     lookup_values(sqlite, known, entity.into(), attribute)
 }
 
-fn run_statement<'sqlite, 'stmt, 'bound>(
+fn run_statement<'sqlite, 'stmt, 'bound, V>(
     statement: &'stmt mut rusqlite::Statement<'sqlite>,
-    bindings: &'bound [(String, Rc<rusqlite::types::Value>)],
+    bindings: &'bound [(String, V)],
+) -> Result<rusqlite::Rows<'stmt>>
+where
+    V: ToSql,
+{
+    let refs: Vec<(&str, &dyn ToSql)> = bindings
+        .iter()
+        .map(|(k, v)| (k.as_str(), v as &dyn ToSql))
+        .collect();
+    run_statement_with_refs(statement, &refs)
+}
+
+fn run_statement_with_refs<'sqlite, 'stmt>(
+    statement: &'stmt mut rusqlite::Statement<'sqlite>,
+    refs: &[(&str, &dyn ToSql)],
 ) -> Result<rusqlite::Rows<'stmt>> {
-    let rows = if bindings.is_empty() {
+    let rows = if refs.is_empty() {
         statement.query(rusqlite::params![])?
     } else {
-        let refs: Vec<(&str, &dyn ToSql)> = bindings
-            .iter()
-            .map(|&(ref k, ref v)| (k.as_str(), v.as_ref() as &dyn ToSql))
-            .collect();
-        statement.query_named(&refs)?
+        statement.query_named(refs)?
     };
     Ok(rows)
 }
@@ -333,33 +461,164 @@ fn run_algebrized_query<'sqlite>(
     sqlite: &'sqlite rusqlite::Connection,
     algebrized: AlgebraicQuery,
 ) -> QueryExecutionResult {
-    assert!(
-        algebrized.unbound_variables().is_empty(),
-        "Unbound variables should be checked by now"
-    );
+    let plan = plan_algebrized_query(known, algebrized)?;
+    run_query_plan(known.schema, sqlite, &plan, None)
+}
+
+/// Plan `query` for any values of `inputs` of the same types, unless its plan depends on those
+/// values.
+fn plan_query_str(known: Known, query: &str, inputs: Option<&QueryInputs>) -> Result<CachedQuery> {
+    let typed = inputs.map(QueryInputs::without_values).unwrap_or_default();
+
+    // A keyword might be an ident, to be resolved to an entid, depending on where it's used.
+    if typed.types().values().any(|t| *t == ValueType::Keyword) {
+        return Ok(CachedQuery::ValueDependent);
+    }
+
+    let parsed = parse_find_string(query)?;
+    let algebrized = match algebrize_with_inputs(known, parsed, 0, typed.clone()) {
+        Ok(algebrized) => algebrized,
+
+        // Some clauses, such as functions, need the values of inputs they're passed. Planning
+        // with those values reports any other errors.
+        Err(_) => return Ok(CachedQuery::ValueDependent),
+    };
+
+    // Every input must be supplied as a parameter: one only used by a predicate, say, isn't.
+    let unbound = algebrized.unbound_variables();
+    if algebrized.parameters() != unbound
+        || !unbound.iter().all(|var| typed.types().contains_key(var))
+    {
+        return Ok(CachedQuery::ValueDependent);
+    }
+    Ok(CachedQuery::Planned(plan_algebrized_query(
+        known, algebrized,
+    )?))
+}
+
+/// Do all of the work of running an algebrized query that doesn't involve SQLite, producing a
+/// plan that can be run repeatedly. Any unbound input variables must be parameters: see
+/// `AlgebraicQuery::parameters`.
+fn plan_algebrized_query(known: Known, algebrized: AlgebraicQuery) -> Result<QueryPlan> {
+    let parameters: Vec<Variable> = algebrized.parameters().into_iter().collect();
     if algebrized.is_known_empty() {
-        // We don't need to do any SQL work at all.
-        return Ok(QueryOutput::empty(&algebrized.find_spec));
+        return Ok(QueryPlan::Empty {
+            find_spec: algebrized.find_spec,
+        });
     }
 
-    let select = query_to_select(known.schema, algebrized)?;
-    match select {
-        ProjectedSelect::Constant(constant) => {
-            constant.project_without_rows().map_err(|e| e.into())
-        }
+    match query_to_select(known.schema, algebrized)? {
+        ProjectedSelect::Constant(projector) => Ok(QueryPlan::Constant { projector }),
         ProjectedSelect::Query { query, projector } => {
             let SQLQuery { sql, args } = query.to_sql_query()?;
+            // Plans are shared across threads, so we can't keep the `Rc`s.
+            let args = args
+                .into_iter()
+                .map(|(k, v)| (k, Rc::try_unwrap(v).unwrap_or_else(|v| (*v).clone())))
+                .collect();
+            Ok(QueryPlan::Query {
+                sql,
+                args,
+                parameters,
+                projector,
+            })
+        }
+    }
+}
 
-            let mut statement = sqlite.prepare(sql.as_str())?;
-            let rows = run_statement(&mut statement, &args)?;
+fn run_query_plan(
+    schema: &Schema,
+    sqlite: &rusqlite::Connection,
+    plan: &QueryPlan,
+    inputs: Option<&QueryInputs>,
+) -> QueryExecutionResult {
+    match plan {
+        QueryPlan::Empty { ref find_spec } => Ok(QueryOutput::empty(find_spec)),
+        QueryPlan::Constant { ref projector } => {
+            projector.project_without_rows().map_err(|e| e.into())
+        }
+        QueryPlan::Query {
+            ref sql,
+            ref args,
+            ref parameters,
+            ref projector,
+        } => {
+            let values = inputs.map(|inputs| inputs.values());
+            let mut bound = Vec::with_capacity(parameters.len());
+            for var in parameters {
+                match values.and_then(|values| values.get(var)) {
+                    Some(value) => bound.push((
+                        format!("${}", parameter_name(var)),
+                        value.to_sql_value_pair().0,
+                    )),
+                    None => bail!(MentatError::UnboundVariables(
+                        vec![var.to_string()].into_iter().collect()
+                    )),
+                }
+            }
 
+            let refs: Vec<(&str, &dyn ToSql)> = args
+                .iter()
+                .map(|(k, v)| (k.as_str(), v as &dyn ToSql))
+                .chain(bound.iter().map(|(k, v)| (k.as_str(), v as &dyn ToSql)))
+                .collect();
+            let mut statement = sqlite.prepare_cached(sql.as_str())?;
+            let rows = run_statement_with_refs(&mut statement, &refs)?;
             projector
-                .project(known.schema, sqlite, rows)
+                .project(schema, sqlite, rows)
                 .map_err(|e| e.into())
         }
     }
 }
 
+/// Like `q_once`, but consult `plans` before parsing and translating the query, and retain the
+/// resulting plan for later use. `generation` is the metadata generation of the caller: see
+/// `QueryPlanCache` for how it is used.
+pub fn q_once_with_plan_cache<T>(
+    sqlite: &rusqlite::Connection,
+    known: Known,
+    plans: &QueryPlanCache,
+    generation: u64,
+    query: &str,
+    inputs: T,
+) -> QueryExecutionResult
+where
+    T: Into<Option<QueryInputs>>,
+{
+    let inputs = inputs.into();
+
+    // Plans are shared by inputs of the same types. Inputs with types but no values aren't
+    // bound, which `q_once` reports.
+    let partially_bound = match inputs {
+        Some(ref inputs) => inputs.types().len() != inputs.values().len(),
+        None => false,
+    };
+    if partially_bound {
+        return q_once(sqlite, known, query, inputs);
+    }
+
+    let key = QueryPlanKey::new(query, inputs.as_ref());
+    let cached = match plans.get(&key, generation) {
+        Some(cached) => cached,
+        None => {
+            let uses_cache = known
+                .cache
+                .map(|cache| cache.has_cached_attributes())
+                .unwrap_or(false);
+            let cached = Arc::new(plan_query_str(known, query, inputs.as_ref())?);
+            plans.insert(key, cached.clone(), generation, uses_cache);
+            cached
+        }
+    };
+
+    match *cached {
+        CachedQuery::Planned(ref plan) => {
+            run_query_plan(known.schema, sqlite, plan, inputs.as_ref())
+        }
+        CachedQuery::ValueDependent => q_once(sqlite, known, query, inputs),
+    }
+}
+
 /// Take an EDN query string, a reference to an open SQLite connection, a Mentat schema, and an
 /// optional collection of input bindings (which should be keyed by `"?varname"`), and execute the
 /// query immediately, blocking the current thread.
@@ -423,6 +682,7 @@ where
         });
     }
 
+    let find_spec = algebrized.find_spec.clone();
     let select = query_to_select(known.schema, algebrized)?;
     match select {
         ProjectedSelect::Constant(constant) => Ok(PreparedQuery::Constant { select: constant }),
@@ -436,11 +696,21 @@ where
                 connection: sqlite,
                 args,
                 projector,
+                find_spec,
             })
         }
     }
 }
 
+/// Return the attributes whose datoms can affect the results of `query`.
+pub fn q_dependencies<T>(known: Known, query: &str, inputs: T) -> Result<AttributeDependencies>
+where
+    T: Into<Option<QueryInputs>>,
+{
+    let algebrized = algebrize_query_str(known, query, inputs)?;
+    Ok(algebrized.cc.attribute_dependencies())
+}
+
 pub fn q_explain<'sqlite, 'query, T>(
     sqlite: &'sqlite rusqlite::Connection,
     known: Known,
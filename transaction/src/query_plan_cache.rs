@@ -0,0 +1,283 @@
+// Copyright 2018 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! A bounded cache of translated queries, shared across threads by a `Conn`.
+//!
+//! Parsing, algebrizing, and translating a query to SQL is deterministic given the query text,
+//! the types of its inputs, and the schema, so repeated calls to `q_once` can skip straight to
+//! running SQL.
+//!
+//! Plans are keyed on the types of inputs, not their values: input values are bound as SQL
+//! parameters each time a plan runs, so running a query with new arguments reuses its plan. A
+//! query whose plan depends on the values themselves -- one that resolves a keyword input as an
+//! ident, or passes an input to a function -- is recorded as such, and planned afresh each run.
+//!
+//! Plans are invalidated wholesale when the schema changes. The algebrizer can also consult the
+//! in-memory attribute cache and bake cached values into a plan; plans built while any attribute
+//! is cached are therefore only reused within the metadata generation that produced them.
+
+use std::collections::{BTreeMap, HashMap};
+
+use std::sync::{Arc, Mutex};
+
+use core_traits::ValueType;
+
+use mentat_core::DatomsView;
+
+use edn::query::{FindSpec, SrcVar, Variable};
+
+use mentat_query_algebrizer::QueryInputs;
+
+use mentat_query_projector::{ConstantProjector, Projector};
+
+/// The number of plans a `Conn` retains by default.
+pub const DEFAULT_QUERY_PLAN_CACHE_CAPACITY: usize = 128;
+
+/// Identifies a query plan: the query text, the types of its inputs, and the views bound to its
+/// sources.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct QueryPlanKey {
+    query: String,
+    types: Vec<(Variable, ValueType)>,
+    sources: Vec<(SrcVar, DatomsView)>,
+}
+
+impl QueryPlanKey {
+    pub fn new(query: &str, inputs: Option<&QueryInputs>) -> QueryPlanKey {
+        let (types, sources) = match inputs {
+            None => (vec![], vec![]),
+            Some(inputs) => (
+                inputs
+                    .types()
+                    .iter()
+                    .map(|(var, t)| (var.clone(), *t))
+                    .collect(),
+                inputs
+                    .sources()
+                    .iter()
+                    .map(|(source, view)| (source.clone(), *view))
+                    .collect(),
+            ),
+        };
+        QueryPlanKey {
+            query: query.to_string(),
+            types,
+            sources,
+        }
+    }
+}
+
+/// Everything `q_once` computes before it touches SQLite.
+pub enum QueryPlan {
+    /// The query is known to produce no results.
+    Empty { find_spec: Arc<FindSpec> },
+
+    /// The query produces results without running SQL.
+    Constant { projector: ConstantProjector },
+
+    /// The query must run the given SQL, binding the values of `parameters`, and project the
+    /// resulting rows.
+    Query {
+        sql: String,
+        args: Vec<(String, rusqlite::types::Value)>,
+        parameters: Vec<Variable>,
+        projector: Box<dyn Projector>,
+    },
+}
+
+/// What the cache retains for a query.
+pub enum CachedQuery {
+    /// A plan to run with the values of the query's inputs.
+    Planned(QueryPlan),
+
+    /// The query can't be planned without the values of its inputs, so each run plans it anew.
+    ValueDependent,
+}
+
+/// Counters describing how well a `QueryPlanCache` is doing.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct QueryPlanCacheStats {
+    /// Lookups that found a usable plan.
+    pub hits: u64,
+
+    /// Lookups that had to plan the query from scratch.
+    pub misses: u64,
+
+    /// Plans discarded to stay within capacity.
+    pub evictions: u64,
+
+    /// The number of times the cache was emptied because the schema changed.
+    pub invalidations: u64,
+
+    /// The number of plans currently held.
+    pub entries: usize,
+
+    /// The maximum number of plans held at once.
+    pub capacity: usize,
+}
+
+struct CachedPlan {
+    plan: Arc<CachedQuery>,
+
+    /// `Some(generation)` if this plan depends on the contents of the attribute cache as of
+    /// `generation`, and so must not be used by any other generation.
+    generation: Option<u64>,
+
+    /// Tick of the most recent use: this plan's key in `QueryPlanCacheInner::recency`.
+    last_used: u64,
+}
+
+struct QueryPlanCacheInner {
+    capacity: usize,
+
+    /// The metadata generation at which the schema last changed. Plans are only built and used by
+    /// readers whose generation is at least this.
+    schema_generation: u64,
+
+    tick: u64,
+    plans: HashMap<QueryPlanKey, CachedPlan>,
+
+    /// The key of every plan, by the tick of its most recent use, least recent first.
+    recency: BTreeMap<u64, QueryPlanKey>,
+
+    stats: QueryPlanCacheStats,
+}
+
+pub struct QueryPlanCache {
+    inner: Mutex<QueryPlanCacheInner>,
+}
+
+impl Default for QueryPlanCache {
+    fn default() -> QueryPlanCache {
+        QueryPlanCache::with_capacity(DEFAULT_QUERY_PLAN_CACHE_CAPACITY)
+    }
+}
+
+impl QueryPlanCache {
+    pub fn with_capacity(capacity: usize) -> QueryPlanCache {
+        QueryPlanCache {
+            inner: Mutex::new(QueryPlanCacheInner {
+                capacity,
+                schema_generation: 0,
+                tick: 0,
+                plans: HashMap::new(),
+                recency: BTreeMap::new(),
+                stats: QueryPlanCacheStats {
+                    capacity,
+                    ..Default::default()
+                },
+            }),
+        }
+    }
+
+    /// Find a plan usable by a reader at metadata generation `generation`.
+    pub fn get(&self, key: &QueryPlanKey, generation: u64) -> Option<Arc<CachedQuery>> {
+        let mut inner = self.inner.lock().unwrap();
+        if generation < inner.schema_generation {
+            // This reader predates the current schema; it can neither use nor populate the cache.
+            return None;
+        }
+
+        inner.tick += 1;
+        let tick = inner.tick;
+        let found = match inner.plans.get_mut(key) {
+            Some(ref mut cached)
+                if cached.generation.is_none() || cached.generation == Some(generation) =>
+            {
+                let last_used = cached.last_used;
+                cached.last_used = tick;
+                Some((cached.plan.clone(), last_used))
+            }
+            _ => None,
+        };
+
+        match found {
+            Some((plan, last_used)) => {
+                inner.recency.remove(&last_used);
+                inner.recency.insert(tick, key.clone());
+                inner.stats.hits += 1;
+                Some(plan)
+            }
+            None => {
+                inner.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Retain a plan built by a reader at metadata generation `generation`. If `uses_cache` is
+    /// true, the plan may include values from the attribute cache, and is only reusable within
+    /// the same generation.
+    pub fn insert(
+        &self,
+        key: QueryPlanKey,
+        plan: Arc<CachedQuery>,
+        generation: u64,
+        uses_cache: bool,
+    ) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.capacity == 0 || generation < inner.schema_generation {
+            return;
+        }
+
+        if !inner.plans.contains_key(&key) && inner.plans.len() >= inner.capacity {
+            inner.evict_one();
+        }
+
+        inner.tick += 1;
+        let tick = inner.tick;
+        let cached = CachedPlan {
+            plan,
+            generation: if uses_cache { Some(generation) } else { None },
+            last_used: tick,
+        };
+        inner.recency.insert(tick, key.clone());
+        if let Some(replaced) = inner.plans.insert(key, cached) {
+            inner.recency.remove(&replaced.last_used);
+        }
+        inner.stats.entries = inner.plans.len();
+    }
+
+    /// Discard every plan: the schema changed at metadata generation `generation`.
+    pub fn invalidate(&self, generation: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.schema_generation = generation;
+        inner.plans.clear();
+        inner.recency.clear();
+        inner.stats.invalidations += 1;
+        inner.stats.entries = 0;
+    }
+
+    /// Change the maximum number of plans held, evicting the least recently used as necessary.
+    /// A capacity of zero disables the cache.
+    pub fn set_capacity(&self, capacity: usize) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.capacity = capacity;
+        inner.stats.capacity = capacity;
+        while inner.plans.len() > capacity {
+            inner.evict_one();
+        }
+    }
+
+    pub fn stats(&self) -> QueryPlanCacheStats {
+        self.inner.lock().unwrap().stats
+    }
+}
+
+impl QueryPlanCacheInner {
+    fn evict_one(&mut self) {
+        let lru = self.recency.keys().next().cloned();
+        if let Some(key) = lru.and_then(|tick| self.recency.remove(&tick)) {
+            self.plans.remove(&key);
+            self.stats.evictions += 1;
+            self.stats.entries = self.plans.len();
+        }
+    }
+}
@@ -0,0 +1,377 @@
+// Copyright 2018 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! A change feed of committed transactions.
+//!
+//! `TxObserver` callbacks run on a shared thread, so one slow observer delays every other, and
+//! none of them sees the datoms that were transacted. A `TxReportReceiver` is instead a bounded
+//! queue owned by its consumer: each commit appends the reports of the transactions it contains,
+//! together with their datoms, in commit order.
+//!
+//! The writer never waits for a consumer. When a queue is full the oldest entry is dropped, and
+//! the consumer is told how many entries it missed before it sees the next one. When the store's
+//! contents change other than by transacting, the consumer is told that instead. Queues are closed
+//! when their `Conn` is dropped.
+
+use std::collections::VecDeque;
+
+use std::error::Error;
+use std::fmt;
+
+use std::pin::Pin;
+
+use std::sync::{Arc, Condvar, Mutex, Weak};
+
+use std::task::{Context, Poll, Waker};
+
+use std::time::{Duration, Instant};
+
+use futures::stream::Stream;
+
+use core_traits::{Entid, TypedValue};
+
+use mentat_core::{Schema, TxReport};
+
+use mentat_db::{db, AttributeSet};
+
+use public_traits::errors::{MentatError, Result};
+
+/// One datom asserted or retracted by a transaction.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialOrd, PartialEq)]
+pub struct TxDatom {
+    pub e: Entid,
+    pub a: Entid,
+    pub v: TypedValue,
+    pub added: bool,
+}
+
+/// A committed transaction: its report, and the datoms it wrote. When the queue filters by
+/// attribute, only datoms of those attributes are included.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CommittedTransaction {
+    pub report: TxReport,
+    pub datoms: Vec<TxDatom>,
+}
+
+/// Why a `TxReportReceiver` didn't produce a transaction.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TxReportQueueError {
+    /// `try_recv` found nothing waiting.
+    Empty,
+
+    /// `recv_timeout` waited without anything arriving.
+    Timeout,
+
+    /// The queue overflowed, and this many of the oldest transactions were dropped. Later calls
+    /// continue with the oldest transaction that was kept.
+    Lagged(u64),
+
+    /// The store's contents were replaced other than by transacting: by undo, redo, switching
+    /// branches, restoring a backup, or another connection writing to the store. Transactions
+    /// still queued from before then were discarded; re-read whatever was derived from the store.
+    /// Later calls continue with transactions committed afterwards.
+    Replaced,
+
+    /// The `Conn` has gone away, and every queued transaction has been received.
+    Closed,
+}
+
+impl fmt::Display for TxReportQueueError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TxReportQueueError::Empty => write!(f, "no transaction waiting"),
+            TxReportQueueError::Timeout => write!(f, "timed out waiting for a transaction"),
+            TxReportQueueError::Lagged(n) => write!(f, "lagged: {} transactions dropped", n),
+            TxReportQueueError::Replaced => write!(f, "store contents replaced"),
+            TxReportQueueError::Closed => write!(f, "transaction report queue closed"),
+        }
+    }
+}
+
+impl Error for TxReportQueueError {}
+
+struct QueueState {
+    buffer: VecDeque<CommittedTransaction>,
+    missed: u64,
+    replaced: bool,
+    closed: bool,
+    waker: Option<Waker>,
+}
+
+struct Queue {
+    capacity: usize,
+    attributes: Option<AttributeSet>,
+    state: Mutex<QueueState>,
+    available: Condvar,
+}
+
+impl Queue {
+    fn push(&self, transactions: &[CommittedTransaction]) {
+        let mut state = self.state.lock().unwrap();
+        let mut pushed = false;
+        for transaction in transactions {
+            let transaction = match self.attributes {
+                None => transaction.clone(),
+                Some(ref attributes) => {
+                    let datoms: Vec<TxDatom> = transaction
+                        .datoms
+                        .iter()
+                        .filter(|datom| attributes.contains(&datom.a))
+                        .cloned()
+                        .collect();
+                    if datoms.is_empty() {
+                        continue;
+                    }
+                    CommittedTransaction {
+                        report: transaction.report.clone(),
+                        datoms,
+                    }
+                }
+            };
+            if state.buffer.len() == self.capacity {
+                state.buffer.pop_front();
+                state.missed += 1;
+            }
+            state.buffer.push_back(transaction);
+            pushed = true;
+        }
+        if pushed {
+            self.wake(&mut state);
+        }
+    }
+
+    fn replace(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.buffer.clear();
+        state.missed = 0;
+        state.replaced = true;
+        self.wake(&mut state);
+    }
+
+    fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        self.wake(&mut state);
+    }
+
+    fn wake(&self, state: &mut QueueState) {
+        self.available.notify_all();
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// Take the next result out of `state`, if there is one to be had without waiting.
+fn take(
+    state: &mut QueueState,
+) -> Option<::std::result::Result<CommittedTransaction, TxReportQueueError>> {
+    if state.replaced {
+        state.replaced = false;
+        return Some(Err(TxReportQueueError::Replaced));
+    }
+    if state.missed > 0 {
+        let missed = state.missed;
+        state.missed = 0;
+        return Some(Err(TxReportQueueError::Lagged(missed)));
+    }
+    if let Some(transaction) = state.buffer.pop_front() {
+        return Some(Ok(transaction));
+    }
+    if state.closed {
+        return Some(Err(TxReportQueueError::Closed));
+    }
+    None
+}
+
+/// The consuming end of a transaction report queue. Receive from it by blocking, by polling, or
+/// as a `futures::Stream`. Dropping it unregisters the queue.
+pub struct TxReportReceiver {
+    queue: Arc<Queue>,
+}
+
+impl TxReportReceiver {
+    /// Wait for the next committed transaction.
+    pub fn recv(&self) -> ::std::result::Result<CommittedTransaction, TxReportQueueError> {
+        let mut state = self.queue.state.lock().unwrap();
+        loop {
+            if let Some(result) = take(&mut state) {
+                return result;
+            }
+            state = self.queue.available.wait(state).unwrap();
+        }
+    }
+
+    /// Return the next committed transaction, or `TxReportQueueError::Empty` if none is waiting.
+    pub fn try_recv(&self) -> ::std::result::Result<CommittedTransaction, TxReportQueueError> {
+        let mut state = self.queue.state.lock().unwrap();
+        take(&mut state).unwrap_or(Err(TxReportQueueError::Empty))
+    }
+
+    /// Wait up to `timeout` for the next committed transaction.
+    pub fn recv_timeout(
+        &self,
+        timeout: Duration,
+    ) -> ::std::result::Result<CommittedTransaction, TxReportQueueError> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.queue.state.lock().unwrap();
+        loop {
+            if let Some(result) = take(&mut state) {
+                return result;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(TxReportQueueError::Timeout);
+            }
+            state = self
+                .queue
+                .available
+                .wait_timeout(state, deadline - now)
+                .unwrap()
+                .0;
+        }
+    }
+
+    /// The number of transactions waiting to be received.
+    pub fn len(&self) -> usize {
+        self.queue.state.lock().unwrap().buffer.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.queue.capacity
+    }
+}
+
+/// Yields `Ok` for each transaction, `Err(TxReportQueueError::Lagged(_))` after an overflow, and
+/// `Err(TxReportQueueError::Replaced)` when the store's contents are replaced. The stream ends
+/// when the queue is closed.
+impl Stream for TxReportReceiver {
+    type Item = ::std::result::Result<CommittedTransaction, TxReportQueueError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let mut state = self.queue.state.lock().unwrap();
+        match take(&mut state) {
+            Some(Err(TxReportQueueError::Closed)) => Poll::Ready(None),
+            Some(result) => Poll::Ready(Some(result)),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// The queues attached to a `Conn`. Receivers own their queues; we only hold weak references, so
+/// a dropped receiver costs nothing beyond the next publish.
+#[derive(Default)]
+pub struct TxReportQueues {
+    queues: Vec<Weak<Queue>>,
+}
+
+impl TxReportQueues {
+    pub fn new() -> Self {
+        TxReportQueues::default()
+    }
+
+    /// Make a queue holding at most `capacity` transactions, and optionally only those that
+    /// touch `attributes`.
+    pub fn subscribe(
+        &mut self,
+        capacity: usize,
+        attributes: Option<AttributeSet>,
+    ) -> Result<TxReportReceiver> {
+        if capacity == 0 {
+            bail!(MentatError::ZeroQueueCapacity);
+        }
+        let queue = Arc::new(Queue {
+            capacity,
+            attributes,
+            state: Mutex::new(QueueState {
+                buffer: VecDeque::with_capacity(capacity),
+                missed: 0,
+                replaced: false,
+                closed: false,
+                waker: None,
+            }),
+            available: Condvar::new(),
+        });
+        self.queues.push(Arc::downgrade(&queue));
+        Ok(TxReportReceiver { queue })
+    }
+
+    /// True if nobody could be listening. Writers use this to avoid collecting datoms at all.
+    pub fn is_empty(&self) -> bool {
+        self.queues.iter().all(|queue| queue.strong_count() == 0)
+    }
+
+    /// Deliver `transactions`, which have just committed, to every live queue.
+    pub fn publish(&mut self, transactions: &[CommittedTransaction]) {
+        self.queues.retain(|queue| match queue.upgrade() {
+            Some(queue) => {
+                queue.push(transactions);
+                true
+            }
+            None => false,
+        });
+    }
+
+    /// Tell every live queue that the store's contents were replaced other than by transacting.
+    pub fn replaced(&mut self) {
+        self.queues.retain(|queue| match queue.upgrade() {
+            Some(queue) => {
+                queue.replace();
+                true
+            }
+            None => false,
+        });
+    }
+}
+
+impl Drop for TxReportQueues {
+    fn drop(&mut self) {
+        for queue in self.queues.iter().filter_map(|queue| queue.upgrade()) {
+            queue.close();
+        }
+    }
+}
+
+/// Read the datoms that `report`'s transaction wrote, from within the writing SQLite transaction.
+/// Returns `None` if the transaction was rolled back to a savepoint.
+pub(crate) fn committed_transaction(
+    conn: &rusqlite::Connection,
+    schema: &Schema,
+    report: TxReport,
+) -> Result<Option<CommittedTransaction>> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT e, a, v, value_type_tag, added FROM transactions WHERE tx = ? ORDER BY e ASC, a ASC, value_type_tag ASC, v ASC, added ASC",
+    )?;
+    let mut datoms = vec![];
+    let mut rows = stmt.query(&[&report.tx_id])?;
+    while let Some(row) = rows.next()? {
+        let a: Entid = row.get(1)?;
+        let v = db::datom_value(conn, schema, a, row.get(2)?, row.get(3)?)?;
+        datoms.push(TxDatom {
+            e: row.get(0)?,
+            a,
+            v,
+            added: row.get(4)?,
+        });
+    }
+
+    // Every transaction asserts at least its `:db/txInstant`.
+    if datoms.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(CommittedTransaction { report, datoms }))
+}